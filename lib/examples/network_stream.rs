@@ -0,0 +1,95 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Stream samples to a TCP server, reconnecting if the connection drops.
+//!
+//! `cargo run --example network_stream -- 192.168.1.10:5000`
+
+use ar2300::cancel;
+use ar2300::prelude::*;
+use std::error::Error;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+/**
+ A TCP connection that reconnects when a write fails. Whatever was being
+ written when the connection dropped is lost; the server should expect
+ to resynchronize on a sample boundary.
+ */
+struct ReconnectingStream {
+    address: String,
+    stream: Option<TcpStream>,
+}
+
+impl ReconnectingStream {
+    fn connected(&mut self) -> io::Result<&mut TcpStream> {
+        while self.stream.is_none() {
+            match TcpStream::connect(&self.address) {
+                Ok(stream) => self.stream = Some(stream),
+                Err(e) => {
+                    eprintln!("Couldn't connect to {}: {}; retrying", self.address, e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+}
+
+impl Write for ReconnectingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.connected()?.write(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                eprintln!("Connection to {} lost: {}", self.address, e);
+                self.stream = None;
+                // Drop this buffer rather than send a partial sample to the new connection
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let address = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:5000".to_string());
+
+    init_device(true, None)?;
+    let queue = new_queue();
+    let receiver = {
+        let (queue, cancel) = (queue.clone(), cancel::on_ctrlc()?);
+        thread::spawn(move || receive_until(queue, cancel, None, None))
+    };
+
+    let out = ReconnectingStream { address, stream: None };
+    let mut writer = Writer::new(queue, Box::new(out));
+    while writer.write(Duration::from_millis(100))? {}
+    writer.flush()?;
+    receiver.join().unwrap()?;
+    Ok(())
+}
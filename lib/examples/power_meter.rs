@@ -0,0 +1,61 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Print the signal power in dBFS once a second until Ctrl-C.
+
+use ar2300::cancel;
+use ar2300::prelude::*;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    init_device(true, None)?;
+    let queue = new_queue();
+    let receiver = {
+        let (queue, cancel) = (queue.clone(), cancel::on_ctrlc()?);
+        thread::spawn(move || receive_until(queue, cancel, None, None))
+    };
+
+    let mut batch = Vec::with_capacity(4096);
+    let mut power = 0.0f64;
+    let mut count = 0u64;
+    let mut last_report = Instant::now();
+    loop {
+        batch.clear();
+        if queue.drain_result(&mut batch, 4096, Duration::from_millis(100)) == DequeueResult::Closed {
+            break;
+        }
+        for &(i, q) in &batch {
+            // Samples are unsigned fractions of full scale; centre them on zero
+            let (i, q) = (2.0 * i as f64 - 1.0, 2.0 * q as f64 - 1.0);
+            power += (i * i + q * q) / 2.0;
+        }
+        count += batch.len() as u64;
+        if last_report.elapsed() >= Duration::from_secs(1) && count > 0 {
+            println!("{:7.2} dBFS", 10.0 * (power / count as f64).max(1e-20).log10());
+            power = 0.0;
+            count = 0;
+            last_report = Instant::now();
+        }
+    }
+    receiver.join().unwrap()?;
+    Ok(())
+}
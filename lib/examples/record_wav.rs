@@ -0,0 +1,56 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Record a fixed length of IQ data to a WAV file.
+//!
+//! `cargo run --example record_wav -- capture.wav 10` records ten seconds.
+
+use ar2300::prelude::*;
+use std::error::Error;
+use std::fs::File;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "capture.wav".to_string());
+    let seconds: u64 = args.next().map_or(Ok(10), |s| s.parse())?;
+
+    init_device(true, None)?;
+    let queue = new_queue();
+    let cancel = CancelToken::new();
+    let receiver = {
+        let (queue, cancel) = (queue.clone(), cancel.clone());
+        thread::spawn(move || receive_until(queue, cancel, None, None))
+    };
+
+    let mut writer = WavWriter::new(queue, File::create(&path)?)?;
+    let started = Instant::now();
+    // Keep writing after cancelling, until the receiver closes the queue
+    while writer.write(Duration::from_millis(100))? {
+        if started.elapsed() >= Duration::from_secs(seconds) {
+            cancel.cancel();
+        }
+    }
+    writer.flush()?;
+    receiver.join().unwrap()?;
+    println!("Recorded {} seconds to {}", seconds, path);
+    Ok(())
+}
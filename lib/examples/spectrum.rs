@@ -0,0 +1,71 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Print the strongest frequency bin of each second of samples until Ctrl-C.
+
+use ar2300::cancel;
+use ar2300::iq::SAMPLE_RATE;
+use ar2300::prelude::*;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::thread;
+use std::time::Duration;
+
+const BINS: usize = 256;
+
+/** The power in each bin of a discrete Fourier transform of the samples. */
+fn spectrum(samples: &[(f32,f32)]) -> Vec<f64> {
+    let n = samples.len() as f64;
+    (0..samples.len()).map(|k| {
+        let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |(re, im), (t, &(i, q))| {
+            let (i, q) = (2.0 * i as f64 - 1.0, 2.0 * q as f64 - 1.0);
+            let phase = -2.0 * PI * k as f64 * t as f64 / n;
+            let (sin, cos) = phase.sin_cos();
+            (re + i * cos - q * sin, im + i * sin + q * cos)
+        });
+        re * re + im * im
+    }).collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    init_device(true, None)?;
+    let queue = new_queue();
+    let receiver = {
+        let (queue, cancel) = (queue.clone(), cancel::on_ctrlc()?);
+        thread::spawn(move || receive_until(queue, cancel, None, None))
+    };
+
+    let mut block = Vec::with_capacity(SAMPLE_RATE as usize);
+    for sample in queue.iter_blocking(Duration::from_millis(100)) {
+        block.push(sample);
+        if block.len() < SAMPLE_RATE as usize {
+            continue;
+        }
+        let power = spectrum(&block[..BINS]);
+        let (peak, _) = power.iter().enumerate()
+            .fold((0, f64::MIN), |best, (k, &p)| if p > best.1 { (k, p) } else { best });
+        // Bins above half way are negative frequencies
+        let bin = if peak >= BINS / 2 { peak as i64 - BINS as i64 } else { peak as i64 };
+        println!("Peak at {:+.1} kHz", bin as f64 * SAMPLE_RATE as f64 / BINS as f64 / 1000.0);
+        block.clear();
+    }
+    receiver.join().unwrap()?;
+    Ok(())
+}
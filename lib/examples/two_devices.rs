@@ -0,0 +1,67 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Capture from every attached AR2300 at once, each to its own file,
+//! until Ctrl-C. Only the first device found is programmed, so load the
+//! firmware into the others first, e.g. by running the CLI with each one
+//! attached on its own.
+
+use ar2300::cancel;
+use ar2300::prelude::*;
+use ar2300::usb::IsIQDevice;
+use rusb::{GlobalContext, UsbContext};
+use std::error::Error;
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    init_device(true, None)?;
+    let cancel = cancel::on_ctrlc()?;
+    let devices: Vec<_> = rusb::devices()?.iter().filter(|d| d.is_iq_device()).collect();
+    println!("Found {} devices", devices.len());
+
+    let mut receivers = Vec::new();
+    let mut writers = Vec::new();
+    for (n, device) in devices.into_iter().enumerate() {
+        let queue = new_queue();
+        let mut receiver = Receiver::new(device, queue.clone())?;
+        receiver.start()?;
+        receivers.push(receiver);
+        let out = File::create(format!("iq-{}.bin", n))?;
+        writers.push(thread::spawn(move || -> Result<(), Ar2300Error> {
+            let mut writer = Writer::new(queue, Box::new(out));
+            while writer.write(Duration::from_millis(100))? {}
+            writer.flush()
+        }));
+    }
+
+    // All the receivers are on the global context, so one loop drives them
+    while !cancel.is_cancelled() {
+        GlobalContext::default().handle_events(Some(Duration::from_millis(50)))?;
+    }
+    for receiver in &mut receivers {
+        receiver.stop();
+    }
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    Ok(())
+}
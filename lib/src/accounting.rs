@@ -0,0 +1,216 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use log::Level;
+use crate::error::Ar2300Error;
+use crate::events::emit;
+use crate::queue::CloseReason;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/**
+ One ledger of what happened to every sample in a capture. The receiver
+ records samples as it hands them to the queue and when the queue drops
+ them, and the writer records them as they are written, so that
+
+ `received == written + dropped + in_flight`
+
+ where `in_flight` is whatever is still in the queue. Share one ledger
+ between a Receiver and its Writer with `Arc`; the stats they report are
+ read from it. With a broadcaster the drops are summed across
+ subscribers, so the ledger only balances for a single queue.
+
+ The ledger also keeps the errors that ended the capture, so the reason
+ its queue was closed with can be checked against them.
+ */
+#[derive(Debug, Default)]
+pub struct SampleAccounting {
+    received: AtomicU64,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    errors: Mutex<Vec<Arc<Ar2300Error>>>,
+}
+
+/** The ledger's totals at one point in time. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccountingSummary {
+    pub received: u64,
+    pub written: u64,
+    pub dropped: u64,
+    /** Samples still waiting to be written, as counted by the caller. */
+    pub in_flight: u64,
+    /** Errors recorded by the receiver or the writer. */
+    pub errors: u64,
+}
+
+impl AccountingSummary {
+    /**
+     Samples received but neither written, dropped nor in flight. Negative
+     if more were accounted for than received. Anything but zero is a bug.
+     */
+    pub fn discrepancy(&self) -> i128 {
+        self.received as i128 - (self.written as i128 + self.dropped as i128 + self.in_flight as i128)
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancy() == 0
+    }
+
+    /**
+     Whether the reason the queue was closed with fits the errors recorded:
+     a queue closed with an error should have one in the ledger, and one
+     that finished should have none. Cancelling or poisoning the queue can
+     happen either way.
+     */
+    pub fn agrees_with(&self, reason: &CloseReason) -> bool {
+        match reason {
+            CloseReason::Error(_) => self.errors > 0,
+            CloseReason::Finished => self.errors == 0,
+            CloseReason::Cancelled | CloseReason::Poisoned => true,
+        }
+    }
+}
+
+impl SampleAccounting {
+    pub fn new() -> SampleAccounting {
+        SampleAccounting::default()
+    }
+
+    /** Record samples handed to the queue. Call before enqueueing, so they are never written first. */
+    pub fn record_received(&self, n: u64) {
+        self.received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /** Record samples the queue dropped or evicted. */
+    pub fn record_dropped(&self, n: u64) {
+        self.dropped.fetch_add(n, Ordering::Relaxed);
+        self.check();
+    }
+
+    /** Record samples written to the output. */
+    pub fn record_written(&self, n: u64) {
+        self.written.fetch_add(n, Ordering::Relaxed);
+        self.check();
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /** Record an error that ended the capture, usually the one its queue is closed with. */
+    pub fn record_error(&self, error: Arc<Ar2300Error>) {
+        self.errors.lock().unwrap_or_else(PoisonError::into_inner).push(error);
+    }
+
+    /** The errors recorded so far, oldest first. */
+    pub fn errors(&self) -> Vec<Arc<Ar2300Error>> {
+        self.errors.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /** The totals, with `in_flight` samples still queued. */
+    pub fn summary(&self, in_flight: u64) -> AccountingSummary {
+        // Read the outflows first, so a concurrent update can't make them exceed what was received
+        let written = self.written();
+        let dropped = self.dropped();
+        AccountingSummary {
+            received: self.received(),
+            written,
+            dropped,
+            in_flight,
+            errors: self.errors.lock().unwrap_or_else(PoisonError::into_inner).len() as u64,
+        }
+    }
+
+    /**
+     The final totals for a finished capture, with `in_flight` samples left
+     in the queue. A ledger that doesn't balance is reported as a bug.
+     */
+    pub fn reconcile(&self, in_flight: u64) -> AccountingSummary {
+        let summary = self.summary(in_flight);
+        if !summary.is_balanced() {
+            emit(Level::Warn, format_args!("Sample accounting doesn't balance, which is a bug: {} samples unaccounted for ({:?})",
+                                           summary.discrepancy(), summary));
+        }
+        summary
+    }
+
+    /**
+     Like `reconcile`, but also checks the reason the queue was closed with,
+     if it was, against the errors recorded. A mismatch, such as a queue
+     closed with an error the ledger never saw, is reported as a bug.
+     */
+    pub fn reconcile_closed(&self, in_flight: u64, reason: Option<&CloseReason>) -> AccountingSummary {
+        let summary = self.reconcile(in_flight);
+        if let Some(reason) = reason {
+            if !summary.agrees_with(reason) {
+                emit(Level::Warn, format_args!("The queue was closed with {:?} but the sample accounting recorded {} errors, which is a bug",
+                                               reason, summary.errors));
+            }
+        }
+        summary
+    }
+
+    /** Nothing can leave the queue that wasn't received. */
+    fn check(&self) {
+        debug_assert!(self.written() + self.dropped() <= self.received(),
+                      "More samples written or dropped than received: {:?}", self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discrepancy_is_what_was_received_but_not_accounted_for() {
+        let accounting = SampleAccounting::new();
+        accounting.record_received(100);
+        accounting.record_written(60);
+        accounting.record_dropped(10);
+        assert!(accounting.reconcile(30).is_balanced());
+        assert_eq!(accounting.reconcile(25).discrepancy(), 5);
+        assert_eq!(accounting.reconcile(40).discrepancy(), -10);
+    }
+
+    #[test]
+    fn the_close_reason_is_checked_against_the_recorded_errors() {
+        let accounting = SampleAccounting::new();
+        let error = Arc::new(Ar2300Error::DecodeFailed("bad frame".to_string()));
+        let summary = accounting.reconcile_closed(0, Some(&CloseReason::Finished));
+        assert!(summary.agrees_with(&CloseReason::Finished));
+        assert!(!summary.agrees_with(&CloseReason::Error(error.clone())));
+
+        accounting.record_error(error.clone());
+        let summary = accounting.reconcile_closed(0, Some(&CloseReason::Error(error.clone())));
+        assert_eq!(summary.errors, 1);
+        assert!(summary.agrees_with(&CloseReason::Error(error.clone())));
+        assert!(!summary.agrees_with(&CloseReason::Finished));
+        assert!(summary.agrees_with(&CloseReason::Cancelled));
+        assert!(Arc::ptr_eq(&accounting.errors()[0], &error));
+    }
+}
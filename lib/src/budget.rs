@@ -0,0 +1,301 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::error::Ar2300Error;
+use crate::iq::ReceiverConfig;
+use std::fmt;
+use std::str::FromStr;
+
+/** The share of the system's memory `MemoryBudget::of_system_memory` is normally given. */
+pub const DEFAULT_SYSTEM_FRACTION: f64 = 0.25;
+
+/** The memory one sample takes in the sample queue. */
+const SAMPLE_BYTES: usize = std::mem::size_of::<(f32,f32)>();
+
+/**
+ The most memory a capture's buffers may take between them. A receiver
+ with a budget checks its queues, buffer pool and transfer buffer against
+ it when built and again when started, and refuses to go on if they could
+ need more, with a breakdown of what each would take. `fit` works the
+ other way round, giving the sample queue whatever the budget leaves.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: u64) -> MemoryBudget {
+        MemoryBudget { bytes }
+    }
+
+    /** A share, between 0 and 1, of the memory installed in the system. */
+    pub fn of_system_memory(fraction: f64) -> Result<MemoryBudget, Ar2300Error> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(Ar2300Error::InvalidConfig(format!("Invalid share of system memory: {}", fraction)));
+        }
+        let mut system = sysinfo::System::new();
+        system.refresh_memory();
+        match system.total_memory() {
+            0 => Err(Ar2300Error::InvalidConfig("The system's memory size isn't known".to_string())),
+            total => Ok(MemoryBudget::new((total as f64 * fraction) as u64))
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /** Check that these allocations fit. The plan is returned either way, inside the error if they don't. */
+    pub fn check(&self, allocations: Vec<Allocation>) -> Result<MemoryPlan, Ar2300Error> {
+        MemoryPlan { budget: Some(*self), allocations }.check()
+    }
+
+    /**
+     The config with as large a sample queue as fits in the budget beside
+     its transfer buffer and `others`, such as a raw queue's buffers, and
+     with this budget set. Fails if there isn't room for a queue holding
+     at least one transfer's samples.
+     */
+    pub fn fit(&self, config: ReceiverConfig, others: &[Allocation]) -> Result<ReceiverConfig, Ar2300Error> {
+        let mut fitted = ReceiverConfig { queue_capacity: 0, memory_budget: Some(*self), ..config };
+        let fixed: u64 = fitted.allocations().iter().chain(others).map(Allocation::bytes).sum();
+        let room = (self.bytes.saturating_sub(fixed) / SAMPLE_BYTES as u64).min(usize::MAX as u64) as usize;
+        fitted.queue_capacity = room.max(config.transfer_samples());
+        let mut allocations = fitted.allocations();
+        allocations.extend_from_slice(others);
+        self.check(allocations).map(|_| fitted)
+    }
+}
+
+impl FromStr for MemoryBudget {
+    type Err = Ar2300Error;
+
+    /** A size in bytes, or in kibibytes, mebibytes or gibibytes with a K, M or G after it, as in `512M`. */
+    fn from_str(s: &str) -> Result<MemoryBudget, Ar2300Error> {
+        let invalid = || Ar2300Error::InvalidConfig(format!("Invalid memory size: '{}'", s));
+        let s = s.trim();
+        let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+            Some((i, _)) => s.split_at(i),
+            None => (s, "")
+        };
+        let multiplier: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            _ => return Err(invalid())
+        };
+        let n: u64 = digits.parse().map_err(|_| invalid())?;
+        n.checked_mul(multiplier).map(MemoryBudget::new).ok_or_else(invalid)
+    }
+}
+
+/** One kind of buffer in a MemoryPlan: how many there can be, and the most each can take. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub name: &'static str,
+    pub count: usize,
+    pub size: usize,
+}
+
+impl Allocation {
+    pub fn bytes(&self) -> u64 {
+        self.count as u64 * self.size as u64
+    }
+}
+
+/**
+ The most memory each of a capture's buffers can take, and the budget
+ they have to fit in, if any. Only buffers whose size the crate controls
+ are counted: not the broadcaster's subscribers, nor the writer's batch.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryPlan {
+    pub budget: Option<MemoryBudget>,
+    pub allocations: Vec<Allocation>,
+}
+
+impl MemoryPlan {
+    pub fn total(&self) -> u64 {
+        self.allocations.iter().map(Allocation::bytes).sum()
+    }
+
+    pub fn fits(&self) -> bool {
+        match self.budget {
+            Some(budget) => self.total() <= budget.bytes(),
+            None => true
+        }
+    }
+
+    /** The plan, or `Ar2300Error::OverBudget` holding it if it doesn't fit its budget. */
+    pub fn check(self) -> Result<MemoryPlan, Ar2300Error> {
+        if self.fits() {
+            Ok(self)
+        } else {
+            Err(Ar2300Error::OverBudget(self))
+        }
+    }
+
+    /** The plan as a JSON object, for metadata. */
+    pub fn to_json(&self) -> String {
+        let allocations: Vec<String> = self.allocations.iter()
+            .map(|a| format!("{{\"name\": \"{}\", \"count\": {}, \"size\": {}}}", a.name, a.count, a.size))
+            .collect();
+        let budget = self.budget.map_or("null".to_string(), |b| b.bytes().to_string());
+        format!("{{\"budget\": {}, \"total\": {}, \"allocations\": [{}]}}", budget, self.total(), allocations.join(", "))
+    }
+}
+
+impl fmt::Display for MemoryPlan {
+    /** One line per allocation, then the total and the budget. */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for a in &self.allocations {
+            writeln!(f, "  {}: {} x {} bytes = {}", a.name, a.count, a.size, format_bytes(a.bytes()))?;
+        }
+        match self.budget {
+            Some(budget) => write!(f, "  total {} of a {} budget", format_bytes(self.total()), format_bytes(budget.bytes())),
+            None => write!(f, "  total {}, no budget", format_bytes(self.total()))
+        }
+    }
+}
+
+/** A size in the largest binary unit that keeps it at least 1. */
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1 << 10 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq::{sigmf_meta_path, Receiver, Writer, PACKET_LENGTH, SAMPLE_RATE};
+    use crate::pool::BufferPool;
+    use crate::queue::Queue;
+    use crate::usb::fake::FakeDevice;
+    use std::sync::Arc;
+
+    fn over_budget(result: Result<MemoryPlan, Ar2300Error>) -> MemoryPlan {
+        match result {
+            Err(Ar2300Error::OverBudget(plan)) => plan,
+            r => panic!("Expected an over-budget error, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn sizes_are_parsed_in_binary_units() {
+        assert_eq!("4096".parse::<MemoryBudget>().unwrap().bytes(), 4096);
+        assert_eq!("512K".parse::<MemoryBudget>().unwrap().bytes(), 512 << 10);
+        assert_eq!("64M".parse::<MemoryBudget>().unwrap().bytes(), 64 << 20);
+        assert_eq!("2GiB".parse::<MemoryBudget>().unwrap().bytes(), 2 << 30);
+        for bad in ["", "M", "12T", "-1M", "99999999999999G"] {
+            assert!(bad.parse::<MemoryBudget>().is_err(), "{}", bad);
+        }
+        assert!(MemoryBudget::of_system_memory(0.0).is_err());
+        assert!(MemoryBudget::of_system_memory(1.5).is_err());
+    }
+
+    #[test]
+    fn every_profile_is_broken_down_into_its_queue_and_transfer_buffer() {
+        for name in ReceiverConfig::PROFILES.iter() {
+            let config = ReceiverConfig::profile(name).unwrap();
+            let plan = MemoryBudget::new(u64::MAX).check(config.allocations()).unwrap();
+            assert_eq!(plan.allocations, vec![
+                Allocation { name: "sample queue", count: config.queue_capacity, size: 8 },
+                Allocation { name: "transfer buffer", count: 1, size: PACKET_LENGTH * (config.packet_count + 1) },
+            ]);
+            assert_eq!(plan.total(), config.queue_capacity as u64 * 8 + (PACKET_LENGTH * (config.packet_count + 1)) as u64);
+        }
+        // The robust profile's four second queue takes 32 MiB, too much for a 16 MiB budget
+        let plan = over_budget(MemoryBudget::new(16 << 20).check(ReceiverConfig::robust().allocations()));
+        let text = plan.to_string();
+        assert!(text.contains("sample queue: 4194304 x 8 bytes = 32.0 MiB"), "{}", text);
+        assert!(text.contains("of a 16.0 MiB budget"), "{}", text);
+        assert!(Ar2300Error::OverBudget(plan).to_string().contains("sample queue"));
+    }
+
+    #[test]
+    fn fitting_gives_the_queue_what_is_left() {
+        let config = ReceiverConfig::default();
+        let transfer = (PACKET_LENGTH * (config.packet_count + 1)) as u64;
+        let fitted = MemoryBudget::new(1 << 20).fit(config, &[]).unwrap();
+        assert_eq!(fitted.queue_capacity as u64, ((1 << 20) - transfer) / 8);
+        assert_eq!(fitted.memory_budget, Some(MemoryBudget::new(1 << 20)));
+        assert!(MemoryBudget::new(1 << 20).check(fitted.allocations()).is_ok());
+
+        // Other buffers come out of the queue's share
+        let raw = [Allocation { name: "buffer pool", count: 16, size: 4096 }];
+        let fitted = MemoryBudget::new(1 << 20).fit(config, &raw).unwrap();
+        assert_eq!(fitted.queue_capacity as u64, ((1 << 20) - transfer - 16 * 4096) / 8);
+
+        // Too little for even one transfer's samples
+        let plan = over_budget(MemoryBudget::new(4096).fit(config, &[]).map(|_| unreachable!()));
+        assert_eq!(plan.allocations[0].count, config.transfer_samples());
+    }
+
+    #[test]
+    fn a_receiver_over_its_budget_is_refused_with_a_breakdown() {
+        let budget = MemoryBudget::new(1 << 20);
+        let device = Arc::new(FakeDevice::new());
+        let config = ReceiverConfig { pre_start_drain: None, memory_budget: Some(budget), ..ReceiverConfig::default() };
+        // The default queue alone wants 8 MiB
+        let plan = over_budget(Receiver::builder().config(config).build_fake(device.clone(), config.new_queue()).map(|_| unreachable!()));
+        assert_eq!(plan.allocations[0].name, "sample queue");
+
+        // A raw queue added after building is checked on starting
+        let mut receiver = Receiver::builder().config(config).build_fake(device, Queue::new(1 << 16)).unwrap();
+        let plan = receiver.memory_plan().check().unwrap();
+        assert_eq!(plan.total(), (1 << 16) * 8 + receiver.memory_plan().allocations[1].bytes());
+        let pool = BufferPool::new(64, 1 << 16);
+        receiver.set_raw_queue(Some((Queue::new(64), pool)));
+        let plan = over_budget(receiver.start().map(|_| unreachable!()));
+        let names: Vec<&str> = plan.allocations.iter().map(|a| a.name).collect();
+        assert_eq!(names, vec!["sample queue", "transfer buffer", "raw queue", "buffer pool"]);
+        assert!(!receiver.is_running()());
+    }
+
+    #[test]
+    fn a_plan_is_written_as_json() {
+        let plan = MemoryPlan {
+            budget: Some(MemoryBudget::new(1000)),
+            allocations: vec![Allocation { name: "sample queue", count: 100, size: 8 }],
+        };
+        assert_eq!(plan.to_json(),
+                   "{\"budget\": 1000, \"total\": 800, \"allocations\": [{\"name\": \"sample queue\", \"count\": 100, \"size\": 8}]}");
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("capture.cf32");
+        let mut writer = Writer::new(Queue::new(16), Box::new(std::io::sink()));
+        writer.set_memory_plan(Some(plan.clone()));
+        writer.with_sigmf_metadata(&recording, SAMPLE_RATE, 100e6).unwrap();
+        let meta = std::fs::read_to_string(sigmf_meta_path(&recording)).unwrap();
+        assert!(meta.contains(&format!("\"ar2300:memory\": {}", plan.to_json())), "{}", meta);
+        assert_eq!(format_bytes(1023), "1023 bytes");
+        assert_eq!(format_bytes(3 << 29), "1.5 GiB");
+    }
+}
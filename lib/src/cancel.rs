@@ -0,0 +1,132 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/** Returned by operations that gave up because their token was cancelled. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/**
+ A shared flag used to ask blocking operations to give up.
+ Clones share the same state. Once cancelled, a token stays cancelled.
+ */
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /** Cancel the token, waking anything waiting on it. */
+    pub fn cancel(&self) {
+        let (l, cv) = &*self.state;
+        *l.lock().unwrap() = true;
+        cv.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let (l, _) = &*self.state;
+        *l.lock().unwrap()
+    }
+
+    /** Return `Cancelled` if the token has been cancelled. */
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /**
+     Wait until the token is cancelled or the timeout passes.
+     Returns true if the token was cancelled. Use this in place of sleep.
+     */
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (l, cv) = &*self.state;
+        let cancelled = cv.wait_timeout_while(
+            l.lock().unwrap(),
+            timeout,
+            |cancelled| !*cancelled
+        ).unwrap().0;
+        *cancelled
+    }
+}
+
+/**
+ The token that is cancelled when the process receives Ctrl-C. The
+ handler is process-wide, so every caller gets the same token.
+ */
+pub fn on_ctrlc() -> Result<CancelToken, crate::error::Ar2300Error> {
+    let handle = crate::global::init(crate::global::Options { ctrlc: true, ..Default::default() })?;
+    Ok(handle.ctrlc_token().expect("Ctrl-C handler installed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Ar2300Error;
+    use std::time::Instant;
+
+    #[test]
+    fn check_fails_once_cancelled() {
+        let token = CancelToken::new();
+        assert_eq!(token.check(), Ok(()));
+        token.clone().cancel();
+        assert_eq!(token.check(), Err(Cancelled));
+        assert!(matches!(Ar2300Error::from(Cancelled), Ar2300Error::Cancelled));
+    }
+
+    #[test]
+    fn a_wait_ends_as_soon_as_the_token_is_cancelled() {
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let started = Instant::now();
+        assert!(token.wait_timeout(Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        handle.join().unwrap();
+        // and doesn't wait at all afterwards
+        assert!(token.wait_timeout(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn a_wait_times_out_if_not_cancelled() {
+        let token = CancelToken::new();
+        assert!(!token.wait_timeout(Duration::from_millis(10)));
+    }
+}
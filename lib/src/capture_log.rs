@@ -0,0 +1,363 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::accounting::{AccountingSummary, SampleAccounting};
+use crate::clock::{CaptureClock, ClockStep};
+use crate::diagnostics::BandwidthReport;
+use crate::error::Ar2300Error;
+use crate::events::{Event, EventLog, EventSubscriber};
+use crate::queue::{CloseReason, Queue};
+use crate::session::SessionId;
+use crate::timeline::format_time;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/** How often `CaptureLog::follow` records the ledger unless told otherwise. */
+pub const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/** The longest a followed event waits to be written. */
+const FOLLOW_WAIT: Duration = Duration::from_millis(100);
+
+/** Where a recording's capture log goes: next to it, with `.log` appended to its name. */
+pub fn capture_log_path(recording: &Path) -> PathBuf {
+    let mut path = recording.as_os_str().to_owned();
+    path.push(".log");
+    PathBuf::from(path)
+}
+
+/** One line of a capture log. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    /** The capture is starting, with the receiver profile it uses and its session. */
+    Start { recording: String, profile: String, session: Option<SessionId> },
+    /** What the bandwidth check found before the capture. */
+    Bandwidth(BandwidthReport),
+    /** A warning or error published on the EventLog, or a summary of lost events. */
+    Event(Event),
+    /** The ledger's totals so far. */
+    Stats(AccountingSummary),
+    /** The system clock was stepped. The log's times carry on regardless. */
+    ClockStep(ClockStep),
+    /** The capture stopped, with the reason its queue was closed, if it was, and the final totals. */
+    Stop { reason: Option<CloseReason>, summary: AccountingSummary },
+}
+
+impl Entry {
+    /**
+     The entry as one line of JSON, without the newline. Every line has
+     `time` and `kind` fields; the others depend on the kind.
+     */
+    pub fn to_json(&self, time: SystemTime) -> String {
+        let mut line = format!("{{\"time\":\"{}\"", format_time(time));
+        match self {
+            Entry::Start { recording, profile, session } => {
+                let _ = write!(line, ",\"kind\":\"start\",\"recording\":{},\"profile\":{}",
+                               json_string(recording), json_string(profile));
+                push_session(&mut line, *session);
+            },
+            Entry::Bandwidth(report) => {
+                let _ = write!(line, ",\"kind\":\"bandwidth\",\"bandwidth\":{}", report.to_json());
+            },
+            Entry::Event(event) => {
+                let _ = write!(line, ",\"kind\":\"event\",\"level\":\"{}\",\"message\":{}",
+                               event.level, json_string(&event.message));
+                push_session(&mut line, event.session);
+                if let Some(dropped) = event.dropped {
+                    let _ = write!(line, ",\"dropped\":{{\"total\":{},\"serious\":{}}}", dropped.total, dropped.serious);
+                }
+            },
+            Entry::Stats(summary) => {
+                line.push_str(",\"kind\":\"stats\"");
+                push_summary(&mut line, summary);
+            },
+            Entry::ClockStep(step) => {
+                let _ = write!(line, ",\"kind\":\"clock-step\",\"at\":\"{}\",\"offset_ms\":{}",
+                               format_time(step.at), step.offset_ms);
+            },
+            Entry::Stop { reason, summary } => {
+                let reason = match reason {
+                    None => "\"open\"".to_string(),
+                    Some(CloseReason::Finished) => "\"finished\"".to_string(),
+                    Some(CloseReason::Cancelled) => "\"cancelled\"".to_string(),
+                    Some(CloseReason::Poisoned) => "\"poisoned\"".to_string(),
+                    Some(CloseReason::Error(e)) => format!("\"error\",\"error\":{}", json_string(&e.to_string())),
+                };
+                let _ = write!(line, ",\"kind\":\"stop\",\"reason\":{}", reason);
+                push_summary(&mut line, summary);
+            },
+        }
+        line.push('}');
+        line
+    }
+}
+
+fn push_session(line: &mut String, session: Option<SessionId>) {
+    if let Some(session) = session {
+        let _ = write!(line, ",\"session\":\"{}\"", session);
+    }
+}
+
+fn push_summary(line: &mut String, summary: &AccountingSummary) {
+    let _ = write!(line, ",\"received\":{},\"written\":{},\"dropped\":{},\"in_flight\":{},\"errors\":{}",
+                   summary.received, summary.written, summary.dropped, summary.in_flight, summary.errors);
+}
+
+/** A JSON string literal holding `s`. */
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            },
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/**
+ A machine-readable log of one capture, one JSON object per line, for
+ debugging it afterwards. Each entry is flushed as it is written, so a
+ crash loses at most the entry being written. Entries are timed by a
+ CaptureClock, so their times only go forward, and a step in the system
+ clock is recorded rather than showing up as a jump between entries.
+ */
+pub struct CaptureLog {
+    out: Box<dyn Write + Send>,
+    clock: CaptureClock,
+}
+
+impl CaptureLog {
+    /** A log timed by a CaptureClock started now on the system's clocks. */
+    pub fn new(out: Box<dyn Write + Send>) -> CaptureLog {
+        CaptureLog::with_clock(out, CaptureClock::start())
+    }
+
+    pub fn with_clock(out: Box<dyn Write + Send>, clock: CaptureClock) -> CaptureLog {
+        CaptureLog { out, clock }
+    }
+
+    /** Append to the log file at `path`, creating it if needed. */
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<CaptureLog, Ar2300Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(CaptureLog::new(Box::new(file)))
+    }
+
+    /** Write an entry, timestamped now by the log's clock. */
+    pub fn record(&mut self, entry: &Entry) -> Result<(), Ar2300Error> {
+        let mut line = entry.to_json(self.clock.now());
+        line.push('\n');
+        self.out.write_all(line.as_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /**
+     Record every event published on `events` from now on, and the ledger's
+     totals and any step in the system clock every `stats_interval`, on a thread of its own until the
+     returned follower is finished. A write that fails ends the following;
+     `CaptureFollower::finish` returns the error.
+     */
+    pub fn follow(self,
+                  events: &EventLog,
+                  accounting: Arc<SampleAccounting>,
+                  queue: Queue<(f32,f32)>,
+                  stats_interval: Duration) -> CaptureFollower {
+        let subscriber = Arc::new(events.subscribe());
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let subscriber = subscriber.clone();
+            let stopping = stopping.clone();
+            let accounting = accounting.clone();
+            let queue = queue.clone();
+            std::thread::spawn(move || follow(self, &subscriber, &stopping, &accounting, &queue, stats_interval))
+        };
+        CaptureFollower { subscriber, stopping, thread, accounting, queue }
+    }
+}
+
+fn follow(mut log: CaptureLog,
+          subscriber: &EventSubscriber,
+          stopping: &AtomicBool,
+          accounting: &SampleAccounting,
+          queue: &Queue<(f32,f32)>,
+          stats_interval: Duration) -> Result<CaptureLog, Ar2300Error> {
+    let mut last_stats = Instant::now();
+    loop {
+        let wait = stats_interval.saturating_sub(last_stats.elapsed()).min(FOLLOW_WAIT);
+        match subscriber.next(wait) {
+            Some(event) => log.record(&Entry::Event(event))?,
+            // Once unsubscribed, nothing is left when `next` returns None
+            None if stopping.load(Ordering::Acquire) => return Ok(log),
+            None => {}
+        }
+        if last_stats.elapsed() >= stats_interval {
+            if let Some(step) = log.clock.check_step() {
+                log.record(&Entry::ClockStep(step))?;
+            }
+            log.record(&Entry::Stats(accounting.summary(queue.len() as u64)))?;
+            last_stats = Instant::now();
+        }
+    }
+}
+
+/** A CaptureLog following a capture, from `CaptureLog::follow`. */
+pub struct CaptureFollower {
+    subscriber: Arc<EventSubscriber>,
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<Result<CaptureLog, Ar2300Error>>,
+    accounting: Arc<SampleAccounting>,
+    queue: Queue<(f32,f32)>,
+}
+
+impl CaptureFollower {
+    /**
+     Stop following, once every event already published is written, and
+     end the log with the queue's close reason and the ledger's final
+     totals. Call it after the capture's threads have finished.
+     */
+    pub fn finish(self) -> Result<(), Ar2300Error> {
+        self.subscriber.unsubscribe();
+        self.stopping.store(true, Ordering::Release);
+        let mut log = self.thread.join().unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+        let summary = self.accounting.summary(self.queue.len() as u64);
+        log.record(&Entry::Stop { reason: self.queue.close_reason(), summary })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq::tests::{deliver_all, fake_receiver, valid_transfer};
+    use crate::clock::tests::ManualClock;
+    use crate::diagnostics::{BandwidthCheck, Decision};
+    use crate::usb::fake::FakeDevice;
+    use log::Level;
+    use std::sync::Mutex;
+
+    /** An output that keeps what is written. */
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Lines {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    #[test]
+    fn entries_are_single_lines_of_json() {
+        let time = SystemTime::UNIX_EPOCH;
+        let event = Event { level: Level::Warn, message: "a \"quoted\"\nmessage\\".to_string(), session: None, dropped: None };
+        assert_eq!(Entry::Event(event).to_json(time),
+                   r#"{"time":"1970-01-01T00:00:00.000000000Z","kind":"event","level":"WARN","message":"a \"quoted\"\nmessage\\"}"#);
+        let summary = AccountingSummary { received: 10, written: 7, dropped: 1, in_flight: 2, errors: 1 };
+        let reason = CloseReason::Error(Arc::new(Ar2300Error::DecodeFailed("bad\tframe".to_string())));
+        assert_eq!(Entry::Stop { reason: Some(reason), summary }.to_json(time),
+                   concat!(r#"{"time":"1970-01-01T00:00:00.000000000Z","kind":"stop","reason":"error","error":"bad\tframe","#,
+                           r#""received":10,"written":7,"dropped":1,"in_flight":2,"errors":1}"#));
+        let report = BandwidthReport { policy: BandwidthCheck::Enforce, required: 9.6e6, measured: Some(2e7), margin: 0.25, decision: Decision::Passed };
+        assert_eq!(Entry::Bandwidth(report).to_json(time),
+                   concat!(r#"{"time":"1970-01-01T00:00:00.000000000Z","kind":"bandwidth","bandwidth":{"policy": "enforce", "#,
+                           r#""required": 9600000, "measured": 20000000, "margin": 0.25, "decision": "passed"}}"#));
+        assert_eq!(capture_log_path(Path::new("/data/capture.cf32")), PathBuf::from("/data/capture.cf32.log"));
+    }
+
+    #[test]
+    fn a_scripted_capture_is_logged_in_order() {
+        let device = Arc::new(FakeDevice::new());
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Overflow), Vec::new());
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Pipe), Vec::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let lines = Lines::default();
+        let mut log = CaptureLog::new(Box::new(lines.clone()));
+        log.record(&Entry::Start { recording: "capture.cf32".to_string(), profile: "default".to_string(), session: None }).unwrap();
+        let follower = log.follow(EventLog::global(), receiver.accounting(), queue.clone(), Duration::from_millis(1));
+        receiver.start().unwrap();
+        deliver_all(&receiver, &device, None);
+        // Long enough for a snapshot of the ledger
+        std::thread::sleep(Duration::from_millis(20));
+        receiver.stop_with(CloseReason::Error(receiver.failure().unwrap()));
+        follower.finish().unwrap();
+
+        let lines = lines.lines();
+        assert!(lines[0].contains(r#""kind":"start","recording":"capture.cf32""#), "{}", lines[0]);
+        let last = lines.last().unwrap();
+        assert!(last.contains(r#""kind":"stop","reason":"error","error":"USB error: "#), "{}", last);
+        assert!(last.contains(&format!(r#""received":{},"#, 2 * crate::iq::tests::SAMPLES_PER_TRANSFER)), "{}", last);
+        assert!(last.ends_with(r#""errors":1}"#), "{}", last);
+        // Other tests publish on the global log too, so look for this capture's events in order
+        let overflow = lines.iter().position(|l| l.contains(r#""message":"USB overflow, discarding transfer""#)).unwrap();
+        let error = lines.iter().position(|l| l.contains(r#""level":"ERROR","message":"Error reading IQ data: "#)).unwrap();
+        assert!(0 < overflow && overflow < error && error < lines.len() - 1, "{:#?}", lines);
+        assert!(lines.iter().any(|l| l.contains(r#""kind":"stats""#)), "{:#?}", lines);
+    }
+
+    #[test]
+    fn a_clock_step_is_logged_without_moving_the_logs_times() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let source = ManualClock::new(start);
+        let lines = Lines::default();
+        let mut log = CaptureLog::with_clock(Box::new(lines.clone()), CaptureClock::start_on(source.clone()));
+        log.record(&Entry::Start { recording: "capture.cf32".to_string(), profile: "default".to_string(), session: None }).unwrap();
+        source.advance(Duration::from_secs(1));
+        source.step(-2_000);
+        let events = EventLog::new();
+        let follower = log.follow(&events, Arc::new(SampleAccounting::new()), Queue::new(16), Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(20));
+        follower.finish().unwrap();
+
+        let lines = lines.lines();
+        let steps: Vec<&String> = lines.iter().filter(|l| l.contains(r#""kind":"clock-step""#)).collect();
+        assert_eq!(steps.len(), 1, "{:#?}", lines);
+        assert!(steps[0].ends_with(r#""at":"2023-11-14T22:13:21.000000000Z","offset_ms":-2000}"#), "{}", steps[0]);
+        assert!(lines[0].starts_with(r#"{"time":"2023-11-14T22:13:20.000000000Z""#), "{}", lines[0]);
+        assert!(lines[1..].iter().all(|l| l.starts_with(r#"{"time":"2023-11-14T22:13:21.000000000Z""#)), "{:#?}", lines);
+    }
+}
@@ -0,0 +1,225 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::events::emit;
+use crate::timeline::format_time;
+use log::Level;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/** How far the system clock has to move from a CaptureClock's time before it is reported as a step. */
+pub const DEFAULT_STEP_THRESHOLD: Duration = Duration::from_millis(500);
+
+/**
+ Where a CaptureClock reads the time. `SystemClock` reads the system's
+ clocks; tests and simulations can supply their own, to step the wall
+ clock under a capture.
+ */
+pub trait ClockSource: Send + Sync {
+    /** The wall-clock time, which can jump when the system clock is set. */
+    fn wall(&self) -> SystemTime;
+    /** A clock that only moves forward, for measuring intervals. */
+    fn monotonic(&self) -> Instant;
+}
+
+/** The system's own clocks. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/** A jump in the system clock noticed by `CaptureClock::check_step`. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockStep {
+    /** The capture's time when the step was noticed. */
+    pub at: SystemTime,
+    /** How far the system clock moved since it was last in step, in milliseconds. Negative if it went back. */
+    pub offset_ms: i64,
+}
+
+/**
+ A capture's wall-clock time. The wall clock is read once, when the
+ capture starts; every later time is that plus the time elapsed on the
+ monotonic clock, so times keep going forward however the system clock
+ is set meanwhile. `check_step` compares the two, for reporting a step
+ instead of letting it go unnoticed.
+ */
+#[derive(Clone)]
+pub struct CaptureClock {
+    source: Arc<dyn ClockSource>,
+    start_wall: SystemTime,
+    start_monotonic: Instant,
+    threshold: Duration,
+    /** The system clock's offset from this clock as of the last step reported, in milliseconds. */
+    reported_ms: i64,
+}
+
+impl CaptureClock {
+    /** Start a clock on the system's clocks. */
+    pub fn start() -> CaptureClock {
+        CaptureClock::start_on(Arc::new(SystemClock))
+    }
+
+    /** Start a clock reading `source`. */
+    pub fn start_on(source: Arc<dyn ClockSource>) -> CaptureClock {
+        let start_monotonic = source.monotonic();
+        let start_wall = source.wall();
+        CaptureClock { source, start_wall, start_monotonic, threshold: DEFAULT_STEP_THRESHOLD, reported_ms: 0 }
+    }
+
+    /** Report steps of at least this much. The default is DEFAULT_STEP_THRESHOLD. */
+    pub fn set_step_threshold(&mut self, threshold: Duration) {
+        self.threshold = threshold;
+    }
+
+    /** When the capture started, by the wall clock. */
+    pub fn started(&self) -> SystemTime {
+        self.start_wall
+    }
+
+    /** The time now: the start plus the time elapsed since, by the monotonic clock. */
+    pub fn now(&self) -> SystemTime {
+        self.start_wall + self.source.monotonic().saturating_duration_since(self.start_monotonic)
+    }
+
+    /**
+     Compare the system clock with this clock, and report a step, as a
+     warning, if it has moved by at least the threshold since it was last
+     in step. Each step is reported once; call this periodically.
+     */
+    pub fn check_step(&mut self) -> Option<ClockStep> {
+        let now = self.now();
+        let offset_ms = match self.source.wall().duration_since(now) {
+            Ok(ahead) => ahead.as_millis().min(i64::MAX as u128) as i64,
+            Err(behind) => -(behind.duration().as_millis().min(i64::MAX as u128) as i64),
+        };
+        let moved = offset_ms.saturating_sub(self.reported_ms);
+        if moved.unsigned_abs() < self.threshold.as_millis() as u64 {
+            return None;
+        }
+        self.reported_ms = offset_ms;
+        let step = ClockStep { at: now, offset_ms: moved };
+        emit(Level::Warn, format_args!("The system clock stepped {} by {} ms at {}; capture times are unaffected",
+                                       if moved > 0 { "forward" } else { "back" }, moved.unsigned_abs(), format_time(now)));
+        Some(step)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::timeline::{Position, Timeline};
+    use std::sync::Mutex;
+
+    /** A clock whose monotonic time only moves when told, and whose wall clock can be stepped. */
+    pub(crate) struct ManualClock {
+        base: Instant,
+        state: Mutex<(SystemTime, Duration)>,
+    }
+
+    impl ManualClock {
+        pub(crate) fn new(wall: SystemTime) -> Arc<ManualClock> {
+            Arc::new(ManualClock { base: Instant::now(), state: Mutex::new((wall, Duration::ZERO)) })
+        }
+
+        /** Let time pass on both clocks. */
+        pub(crate) fn advance(&self, d: Duration) {
+            let mut state = self.state.lock().unwrap();
+            state.0 += d;
+            state.1 += d;
+        }
+
+        /** Set the wall clock forward, or back if `ms` is negative, without time passing. */
+        pub(crate) fn step(&self, ms: i64) {
+            let mut state = self.state.lock().unwrap();
+            let d = Duration::from_millis(ms.unsigned_abs());
+            state.0 = if ms < 0 { state.0 - d } else { state.0 + d };
+        }
+    }
+
+    impl ClockSource for ManualClock {
+        fn wall(&self) -> SystemTime {
+            self.state.lock().unwrap().0
+        }
+
+        fn monotonic(&self) -> Instant {
+            self.base + self.state.lock().unwrap().1
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn times_keep_going_forward_through_steps_either_way() {
+        let source = ManualClock::new(at(1_700_000_000));
+        let mut clock = CaptureClock::start_on(source.clone());
+        let mut times = vec![clock.now()];
+        for step in [-2_000, 0, 5_000, -30_000, 0] {
+            source.step(step);
+            source.advance(Duration::from_millis(250));
+            times.push(clock.now());
+        }
+        assert!(times.windows(2).all(|w| w[1] - Duration::from_millis(250) == w[0]), "{:?}", times);
+        assert_eq!(clock.now(), at(1_700_000_000) + Duration::from_millis(1250));
+
+        // A timeline derived from the clock's start ends where the clock says the capture ended
+        let rate = 2_400_000.0;
+        let samples = 1_250 * 2_400;
+        let timeline = Timeline::new(clock.started(), rate, samples, &[]);
+        assert_eq!(timeline.time_at(samples).unwrap(), clock.now());
+        let indices: Vec<Position> = times.iter().map(|t| timeline.index_at(*t)).collect();
+        assert_eq!(indices[0], Position::Sample(0));
+        assert_eq!(indices[1], Position::Sample(600_000));
+        assert_eq!(indices[5], Position::After);
+        assert!(clock.check_step().is_some());
+    }
+
+    #[test]
+    fn each_step_is_reported_once() {
+        let source = ManualClock::new(at(1_700_000_000));
+        let mut clock = CaptureClock::start_on(source.clone());
+        source.advance(Duration::from_secs(1));
+        assert_eq!(clock.check_step(), None);
+
+        source.step(-2_000);
+        source.advance(Duration::from_secs(1));
+        let step = clock.check_step().unwrap();
+        assert_eq!(step, ClockStep { at: at(1_700_000_002), offset_ms: -2_000 });
+        assert_eq!(clock.check_step(), None);
+
+        // Below the threshold, then past it; the report is relative to the last one
+        source.step(400);
+        assert_eq!(clock.check_step(), None);
+        source.step(3_600);
+        assert_eq!(clock.check_step().unwrap().offset_ms, 4_000);
+        assert_eq!(clock.check_step(), None);
+        assert_eq!(clock.now(), at(1_700_000_002));
+    }
+}
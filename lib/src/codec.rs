@@ -0,0 +1,467 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/** An I/Q sample pair, each scaled to [0.0, 1.0]. */
+pub type IqSample = (f32, f32);
+
+/** The size of one packet in the raw stream: a 32 bit I and a 32 bit Q code. */
+pub const PACKET_SIZE: usize = 8;
+
+/** The largest frame any FrameFormat may use. */
+pub const MAX_FRAME_SIZE: usize = 16;
+
+/**
+ The layout of one frame, an I/Q pair, in the raw stream: its size, the
+ flag bit set in every valid frame, and how it converts to a sample.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct FrameFormat {
+    pub name: &'static str,
+    /** Bytes per frame, at most MAX_FRAME_SIZE. */
+    pub frame_size: usize,
+    /** The byte within the frame holding the sync flag. */
+    pub sync_byte: usize,
+    /** The bits of `sync_byte` that are set in every valid frame. */
+    pub sync_mask: u8,
+    /** Convert a valid frame to a sample. */
+    pub convert: fn(&[u8], Rounding) -> IqSample,
+}
+
+impl FrameFormat {
+    /** The AR2300's stream: 32 bit little-endian I and Q codes, flagged in bit 0 of byte 1. */
+    pub const AR2300: FrameFormat = FrameFormat {
+        name: "ar2300",
+        frame_size: PACKET_SIZE,
+        sync_byte: 1,
+        sync_mask: 0x01,
+        convert: read_packet,
+    };
+
+    /** Every format the decoder supports. */
+    pub const FORMATS: [FrameFormat; 1] = [FrameFormat::AR2300];
+
+    /** Look up a format by name. */
+    pub fn by_name(name: &str) -> Option<FrameFormat> {
+        FrameFormat::FORMATS.iter().copied().find(|f| f.name == name)
+    }
+
+    fn is_valid(&self, frame: &[u8]) -> bool {
+        (frame[self.sync_byte] & self.sync_mask) == self.sync_mask
+    }
+
+    /** The offset of the first valid frame in the buffer, if there is one. */
+    fn find_frame(&self, buffer: &[u8]) -> Option<usize> {
+        (0..=buffer.len().saturating_sub(self.frame_size))
+            .find(|&i| buffer.len() - i > self.sync_byte && self.is_valid(&buffer[i..]))
+    }
+}
+
+impl Default for FrameFormat {
+    fn default() -> Self {
+        FrameFormat::AR2300
+    }
+}
+
+/**
+ Formats are equal if their names and layouts match. The conversion isn't
+ compared: function pointers aren't a reliable identity, since one
+ function can end up at several addresses and identical functions can be
+ merged into one.
+ */
+impl PartialEq for FrameFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name &&
+            self.frame_size == other.frame_size &&
+            self.sync_byte == other.sync_byte &&
+            self.sync_mask == other.sync_mask
+    }
+}
+
+impl Eq for FrameFormat {}
+
+const BASE: f32 = 2f32 * 2147483648.0f32;
+
+/**
+ How a 32 bit sample code is rounded to the 24 bit mantissa of an f32.
+
+ Samples are converted as unsigned fractions of full scale: `code / 2^32`,
+ where `code` is what is left of the 32 bit word once the flag bit has
+ been moved out of it. The largest such code is 0xfffeffff, so full
+ scale is never reached and the output range is [0.0, 1.0) in either
+ mode. `Nearest` rounds the low bits that don't fit an f32's 24 bit
+ mantissa; `TowardZero` drops them, which biases samples above 2^24
+ down by half a step on average.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /** Round to nearest, ties to even. This is what existing recordings use. */
+    #[default]
+    Nearest,
+    /** Truncate toward zero. */
+    TowardZero,
+}
+
+/** Convert an unsigned code to an f32 using the given rounding mode. */
+fn to_f32(n: u32, rounding: Rounding) -> f32 {
+    match rounding {
+        Rounding::Nearest => n as f32,
+        Rounding::TowardZero => {
+            let significant = 32 - n.leading_zeros();
+            if significant > 24 {
+                let mask = !((1u32 << (significant - 24)) - 1);
+                (n & mask) as f32
+            } else {
+                n as f32
+            }
+        }
+    }
+}
+
+fn read_packet(packet: &[u8], rounding: Rounding) -> (f32, f32) {
+    let i = LittleEndian::read_u32(&packet[0..4]);
+    let q = LittleEndian::read_u32(&packet[4..8]);
+
+    let f = |n: u32| -> f32 {
+        let mut n16 = [
+            (n >> 16) as u16,
+            n as u16,
+        ];
+        // received data processing.
+        if (n16[0] & 0x8000) == 0x8000 {
+            n16[1] |= 0x0001;
+        } else {
+            n16[1] &= 0xfffe;
+        }
+        n16[0] <<= 1;
+        to_f32(((n16[0] as u32) << 16) | (n16[1] as u32), rounding) / BASE
+    };
+
+    (f(i), f(q))
+}
+
+/** Counts describing how a run of raw transfer data was decoded. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecodeReport {
+    /** Transfers (or blocks) decoded. */
+    pub transfers: u64,
+    /** Raw bytes decoded. */
+    pub bytes: u64,
+    /** Samples produced. */
+    pub samples: u64,
+    /** Transfers in which no valid packet could be found. */
+    pub unsynced_transfers: u64,
+    /** Bytes skipped while searching for the first valid packet of a transfer. */
+    pub skipped_bytes: u64,
+    /** Frames that failed validation after sync. */
+    pub invalid_packets: u64,
+    /** Trailing bytes too short to form a frame. */
+    pub partial_bytes: u64,
+    /** Transfers discarded because the device sent more data than fits. */
+    pub overflows: u64,
+}
+
+impl DecodeReport {
+    pub(crate) fn add(&mut self, other: &DecodeReport) {
+        self.transfers += other.transfers;
+        self.bytes += other.bytes;
+        self.samples += other.samples;
+        self.unsynced_transfers += other.unsynced_transfers;
+        self.skipped_bytes += other.skipped_bytes;
+        self.invalid_packets += other.invalid_packets;
+        self.partial_bytes += other.partial_bytes;
+        self.overflows += other.overflows;
+    }
+}
+/**
+ Decoder state carried from one block of raw data to the next: the frame
+ format, whether the stream is synchronized and the bytes of a frame split
+ across blocks.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecodeState {
+    format: FrameFormat,
+    remainder: [u8; MAX_FRAME_SIZE],
+    remainder_len: usize,
+    synced: bool,
+}
+
+impl DecodeState {
+    pub fn new() -> Self {
+        DecodeState::default()
+    }
+
+    /** Start decoding a stream in the given format. Panics if its frames are larger than MAX_FRAME_SIZE. */
+    pub fn with_format(format: FrameFormat) -> Self {
+        assert!(format.frame_size <= MAX_FRAME_SIZE && format.sync_byte < format.frame_size,
+                "Unsupported frame format {}", format.name);
+        DecodeState {
+            format,
+            ..DecodeState::default()
+        }
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /** Bytes of an incomplete frame waiting for the next block. */
+    pub fn pending_bytes(&self) -> usize {
+        self.remainder_len
+    }
+
+    /** Forget the sync and any pending bytes, e.g. after data was lost. The format is kept. */
+    pub fn reset(&mut self) {
+        *self = DecodeState::with_format(self.format);
+    }
+}
+
+/**
+ Decode a block of raw IQ data that continues the stream described by
+ `state`, appending samples to `out`. Until synchronized, the block is
+ searched for the first valid frame. A frame split across blocks is
+ completed from the start of the next one. Does no I/O and allocates
+ nothing beyond growing `out`.
+ */
+pub fn decode_block(buffer: &[u8], state: &mut DecodeState, rounding: Rounding,
+                    out: &mut Vec<IqSample>) -> DecodeReport {
+    let mut report = DecodeReport {
+        transfers: 1,
+        bytes: buffer.len() as u64,
+        ..DecodeReport::default()
+    };
+    let format = state.format;
+    let frame_size = format.frame_size;
+    let mut buf = buffer;
+    if state.synced && state.remainder_len > 0 {
+        let needed = frame_size - state.remainder_len;
+        if buf.len() < needed {
+            state.remainder[state.remainder_len..state.remainder_len + buf.len()].copy_from_slice(buf);
+            state.remainder_len += buf.len();
+            return report;
+        }
+        state.remainder[state.remainder_len..frame_size].copy_from_slice(&buf[..needed]);
+        state.remainder_len = 0;
+        buf = &buf[needed..];
+        decode_frame(&format, &state.remainder[..frame_size], rounding, out, &mut report);
+    }
+    if !state.synced {
+        match format.find_frame(buf) {
+            Some(offset) => {
+                report.skipped_bytes = offset as u64;
+                buf = &buf[offset..];
+                state.synced = true;
+            },
+            None => {
+                report.unsynced_transfers = 1;
+                report.skipped_bytes = buf.len() as u64;
+                return report;
+            }
+        }
+    }
+    let mut frames = buf.chunks_exact(frame_size);
+    for frame in &mut frames {
+        decode_frame(&format, frame, rounding, out, &mut report);
+    }
+    let rest = frames.remainder();
+    state.remainder[..rest.len()].copy_from_slice(rest);
+    state.remainder_len = rest.len();
+    report
+}
+
+fn decode_frame(format: &FrameFormat, frame: &[u8], rounding: Rounding,
+                out: &mut Vec<IqSample>, report: &mut DecodeReport) {
+    if format.is_valid(frame) {
+        out.push((format.convert)(frame, rounding));
+        report.samples += 1;
+    } else {
+        report.invalid_packets += 1;
+    }
+}
+
+/**
+ Decode one transfer's worth of raw IQ data, appending samples to `out`.
+ Each transfer is synchronized independently by searching for the first
+ valid packet, and a trailing partial packet is dropped.
+ */
+pub fn decode(buffer: &[u8], rounding: Rounding, out: &mut Vec<IqSample>) -> DecodeReport {
+    decode_with_format(buffer, FrameFormat::AR2300, rounding, out)
+}
+
+/** Like `decode`, for a stream in the given frame format. */
+pub fn decode_with_format(buffer: &[u8], format: FrameFormat, rounding: Rounding,
+                          out: &mut Vec<IqSample>) -> DecodeReport {
+    let mut state = DecodeState::with_format(format);
+    let mut report = decode_block(buffer, &mut state, rounding, out);
+    report.partial_bytes = state.pending_bytes() as u64;
+    report
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /**
+     A second layout, only for tests: 16 bit little-endian I and Q codes,
+     flagged in the top bit of the last byte, which Q doesn't use.
+     */
+    pub(crate) const HALF: FrameFormat = FrameFormat {
+        name: "test-half",
+        frame_size: 4,
+        sync_byte: 3,
+        sync_mask: 0x80,
+        convert: read_half,
+    };
+
+    fn read_half(frame: &[u8], _: Rounding) -> IqSample {
+        let i = LittleEndian::read_u16(&frame[0..2]);
+        let q = LittleEndian::read_u16(&frame[2..4]) & 0x7fff;
+        (i as f32 / 65536.0, q as f32 / 32768.0)
+    }
+
+    /** A HALF frame holding the given codes. */
+    pub(crate) fn half_frame(i: u16, q: u16) -> [u8; 4] {
+        let mut frame = [0; 4];
+        LittleEndian::write_u16(&mut frame[0..2], i);
+        LittleEndian::write_u16(&mut frame[2..4], q | 0x8000);
+        frame
+    }
+
+    fn frame(i: u32, q: u32) -> [u8; PACKET_SIZE] {
+        let mut frame = [0; PACKET_SIZE];
+        LittleEndian::write_u32(&mut frame[0..4], i);
+        LittleEndian::write_u32(&mut frame[4..8], q);
+        frame
+    }
+
+    #[test]
+    fn extreme_codes_convert_to_the_reference_values() {
+        // Raw word, then the f32 bits with Nearest and TowardZero
+        let vectors: [(u32, u32, u32); 9] = [
+            (0x0000_0000, 0x0000_0000, 0x0000_0000),
+            (0x0000_0001, 0x0000_0000, 0x0000_0000),
+            (0x0000_ffff, 0x377f_fe00, 0x377f_fe00),
+            (0x0001_0000, 0x3800_0000, 0x3800_0000),
+            (0x7fff_ffff, 0x3f7f_ff00, 0x3f7f_feff),
+            (0x8000_0000, 0x2f80_0000, 0x2f80_0000),
+            (0x8000_0001, 0x2f80_0000, 0x2f80_0000),
+            (0xfffe_ffff, 0x3f7f_fd00, 0x3f7f_fcff),
+            (0xffff_ffff, 0x3f7f_ff00, 0x3f7f_feff),
+        ];
+        for &(code, nearest, toward_zero) in &vectors {
+            let (i, q) = read_packet(&frame(code, code), Rounding::Nearest);
+            assert_eq!((i.to_bits(), q.to_bits()), (nearest, nearest), "code {:#x}", code);
+            let (i, _) = read_packet(&frame(code, 0), Rounding::TowardZero);
+            assert_eq!(i.to_bits(), toward_zero, "code {:#x}", code);
+        }
+    }
+
+    #[test]
+    fn samples_stay_below_full_scale() {
+        for rounding in [Rounding::Nearest, Rounding::TowardZero] {
+            let (i, q) = read_packet(&frame(u32::MAX, u32::MAX), rounding);
+            assert!(i < 1.0 && q < 1.0);
+        }
+    }
+
+    #[test]
+    fn default_rounding_matches_existing_recordings() {
+        assert_eq!(Rounding::default(), Rounding::Nearest);
+        // What a plain cast gives, as before rounding was selectable
+        let code = 0x7654_3210u32;
+        assert_eq!(to_f32(code, Rounding::default()).to_bits(), (code as f32).to_bits());
+    }
+
+    /** The mean error, in units of the code, of converting a ramp of codes with the rounding mode. */
+    fn ramp_bias(rounding: Rounding) -> f64 {
+        let start = 1u32 << 30;
+        let n = 1u32 << 16;
+        let total: f64 = (start..start + n)
+            .map(|code| to_f32(code, rounding) as f64 - code as f64)
+            .sum();
+        total / n as f64
+    }
+
+    #[test]
+    fn round_to_nearest_removes_the_truncation_bias() {
+        // Codes near 2^30 lose 7 bits, a step of 128
+        let truncated = ramp_bias(Rounding::TowardZero);
+        let nearest = ramp_bias(Rounding::Nearest);
+        assert!((truncated + 63.5).abs() < 0.5, "truncation bias {}", truncated);
+        assert!(nearest.abs() < 0.5, "round to nearest bias {}", nearest);
+    }
+
+    #[test]
+    fn formats_with_the_same_name_and_layout_are_equal() {
+        assert_eq!(FrameFormat::by_name("ar2300"), Some(FrameFormat::AR2300));
+        assert_eq!(FrameFormat::by_name("test-half"), None);
+        assert_ne!(HALF, FrameFormat::AR2300);
+        // Whatever their conversion
+        assert_eq!(FrameFormat { convert: read_half, ..FrameFormat::AR2300 }, FrameFormat::AR2300);
+        let renamed = FrameFormat { name: "ar2300", ..HALF };
+        assert_ne!(renamed, FrameFormat::AR2300);
+        assert_ne!(FrameFormat { frame_size: 4, ..FrameFormat::AR2300 }, FrameFormat::AR2300);
+        assert_ne!(FrameFormat { sync_byte: 0, ..FrameFormat::AR2300 }, FrameFormat::AR2300);
+        assert_ne!(FrameFormat { sync_mask: 0x02, ..FrameFormat::AR2300 }, FrameFormat::AR2300);
+    }
+
+    #[test]
+    fn another_format_decodes_across_blocks() {
+        let frames: Vec<u8> = (0..100u16).flat_map(|k| half_frame(k * 600, k * 300)).collect();
+        // Two junk bytes without the flag before the first frame
+        let stream: Vec<u8> = [0x00, 0x00].iter().copied().chain(frames).collect();
+        let mut state = DecodeState::with_format(HALF);
+        let mut out = Vec::new();
+        let mut report = DecodeReport::default();
+        for block in stream.chunks(7) {
+            report.add(&decode_block(block, &mut state, Rounding::default(), &mut out));
+        }
+        assert_eq!(state.format(), HALF);
+        assert_eq!(report.skipped_bytes, 2);
+        assert_eq!(report.invalid_packets, 0);
+        assert_eq!(out.len(), 100);
+        for (k, sample) in out.iter().enumerate() {
+            assert_eq!(*sample, read_half(&half_frame(k as u16 * 600, k as u16 * 300), Rounding::default()));
+        }
+        // The AR2300 layout doesn't find the same frames
+        let mut ar2300 = Vec::new();
+        decode_with_format(&stream, FrameFormat::AR2300, Rounding::default(), &mut ar2300);
+        assert_ne!(ar2300.len(), 100);
+    }
+
+    #[test]
+    fn reset_keeps_the_format() {
+        let mut state = DecodeState::with_format(HALF);
+        let stream: Vec<u8> = half_frame(1, 1).iter().chain(&half_frame(2, 2)[..3]).copied().collect();
+        decode_block(&stream, &mut state, Rounding::default(), &mut Vec::new());
+        assert!(state.is_synced());
+        assert_eq!(state.pending_bytes(), 3);
+        state.reset();
+        assert!(!state.is_synced());
+        assert_eq!(state.pending_bytes(), 0);
+        assert_eq!(state.format(), HALF);
+    }
+}
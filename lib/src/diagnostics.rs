@@ -0,0 +1,282 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::events::emit;
+use crate::format::SampleFormat;
+use crate::iq::SAMPLE_RATE;
+use log::{info, Level};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const PROBE_CHUNK: usize = 64 * 1024;
+
+/** The headroom `check_bandwidth` is normally asked for: 25% above the stream's rate. */
+pub const DEFAULT_MARGIN: f64 = 0.25;
+
+/** What to do when the output looks too slow for the stream. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandwidthCheck {
+    /** Don't probe the output. */
+    Off,
+    /** Probe and warn, but start anyway. */
+    Warn,
+    /** Probe and refuse to start. */
+    Enforce,
+}
+
+/** The byte rate a Writer produces at the AR2300's sample rate, writing `format`. */
+pub fn required_byte_rate(format: SampleFormat) -> f64 {
+    SAMPLE_RATE as f64 * format.bytes_per_sample() as f64
+}
+
+/**
+ Something whose write rate can be measured before a capture starts.
+ `FileProbe` writes next to the output; tests supply their own.
+ */
+pub trait WriteProbe {
+    /** Write a burst of `bytes` and return the rate it was written at, in bytes per second. */
+    fn measure(&self, bytes: u64) -> io::Result<f64>;
+}
+
+/** Probes the disk a file will be written to. See `probe_write_rate`. */
+#[derive(Clone, Debug)]
+pub struct FileProbe {
+    path: PathBuf,
+}
+
+impl FileProbe {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileProbe {
+        FileProbe { path: path.as_ref().to_path_buf() }
+    }
+}
+
+impl WriteProbe for FileProbe {
+    fn measure(&self, bytes: u64) -> io::Result<f64> {
+        probe_write_rate(&self.path, bytes)
+    }
+}
+
+/**
+ Measure how fast data can be written to disk next to `path`. A burst of
+ `bytes` zeros is written and synced to a temporary file beside it, which
+ is deleted afterwards. Returns bytes per second.
+ */
+pub fn probe_write_rate(path: &Path, bytes: u64) -> io::Result<f64> {
+    let probe_path = probe_path(path);
+    let result = write_burst(&probe_path, bytes);
+    let _ = fs::remove_file(&probe_path);
+    result
+}
+
+fn probe_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".probe");
+    path.with_file_name(name)
+}
+
+fn write_burst(path: &Path, bytes: u64) -> io::Result<f64> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let chunk = vec![0u8; PROBE_CHUNK];
+    let started = Instant::now();
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let n = remaining.min(PROBE_CHUNK as u64) as usize;
+        file.write_all(&chunk[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()?;
+    let elapsed = started.elapsed().as_secs_f64();
+    Ok(bytes as f64 / elapsed.max(f64::EPSILON))
+}
+
+/**
+ Returns true if the measured rate covers the required rate with `margin`
+ to spare, e.g. a margin of 0.5 asks for 50% headroom.
+ */
+pub fn is_fast_enough(required: f64, measured: f64, margin: f64) -> bool {
+    measured >= required * (1.0 + margin)
+}
+
+/** What `check_bandwidth` decided. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /** The check is off, so nothing was probed. */
+    Skipped,
+    /** The output is fast enough. */
+    Passed,
+    /** The output is too slow, and the policy is to warn. */
+    Warned,
+    /** The output is too slow, and the policy is to refuse to start. */
+    Refused,
+}
+
+/** What was known about the output's bandwidth when a capture started. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthReport {
+    pub policy: BandwidthCheck,
+    /** Bytes per second the stream needs. */
+    pub required: f64,
+    /** Bytes per second the probe managed, if it was run. */
+    pub measured: Option<f64>,
+    pub margin: f64,
+    pub decision: Decision,
+}
+
+impl BandwidthReport {
+    /** The report as a JSON object, for metadata. */
+    pub fn to_json(&self) -> String {
+        let policy = match self.policy {
+            BandwidthCheck::Off => "off",
+            BandwidthCheck::Warn => "warn",
+            BandwidthCheck::Enforce => "enforce",
+        };
+        let decision = match self.decision {
+            Decision::Skipped => "skipped",
+            Decision::Passed => "passed",
+            Decision::Warned => "warned",
+            Decision::Refused => "refused",
+        };
+        let measured = self.measured.map_or("null".to_string(), |m| format!("{:.0}", m));
+        format!("{{\"policy\": \"{}\", \"required\": {:.0}, \"measured\": {}, \"margin\": {}, \"decision\": \"{}\"}}",
+                policy, self.required, measured, self.margin, decision)
+    }
+}
+
+/**
+ Apply a bandwidth policy: unless it is off, measure the output with a
+ burst of `burst` bytes and decide whether it can keep up with `required`
+ bytes per second with `margin` to spare. The result is logged, and a
+ warning published if the output is too slow; refusing to start is left
+ to the caller.
+ */
+pub fn check_bandwidth(policy: BandwidthCheck,
+                       required: f64,
+                       margin: f64,
+                       probe: &dyn WriteProbe,
+                       burst: u64) -> io::Result<BandwidthReport> {
+    let mut report = BandwidthReport { policy, required, measured: None, margin, decision: Decision::Skipped };
+    if policy == BandwidthCheck::Off {
+        return Ok(report);
+    }
+    let measured = probe.measure(burst)?;
+    report.measured = Some(measured);
+    info!("Output bandwidth: required {:.1} MB/s, measured {:.1} MB/s", required / 1e6, measured / 1e6);
+    report.decision = match (is_fast_enough(required, measured, margin), policy) {
+        (true, _) => Decision::Passed,
+        (false, BandwidthCheck::Enforce) => Decision::Refused,
+        (false, _) => {
+            emit(Level::Warn, format_args!("The output can't keep up with the IQ stream: {:.1} MB/s needed, {:.1} MB/s measured",
+                                           required * (1.0 + margin) / 1e6, measured / 1e6));
+            Decision::Warned
+        }
+    };
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq::{sigmf_meta_path, Writer};
+    use crate::queue::Queue;
+    use std::cell::Cell;
+
+    /** A probe that reports a fixed rate, or fails, and remembers the burst it was asked for. */
+    struct FixedProbe {
+        rate: Option<f64>,
+        burst: Cell<Option<u64>>,
+    }
+
+    impl FixedProbe {
+        fn new(rate: Option<f64>) -> FixedProbe {
+            FixedProbe { rate, burst: Cell::new(None) }
+        }
+    }
+
+    impl WriteProbe for FixedProbe {
+        fn measure(&self, bytes: u64) -> io::Result<f64> {
+            self.burst.set(Some(bytes));
+            self.rate.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+        }
+    }
+
+    #[test]
+    fn the_required_rate_follows_the_format() {
+        assert_eq!(required_byte_rate(SampleFormat::BigEndianF32), SAMPLE_RATE as f64 * 8.0);
+        assert_eq!(required_byte_rate(SampleFormat::LittleEndianI32), SAMPLE_RATE as f64 * 8.0);
+        assert_eq!(required_byte_rate(SampleFormat::LittleEndianI16), SAMPLE_RATE as f64 * 4.0);
+    }
+
+    #[test]
+    fn fast_enough_means_the_rate_plus_the_margin() {
+        assert!(is_fast_enough(100.0, 125.0, 0.25));
+        assert!(!is_fast_enough(100.0, 124.9, 0.25));
+        assert!(is_fast_enough(100.0, 100.0, 0.0));
+        assert!(!is_fast_enough(100.0, 99.9, 0.0));
+        assert!(!is_fast_enough(100.0, f64::NAN, 0.25));
+    }
+
+    #[test]
+    fn the_policy_decides_what_a_slow_output_means() {
+        let required = required_byte_rate(SampleFormat::LittleEndianI16);
+        let fast = FixedProbe::new(Some(required * 2.0));
+        let slow = FixedProbe::new(Some(required));
+        let cases = [
+            (BandwidthCheck::Off, &slow, Decision::Skipped),
+            (BandwidthCheck::Warn, &fast, Decision::Passed),
+            (BandwidthCheck::Warn, &slow, Decision::Warned),
+            (BandwidthCheck::Enforce, &fast, Decision::Passed),
+            (BandwidthCheck::Enforce, &slow, Decision::Refused),
+        ];
+        for (policy, probe, decision) in cases {
+            probe.burst.set(None);
+            let report = check_bandwidth(policy, required, DEFAULT_MARGIN, probe, 4096).unwrap();
+            assert_eq!(report.decision, decision, "{:?}", policy);
+            assert_eq!(report.measured, probe.burst.get().map(|_| probe.rate.unwrap()), "{:?}", policy);
+            assert_eq!(probe.burst.get(), if policy == BandwidthCheck::Off { None } else { Some(4096) });
+        }
+        let failing = FixedProbe::new(None);
+        assert!(check_bandwidth(BandwidthCheck::Enforce, required, DEFAULT_MARGIN, &failing, 4096).is_err());
+    }
+
+    #[test]
+    fn a_file_probe_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("capture.cf32");
+        assert!(FileProbe::new(&output).measure(1 << 20).unwrap() > 0.0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let report = BandwidthReport {
+            policy: BandwidthCheck::Warn,
+            required: 9_600_000.0,
+            measured: Some(5_000_000.4),
+            margin: 0.25,
+            decision: Decision::Warned,
+        };
+        assert_eq!(report.to_json(),
+                   r#"{"policy": "warn", "required": 9600000, "measured": 5000000, "margin": 0.25, "decision": "warned"}"#);
+        let mut writer = Writer::new(Queue::new(16), Box::new(io::sink()));
+        writer.set_bandwidth_report(Some(report));
+        writer.with_sigmf_metadata(&output, SAMPLE_RATE, 100e6).unwrap();
+        let meta = fs::read_to_string(sigmf_meta_path(&output)).unwrap();
+        assert!(meta.contains(&format!("\"ar2300:bandwidth\": {}", report.to_json())), "{}", meta);
+    }
+}
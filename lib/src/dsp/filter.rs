@@ -0,0 +1,287 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+
+/** The most taps a filter may have. Longer filters are almost certainly a mistake. */
+pub const MAX_TAPS: usize = 65_535;
+
+/** The narrowest transition band `design_lowpass` accepts, as a fraction of the sample rate. */
+pub const MIN_TRANSITION: f64 = 1e-5;
+
+/** Why a set of coefficients or a filter spec was rejected. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterError {
+    /** No coefficients were given. */
+    Empty,
+    /** The coefficient at this index is NaN or infinite. */
+    NonFinite { index: usize },
+    /** More than MAX_TAPS coefficients were given. */
+    TooLong { len: usize },
+    /** A linear-phase low-pass needs an odd number of taps, so its delay is a whole sample. */
+    EvenLength { len: usize },
+    /** Normalization was asked for, but the taps sum to zero. */
+    ZeroGain,
+    /** A design parameter is out of range. */
+    InvalidConfig(String),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::Empty => write!(f, "No filter coefficients"),
+            FilterError::NonFinite { index } => write!(f, "Filter coefficient {} is not finite", index),
+            FilterError::TooLong { len } => write!(f, "{} filter coefficients is more than the limit of {}", len, MAX_TAPS),
+            FilterError::EvenLength { len } => write!(f, "A low-pass filter needs an odd number of taps, not {}", len),
+            FilterError::ZeroGain => write!(f, "Filter coefficients sum to zero and can't be normalized"),
+            FilterError::InvalidConfig(reason) => write!(f, "Invalid filter specification: {}", reason),
+        }
+    }
+}
+
+impl Error for FilterError {}
+
+/** Coefficients that passed validation, and the scale applied to them. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatedTaps {
+    pub taps: Vec<f32>,
+    /** What the coefficients were multiplied by, 1.0 if they weren't normalized. */
+    pub scale: f32,
+}
+
+/**
+ Check user-supplied low-pass coefficients: there must be an odd number of
+ them, no more than MAX_TAPS, all finite. With `normalize` set they are
+ scaled to unity gain at DC and the scale is reported.
+ */
+// `is_multiple_of` needs Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+pub fn validate(taps: &[f32], normalize: bool) -> Result<ValidatedTaps, FilterError> {
+    if taps.is_empty() {
+        return Err(FilterError::Empty);
+    }
+    if taps.len() > MAX_TAPS {
+        return Err(FilterError::TooLong { len: taps.len() });
+    }
+    if taps.len() % 2 == 0 {
+        return Err(FilterError::EvenLength { len: taps.len() });
+    }
+    if let Some(index) = taps.iter().position(|t| !t.is_finite()) {
+        return Err(FilterError::NonFinite { index });
+    }
+    let mut scale = 1.0;
+    if normalize {
+        let gain: f64 = taps.iter().map(|&t| t as f64).sum();
+        if gain.abs() < f64::EPSILON {
+            return Err(FilterError::ZeroGain);
+        }
+        scale = (1.0 / gain) as f32;
+    }
+    Ok(ValidatedTaps {
+        taps: taps.iter().map(|t| t * scale).collect(),
+        scale,
+    })
+}
+
+/**
+ Design a low-pass filter with a Kaiser-windowed sinc. Frequencies are
+ fractions of the sample rate: `cutoff` is the middle of the transition
+ band, `transition` its width, and the stopband is attenuated by at least
+ `attenuation` dB. The taps have unity gain at DC.
+ */
+pub fn design_lowpass(cutoff: f64, transition: f64, attenuation: f64) -> Result<Vec<f32>, FilterError> {
+    if !(cutoff > 0.0 && cutoff < 0.5) {
+        return Err(FilterError::InvalidConfig(format!("cutoff {} is not between 0 and 0.5", cutoff)));
+    }
+    if !(transition.is_finite() && transition >= MIN_TRANSITION) {
+        return Err(FilterError::InvalidConfig(format!("transition {} is narrower than {}", transition, MIN_TRANSITION)));
+    }
+    if !(cutoff - transition / 2.0 > 0.0 && cutoff + transition / 2.0 < 0.5) {
+        return Err(FilterError::InvalidConfig(format!("transition {} doesn't fit around cutoff {}", transition, cutoff)));
+    }
+    if !(attenuation > 0.0 && attenuation.is_finite()) {
+        return Err(FilterError::InvalidConfig(format!("attenuation {} dB is not positive", attenuation)));
+    }
+
+    // Kaiser's estimates for the window's shape and length
+    let beta = if attenuation > 50.0 {
+        0.1102 * (attenuation - 8.7)
+    } else if attenuation >= 21.0 {
+        0.5842 * (attenuation - 21.0).powf(0.4) + 0.07886 * (attenuation - 21.0)
+    } else {
+        0.0
+    };
+    // Checked as a float, since a huge estimate would saturate the cast
+    let estimate = ((attenuation - 7.95) / (14.36 * transition)).ceil().max(1.0) + 1.0;
+    if estimate > MAX_TAPS as f64 {
+        return Err(FilterError::TooLong { len: estimate.min(usize::MAX as f64) as usize });
+    }
+    let len = (estimate as usize) | 1;
+    if len > MAX_TAPS {
+        return Err(FilterError::TooLong { len });
+    }
+
+    let middle = (len / 2) as f64;
+    let denominator = bessel_i0(beta);
+    let taps: Vec<f64> = (0..len).map(|n| {
+        let x = n as f64 - middle;
+        let sinc = if x == 0.0 {
+            2.0 * cutoff
+        } else {
+            (2.0 * PI * cutoff * x).sin() / (PI * x)
+        };
+        let r = x / middle.max(1.0);
+        sinc * bessel_i0(beta * (1.0 - r * r).max(0.0).sqrt()) / denominator
+    }).collect();
+    let gain: f64 = taps.iter().sum();
+    Ok(taps.iter().map(|t| (t / gain) as f32).collect())
+}
+
+/**
+ The filter's magnitude response in dB at `n_points` frequencies spaced
+ evenly from DC to half the sample rate, both included.
+ */
+pub fn response(taps: &[f32], n_points: usize) -> Vec<f32> {
+    let step = if n_points > 1 { 0.5 / (n_points - 1) as f64 } else { 0.0 };
+    (0..n_points).map(|k| {
+        let w = 2.0 * PI * step * k as f64;
+        let (re, im) = taps.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, &t)| {
+            let phase = w * n as f64;
+            (re + t as f64 * phase.cos(), im - t as f64 * phase.sin())
+        });
+        (20.0 * (re * re + im * im).sqrt().max(1e-12).log10()) as f32
+    }).collect()
+}
+
+/**
+ The smallest attenuation, in dB below the DC gain, from `stopband` (a
+ fraction of the sample rate) up to half the sample rate, measured at
+ `n_points` frequencies across the whole band.
+ */
+pub fn stopband_attenuation(taps: &[f32], stopband: f64, n_points: usize) -> f32 {
+    let magnitudes = response(taps, n_points);
+    let dc = magnitudes.first().copied().unwrap_or(0.0);
+    let step = if n_points > 1 { 0.5 / (n_points - 1) as f64 } else { 0.0 };
+    magnitudes.iter().enumerate()
+        .filter(|(k, _)| *k as f64 * step >= stopband)
+        .map(|(_, m)| dc - m)
+        .fold(f32::INFINITY, f32::min)
+}
+
+/** The zeroth-order modified Bessel function of the first kind, from its power series. */
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= (half / k as f64) * (half / k as f64);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POINTS: usize = 4096;
+
+    /** The gain in dB at `frequency`, relative to DC. */
+    fn gain_at(taps: &[f32], frequency: f64) -> f32 {
+        let magnitudes = response(taps, POINTS);
+        let k = (frequency / 0.5 * (POINTS - 1) as f64).round() as usize;
+        magnitudes[k] - magnitudes[0]
+    }
+
+    #[test]
+    fn designed_filters_meet_their_specs() {
+        for &(cutoff, transition, attenuation) in &[(0.1, 0.05, 60.0), (0.2, 0.02, 80.0), (0.05, 0.02, 40.0), (0.25, 0.1, 30.0)] {
+            let taps = design_lowpass(cutoff, transition, attenuation).unwrap();
+            assert_eq!(taps.len() % 2, 1);
+            assert!((taps.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+            let spec = format!("cutoff {} transition {} attenuation {}", cutoff, transition, attenuation);
+            // Kaiser's estimates are close, not exact; allow a dB
+            let achieved = stopband_attenuation(&taps, cutoff + transition / 2.0, POINTS);
+            assert!(achieved > attenuation as f32 - 1.0, "{}: achieved {} dB", spec, achieved);
+            // The passband ripple is about the size of the stopband's; allow twice that
+            let ripple = 20.0 * (1.0 + 2.0 * 10f64.powf(-attenuation / 20.0)).log10();
+            let passband = gain_at(&taps, cutoff - transition / 2.0);
+            assert!((passband.abs() as f64) < ripple, "{}: passband edge at {} dB", spec, passband);
+            // Half the amplitude in the middle of the transition band
+            let middle = gain_at(&taps, cutoff);
+            assert!((middle + 6.02).abs() < 0.5, "{}: {} dB at the cutoff", spec, middle);
+        }
+    }
+
+    #[test]
+    fn a_sharper_spec_needs_more_taps() {
+        let loose = design_lowpass(0.1, 0.05, 60.0).unwrap();
+        assert!(design_lowpass(0.1, 0.01, 60.0).unwrap().len() > loose.len());
+        assert!(design_lowpass(0.1, 0.05, 90.0).unwrap().len() > loose.len());
+    }
+
+    #[test]
+    fn impossible_specs_are_rejected() {
+        let invalid = |r: Result<Vec<f32>, FilterError>| matches!(r, Err(FilterError::InvalidConfig(_)));
+        assert!(invalid(design_lowpass(0.0, 0.01, 60.0)));
+        assert!(invalid(design_lowpass(0.5, 0.01, 60.0)));
+        assert!(invalid(design_lowpass(f64::NAN, 0.01, 60.0)));
+        assert!(invalid(design_lowpass(0.1, 0.0, 60.0)));
+        assert!(invalid(design_lowpass(0.1, -0.01, 60.0)));
+        assert!(invalid(design_lowpass(0.1, f64::NAN, 60.0)));
+        assert!(invalid(design_lowpass(0.1, f64::INFINITY, 60.0)));
+        assert!(invalid(design_lowpass(0.1, 1e-300, 60.0)));
+        assert!(invalid(design_lowpass(0.1, f64::MIN_POSITIVE, 60.0)));
+        assert!(invalid(design_lowpass(0.1, 0.3, 60.0)));
+        assert!(invalid(design_lowpass(0.1, 0.01, 0.0)));
+        assert!(invalid(design_lowpass(0.1, 0.01, f64::INFINITY)));
+    }
+
+    #[test]
+    fn specs_needing_too_many_taps_are_rejected() {
+        assert!(matches!(design_lowpass(0.1, MIN_TRANSITION, 60.0), Err(FilterError::TooLong { .. })));
+        assert!(matches!(design_lowpass(0.1, 0.01, 1e300), Err(FilterError::TooLong { .. })));
+    }
+
+    #[test]
+    fn invalid_coefficients_are_rejected_by_name() {
+        assert_eq!(validate(&[], false), Err(FilterError::Empty));
+        assert_eq!(validate(&[0.25, 0.5, 0.25, 0.0], false), Err(FilterError::EvenLength { len: 4 }));
+        assert_eq!(validate(&[0.25, f32::NAN, 0.25], false), Err(FilterError::NonFinite { index: 1 }));
+        assert_eq!(validate(&[0.25, 0.5, f32::INFINITY], false), Err(FilterError::NonFinite { index: 2 }));
+        assert_eq!(validate(&vec![0.0; MAX_TAPS + 2], false), Err(FilterError::TooLong { len: MAX_TAPS + 2 }));
+        assert_eq!(validate(&[1.0, -2.0, 1.0], true), Err(FilterError::ZeroGain));
+        assert_eq!(FilterError::NonFinite { index: 1 }.to_string(), "Filter coefficient 1 is not finite");
+    }
+
+    #[test]
+    fn normalization_reports_its_scale() {
+        let validated = validate(&[1.0, 2.0, 1.0], true).unwrap();
+        assert_eq!(validated.scale, 0.25);
+        assert_eq!(validated.taps, vec![0.25, 0.5, 0.25]);
+        let untouched = validate(&[1.0, 2.0, 1.0], false).unwrap();
+        assert_eq!(untouched.scale, 1.0);
+        assert_eq!(untouched.taps, vec![1.0, 2.0, 1.0]);
+    }
+}
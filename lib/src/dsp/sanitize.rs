@@ -0,0 +1,185 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use std::error::Error;
+use std::fmt;
+
+/** What `sanitize` does when it finds a bad sample. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SanitizePolicy {
+    /** Replace each bad sample with zero and count it. */
+    ReplaceWithZero,
+    /** Leave the block untouched and report the first bad sample. */
+    Abort,
+}
+
+/** What was wrong with a bad sample. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /** A component is NaN or infinite. */
+    NonFinite,
+    /** A component's magnitude is beyond the bound. */
+    OutOfRange,
+}
+
+/** The first bad sample in a block, returned by `sanitize` under `SanitizePolicy::Abort`. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BadSample {
+    /** Its index in the block. */
+    pub index: usize,
+    pub value: (f32, f32),
+    pub problem: Problem,
+}
+
+impl fmt::Display for BadSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problem = match self.problem {
+            Problem::NonFinite => "is not finite",
+            Problem::OutOfRange => "is out of range"
+        };
+        write!(f, "Sample {} ({}, {}) {}", self.index, self.value.0, self.value.1, problem)
+    }
+}
+
+impl Error for BadSample {}
+
+/** The bad samples `sanitize` replaced. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SanitizeReport {
+    pub non_finite: u64,
+    pub out_of_range: u64,
+}
+
+impl SanitizeReport {
+    pub fn total(&self) -> u64 {
+        self.non_finite + self.out_of_range
+    }
+
+    pub fn add(&mut self, other: &SanitizeReport) {
+        self.non_finite += other.non_finite;
+        self.out_of_range += other.out_of_range;
+    }
+}
+
+/** What is wrong with the sample, if anything. */
+fn check(sample: (f32, f32), bound: f32) -> Option<Problem> {
+    let (i, q) = sample;
+    if !i.is_finite() || !q.is_finite() {
+        Some(Problem::NonFinite)
+    } else if i.abs() > bound || q.abs() > bound {
+        Some(Problem::OutOfRange)
+    } else {
+        None
+    }
+}
+
+/**
+ Check a block of samples for NaN, infinity and components whose magnitude
+ is beyond `bound`. Under `ReplaceWithZero` bad samples are zeroed and
+ counted in the report; under `Abort` nothing is changed and the first bad
+ sample is returned, so a caller can stop before writing any of the block.
+ One pass over the block, with no allocation.
+ */
+pub fn sanitize(samples: &mut [(f32, f32)], bound: f32, policy: SanitizePolicy) -> Result<SanitizeReport, BadSample> {
+    let mut report = SanitizeReport::default();
+    for (index, sample) in samples.iter_mut().enumerate() {
+        let problem = match check(*sample, bound) {
+            Some(problem) => problem,
+            None => continue
+        };
+        if policy == SanitizePolicy::Abort {
+            return Err(BadSample { index, value: *sample, problem });
+        }
+        match problem {
+            Problem::NonFinite => report.non_finite += 1,
+            Problem::OutOfRange => report.out_of_range += 1
+        }
+        *sample = (0.0, 0.0);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** A stage gone wrong: every third sample NaN, every fifth too large. */
+    fn misbehaving_stage(n: usize) -> Vec<(f32, f32)> {
+        (0..n).map(|i| match i {
+            i if i % 3 == 1 => (f32::NAN, 0.5),
+            i if i % 5 == 2 => (0.5, -4.0),
+            i => (i as f32 / n as f32, 0.25)
+        }).collect()
+    }
+
+    #[test]
+    fn good_samples_pass_unchanged() {
+        let mut samples = vec![(0.0, 0.0), (1.0, -1.0), (0.5, 0.25)];
+        let before = samples.clone();
+        for &policy in &[SanitizePolicy::ReplaceWithZero, SanitizePolicy::Abort] {
+            assert_eq!(sanitize(&mut samples, 1.0, policy), Ok(SanitizeReport::default()));
+            assert_eq!(samples, before);
+        }
+    }
+
+    #[test]
+    fn replace_zeroes_and_counts_bad_samples() {
+        let mut samples = misbehaving_stage(15);
+        let report = sanitize(&mut samples, 1.0, SanitizePolicy::ReplaceWithZero).unwrap();
+        // 1, 4, 7, 10 and 13 are NaN; 2 and 12 are out of range
+        assert_eq!(report, SanitizeReport { non_finite: 5, out_of_range: 2 });
+        assert_eq!(report.total(), 7);
+        assert!(samples.iter().all(|&s| check(s, 1.0).is_none()));
+        assert_eq!(samples[1], (0.0, 0.0));
+        assert_eq!(samples[2], (0.0, 0.0));
+        assert_eq!(samples[3], (0.2, 0.25));
+    }
+
+    #[test]
+    fn abort_reports_the_first_bad_sample_and_changes_nothing() {
+        let mut samples = misbehaving_stage(15);
+        let before = format!("{:?}", samples);
+        let bad = sanitize(&mut samples, 1.0, SanitizePolicy::Abort).unwrap_err();
+        assert_eq!((bad.index, bad.problem), (1, Problem::NonFinite));
+        assert!(bad.value.0.is_nan());
+        assert_eq!(format!("{:?}", samples), before);
+        assert!(bad.to_string().contains("Sample 1"));
+    }
+
+    #[test]
+    fn infinities_and_the_bound_are_checked() {
+        let mut samples = vec![(1.0, 1.0), (f32::INFINITY, 0.0), (0.0, f32::NEG_INFINITY), (-1.5, 0.0)];
+        let bad = sanitize(&mut samples.clone(), 1.0, SanitizePolicy::Abort).unwrap_err();
+        assert_eq!((bad.index, bad.problem), (1, Problem::NonFinite));
+        // The bound itself is allowed
+        assert_eq!(sanitize(&mut samples, 1.0, SanitizePolicy::ReplaceWithZero),
+                   Ok(SanitizeReport { non_finite: 2, out_of_range: 1 }));
+        let mut samples = vec![(-1.5, 0.0)];
+        assert_eq!(sanitize(&mut samples, 2.0, SanitizePolicy::Abort), Ok(SanitizeReport::default()));
+    }
+
+    #[test]
+    fn reports_add_up() {
+        let mut total = SanitizeReport::default();
+        total.add(&SanitizeReport { non_finite: 2, out_of_range: 1 });
+        total.add(&SanitizeReport { non_finite: 1, out_of_range: 0 });
+        assert_eq!(total, SanitizeReport { non_finite: 3, out_of_range: 1 });
+    }
+}
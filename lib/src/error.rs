@@ -0,0 +1,150 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::budget::MemoryPlan;
+use crate::cancel::Cancelled;
+use crate::firmware::FirmwareError;
+use crate::global::IncompatibleGlobalConfig;
+use crate::message::{EnglishRenderer, MessageRenderer};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/** The errors returned by the crate's public API. */
+#[derive(Debug)]
+pub enum Ar2300Error {
+    /** No AR2300 IQ device is connected. */
+    DeviceNotFound,
+    /** The IQ interface couldn't be claimed, e.g. because another program holds it. */
+    InterfaceUnavailable(String),
+    /** A USB operation failed. */
+    UsbError(rusb::Error),
+    /** Programming the firmware failed. */
+    FirmwareError(FirmwareError),
+    /** The operation gave up because its CancelToken was cancelled. */
+    Cancelled,
+    /** `Receiver::prepare` or `start` was called on a running receiver. */
+    AlreadyRunning,
+    /** `Receiver::trigger` was called before `prepare`. */
+    NotPrepared,
+    /** A setting is out of range. */
+    InvalidConfig(String),
+    /** A before-start or after-stop hook failed. */
+    HookFailed(String),
+    /** The data couldn't be decoded, or the capture was aborted because of it. */
+    DecodeFailed(String),
+    /** Reading input or writing samples failed. */
+    IoError(io::Error),
+    /** Process-wide settings conflicting with ones already in place were requested. */
+    IncompatibleGlobalConfig(IncompatibleGlobalConfig),
+    /** A receiver's buffers could take more memory than its budget, broken down in the plan. */
+    OverBudget(MemoryPlan),
+    /** The Ctrl-C handler couldn't be installed. */
+    CtrlCError(ctrlc::Error),
+}
+
+impl Ar2300Error {
+    /**
+     A copy of the error, to record somewhere else, such as a queue's close
+     reason, while the original is returned. I/O errors can't be cloned, so
+     the copy of one keeps its kind and message but not its source, and a
+     missing signal becomes a system error with its message.
+     */
+    pub(crate) fn duplicate(&self) -> Ar2300Error {
+        match self {
+            Ar2300Error::DeviceNotFound => Ar2300Error::DeviceNotFound,
+            Ar2300Error::InterfaceUnavailable(reason) => Ar2300Error::InterfaceUnavailable(reason.clone()),
+            Ar2300Error::UsbError(e) => Ar2300Error::UsbError(*e),
+            Ar2300Error::FirmwareError(e) => Ar2300Error::FirmwareError(e.clone()),
+            Ar2300Error::Cancelled => Ar2300Error::Cancelled,
+            Ar2300Error::AlreadyRunning => Ar2300Error::AlreadyRunning,
+            Ar2300Error::NotPrepared => Ar2300Error::NotPrepared,
+            Ar2300Error::InvalidConfig(reason) => Ar2300Error::InvalidConfig(reason.clone()),
+            Ar2300Error::HookFailed(reason) => Ar2300Error::HookFailed(reason.clone()),
+            Ar2300Error::DecodeFailed(reason) => Ar2300Error::DecodeFailed(reason.clone()),
+            Ar2300Error::IoError(e) => Ar2300Error::IoError(duplicate_io(e)),
+            Ar2300Error::IncompatibleGlobalConfig(e) => Ar2300Error::IncompatibleGlobalConfig(e.clone()),
+            Ar2300Error::OverBudget(plan) => Ar2300Error::OverBudget(plan.clone()),
+            Ar2300Error::CtrlCError(e) => Ar2300Error::CtrlCError(match e {
+                ctrlc::Error::MultipleHandlers => ctrlc::Error::MultipleHandlers,
+                ctrlc::Error::System(e) => ctrlc::Error::System(duplicate_io(e)),
+                e @ ctrlc::Error::NoSuchSignal(_) => ctrlc::Error::System(io::Error::new(io::ErrorKind::NotFound, e.to_string())),
+            }),
+        }
+    }
+}
+
+fn duplicate_io(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+impl fmt::Display for Ar2300Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&EnglishRenderer.error(self))
+    }
+}
+
+impl Error for Ar2300Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Ar2300Error::UsbError(e) => Some(e),
+            Ar2300Error::FirmwareError(e) => Some(e),
+            Ar2300Error::IoError(e) => Some(e),
+            Ar2300Error::IncompatibleGlobalConfig(e) => Some(e),
+            Ar2300Error::CtrlCError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<rusb::Error> for Ar2300Error {
+    fn from(e: rusb::Error) -> Self {
+        Ar2300Error::UsbError(e)
+    }
+}
+
+impl From<FirmwareError> for Ar2300Error {
+    fn from(e: FirmwareError) -> Self {
+        Ar2300Error::FirmwareError(e)
+    }
+}
+
+impl From<Cancelled> for Ar2300Error {
+    fn from(_: Cancelled) -> Self {
+        Ar2300Error::Cancelled
+    }
+}
+
+impl From<io::Error> for Ar2300Error {
+    fn from(e: io::Error) -> Self {
+        Ar2300Error::IoError(e)
+    }
+}
+
+impl From<IncompatibleGlobalConfig> for Ar2300Error {
+    fn from(e: IncompatibleGlobalConfig) -> Self {
+        Ar2300Error::IncompatibleGlobalConfig(e)
+    }
+}
+
+impl From<ctrlc::Error> for Ar2300Error {
+    fn from(e: ctrlc::Error) -> Self {
+        Ar2300Error::CtrlCError(e)
+    }
+}
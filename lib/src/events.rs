@@ -0,0 +1,354 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::queue::{DequeueResult, OverflowPolicy, Queue};
+use crate::session::SessionId;
+use log::Level;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/** How many events a subscriber holds unless it asks for another capacity. */
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/** A warning or error reported by the crate, as published on an EventLog. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub level: Level,
+    pub message: String,
+    /** The capture that published it, if it came from one. */
+    pub session: Option<SessionId>,
+    /** Set on the event that stands in for events a subscriber lost. */
+    pub dropped: Option<DroppedEvents>,
+}
+
+/** Events a subscriber lost because it fell behind. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DroppedEvents {
+    pub total: u64,
+    /** How many of them were warnings or errors. */
+    pub serious: u64,
+}
+
+impl DroppedEvents {
+    /** The event reporting these losses: a warning if any of them were serious. */
+    fn summary(self) -> Event {
+        Event {
+            level: if self.serious > 0 { Level::Warn } else { Level::Info },
+            message: format!("{} events dropped, {} of them warnings or errors", self.total, self.serious),
+            session: None,
+            dropped: Some(self),
+        }
+    }
+}
+
+/** A snapshot of an EventLog's subscribers, from `EventLog::stats`. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventStats {
+    pub subscribers: usize,
+    /** Events waiting across every subscriber. */
+    pub queued: usize,
+    /** The most events the subscribers can hold between them. */
+    pub capacity: usize,
+    /** Events lost across every subscriber. */
+    pub dropped: u64,
+}
+
+/** One subscriber's queue and what it has lost. */
+struct Channel {
+    queue: Queue<Event>,
+    capacity: usize,
+    /** Losses not yet reported. Also keeps senders from interleaving. */
+    pending: Mutex<DroppedEvents>,
+    dropped: AtomicU64,
+}
+
+impl Channel {
+    fn send(&self, event: &Event) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        // Only the subscriber takes events out while this is held, so the
+        // queue can't fill up between checking its length and enqueueing.
+        let mut len = self.queue.len();
+        // A summary waits for the subscriber to make room, so it counts
+        // everything lost until then
+        if pending.total > 0 && len < self.capacity {
+            self.queue.enqueue(pending.summary());
+            *pending = DroppedEvents::default();
+            len += 1;
+        }
+        if len < self.capacity {
+            self.queue.enqueue(event.clone());
+        } else {
+            pending.total += 1;
+            if event.level <= Level::Warn {
+                pending.serious += 1;
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/**
+ Publishes the crate's warnings and errors to subscribers, as well as
+ logging them, so a program can show or record them as they happen.
+ Each subscriber has its own bounded queue. One that falls behind loses
+ the newest events rather than letting memory grow, and is told how many
+ it lost by a summary event in their place. With no subscribers,
+ publishing costs nothing beyond logging.
+ */
+pub struct EventLog {
+    subscribers: Mutex<Vec<Arc<Channel>>>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog::new()
+    }
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        EventLog { subscribers: Mutex::new(Vec::new()) }
+    }
+
+    /** The log every part of the crate publishes to. */
+    pub fn global() -> &'static EventLog {
+        static GLOBAL: EventLog = EventLog::new();
+        &GLOBAL
+    }
+
+    /** Receive the events published from now on, holding up to DEFAULT_CAPACITY of them. */
+    pub fn subscribe(&self) -> EventSubscriber {
+        self.subscribe_with(DEFAULT_CAPACITY)
+    }
+
+    /** Like `subscribe`, holding up to `capacity` events. */
+    pub fn subscribe_with(&self, capacity: usize) -> EventSubscriber {
+        let capacity = capacity.max(1);
+        let channel = Arc::new(Channel {
+            queue: Queue::named_with_policy("events", capacity, OverflowPolicy::DropNewest),
+            capacity,
+            pending: Mutex::new(DroppedEvents::default()),
+            dropped: AtomicU64::new(0),
+        });
+        self.lock().push(channel.clone());
+        EventSubscriber { channel }
+    }
+
+    /** Send an event to every subscriber. Subscribers that have closed are dropped. */
+    pub fn publish(&self, level: Level, message: fmt::Arguments) {
+        self.publish_in(None, level, message);
+    }
+
+    /** Like `publish`, tagging the event with the capture it came from. */
+    pub fn publish_in(&self, session: Option<SessionId>, level: Level, message: fmt::Arguments) {
+        let mut subscribers = self.lock();
+        subscribers.retain(|c| !c.queue.is_closed());
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = Event { level, message: message.to_string(), session, dropped: None };
+        for channel in subscribers.iter() {
+            channel.send(&event);
+        }
+    }
+
+    /** The subscribers' queue depths, capacities and losses. */
+    pub fn stats(&self) -> EventStats {
+        let subscribers = self.lock();
+        let live = subscribers.iter().filter(|c| !c.queue.is_closed());
+        live.fold(EventStats::default(), |mut stats, c| {
+            stats.subscribers += 1;
+            stats.queued += c.queue.len();
+            stats.capacity += c.capacity;
+            stats.dropped += c.dropped.load(Ordering::Relaxed);
+            stats
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Arc<Channel>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/** A subscription to an EventLog, from `EventLog::subscribe`. */
+pub struct EventSubscriber {
+    channel: Arc<Channel>,
+}
+
+impl EventSubscriber {
+    /**
+     Wait up to `timeout` for the next event. Returns None on timeout and
+     once unsubscribed and every event has been taken, including a last
+     summary of anything lost.
+     */
+    pub fn next(&self, timeout: Duration) -> Option<Event> {
+        match self.channel.queue.dequeue_result(timeout) {
+            DequeueResult::Item(event) => Some(event),
+            DequeueResult::Closed => {
+                let mut pending = self.channel.pending.lock().unwrap_or_else(|e| e.into_inner());
+                if pending.total > 0 {
+                    let summary = pending.summary();
+                    *pending = DroppedEvents::default();
+                    Some(summary)
+                } else {
+                    None
+                }
+            },
+            DequeueResult::Timeout => None
+        }
+    }
+
+    /** Events this subscriber has lost. */
+    pub fn dropped(&self) -> u64 {
+        self.channel.dropped.load(Ordering::Relaxed)
+    }
+
+    /** Stop receiving events. Those already queued can still be taken. */
+    pub fn unsubscribe(&self) {
+        self.channel.queue.close();
+    }
+}
+
+impl Drop for EventSubscriber {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+/**
+ Log a message and publish it on the global EventLog. Every warning and
+ error in the crate goes through here, except the queue's own, which
+ can't publish on a queue.
+ */
+pub(crate) fn emit(level: Level, message: fmt::Arguments) {
+    emit_in(None, level, message);
+}
+
+/** Like `emit`, for a message from a capture, which is tagged with its session. */
+pub(crate) fn emit_in(session: Option<SessionId>, level: Level, message: fmt::Arguments) {
+    match session {
+        Some(session) => log::log!(level, "[{}] {}", session, message),
+        None => log::log!(level, "{}", message)
+    }
+    EventLog::global().publish_in(session, level, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(log: &EventLog, level: Level, n: u64) {
+        for k in 0..n {
+            log.publish(level, format_args!("event {}", k));
+        }
+    }
+
+    /** Everything the subscriber can take without waiting. */
+    fn drain(subscriber: &EventSubscriber) -> Vec<Event> {
+        std::iter::from_fn(|| subscriber.next(Duration::ZERO)).collect()
+    }
+
+    #[test]
+    fn a_stalled_subscriber_stays_bounded_and_is_told_what_it_lost() {
+        let log = EventLog::new();
+        let stalled = log.subscribe_with(100);
+        publish(&log, Level::Warn, 3_000);
+        publish(&log, Level::Info, 2_000);
+        let stats = log.stats();
+        assert_eq!(stats.subscribers, 1);
+        assert_eq!(stats.queued, 100);
+        assert_eq!(stats.capacity, 100);
+        assert_eq!(stats.dropped, 4_900);
+        // The subscriber catches up; the next event is preceded by the summary
+        let kept = drain(&stalled);
+        assert_eq!(kept.len(), 100);
+        assert!(kept.iter().all(|e| e.dropped.is_none()));
+        log.publish(Level::Error, format_args!("after the stall"));
+        let after = drain(&stalled);
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].dropped, Some(DroppedEvents { total: 4_900, serious: 2_900 }));
+        assert_eq!(after[0].level, Level::Warn);
+        assert!(after[0].message.contains("4900 events dropped"), "{}", after[0].message);
+        assert_eq!(after[1].message, "after the stall");
+        assert_eq!(stalled.dropped(), 4_900);
+    }
+
+    #[test]
+    fn losses_are_reported_even_when_the_queue_stays_full() {
+        let log = EventLog::new();
+        let subscriber = log.subscribe_with(2);
+        publish(&log, Level::Info, 5);
+        // Room for one more, which goes to the summary
+        assert_eq!(subscriber.next(Duration::ZERO).unwrap().message, "event 0");
+        publish(&log, Level::Info, 1);
+        let events = drain(&subscriber);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].dropped, Some(DroppedEvents { total: 3, serious: 0 }));
+        assert_eq!(events[1].level, Level::Info);
+        // The event that made way for the summary is counted in the next one
+        publish(&log, Level::Info, 1);
+        assert_eq!(drain(&subscriber)[0].dropped, Some(DroppedEvents { total: 1, serious: 0 }));
+    }
+
+    #[test]
+    fn the_last_losses_are_reported_after_unsubscribing() {
+        let log = EventLog::new();
+        let subscriber = log.subscribe_with(1);
+        publish(&log, Level::Error, 4);
+        subscriber.unsubscribe();
+        let events = drain(&subscriber);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].dropped, Some(DroppedEvents { total: 3, serious: 3 }));
+        assert_eq!(subscriber.next(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn each_subscriber_has_its_own_capacity() {
+        let log = EventLog::new();
+        let small = log.subscribe_with(1);
+        let large = log.subscribe();
+        publish(&log, Level::Warn, 10);
+        assert_eq!(drain(&large).len(), 10);
+        assert_eq!(drain(&small).len(), 1);
+        assert_eq!((small.dropped(), large.dropped()), (9, 0));
+    }
+
+    #[test]
+    fn nothing_is_kept_without_subscribers() {
+        let log = EventLog::new();
+        publish(&log, Level::Warn, 10);
+        assert_eq!(log.stats(), EventStats::default());
+        let subscriber = log.subscribe();
+        drop(subscriber);
+        publish(&log, Level::Warn, 10);
+        assert_eq!(log.stats(), EventStats::default());
+    }
+
+    #[test]
+    fn emit_publishes_on_the_global_log() {
+        let subscriber = EventLog::global().subscribe_with(1 << 16);
+        emit(Level::Warn, format_args!("a warning from {}", "emit"));
+        let events = drain(&subscriber);
+        // Other tests may be publishing too
+        assert!(events.iter().any(|e| e.message == "a warning from emit" && e.level == Level::Warn));
+    }
+}
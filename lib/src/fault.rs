@@ -0,0 +1,365 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::codec::{IqSample, PACKET_SIZE};
+use crate::error::Ar2300Error;
+use crate::iq::PACKET_LENGTH;
+use crate::timeline::Gap;
+use crate::usb::fake::FakeDevice;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/** Samples numbered beyond this can't be told apart by `sample_index`. */
+const MAX_SAMPLES: u64 = 1 << 30;
+
+/**
+ A schedule of faults for a simulated device, so an application's
+ handling of the ways a capture goes wrong can be tested without waiting
+ for them to happen. The device streams `transfers` transfers of valid
+ frames and then stops sending; each frame carries its index in the
+ device's stream, which `sample_index` reads back, so samples lost along
+ the way show up as gaps in the recording.
+
+ Applying a plan is deterministic: the same plan always gives the same
+ transfers. Plans are usually loaded from TOML:
+
+ ```toml
+ transfers = 20
+
+ [[fault]]
+ kind = "failed-packet"
+ transfer = 3
+ packet = 1
+ ```
+
+ The simulated device delivers transfers as fast as the receiver asks for
+ them, so there is no clock to drift; a drifting clock can be simulated
+ with `Timeline`'s measured rate instead.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultPlan {
+    /** Transfers the device sends, counting any the receiver skips at start-up. */
+    pub transfers: u64,
+    #[serde(default, rename = "fault")]
+    pub faults: Vec<Fault>,
+}
+
+/** Something going wrong at a given point of a FaultPlan. Transfers and packets count from zero. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Fault {
+    /**
+     The packet carries only its first `bytes`, a whole number of frames.
+     Nothing is lost: the stream carries on in the next packet.
+     */
+    ShortPacket { transfer: u64, packet: usize, bytes: usize },
+    /** The packet fails, and the samples it would have carried are lost. */
+    FailedPacket { transfer: u64, packet: usize },
+    /** The transfer overflows, and the receiver discards all of it. */
+    Overflow { transfer: u64 },
+    /** The device loses `samples` samples from index `at` of its stream. */
+    Gap { at: u64, samples: u64 },
+    /** The transfer fails with an I/O error, which stops the capture. */
+    TransferError { transfer: u64 },
+    /** The device goes away during the transfer. Nothing is sent after it. */
+    Disconnect { transfer: u64 },
+}
+
+/** One transfer as the plan lays it out. */
+struct Planned {
+    result: rusb::Result<()>,
+    /** The stream indices each packet carries, or `None` if it fails. */
+    packets: Vec<Option<Vec<u64>>>,
+}
+
+impl FaultPlan {
+    /** Parse a plan from TOML. */
+    pub fn from_toml(toml: &str) -> Result<FaultPlan, Ar2300Error> {
+        toml::from_str(toml).map_err(|e| Ar2300Error::InvalidConfig(format!("Invalid fault plan: {}", e)))
+    }
+
+    /** Read a plan from a TOML file. */
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<FaultPlan, Ar2300Error> {
+        FaultPlan::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /**
+     Queue the plan's transfers on the device, for a receiver with
+     `packet_count` packets per transfer of the default length.
+     */
+    pub fn apply(&self, device: &FakeDevice, packet_count: usize) -> Result<(), Ar2300Error> {
+        for planned in self.plan(packet_count)? {
+            match planned.result {
+                Ok(()) => device.complete_packets(planned.packets.iter().map(|packet| match packet {
+                    Some(indices) => Ok(indices.iter().flat_map(|&i| frame(i)).collect()),
+                    None => Err(rusb::Error::Io)
+                }).collect()),
+                Err(e) => device.complete(Err(e), Vec::new()),
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     The gaps a recording made from this plan should have, if the receiver
+     skips `skipped` transfers at start-up. A receiver that gives up on too
+     many overflows in a second records less.
+     */
+    pub fn expected_gaps(&self, packet_count: usize, skipped: usize) -> Result<Vec<Gap>, Ar2300Error> {
+        let mut recorded = Vec::new();
+        let mut skipped = skipped;
+        for planned in self.plan(packet_count)? {
+            match planned.result {
+                Ok(()) if skipped > 0 => skipped -= 1,
+                Ok(()) => recorded.extend(planned.packets.into_iter().flatten().flatten()),
+                Err(rusb::Error::Overflow) => {},
+                // Any other error stops the capture
+                Err(_) => break,
+            }
+        }
+        Ok(gaps_in(recorded))
+    }
+
+    fn validate(&self, packet_count: usize) -> Result<(), Ar2300Error> {
+        let invalid = |fault: &Fault, problem: &str| Err(Ar2300Error::InvalidConfig(format!("Invalid fault {:?}: {}", fault, problem)));
+        // Frames the device sends, numbered past any it loses
+        let lost: u64 = self.faults.iter().map(|f| match *f {
+            Fault::Gap { samples, .. } => samples,
+            _ => 0
+        }).fold(0, u64::saturating_add);
+        let frames = self.transfers.saturating_mul((packet_count * PACKET_LENGTH / PACKET_SIZE) as u64);
+        if frames.saturating_add(lost) > MAX_SAMPLES {
+            return Err(Ar2300Error::InvalidConfig(format!("A fault plan can stream at most {} samples", MAX_SAMPLES)));
+        }
+        for fault in &self.faults {
+            let (transfer, packet) = match *fault {
+                Fault::ShortPacket { transfer, packet, bytes } => {
+                    if bytes >= PACKET_LENGTH || bytes % PACKET_SIZE != 0 {
+                        return invalid(fault, "a short packet must carry fewer whole frames than a packet holds");
+                    }
+                    (transfer, Some(packet))
+                },
+                Fault::FailedPacket { transfer, packet } => (transfer, Some(packet)),
+                Fault::Overflow { transfer } | Fault::TransferError { transfer } | Fault::Disconnect { transfer } => (transfer, None),
+                Fault::Gap { .. } => continue,
+            };
+            if transfer >= self.transfers {
+                return invalid(fault, "the plan doesn't send that many transfers");
+            }
+            if matches!(packet, Some(p) if p >= packet_count) {
+                return invalid(fault, "transfers don't have that many packets");
+            }
+        }
+        Ok(())
+    }
+
+    /** Lay out every transfer, numbering the frames the device sends. */
+    fn plan(&self, packet_count: usize) -> Result<Vec<Planned>, Ar2300Error> {
+        self.validate(packet_count)?;
+        let frames_per_packet = PACKET_LENGTH / PACKET_SIZE;
+        let lost = |index: u64| self.faults.iter().any(|f| matches!(*f, Fault::Gap { at, samples } if index >= at && index - at < samples));
+        let mut stream = (0..).filter(|&index| !lost(index));
+        let mut planned = Vec::new();
+        for transfer in 0..self.transfers {
+            let faults: Vec<Fault> = self.faults.iter().copied().filter(|f| match *f {
+                Fault::ShortPacket { transfer: t, .. } | Fault::FailedPacket { transfer: t, .. } |
+                Fault::Overflow { transfer: t } | Fault::TransferError { transfer: t } |
+                Fault::Disconnect { transfer: t } => t == transfer,
+                Fault::Gap { .. } => false,
+            }).collect();
+            let error = faults.iter().find_map(|f| match f {
+                Fault::Disconnect { .. } => Some(rusb::Error::NoDevice),
+                Fault::TransferError { .. } => Some(rusb::Error::Io),
+                Fault::Overflow { .. } => Some(rusb::Error::Overflow),
+                _ => None
+            });
+            let packets = (0..packet_count).map(|packet| {
+                let mut frames = frames_per_packet;
+                let mut failed = false;
+                for fault in &faults {
+                    match *fault {
+                        Fault::ShortPacket { packet: p, bytes, .. } if p == packet => frames = frames.min(bytes / PACKET_SIZE),
+                        Fault::FailedPacket { packet: p, .. } if p == packet => failed = true,
+                        _ => {}
+                    }
+                }
+                let indices: Vec<u64> = stream.by_ref().take(frames).collect();
+                if failed { None } else { Some(indices) }
+            }).collect();
+            planned.push(Planned { result: error.map_or(Ok(()), Err), packets });
+            if error == Some(rusb::Error::NoDevice) {
+                break;
+            }
+        }
+        Ok(planned)
+    }
+}
+
+/**
+ A frame numbered `index`: the high bits of the index go in I, above its
+ sync flag, and the low 16 in Q, placed so that both decode to exact f32s.
+ */
+fn frame(index: u64) -> [u8; PACKET_SIZE] {
+    let mut frame = [0; PACKET_SIZE];
+    LittleEndian::write_u32(&mut frame[0..4], ((index >> 16) as u32) << 9 | 0x100);
+    LittleEndian::write_u32(&mut frame[4..8], ((index & 0xffff) as u32) << 8);
+    frame
+}
+
+/**
+ The index in the simulated device's stream of a sample decoded from one
+ of its frames. Use it with `gaps_in` to find what a recording lost.
+ */
+pub fn sample_index(sample: IqSample) -> u64 {
+    // Undo the decoder's move of the top bit, which is clear in every frame
+    let code = |x: f32| {
+        let code = (x as f64 * 4294967296.0) as u32;
+        (code >> 17) << 16 | (code & 0xfffe)
+    };
+    ((code(sample.0) >> 9) as u64) << 16 | (code(sample.1) >> 8) as u64
+}
+
+/** The gaps in a recording whose samples have the given stream indices, in order. */
+pub fn gaps_in<I: IntoIterator<Item = u64>>(indices: I) -> Vec<Gap> {
+    let mut gaps = Vec::new();
+    let mut next = None;
+    for (position, index) in indices.into_iter().enumerate() {
+        match next {
+            Some(next) if index > next => gaps.push(Gap { at: position as u64, missing: index - next }),
+            _ => {}
+        }
+        next = Some(index + 1);
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::codec::{decode, Rounding};
+    use crate::iq::ReceiverConfig;
+    use crate::queue::{CloseReason, Queue};
+    use crate::{receive_fault_plan, write};
+    use byteorder::BigEndian;
+    use std::sync::{Arc, Mutex};
+
+    const EXAMPLE: &str = include_str!("../testdata/fault-plan.toml");
+
+    /** An output that keeps what is written. */
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /** What capturing a plan gave. */
+    struct Capture {
+        /** The stream index of every sample written. */
+        indices: Vec<u64>,
+        queue: Queue<(f32,f32)>,
+        result: Result<(), Ar2300Error>,
+    }
+
+    /** Capture the plan through the simulated device, writing every sample. */
+    fn capture(plan: &FaultPlan) -> Capture {
+        let queue = Queue::new(1 << 20);
+        let result = receive_fault_plan(queue.clone(), CancelToken::new(), ReceiverConfig::default(), None, None, None, plan);
+        let recording = Recording::default();
+        write(queue.clone(), Box::new(recording.clone())).unwrap();
+        let bytes = recording.0.lock().unwrap();
+        let indices = bytes.chunks(8)
+            .map(|s| sample_index((BigEndian::read_f32(&s[0..4]), BigEndian::read_f32(&s[4..8]))))
+            .collect();
+        Capture { indices, queue, result }
+    }
+
+    #[test]
+    fn frames_decode_to_their_index() {
+        for &index in &[0, 1, 0xff, 0x100, 0xffff, 0x10000, 0x12345, MAX_SAMPLES - 1] {
+            for &rounding in &[Rounding::Nearest, Rounding::TowardZero] {
+                let mut samples = Vec::new();
+                decode(&frame(index), rounding, &mut samples);
+                assert_eq!(samples.iter().map(|&s| sample_index(s)).collect::<Vec<_>>(), vec![index]);
+            }
+        }
+    }
+
+    #[test]
+    fn gaps_are_found_where_indices_jump() {
+        assert_eq!(gaps_in(vec![5, 6, 9, 10, 20]), vec![Gap { at: 2, missing: 2 }, Gap { at: 4, missing: 9 }]);
+        assert_eq!(gaps_in(vec![]), vec![]);
+    }
+
+    #[test]
+    fn the_example_plan_loads() {
+        let plan = FaultPlan::from_toml(EXAMPLE).unwrap();
+        assert_eq!(plan.transfers, 12);
+        assert_eq!(plan.faults[0], Fault::ShortPacket { transfer: 2, packet: 0, bytes: 512 });
+        assert_eq!(FaultPlan::from_toml(&toml::to_string(&plan).unwrap()).unwrap(), plan);
+    }
+
+    #[test]
+    fn a_capture_has_exactly_the_gaps_its_plan_makes() {
+        let plan = FaultPlan::from_toml(EXAMPLE).unwrap();
+        let expected = plan.expected_gaps(ReceiverConfig::default().packet_count, 1).unwrap();
+        assert_eq!(expected.len(), 3);
+        let capture = capture(&plan);
+        capture.result.unwrap();
+        assert_eq!(capture.queue.close_reason(), Some(CloseReason::Finished));
+        assert_eq!(gaps_in(capture.indices), expected);
+    }
+
+    #[test]
+    fn a_disconnect_stops_the_capture() {
+        let plan = FaultPlan { transfers: 10, faults: vec![Fault::Disconnect { transfer: 4 }] };
+        let Capture { indices, queue, result } = capture(&plan);
+        assert!(result.is_err());
+        match queue.close_reason() {
+            Some(CloseReason::Error(e)) => assert!(matches!(*e, Ar2300Error::UsbError(rusb::Error::NoDevice)), "{:?}", e),
+            reason => panic!("Unexpected close reason {:?}", reason)
+        }
+        // Transfers 1 to 3 were recorded, without gaps
+        let per_transfer = ReceiverConfig::default().packet_count * PACKET_LENGTH / PACKET_SIZE;
+        assert_eq!(indices.len(), 3 * per_transfer);
+        assert_eq!(indices.first(), Some(&(per_transfer as u64)));
+        assert!(gaps_in(indices).is_empty());
+    }
+
+    #[test]
+    fn bad_plans_are_rejected() {
+        let plan = |fault| FaultPlan { transfers: 4, faults: vec![fault] };
+        assert!(plan(Fault::ShortPacket { transfer: 1, packet: 0, bytes: 100 }).expected_gaps(2, 1).is_err());
+        assert!(plan(Fault::FailedPacket { transfer: 1, packet: 2 }).expected_gaps(2, 1).is_err());
+        assert!(plan(Fault::Overflow { transfer: 4 }).expected_gaps(2, 1).is_err());
+        assert!(FaultPlan::from_toml("transfers = 4\n[[fault]]\nkind = \"meteor-strike\"\n").is_err());
+        assert!(FaultPlan::from_toml("transfers = 4\nseed = 1\n").is_err());
+    }
+}
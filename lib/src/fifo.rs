@@ -0,0 +1,386 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use log::{info, Level};
+use crate::events::emit;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const READER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/** The size of one sample as written by Writer. */
+const DEFAULT_FRAME_SIZE: usize = 8;
+
+/** What a FifoWriter does when its reader goes away. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipePolicy {
+    /** Return the broken pipe error to the caller. */
+    Terminate,
+    /** Wait up to the given time for a new reader, then resume writing. */
+    Reconnect(Duration),
+}
+
+/** Returns true if the given path is a named pipe. */
+pub fn is_fifo(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(m) => m.file_type().is_fifo(),
+        Err(_) => false
+    }
+}
+
+/**
+ A writer for a named pipe that can survive its reader restarting.
+ A new reader always starts on a frame boundary: if the old reader left
+ partway through a frame, the rest of that frame is dropped.
+ */
+pub struct FifoWriter<W: Write = File> {
+    path: PathBuf,
+    file: W,
+    // Opens the pipe again for a new reader
+    reopen: Reopen<W>,
+    policy: PipePolicy,
+    reconnects: u64,
+    frame_size: usize,
+    // Bytes written into the current frame
+    offset: usize,
+    // Bytes of a torn frame still to be dropped
+    skip: usize,
+}
+
+type Reopen<W> = Box<dyn FnMut(&Path, Duration) -> io::Result<W> + Send>;
+
+impl FifoWriter {
+    /** Open a named pipe for writing, waiting up to `timeout` for a reader to appear. */
+    pub fn open(path: &Path, policy: PipePolicy, timeout: Duration) -> io::Result<FifoWriter> {
+        let file = open_writer(path, timeout)?;
+        Ok(FifoWriter::with_sink(path, file, Box::new(open_writer), policy))
+    }
+}
+
+impl<W: Write> FifoWriter<W> {
+    fn with_sink(path: &Path, file: W, reopen: Reopen<W>, policy: PipePolicy) -> FifoWriter<W> {
+        FifoWriter {
+            path: path.to_path_buf(),
+            file,
+            reopen,
+            policy,
+            reconnects: 0,
+            frame_size: DEFAULT_FRAME_SIZE,
+            offset: 0,
+            skip: 0,
+        }
+    }
+
+    /**
+     Set the size of the units that must not be split across readers.
+     Defaults to one sample, 8 bytes. Takes effect immediately: the bytes
+     already written into the current frame count towards the new size, so
+     call it on a frame boundary to keep frames whole.
+     */
+    pub fn set_frame_size(&mut self, frame_size: usize) {
+        self.frame_size = frame_size.max(1);
+        self.offset %= self.frame_size;
+    }
+
+    /** The number of times a new reader has been picked up after the previous one left. */
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+}
+
+impl<W: Write> Write for FifoWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            if self.skip > 0 {
+                // Report the dropped bytes as written so the caller moves on
+                let n = self.skip.min(buf.len());
+                self.skip -= n;
+                self.offset = (self.offset + n) % self.frame_size;
+                return Ok(n);
+            }
+            match self.file.write(buf) {
+                Ok(n) => {
+                    self.offset = (self.offset + n) % self.frame_size;
+                    return Ok(n);
+                },
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                    match self.policy {
+                        PipePolicy::Terminate => return Err(e),
+                        PipePolicy::Reconnect(timeout) => {
+                            emit(Level::Warn, format_args!("Reader of {} went away, waiting for a new one", self.path.display()));
+                            self.file = (self.reopen)(&self.path, timeout)?;
+                            self.reconnects += 1;
+                            info!("New reader connected to {}", self.path.display());
+                            if self.offset != 0 {
+                                self.skip = self.frame_size - self.offset;
+                            }
+                        }
+                    }
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/**
+ Open a named pipe for writing without blocking forever.
+ A non-blocking open fails with ENXIO until a reader has the pipe open,
+ so poll until one appears, then switch the file back to blocking mode.
+ */
+fn open_writer(path: &Path, timeout: Duration) -> io::Result<File> {
+    let started = Instant::now();
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path) {
+            Ok(file) => {
+                set_blocking(&file)?;
+                return Ok(file);
+            },
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                if started.elapsed() >= timeout {
+                    return Err(io::Error::new(ErrorKind::TimedOut,
+                        format!("No reader opened {} within {:?}", path.display(), timeout)));
+                }
+                sleep(READER_POLL_INTERVAL);
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+fn set_blocking(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn mkfifo(dir: &tempfile::TempDir) -> PathBuf {
+        let path = dir.path().join("iq.fifo");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        path
+    }
+
+    /** Open the pipe for reading on another thread and read `n` bytes, or everything if `n` is None. */
+    fn reader(path: &Path, n: Option<usize>) -> thread::JoinHandle<Vec<u8>> {
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            let mut file = File::open(&path).unwrap();
+            let mut data = Vec::new();
+            match n {
+                Some(n) => {
+                    data.resize(n, 0);
+                    file.read_exact(&mut data).unwrap();
+                },
+                None => {
+                    file.read_to_end(&mut data).unwrap();
+                }
+            }
+            data
+        })
+    }
+
+    #[test]
+    fn detects_named_pipes() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = mkfifo(&dir);
+        let file = dir.path().join("iq.bin");
+        File::create(&file).unwrap();
+        assert!(is_fifo(&fifo));
+        assert!(!is_fifo(&file));
+        assert!(!is_fifo(&dir.path().join("missing")));
+    }
+
+    #[test]
+    fn open_times_out_without_a_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = mkfifo(&dir);
+        let started = Instant::now();
+        let e = FifoWriter::open(&fifo, PipePolicy::Terminate, Duration::from_millis(150)).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn writes_reach_the_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = mkfifo(&dir);
+        let read = reader(&fifo, None);
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut writer = FifoWriter::open(&fifo, PipePolicy::Terminate, Duration::from_secs(5)).unwrap();
+        writer.write_all(&data).unwrap();
+        drop(writer);
+        assert_eq!(read.join().unwrap(), data);
+    }
+
+    #[test]
+    fn terminate_policy_returns_the_broken_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = mkfifo(&dir);
+        let read = reader(&fifo, Some(8));
+        let mut writer = FifoWriter::open(&fifo, PipePolicy::Terminate, Duration::from_secs(5)).unwrap();
+        writer.write_all(&[0; 8]).unwrap();
+        read.join().unwrap();
+        let e = loop {
+            if let Err(e) = writer.write(&[0; 8]) {
+                break e;
+            }
+        };
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(writer.reconnects(), 0);
+    }
+
+    #[test]
+    fn reconnect_policy_resumes_with_a_new_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = mkfifo(&dir);
+        let first = reader(&fifo, Some(8));
+        let mut writer = FifoWriter::open(&fifo, PipePolicy::Reconnect(Duration::from_secs(5)),
+                                          Duration::from_secs(5)).unwrap();
+        writer.write_all(&[1; 8]).unwrap();
+        assert_eq!(first.join().unwrap(), vec![1; 8]);
+        // The first reader has gone; keep writing until the writer notices and waits for a new one
+        let path = fifo.clone();
+        let second = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            reader(&path, None).join().unwrap()
+        });
+        let frame = [2u8; 8];
+        while writer.reconnects() == 0 {
+            writer.write_all(&frame).unwrap();
+        }
+        writer.write_all(&[3; 8]).unwrap();
+        drop(writer);
+        let data = second.join().unwrap();
+        assert_eq!(data.len() % 8, 0);
+        assert_eq!(&data[data.len() - 8..], &[3; 8]);
+    }
+
+    /** A sink that takes at most `max_write` bytes per write, and breaks after taking `break_after`. */
+    struct ShortSink {
+        data: Arc<Mutex<Vec<u8>>>,
+        max_write: usize,
+        break_after: Option<usize>,
+    }
+
+    impl ShortSink {
+        fn new(max_write: usize, break_after: Option<usize>) -> (ShortSink, Arc<Mutex<Vec<u8>>>) {
+            let data = Arc::new(Mutex::new(Vec::new()));
+            (ShortSink { data: data.clone(), max_write, break_after }, data)
+        }
+    }
+
+    impl Write for ShortSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.data.lock().unwrap();
+            let room = match self.break_after {
+                Some(limit) if data.len() >= limit => return Err(ErrorKind::BrokenPipe.into()),
+                Some(limit) => limit - data.len(),
+                None => usize::MAX
+            };
+            let n = buf.len().min(self.max_write).min(room);
+            data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /** A writer on a ShortSink that reconnects to `next`. */
+    fn short_writer(first: ShortSink, next: ShortSink) -> FifoWriter<ShortSink> {
+        let mut next = Some(next);
+        let reopen: Reopen<ShortSink> = Box::new(move |_, _| {
+            next.take().ok_or_else(|| io::Error::new(ErrorKind::TimedOut, "No more readers"))
+        });
+        FifoWriter::with_sink(Path::new("mock"), first, reopen, PipePolicy::Reconnect(Duration::ZERO))
+    }
+
+    #[test]
+    fn short_writes_keep_frames_whole_across_a_reconnect() {
+        let data: Vec<u8> = (0..40).collect();
+        // The first reader leaves three bytes into the second frame
+        let (first, first_data) = ShortSink::new(3, Some(11));
+        let (second, second_data) = ShortSink::new(3, None);
+        let mut writer = short_writer(first, second);
+        writer.write_all(&data).unwrap();
+        assert_eq!(writer.reconnects(), 1);
+        assert_eq!(*first_data.lock().unwrap(), data[..11].to_vec());
+        // The rest of the torn frame is dropped, so the new reader starts on a boundary
+        assert_eq!(*second_data.lock().unwrap(), data[16..].to_vec());
+    }
+
+    #[test]
+    fn a_reader_leaving_on_a_boundary_loses_nothing() {
+        let data: Vec<u8> = (0..40).collect();
+        let (first, first_data) = ShortSink::new(5, Some(16));
+        let (second, second_data) = ShortSink::new(5, None);
+        let mut writer = short_writer(first, second);
+        writer.write_all(&data).unwrap();
+        assert_eq!(*first_data.lock().unwrap(), data[..16].to_vec());
+        assert_eq!(*second_data.lock().unwrap(), data[16..].to_vec());
+    }
+
+    #[test]
+    fn the_frame_size_applies_to_torn_frames() {
+        let data: Vec<u8> = (0..40).collect();
+        let (first, first_data) = ShortSink::new(7, Some(14));
+        let (second, second_data) = ShortSink::new(7, None);
+        let mut writer = short_writer(first, second);
+        writer.set_frame_size(12);
+        writer.write_all(&data).unwrap();
+        assert_eq!(*first_data.lock().unwrap(), data[..14].to_vec());
+        assert_eq!(*second_data.lock().unwrap(), data[24..].to_vec());
+    }
+
+    #[test]
+    fn no_new_reader_is_an_error() {
+        let (first, _) = ShortSink::new(8, Some(4));
+        let mut writer = FifoWriter::with_sink(Path::new("mock"), first,
+                                               Box::new(|_, _| Err(ErrorKind::TimedOut.into())),
+                                               PipePolicy::Reconnect(Duration::ZERO));
+        assert_eq!(writer.write_all(&[0; 8]).unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}
@@ -17,88 +17,384 @@
     along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use rusb::{Device, GlobalContext, DeviceHandle, LogLevel};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::error::Ar2300Error;
+use crate::fx2::{self, Fx2Loader, Fx2Transport};
+use crate::message::{EnglishRenderer, MessageRenderer};
+use crate::usb;
+use log::Level;
+use crate::events::emit;
+use rusb::{Device, DeviceHandle, UsbContext};
 use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::time::Duration;
-use std::str;
-
-const FIRMWARE_HEX: &str = include_str!("fx2fw.hex");
-const RESET_ADDRESS: u16 = 0xe600;
-const RESET_COMMAND: [u8;1] = [1];
-const RUN_COMMAND: [u8;1] = [0];
-
-/** Program the device */
-pub fn program(device: &Device<GlobalContext>) -> Result<usize, Box<dyn Error>> {
-    rusb::set_log_level(LogLevel::Info);
-    let handle = device.open()?;
-    reset(&handle)?;
-    let bytes_written= write_firmware(&handle, FIRMWARE_HEX)?;
-    run(&handle)?;
+
+pub(crate) const FIRMWARE_HEX: &str = include_str!("fx2fw.hex");
+const RENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** Errors specific to programming the AR2300. */
+#[derive(Clone, Debug)]
+pub enum FirmwareError {
+    /** The device isn't waiting in the FX2 boot loader to be programmed. */
+    NotInBootloader { current_state: String },
+    /** Writing the firmware to the device failed. */
+    ProgrammingFailed { reason: String },
+    /** The firmware was written, but the device didn't come back. */
+    RenumerationTimeout { waited: Duration },
+    /** The device came back, but still looks unprogrammed. */
+    StillUnprogrammed,
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&EnglishRenderer.firmware_error(self))
+    }
+}
+
+impl Error for FirmwareError {}
+
+/** Where a device stands as far as programming it goes. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoardState {
+    /** Not an AR2300 IQ board. */
+    NotIqBoard,
+    /** An AR2300 IQ board waiting in the FX2 boot loader. */
+    Bootloader,
+    /** An AR2300 IQ board running firmware. */
+    Programmed,
+}
+
+/**
+ Work out the board's state from its device descriptor. The boot loader
+ enumerates with the board's vendor and product IDs but no string
+ descriptors, while the AR2300 firmware names a manufacturer, so the
+ device needn't be opened to tell them apart.
+ */
+pub fn board_state(vendor_id: u16, product_id: u16, manufacturer_string_index: Option<u8>) -> BoardState {
+    if vendor_id != usb::IQ_VENDOR_ID || product_id != usb::IQ_PRODUCT_ID {
+        BoardState::NotIqBoard
+    } else if manufacturer_string_index.is_some() {
+        BoardState::Programmed
+    } else {
+        BoardState::Bootloader
+    }
+}
+
+/** The state of the given device, from its descriptor. */
+pub fn device_state<C: UsbContext>(device: &Device<C>) -> BoardState {
+    match device.device_descriptor() {
+        Ok(desc) => board_state(desc.vendor_id(), desc.product_id(), desc.manufacturer_string_index()),
+        Err(_) => BoardState::NotIqBoard
+    }
+}
+
+/** Returns true if the device is an AR2300 IQ board running its firmware. */
+pub fn is_programmed<C: UsbContext>(device: &Device<C>) -> bool {
+    device_state(device) == BoardState::Programmed
+}
+
+/**
+ Check that a board in the given state may be programmed: only one waiting
+ in the boot loader, unless `force` is set to reprogram a board already
+ running firmware. Anything that isn't an AR2300 IQ board is always refused.
+ */
+pub fn check_state(state: BoardState, force: bool) -> Result<(), FirmwareError> {
+    let current_state = match state {
+        BoardState::Bootloader => return Ok(()),
+        BoardState::Programmed if force => return Ok(()),
+        BoardState::Programmed => "already running firmware",
+        BoardState::NotIqBoard => "not an AR2300 IQ board"
+    };
+    Err(FirmwareError::NotInBootloader { current_state: current_state.to_string() })
+}
+
+/**
+ Check that the device is an unprogrammed AR2300 IQ board, waiting in the
+ boot loader. Programming anything else could knock a working device off
+ the bus.
+ */
+pub fn check_bootloader<C: UsbContext>(device: &Device<C>) -> Result<(), FirmwareError> {
+    check_state(device_state(device), false)
+}
+
+/**
+ Program the device and wait for it to come back running the firmware.
+ Fails with `FirmwareError::NotInBootloader` if the device has already been
+ programmed.
+ */
+pub fn program<C: UsbContext>(device: &Device<C>) -> Result<usize, Ar2300Error> {
+    program_with(device, false, &CancelToken::new())
+}
+
+/**
+ Like `program`, but with `force` set a board already running firmware is
+ reprogrammed. See `check_state`.
+ Waiting for the device to come back stops with `Ar2300Error::Cancelled`
+ if the token is cancelled.
+ */
+pub fn program_with<C: UsbContext>(device: &Device<C>,
+                    force: bool,
+                    cancel: &CancelToken) -> Result<usize, Ar2300Error> {
+    program_hex_with(device, FIRMWARE_HEX, force, cancel)
+}
+
+/**
+ Like `program`, loading the Intel hex file at `path` instead of the
+ embedded firmware. The file is read when this is called.
+ */
+pub fn program_from_file<C: UsbContext>(device: &Device<C>, path: &Path) -> Result<usize, Ar2300Error> {
+    let firmware = read_firmware_file(path)?;
+    program_hex_with(device, &firmware, false, &CancelToken::new())
+}
+
+/** Like `program_with`, loading the given Intel hex firmware. */
+pub fn program_hex_with<C: UsbContext>(device: &Device<C>,
+                                       firmware: &str,
+                                       force: bool,
+                                       cancel: &CancelToken) -> Result<usize, Ar2300Error> {
+    crate::global::init(crate::global::Options::default())?;
+    let mut loader = Fx2Loader::new(device.open()?);
+    loader.set_cancel(cancel.clone());
+    let bytes_written = load(&loader, device_state(device), force, firmware)?;
+    match loader.wait_renumeration_in(device.context(), is_programmed, RENUMERATION_TIMEOUT) {
+        Ok(_) => Ok(bytes_written),
+        Err(e) => Err(renumeration_error(e.as_ref(), || usb::find_iq_device_in(device.context()).is_some()))
+    }
+}
+
+/**
+ The error for a device that didn't come back running the firmware.
+ `still_present` says whether the unprogrammed board is still on the bus;
+ it isn't asked if the wait was cancelled.
+ */
+fn renumeration_error<F: FnOnce() -> bool>(e: &(dyn Error + 'static), still_present: F) -> Ar2300Error {
+    if e.is::<Cancelled>() {
+        Ar2300Error::Cancelled
+    } else if still_present() {
+        FirmwareError::StillUnprogrammed.into()
+    } else {
+        FirmwareError::RenumerationTimeout { waited: RENUMERATION_TIMEOUT }.into()
+    }
+}
+
+/**
+ Check the board's state, then write the firmware through the loader and
+ release the CPU to run it. The device then drops off the bus and comes
+ back running the firmware.
+ */
+fn load<T: Fx2Transport>(loader: &Fx2Loader<T>,
+                         state: BoardState,
+                         force: bool,
+                         firmware: &str) -> Result<usize, Ar2300Error> {
+    check_state(state, force)?;
+    let records = fx2::parse_hex_records(firmware)
+        .map_err(|e| FirmwareError::ProgrammingFailed { reason: e.to_string() })?;
+    let bytes_written = loader.hold_in_reset()
+        .and_then(|_| loader.download_hex(&records))
+        .and_then(|n| loader.release_reset().map(|_| n))
+        .map_err(|e| FirmwareError::ProgrammingFailed { reason: e.to_string() })?;
     Ok(bytes_written)
 }
 
+/**
+ Read an Intel hex firmware file, which must be named .hex or .ihx.
+ Errors name the file.
+ */
+pub fn read_firmware_file(path: &Path) -> Result<String, Ar2300Error> {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    if !matches!(extension.as_deref(), Some("hex") | Some("ihx")) {
+        return Err(Ar2300Error::InvalidConfig(
+            format!("Firmware file {} is not an Intel hex file (.hex or .ihx)", path.display())));
+    }
+    fs::read_to_string(path).map_err(|e| Ar2300Error::IoError(
+        io::Error::new(e.kind(), format!("Couldn't read firmware file {}: {}", path.display(), e))))
+}
+
 /** Reset the device */
-pub fn reset(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<usize> {
-    write_ram(handle, RESET_ADDRESS, &RESET_COMMAND)
+pub fn reset<C: UsbContext>(handle: &DeviceHandle<C>) -> rusb::Result<usize> {
+    Fx2Loader::new(handle).hold_in_reset()
 }
 
 /** Start the device */
-pub fn run(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<usize> {
-    write_ram(handle, RESET_ADDRESS, &RUN_COMMAND)
+pub fn run<C: UsbContext>(handle: &DeviceHandle<C>) -> rusb::Result<usize> {
+    Fx2Loader::new(handle).release_reset()
 }
 
 /** Write firmware to the given device */
-pub fn write_firmware(handle: &DeviceHandle<GlobalContext>, firmware: &str) -> Result<usize, Box<dyn Error>> {
-    let mut bytes_written: usize = 0;
-    for line in firmware.lines() {
-        // Parse Intel hex file format
-        if !line.starts_with(&":") || line.len() < 11 {
-            continue;
-        }
-        let num_bytes = usize::from_str_radix(&line[1..3], 16)?;
-        let address = u16::from_str_radix(&line[3..7], 16)?;
-        let typ = u8::from_str_radix(&line[7..9], 16)?;
-        match typ {
-            0 => {
-                // Data
-                let hex = &line[9..line.len()-2];
-                let data= parse_hex(hex);
-                if data.len() != num_bytes {
-                    // Bad Data Length
-                    eprintln!("Bad data length. Expected: {}, Received: {}", num_bytes, data.len());
-                    continue;
-                }
-                bytes_written += write_ram(handle, address, &data)?;
-            },
-            1 => {
-                // EOF
-                break;
-            } ,
-            _ => {}
+pub fn write_firmware<C: UsbContext>(handle: &DeviceHandle<C>, firmware: &str) -> Result<usize, Ar2300Error> {
+    let records = fx2::parse_hex_records(firmware)
+        .map_err(|e| FirmwareError::ProgrammingFailed { reason: e.to_string() })?;
+    Ok(Fx2Loader::new(handle).download_hex(&records)?)
+}
+
+/**
+ Read `len` bytes of the device's RAM starting at `address`, through the
+ boot loader. The result is shorter than `len` if the device returns
+ less. Useful for checking a load, or the CPUCS register before `run`.
+ */
+pub fn read_ram<C: UsbContext>(handle: &DeviceHandle<C>, address: u16, len: usize) -> Result<Vec<u8>, rusb::Error> {
+    if address as usize + len > u16::MAX as usize + 1 {
+        return Err(rusb::Error::InvalidParam);
+    }
+    let mut data = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        let start = read;
+        let end = (start + fx2::MAX_CHUNK).min(len);
+        let n = handle.read_control(0xc0, fx2::FIRMWARE_LOAD_REQUEST, address + start as u16, 0,
+                                    &mut data[start..end], READ_TIMEOUT)?;
+        read += n;
+        if n < end - start {
+            break;
         }
     }
-    Ok(bytes_written)
+    data.truncate(read);
+    Ok(data)
 }
 
-/** Parse a hex string into a byte vector */
-fn parse_hex(data: &str) -> Vec<u8> {
-    data
-        .as_bytes()
-        .chunks(2)
-        .map(str::from_utf8)
-        .map(|x|
-            match x {
-                Ok(s) => match u8::from_str_radix(s, 16) {
-                    Ok(b) => b,
-                    Err(_) => 0
-                }
-                Err(_) => 0
-            })
-        .collect::<Vec<u8>>()
+/** Write data to RAM */
+pub fn write_ram<C: UsbContext>(handle: &DeviceHandle<C>, address: u16, data: &[u8]) -> rusb::Result<usize> {
+    handle.write_ram(address, data)
 }
 
-/** Write data to RAM */
-pub fn write_ram(handle: &DeviceHandle<GlobalContext>, address: u16, data: &[u8]) -> rusb::Result<usize> {
-    let bytes_written = handle.write_control(0x40, 0xa0, address, 0, data, Duration::from_secs(5))?;
-    Ok(bytes_written)
-}
\ No newline at end of file
+/**
+ Read each data record of the firmware back from the device's RAM and
+ compare it with what should be there. Returns false, logging the first
+ difference, if anything doesn't match. Call it after `reset` and before
+ `run`, while the boot loader still answers RAM reads.
+ */
+pub fn verify<C: UsbContext>(handle: &DeviceHandle<C>, firmware: &str) -> Result<bool, Ar2300Error> {
+    let records = fx2::parse_hex_records(firmware)
+        .map_err(|e| FirmwareError::ProgrammingFailed { reason: e.to_string() })?;
+    for record in &records {
+        let actual = read_ram(handle, record.address, record.data.len())?;
+        if let Some(offset) = (0..record.data.len()).find(|&i| actual.get(i) != Some(&record.data[i])) {
+            emit(Level::Warn, format_args!("Firmware mismatch at {:#06x}. Expected: {:02X}, Read: {}",
+                                           record.address as usize + offset,
+                                           record.data[offset],
+                                           actual.get(offset).map_or("nothing".to_string(), |b| format!("{:02X}", b))));
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /** Records the addresses written to. */
+    #[derive(Default)]
+    struct MockTransport {
+        writes: RefCell<Vec<u16>>,
+    }
+
+    impl Fx2Transport for MockTransport {
+        fn write_ram(&self, address: u16, data: &[u8]) -> rusb::Result<usize> {
+            self.writes.borrow_mut().push(address);
+            Ok(data.len())
+        }
+    }
+
+    const CPUCS: u16 = 0xe600;
+
+    fn load_into(state: BoardState, force: bool) -> (Result<usize, Ar2300Error>, Vec<u16>) {
+        let loader = Fx2Loader::new(MockTransport::default());
+        let result = load(&loader, state, force, FIRMWARE_HEX);
+        (result, loader.into_inner().writes.into_inner())
+    }
+
+    #[test]
+    fn the_state_comes_from_the_descriptor() {
+        assert_eq!(board_state(0x08d0, 0xa001, None), BoardState::Bootloader);
+        assert_eq!(board_state(0x08d0, 0xa001, Some(1)), BoardState::Programmed);
+        assert_eq!(board_state(0x04b4, 0x8613, None), BoardState::NotIqBoard);
+        assert_eq!(board_state(0x08d0, 0xa002, Some(1)), BoardState::NotIqBoard);
+    }
+
+    #[test]
+    fn a_board_in_the_boot_loader_is_programmed() {
+        let (result, writes) = load_into(BoardState::Bootloader, false);
+        assert!(result.unwrap() > 0);
+        assert_eq!(writes.first(), Some(&CPUCS));
+        assert_eq!(writes.last(), Some(&CPUCS));
+        assert!(writes.len() > 2);
+    }
+
+    #[test]
+    fn a_programmed_board_is_refused_without_touching_it() {
+        let (result, writes) = load_into(BoardState::Programmed, false);
+        match result {
+            Err(Ar2300Error::FirmwareError(FirmwareError::NotInBootloader { current_state })) =>
+                assert_eq!(current_state, "already running firmware"),
+            r => panic!("{:?}", r)
+        }
+        assert!(writes.is_empty());
+    }
+
+    #[test]
+    fn force_reprograms_a_programmed_board() {
+        let (forced, forced_writes) = load_into(BoardState::Programmed, true);
+        let (normal, normal_writes) = load_into(BoardState::Bootloader, false);
+        assert_eq!(forced.unwrap(), normal.unwrap());
+        assert_eq!(forced_writes, normal_writes);
+    }
+
+    #[test]
+    fn other_devices_are_refused_even_with_force() {
+        for &force in &[false, true] {
+            let (result, writes) = load_into(BoardState::NotIqBoard, force);
+            assert!(matches!(result, Err(Ar2300Error::FirmwareError(FirmwareError::NotInBootloader { .. }))));
+            assert!(writes.is_empty());
+        }
+    }
+
+    #[test]
+    fn a_malformed_firmware_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.hex");
+        fs::write(&path, ":03000000010203F7\n:10é0000000000000000000000000000000000\n:00000001FF\n").unwrap();
+        let firmware = read_firmware_file(&path).unwrap();
+        let loader = Fx2Loader::new(MockTransport::default());
+        match load(&loader, BoardState::Bootloader, false, &firmware) {
+            Err(Ar2300Error::FirmwareError(FirmwareError::ProgrammingFailed { reason })) =>
+                assert_eq!(reason, "Line 2 is not a valid record"),
+            r => panic!("{:?}", r)
+        }
+        // Nothing is written when the file doesn't parse
+        assert!(loader.into_inner().writes.into_inner().is_empty());
+    }
+
+    #[test]
+    fn a_firmware_file_must_be_hex() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("firmware.bin");
+        fs::write(&path, ":00000001FF\n").unwrap();
+        assert!(matches!(read_firmware_file(&path), Err(Ar2300Error::InvalidConfig(_))));
+        let missing = read_firmware_file(&dir.path().join("missing.hex")).unwrap_err();
+        assert!(missing.to_string().contains("missing.hex"), "{}", missing);
+    }
+
+    #[test]
+    fn a_cancelled_renumeration_wait_is_reported_as_cancelled() {
+        let cancelled: Box<dyn Error> = Cancelled.into();
+        let e = renumeration_error(cancelled.as_ref(), || panic!("the bus isn't checked after a cancel"));
+        assert!(matches!(e, Ar2300Error::Cancelled));
+    }
+
+    #[test]
+    fn a_renumeration_timeout_says_whether_the_board_is_still_there() {
+        let timeout: Box<dyn Error> = "Device did not re-enumerate".into();
+        assert!(matches!(renumeration_error(timeout.as_ref(), || true),
+                         Ar2300Error::FirmwareError(FirmwareError::StillUnprogrammed)));
+        assert!(matches!(renumeration_error(timeout.as_ref(), || false),
+                         Ar2300Error::FirmwareError(FirmwareError::RenumerationTimeout { .. })));
+    }
+}
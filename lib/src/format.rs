@@ -0,0 +1,223 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+/**
+ How samples are stored: I followed by Q, in the given type and byte
+ order. Every conversion goes through the canonical `(f32, f32)` sample,
+ so each writer and reader shares the same rounding and saturation.
+
+ Integer formats scale by the type's maximum, rounding to the nearest
+ value, so a round trip moves a sample in [-1.0, 1.0] by at most half a
+ step of the integer type, plus the rounding of the result to f32. Values
+ outside [-1.0, 1.0] saturate at the rails and NaN becomes zero. Reading
+ an integer gives back a value in [-1.0, 1.0], so the most negative code
+ reads as -1.0. Round trips through the f32 formats are exact. The
+ decoder's [0.0, 1.0] range uses the positive half of the integer range.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleFormat {
+    /** The original format, which existing recordings use. */
+    #[default]
+    BigEndianF32,
+    LittleEndianF32,
+    /** Interleaved signed 16 bit integers, as GNU Radio, SDR# and HDSDR expect. */
+    LittleEndianI16,
+    LittleEndianI32,
+}
+
+impl SampleFormat {
+    /** Bytes per sample, I and Q together. */
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::LittleEndianI16 => 4,
+            _ => 8
+        }
+    }
+
+    /** The SigMF name for this format. */
+    pub fn sigmf_datatype(&self) -> &'static str {
+        match self {
+            SampleFormat::BigEndianF32 => "cf32_be",
+            SampleFormat::LittleEndianF32 => "cf32_le",
+            SampleFormat::LittleEndianI16 => "ci16_le",
+            SampleFormat::LittleEndianI32 => "ci32_le",
+        }
+    }
+
+    /** Append one sample to `out`. */
+    pub(crate) fn encode(&self, (i, q): (f32, f32), out: &mut Vec<u8>) {
+        match self {
+            SampleFormat::BigEndianF32 => {
+                out.extend_from_slice(&i.to_be_bytes());
+                out.extend_from_slice(&q.to_be_bytes());
+            },
+            SampleFormat::LittleEndianF32 => {
+                out.extend_from_slice(&i.to_le_bytes());
+                out.extend_from_slice(&q.to_le_bytes());
+            },
+            SampleFormat::LittleEndianI16 => {
+                out.extend_from_slice(&to_i16(i).to_le_bytes());
+                out.extend_from_slice(&to_i16(q).to_le_bytes());
+            },
+            SampleFormat::LittleEndianI32 => {
+                out.extend_from_slice(&to_i32(i).to_le_bytes());
+                out.extend_from_slice(&to_i32(q).to_le_bytes());
+            },
+        }
+    }
+
+    /**
+     Read one sample from the start of `bytes`, or None if there are fewer
+     than `bytes_per_sample` of them.
+     */
+    pub fn decode(&self, bytes: &[u8]) -> Option<(f32, f32)> {
+        let half = self.bytes_per_sample() / 2;
+        if bytes.len() < half * 2 {
+            return None;
+        }
+        let (i, q) = (&bytes[..half], &bytes[half..half * 2]);
+        Some(match self {
+            SampleFormat::BigEndianF32 =>
+                (f32::from_be_bytes(array(i)), f32::from_be_bytes(array(q))),
+            SampleFormat::LittleEndianF32 =>
+                (f32::from_le_bytes(array(i)), f32::from_le_bytes(array(q))),
+            SampleFormat::LittleEndianI16 =>
+                (from_i16(i16::from_le_bytes(array(i))), from_i16(i16::from_le_bytes(array(q)))),
+            SampleFormat::LittleEndianI32 =>
+                (from_i32(i32::from_le_bytes(array(i))), from_i32(i32::from_le_bytes(array(q)))),
+        })
+    }
+}
+
+/** Copy a slice already checked to be N bytes long into an array. */
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    array
+}
+
+fn to_i16(x: f32) -> i16 {
+    (x.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+fn from_i16(v: i16) -> f32 {
+    (v as f32 / i16::MAX as f32).max(-1.0)
+}
+
+fn to_i32(x: f32) -> i32 {
+    (x.clamp(-1.0, 1.0) as f64 * i32::MAX as f64).round() as i32
+}
+
+fn from_i32(v: i32) -> f32 {
+    (v as f64 / i32::MAX as f64).max(-1.0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const FLOATS: [SampleFormat; 2] = [SampleFormat::BigEndianF32, SampleFormat::LittleEndianF32];
+    const INTEGERS: [SampleFormat; 2] = [SampleFormat::LittleEndianI16, SampleFormat::LittleEndianI32];
+
+    fn round_trip(format: SampleFormat, sample: (f32, f32)) -> (f32, f32) {
+        let mut bytes = Vec::new();
+        format.encode(sample, &mut bytes);
+        assert_eq!(bytes.len(), format.bytes_per_sample());
+        format.decode(&bytes).unwrap()
+    }
+
+    /** Half a step of the format's integer type, plus the rounding of the result to f32. */
+    fn bound(format: SampleFormat) -> f32 {
+        let max = match format {
+            SampleFormat::LittleEndianI16 => i16::MAX as f64,
+            _ => i32::MAX as f64
+        };
+        (0.5 / max) as f32 + f32::EPSILON / 2.0
+    }
+
+    #[test]
+    fn the_rails_are_exact() {
+        for &format in INTEGERS.iter() {
+            assert_eq!(round_trip(format, (1.0, -1.0)), (1.0, -1.0));
+            assert_eq!(round_trip(format, (0.0, -0.0)), (0.0, 0.0));
+        }
+        assert_eq!((to_i16(1.0), to_i16(-1.0)), (i16::MAX, -i16::MAX));
+        assert_eq!((to_i32(1.0), to_i32(-1.0)), (i32::MAX, -i32::MAX));
+        // The most negative code has no positive twin, so reads as -1.0
+        assert_eq!((from_i16(i16::MIN), from_i32(i32::MIN)), (-1.0, -1.0));
+    }
+
+    #[test]
+    fn a_short_sample_isnt_decoded() {
+        for &format in FLOATS.iter().chain(INTEGERS.iter()) {
+            assert_eq!(format.decode(&vec![0; format.bytes_per_sample() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn infinities_saturate_and_nan_becomes_zero() {
+        for &format in INTEGERS.iter() {
+            assert_eq!(round_trip(format, (f32::INFINITY, f32::NEG_INFINITY)), (1.0, -1.0));
+            assert_eq!(round_trip(format, (f32::NAN, -f32::NAN)), (0.0, 0.0));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn float_formats_round_trip_exactly(i in any::<f32>(), q in any::<f32>()) {
+            for &format in FLOATS.iter() {
+                let (i2, q2) = round_trip(format, (i, q));
+                // Compared as bits, so NaNs and signed zeros count too
+                prop_assert_eq!((i2.to_bits(), q2.to_bits()), (i.to_bits(), q.to_bits()));
+            }
+        }
+
+        #[test]
+        fn integer_formats_round_trip_within_half_a_step(i in -1.0f32..=1.0, q in -1.0f32..=1.0) {
+            for &format in INTEGERS.iter() {
+                let (i2, q2) = round_trip(format, (i, q));
+                prop_assert!((i2 - i).abs() <= bound(format), "{:?}: {} became {}", format, i, i2);
+                prop_assert!((q2 - q).abs() <= bound(format), "{:?}: {} became {}", format, q, q2);
+            }
+        }
+
+        #[test]
+        fn i16_codes_survive_a_trip_through_f32(v in -i16::MAX..=i16::MAX) {
+            prop_assert_eq!(to_i16(from_i16(v)), v);
+        }
+
+        #[test]
+        fn out_of_range_samples_saturate(x in 1.0f32..=f32::MAX) {
+            for &format in INTEGERS.iter() {
+                prop_assert_eq!(round_trip(format, (x, -x)), (1.0, -1.0));
+            }
+        }
+
+        #[test]
+        fn decoded_integers_are_in_range(bytes in proptest::collection::vec(any::<u8>(), 8)) {
+            for &format in INTEGERS.iter() {
+                let (i, q) = format.decode(&bytes).unwrap();
+                prop_assert!((-1.0..=1.0).contains(&i) && (-1.0..=1.0).contains(&q));
+            }
+        }
+    }
+}
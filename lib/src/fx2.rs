@@ -0,0 +1,445 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::cancel::{CancelToken, Cancelled};
+use log::Level;
+use crate::events::emit;
+use rusb::{Device, DeviceHandle, GlobalContext, UsbContext};
+use simple_error::bail;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::str;
+use std::time::{Duration, Instant};
+
+/** The CPU control and status register. Bit 0 holds the 8051 in reset. */
+pub const CPUCS_ADDRESS: u16 = 0xe600;
+/** The vendor request the FX2 boot loader uses for RAM reads and writes. */
+pub const FIRMWARE_LOAD_REQUEST: u8 = 0xa0;
+
+const RESET_COMMAND: [u8;1] = [1];
+const RUN_COMMAND: [u8;1] = [0];
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+// The largest payload a single control transfer can carry.
+pub(crate) const MAX_CHUNK: usize = 4096;
+const RENUMERATION_POLL: Duration = Duration::from_millis(100);
+
+/** The link to an FX2's boot loader. */
+pub trait Fx2Transport {
+    /** Write data to the FX2's RAM, returning the number of bytes written. */
+    fn write_ram(&self, address: u16, data: &[u8]) -> rusb::Result<usize>;
+}
+
+impl<C: UsbContext> Fx2Transport for DeviceHandle<C> {
+    fn write_ram(&self, address: u16, data: &[u8]) -> rusb::Result<usize> {
+        self.write_control(0x40, FIRMWARE_LOAD_REQUEST, address, 0, data, CONTROL_TIMEOUT)
+    }
+}
+
+impl<T: Fx2Transport + ?Sized> Fx2Transport for &T {
+    fn write_ram(&self, address: u16, data: &[u8]) -> rusb::Result<usize> {
+        (**self).write_ram(address, data)
+    }
+}
+
+/** A data record from an Intel hex file. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HexRecord {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+/**
+ Parse the data records of an Intel hex file, stopping at the end of file
+ record. Lines that aren't records are ignored, as are records whose data
+ doesn't match their length. A record whose checksum is wrong is an error
+ naming the line, since the file is corrupt.
+
+ Extended linear address records set the upper 16 bits of the addresses
+ that follow, and extended segment address records add sixteen times
+ their segment to them. The FX2 only has a 16 bit address space, so a
+ data record that ends up above it is an error rather than being written
+ to the wrong place.
+ */
+pub fn parse_hex_records(hex: &str) -> Result<Vec<HexRecord>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    let mut base: u32 = 0;
+    for (i, line) in hex.lines().enumerate() {
+        if !line.starts_with(':') || line.len() < 11 {
+            continue;
+        }
+        if !line.is_ascii() {
+            bail!("Line {} is not a valid record", i + 1);
+        }
+        let checksum = u8::from_str_radix(&line[line.len()-2..], 16)?;
+        let expected = record_checksum(&parse_hex(&line[1..line.len()-2]));
+        if checksum != expected {
+            bail!("Bad checksum on line {}. Expected: {:02X}, Received: {:02X}", i + 1, expected, checksum);
+        }
+        let num_bytes = usize::from_str_radix(&line[1..3], 16)?;
+        let address = u16::from_str_radix(&line[3..7], 16)?;
+        let typ = u8::from_str_radix(&line[7..9], 16)?;
+        match typ {
+            0 => {
+                // Data
+                let data = parse_hex(&line[9..line.len()-2]);
+                if data.len() != num_bytes {
+                    emit(Level::Warn, format_args!("Bad data length. Expected: {}, Received: {}", num_bytes, data.len()));
+                    continue;
+                }
+                let full_address = base + address as u32;
+                let address = match u16::try_from(full_address) {
+                    Ok(address) => address,
+                    Err(_) => bail!("Record on line {} is at {:#x}, beyond the 16 bit address space",
+                                    i + 1, full_address)
+                };
+                records.push(HexRecord { address, data });
+            },
+            1 => {
+                // EOF
+                break;
+            },
+            2 => {
+                // Extended segment address
+                if num_bytes != 2 || line.len() != 15 {
+                    bail!("Bad extended segment address record on line {}", i + 1);
+                }
+                base = (u16::from_str_radix(&line[9..13], 16)? as u32) << 4;
+            },
+            4 => {
+                // Extended linear address
+                if num_bytes != 2 || line.len() != 15 {
+                    bail!("Bad extended linear address record on line {}", i + 1);
+                }
+                base = (u16::from_str_radix(&line[9..13], 16)? as u32) << 16;
+            },
+            _ => {}
+        }
+    }
+    Ok(records)
+}
+
+/**
+ The checksum of a record: the two's complement of the low byte of the sum
+ of its length, address, type and data bytes.
+ */
+fn record_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+/** Parse a hex string into a byte vector */
+fn parse_hex(data: &str) -> Vec<u8> {
+    data
+        .as_bytes()
+        .chunks(2)
+        .map(str::from_utf8)
+        .map(|x|
+            match x {
+                Ok(s) => u8::from_str_radix(s, 16).unwrap_or_default(),
+                Err(_) => 0
+            })
+        .collect::<Vec<u8>>()
+}
+
+/**
+ Loads firmware into an FX2's RAM. Each step can be used on its own; the
+ usual order is `hold_in_reset`, one or more downloads, `release_reset`,
+ then `wait_renumeration` if the firmware re-enumerates.
+ */
+pub struct Fx2Loader<T: Fx2Transport> {
+    transport: T,
+    cancel: CancelToken,
+}
+
+impl<T: Fx2Transport> Fx2Loader<T> {
+    pub fn new(transport: T) -> Self {
+        Fx2Loader { transport, cancel: CancelToken::new() }
+    }
+
+    /** Make `wait_renumeration` give up with `Cancelled` when the token is cancelled. */
+    pub fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /** Stop the 8051 so its RAM can be written. */
+    pub fn hold_in_reset(&self) -> rusb::Result<usize> {
+        self.transport.write_ram(CPUCS_ADDRESS, &RESET_COMMAND)
+    }
+
+    /** Start the 8051 running whatever is in RAM. */
+    pub fn release_reset(&self) -> rusb::Result<usize> {
+        self.transport.write_ram(CPUCS_ADDRESS, &RUN_COMMAND)
+    }
+
+    /** Write each record to RAM, returning the total bytes written. */
+    pub fn download_hex(&self, records: &[HexRecord]) -> rusb::Result<usize> {
+        let mut bytes_written = 0;
+        for record in records {
+            bytes_written += self.transport.write_ram(record.address, &record.data)?;
+        }
+        Ok(bytes_written)
+    }
+
+    /** Write a binary image to RAM starting at the given address. */
+    pub fn download_bin(&self, address: u16, bytes: &[u8]) -> rusb::Result<usize> {
+        let mut bytes_written = 0;
+        for (i, chunk) in bytes.chunks(MAX_CHUNK).enumerate() {
+            let offset = i * MAX_CHUNK;
+            if address as usize + offset > u16::MAX as usize {
+                return Err(rusb::Error::InvalidParam);
+            }
+            bytes_written += self.transport.write_ram(address + offset as u16, chunk)?;
+        }
+        Ok(bytes_written)
+    }
+
+    /**
+     Wait for a device matching the filter to appear on the bus, such as
+     the board coming back with new descriptors after its firmware starts.
+     The bus is polled every 100 ms, and cancellation is noticed between
+     polls.
+     */
+    pub fn wait_renumeration<F>(&self, filter: F, timeout: Duration) -> Result<Device<GlobalContext>, Box<dyn Error>>
+        where F: Fn(&Device<GlobalContext>) -> bool {
+        self.wait_renumeration_in(&GlobalContext::default(), filter, timeout)
+    }
+
+    /** Like `wait_renumeration`, watching the devices seen by the given context. */
+    pub fn wait_renumeration_in<C, F>(&self, context: &C, filter: F, timeout: Duration) -> Result<Device<C>, Box<dyn Error>>
+        where C: UsbContext, F: Fn(&Device<C>) -> bool {
+        self.poll_until(timeout, || {
+            context.devices().ok().and_then(|devices| devices.iter().find(|d| filter(d)))
+        })
+    }
+
+    /** Calls `find` every `RENUMERATION_POLL` until it yields a value, the timeout passes or the loader is cancelled. */
+    fn poll_until<D, F>(&self, timeout: Duration, mut find: F) -> Result<D, Box<dyn Error>>
+        where F: FnMut() -> Option<D> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.cancel.check()?;
+            if let Some(found) = find() {
+                return Ok(found);
+            }
+            if Instant::now() >= deadline {
+                bail!("Device did not re-enumerate within {:?}", timeout);
+            }
+            if self.cancel.wait_timeout(RENUMERATION_POLL) {
+                return Err(Cancelled.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /** Records every RAM write, failing any that touch `fail_at`. */
+    #[derive(Default)]
+    struct MockTransport {
+        writes: RefCell<Vec<(u16, Vec<u8>)>>,
+        fail_at: Option<u16>,
+    }
+
+    impl Fx2Transport for MockTransport {
+        fn write_ram(&self, address: u16, data: &[u8]) -> rusb::Result<usize> {
+            if self.fail_at == Some(address) {
+                return Err(rusb::Error::Pipe);
+            }
+            self.writes.borrow_mut().push((address, data.to_vec()));
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn reset_and_run_write_cpucs() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        assert_eq!(loader.hold_in_reset(), Ok(1));
+        assert_eq!(loader.release_reset(), Ok(1));
+        assert_eq!(*loader.transport().writes.borrow(), vec![(0xe600, vec![1]), (0xe600, vec![0])]);
+    }
+
+    /** Two data records and the end of file record. */
+    const HEX: &str = ":03000000010203F7\n:0100100004EB\n:00000001FF\n";
+
+    #[test]
+    fn hex_records_are_parsed() {
+        assert_eq!(parse_hex_records(HEX).unwrap(), vec![
+            HexRecord { address: 0x0000, data: vec![1, 2, 3] },
+            HexRecord { address: 0x0010, data: vec![4] },
+        ]);
+    }
+
+    #[test]
+    fn extended_address_records_move_the_data_that_follows() {
+        let hex = concat!(
+            ":020000020100FB\n",  // Segment 0x0100, so a base of 0x1000
+            ":0100200005DA\n",
+            ":020000040000FA\n",  // Linear base 0, replacing the segment's
+            ":0100200006D9\n",
+            ":00000001FF\n",
+        );
+        let records = parse_hex_records(hex).unwrap();
+        assert_eq!(records, vec![
+            HexRecord { address: 0x1020, data: vec![5] },
+            HexRecord { address: 0x0020, data: vec![6] },
+        ]);
+        let loader = Fx2Loader::new(MockTransport::default());
+        loader.download_hex(&records).unwrap();
+        assert_eq!(*loader.transport().writes.borrow(), vec![(0x1020, vec![5]), (0x0020, vec![6])]);
+    }
+
+    #[test]
+    fn data_beyond_the_fx2s_address_space_is_refused() {
+        // A linear base of 0x10000
+        let e = parse_hex_records(":020000040001F9\n:0100200005DA\n:00000001FF\n").unwrap_err();
+        assert_eq!(e.to_string(), "Record on line 2 is at 0x10020, beyond the 16 bit address space");
+        // Segment 0xF000 puts the base at 0xF0000
+        let e = parse_hex_records(":02000002F0000C\n:0100200005DA\n").unwrap_err();
+        assert_eq!(e.to_string(), "Record on line 2 is at 0xf0020, beyond the 16 bit address space");
+    }
+
+    #[test]
+    fn a_malformed_extended_address_record_is_refused() {
+        // Three address bytes instead of two
+        let e = parse_hex_records(":03000004000100F8\n").unwrap_err();
+        assert_eq!(e.to_string(), "Bad extended linear address record on line 1");
+        let e = parse_hex_records(":0100000201FC\n").unwrap_err();
+        assert_eq!(e.to_string(), "Bad extended segment address record on line 1");
+    }
+
+    #[test]
+    fn a_record_with_other_characters_is_refused() {
+        // Slicing this by byte offsets would split the two byte character
+        let e = parse_hex_records(":03000000010203F7\n:0é00100004EB\n").unwrap_err();
+        assert_eq!(e.to_string(), "Line 2 is not a valid record");
+        assert!(parse_hex_records(":0100100004E€\n").is_err());
+        // Bad digits are an error, not a panic
+        assert!(parse_hex_records(":0100100004XY\n").is_err());
+        assert!(parse_hex_records(":01001000zzEB\n").is_err());
+    }
+
+    #[test]
+    fn a_corrupted_record_fails_its_checksum() {
+        let line = ":0100100004EB";
+        // Any single changed digit, whether in the length, address, type, data or checksum, is caught
+        for position in 1..line.len() {
+            let original = line.as_bytes()[position];
+            let flipped = if original == b'0' { '1' } else { '0' };
+            let mut corrupt = line.to_string();
+            corrupt.replace_range(position..position + 1, &flipped.to_string());
+            let hex = format!(":03000000010203F7\n{}\n:00000001FF\n", corrupt);
+            let e = parse_hex_records(&hex).expect_err(&corrupt);
+            assert!(e.to_string().starts_with("Bad checksum on line 2"), "{}: {}", corrupt, e);
+        }
+    }
+
+    #[test]
+    fn download_hex_writes_each_record() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        let records = vec![
+            HexRecord { address: 0x0000, data: vec![1, 2, 3] },
+            HexRecord { address: 0x1000, data: vec![4] },
+        ];
+        assert_eq!(loader.download_hex(&records), Ok(4));
+        assert_eq!(*loader.transport().writes.borrow(), vec![(0x0000, vec![1, 2, 3]), (0x1000, vec![4])]);
+    }
+
+    #[test]
+    fn download_hex_stops_at_the_first_failure() {
+        let transport = MockTransport { fail_at: Some(0x1000), ..MockTransport::default() };
+        let loader = Fx2Loader::new(transport);
+        let records = vec![
+            HexRecord { address: 0x0000, data: vec![1] },
+            HexRecord { address: 0x1000, data: vec![2] },
+            HexRecord { address: 0x2000, data: vec![3] },
+        ];
+        assert_eq!(loader.download_hex(&records), Err(rusb::Error::Pipe));
+        assert_eq!(loader.into_inner().writes.into_inner(), vec![(0x0000, vec![1])]);
+    }
+
+    #[test]
+    fn download_bin_splits_into_control_transfers() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        let image: Vec<u8> = (0..MAX_CHUNK * 2 + 10).map(|i| i as u8).collect();
+        assert_eq!(loader.download_bin(0x0100, &image), Ok(image.len()));
+        let writes = loader.transport().writes.borrow();
+        let addresses: Vec<u16> = writes.iter().map(|(a, _)| *a).collect();
+        assert_eq!(addresses, vec![0x0100, 0x0100 + MAX_CHUNK as u16, 0x0100 + 2 * MAX_CHUNK as u16]);
+        assert_eq!(writes.iter().flat_map(|(_, d)| d.clone()).collect::<Vec<u8>>(), image);
+    }
+
+    #[test]
+    fn download_bin_refuses_to_pass_the_top_of_memory() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        assert_eq!(loader.download_bin(0xf000, &[0; MAX_CHUNK + 1]), Err(rusb::Error::InvalidParam));
+    }
+
+    #[test]
+    fn the_loader_works_through_a_reference() {
+        let transport = MockTransport::default();
+        let loader = Fx2Loader::new(&transport);
+        loader.hold_in_reset().unwrap();
+        assert_eq!(transport.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn renumeration_polling_finds_the_device() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        let mut polls = 0;
+        let found = loader.poll_until(Duration::from_secs(10), || {
+            polls += 1;
+            if polls == 3 { Some(polls) } else { None }
+        });
+        assert_eq!(found.unwrap(), 3);
+    }
+
+    #[test]
+    fn renumeration_polling_times_out() {
+        let loader = Fx2Loader::new(MockTransport::default());
+        let started = Instant::now();
+        assert!(loader.poll_until(Duration::from_millis(150), || None::<()>).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn renumeration_polling_stops_when_cancelled() {
+        let mut loader = Fx2Loader::new(MockTransport::default());
+        let cancel = CancelToken::new();
+        loader.set_cancel(cancel.clone());
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        let e = loader.poll_until(Duration::from_secs(10), || None::<()>).err().unwrap();
+        canceller.join().unwrap();
+        assert!(e.is::<Cancelled>());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,235 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::cancel::CancelToken;
+use crate::error::Ar2300Error;
+use crate::message::{EnglishRenderer, MessageRenderer};
+use rusb::LogLevel;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::Mutex;
+
+/** The libusb log level used when no caller has asked for one. */
+const DEFAULT_USB_LOG_LEVEL: LogLevel = LogLevel::Info;
+
+/**
+ Process-wide settings. A field left as None (or false) leaves that
+ setting to whoever else initializes the crate.
+ */
+#[derive(Clone, Copy, Default)]
+pub struct Options {
+    /** The log level of libusb's global context. */
+    pub usb_log_level: Option<LogLevel>,
+    /** Install a Ctrl-C handler that cancels `GlobalHandle::ctrlc_token`. */
+    pub ctrlc: bool,
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("usb_log_level", &self.usb_log_level.map(level_name))
+            .field("ctrlc", &self.ctrlc)
+            .finish()
+    }
+}
+
+/** Returned when a caller asks for settings that conflict with ones already in place. */
+#[derive(Clone, Debug)]
+pub struct IncompatibleGlobalConfig {
+    pub setting: &'static str,
+    pub current: String,
+    pub requested: String,
+}
+
+impl fmt::Display for IncompatibleGlobalConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&EnglishRenderer.incompatible_global_config(self))
+    }
+}
+
+impl Error for IncompatibleGlobalConfig {}
+
+/** What has been set up so far. */
+struct State {
+    usb_log_level: Option<LogLevel>,
+    ctrlc_token: Option<CancelToken>,
+}
+
+impl State {
+    const fn new() -> State {
+        State { usb_log_level: None, ctrlc_token: None }
+    }
+}
+
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/** Forget everything `init` has set up, as if the process had just started. */
+#[cfg(test)]
+fn reset() {
+    *STATE.lock().unwrap_or_else(|e| e.into_inner()) = State::new();
+}
+
+// The registrations themselves. Tests record them instead, as the test
+// process can't install a second Ctrl-C handler and may have no libusb.
+#[cfg(test)]
+use tests::{set_ctrlc_handler, set_usb_log_level};
+
+#[cfg(not(test))]
+fn set_usb_log_level(level: LogLevel) {
+    rusb::set_log_level(level);
+}
+
+#[cfg(not(test))]
+fn set_ctrlc_handler(cancel: CancelToken) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        cancel.cancel();
+    })
+}
+
+/** A registration with the crate's process-wide state. */
+#[derive(Clone)]
+pub struct GlobalHandle {
+    ctrlc_token: Option<CancelToken>,
+}
+
+impl GlobalHandle {
+    /** The token cancelled on Ctrl-C, if a handler has been installed. */
+    pub fn ctrlc_token(&self) -> Option<CancelToken> {
+        self.ctrlc_token.clone()
+    }
+}
+
+/**
+ Set up the crate's process-wide state. Safe to call any number of times,
+ from any number of embedders: settings already in place are shared, and
+ asking for different ones fails with
+ `Ar2300Error::IncompatibleGlobalConfig`. All of the crate's global
+ registrations go through here.
+ */
+pub fn init(options: Options) -> Result<GlobalHandle, Ar2300Error> {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(requested) = options.usb_log_level {
+        match state.usb_log_level {
+            Some(current) if mem::discriminant(&current) != mem::discriminant(&requested) =>
+                return Err(IncompatibleGlobalConfig {
+                    setting: "usb_log_level",
+                    current: level_name(current).to_string(),
+                    requested: level_name(requested).to_string(),
+                }.into()),
+            Some(_) => {},
+            None => {
+                set_usb_log_level(requested);
+                state.usb_log_level = Some(requested);
+            }
+        }
+    } else if state.usb_log_level.is_none() {
+        // Not recorded, so a later caller may still choose a level
+        set_usb_log_level(DEFAULT_USB_LOG_LEVEL);
+    }
+    if options.ctrlc && state.ctrlc_token.is_none() {
+        let cancel = CancelToken::new();
+        set_ctrlc_handler(cancel.clone())?;
+        state.ctrlc_token = Some(cancel);
+    }
+    Ok(GlobalHandle { ctrlc_token: state.ctrlc_token.clone() })
+}
+
+fn level_name(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::None => "None",
+        LogLevel::Error => "Error",
+        LogLevel::Warning => "Warning",
+        LogLevel::Info => "Info",
+        LogLevel::Debug => "Debug",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** The registrations `init` has made since the last reset, in order. */
+    static REGISTRATIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /** Held by each test, as they share the process-wide state. */
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    pub(super) fn set_usb_log_level(level: LogLevel) {
+        REGISTRATIONS.lock().unwrap().push(format!("usb_log_level {}", level_name(level)));
+    }
+
+    pub(super) fn set_ctrlc_handler(_cancel: CancelToken) -> Result<(), ctrlc::Error> {
+        REGISTRATIONS.lock().unwrap().push("ctrlc".to_string());
+        Ok(())
+    }
+
+    /** Start a test from a fresh process's state. The guard keeps other tests out until it's dropped. */
+    fn fresh() -> std::sync::MutexGuard<'static, ()> {
+        let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        reset();
+        REGISTRATIONS.lock().unwrap().clear();
+        guard
+    }
+
+    fn registrations() -> Vec<String> {
+        REGISTRATIONS.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn embedders_with_compatible_options_share_one_registration() {
+        let _guard = fresh();
+        let host = init(Options { usb_log_level: Some(LogLevel::Warning), ctrlc: true }).unwrap();
+        let plugin = init(Options { usb_log_level: Some(LogLevel::Warning), ctrlc: false }).unwrap();
+        let other_plugin = init(Options { usb_log_level: None, ctrlc: true }).unwrap();
+        assert_eq!(registrations(), vec!["usb_log_level Warning", "ctrlc"]);
+        // One handler, so Ctrl-C reaches every embedder through the same token
+        host.ctrlc_token().unwrap().cancel();
+        assert!(plugin.ctrlc_token().unwrap().is_cancelled());
+        assert!(other_plugin.ctrlc_token().unwrap().is_cancelled());
+    }
+
+    #[test]
+    fn an_embedder_with_conflicting_options_is_refused() {
+        let _guard = fresh();
+        let host = init(Options { usb_log_level: Some(LogLevel::Info), ctrlc: false }).unwrap();
+        match init(Options { usb_log_level: Some(LogLevel::Debug), ctrlc: true }) {
+            Err(Ar2300Error::IncompatibleGlobalConfig(e)) => {
+                assert_eq!(e.setting, "usb_log_level");
+                assert_eq!(e.current, "Info");
+                assert_eq!(e.requested, "Debug");
+            },
+            r => panic!("Expected IncompatibleGlobalConfig, got {:?}", r.map(|_| ()))
+        }
+        // The refused call changed nothing, not even the setting it had no conflict over
+        assert_eq!(registrations(), vec!["usb_log_level Info"]);
+        assert!(host.ctrlc_token().is_none());
+        assert!(init(Options::default()).is_ok());
+        assert_eq!(registrations(), vec!["usb_log_level Info"]);
+    }
+
+    #[test]
+    fn an_embedder_without_a_preference_leaves_the_choice_open() {
+        let _guard = fresh();
+        init(Options::default()).unwrap();
+        init(Options { usb_log_level: Some(LogLevel::Debug), ctrlc: false }).unwrap();
+        assert_eq!(registrations(), vec!["usb_log_level Info", "usb_log_level Debug"]);
+        assert!(init(Options { usb_log_level: Some(LogLevel::Info), ctrlc: false }).is_err());
+    }
+}
@@ -17,235 +17,3074 @@
     along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
-use rusb::{GlobalContext, DeviceHandle, Device};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use log::{debug, info, Level};
+use crate::events::{emit, emit_in};
+use rusb::{GlobalContext, DeviceHandle, Device, UsbContext};
 use std::error::Error;
-use std::io::Write;
-use std::time::Duration;
-use std::sync::{Arc};
-use std::sync::atomic::{AtomicBool, Ordering};
-use simple_error::{bail};
-use crate::queue::Queue;
-use crate::usb::TransferCallback;
-use crate::usb::IsochronousTransfer;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+pub use crate::codec::{decode, decode_with_format, DecodeReport, FrameFormat, Rounding};
+use crate::codec::PACKET_SIZE;
+pub use crate::format::SampleFormat;
+pub use crate::probe::{probe, ProbeReport};
+use crate::accounting::SampleAccounting;
+use crate::error::Ar2300Error;
+use crate::timeline::format_time;
+use crate::pool::{BufferPool, PooledBuf};
+use crate::budget::{Allocation, MemoryBudget, MemoryPlan};
+use crate::diagnostics::BandwidthReport;
+use crate::session::SessionId;
+use crate::queue::{Broadcaster, CloseReason, DequeueResult, EnqueueResult, Queue};
+use crate::usb::{IsoPackets, TransferCallback};
+use crate::usb::{IsochronousTransfer, IsoTransfer, TEARDOWN_TIMEOUT};
 use crate::usb::claim_interface;
+#[cfg(any(test, feature = "fake-device"))]
+use crate::usb::fake::{FakeDevice, FakeTransfer};
 
 const IQ_INTERFACE: u8 = 0;
 const CONTROL_ENDPOINT: u8 = 0x02;
 const DATA_ENDPOINT: u8 = 0x86;
-const START_CAPTURE: [u8; 6] = [0x5a, 0xa5, 0x00, 0x02, 0x41, 0x53];
-const END_CAPTURE: [u8; 6] =  [0x5a, 0xa5, 0x00, 0x02, 0x41, 0x45];
+pub(crate) const START_CAPTURE: [u8; 6] = [0x5a, 0xa5, 0x00, 0x02, 0x41, 0x53];
+pub(crate) const END_CAPTURE: [u8; 6] =  [0x5a, 0xa5, 0x00, 0x02, 0x41, 0x45];
 const PACKET_ATOM: usize = 512;
-const PACKET_LENGTH: usize = PACKET_ATOM*3;
+pub(crate) const PACKET_LENGTH: usize = PACKET_ATOM*3;
 const PACKET_COUNT: usize = 2;
 
 const BUFFER_LEN: usize = ( PACKET_LENGTH * PACKET_COUNT ) + PACKET_LENGTH;
+const PRE_START_DRAIN: Duration = Duration::from_millis(100);
 
-pub struct Receiver {
-    running: Arc<AtomicBool>,
-    handle: Arc<DeviceHandle<GlobalContext>>,
-    buf: Box<Vec<u8>>,
-    skip_packet: Arc<AtomicBool>,
+/** The AR2300's IQ output rate in samples per second. */
+pub const SAMPLE_RATE: u32 = 1_125_000;
+/** Bytes per sample written by Writer by default: a big-endian f32 each for I and Q. */
+pub const BYTES_PER_SAMPLE: u64 = 8;
+
+/** A function run by the Receiver at a fixed point in its life cycle. */
+pub type Hook = Box<dyn FnMut() -> Result<(), Box<dyn Error>> + Send>;
+
+/**
+ The samples decoded from one USB transfer, for a block queue. `seq`
+ counts the blocks a receiver has produced, from zero, so a jump in it
+ shows where blocks were dropped.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleBlock {
+    pub samples: Vec<(f32,f32)>,
+    pub seq: u64,
+}
+
+/**
+ Captures IQ samples from the device. It works with whichever rusb context
+ the device came from, and handles that context's events while it drains.
+ */
+pub struct Receiver<C: UsbContext = GlobalContext> {
+    port: Port<C>,
+    shared: Arc<Shared>,
+    packet_count: usize,
+    packet_length: usize,
+    data_endpoint: u8,
+    control_endpoint: u8,
+    startup_skip_packets: usize,
+    watermarks: Option<Watermarks>,
+    accounting: Arc<SampleAccounting>,
+    pre_start_drain: Option<Duration>,
+    queue: Queue<(f32,f32)>,
+    broadcaster: Option<Broadcaster<(f32,f32)>>,
+    block_queue: Option<Queue<SampleBlock>>,
+    raw_queue: Option<(Queue<PooledBuf>, BufferPool)>,
+    requested_format: FrameFormat,
+    rounding: Rounding,
+    strictness: Strictness,
+    max_overflows_per_sec: u64,
+    memory_budget: Option<MemoryBudget>,
+    transfer: Option<Transfer<C>>,
+    stopped: bool,
+    before_start: Option<Hook>,
+    after_stop: Option<Hook>,
+}
+
+/**
+ What a Receiver and the callback of its transfer both use: whether the
+ capture is running, the counters, and how decoding is going.
+ */
+struct Shared {
+    session: SessionId,
+    running: AtomicBool,
+    skip_count: AtomicUsize,
+    paused: AtomicBool,
+    draining: AtomicBool,
+    discarded_bytes: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    block_seq: AtomicU64,
+    frame_format: Mutex<FrameFormat>,
+    decode_tracking: Mutex<DecodeTracking>,
+    startup: Mutex<StartupTracking>,
+}
+
+/**
+ The callback of a Receiver's transfer, with the buffer the transfer reads
+ into. It is boxed and owned by the transfer, so the receiver can be moved
+ while capturing, and shares nothing with the receiver but `Shared`. The
+ outputs and decode settings are the receiver's as of when the transfer
+ was submitted.
+ */
+struct Capture {
+    buf: Vec<u8>,
+    shared: Arc<Shared>,
+    accounting: Arc<SampleAccounting>,
     queue: Queue<(f32,f32)>,
+    broadcaster: Option<Broadcaster<(f32,f32)>>,
+    block_queue: Option<Queue<SampleBlock>>,
+    raw_queue: Option<(Queue<PooledBuf>, BufferPool)>,
+    rounding: Rounding,
+    strictness: Strictness,
+    max_overflows_per_sec: u64,
+    watermarks: Option<Watermarks>,
 }
 
-fn valid_packet(buffer: &[u8]) -> bool {
-    (buffer[1] & 0x01) == 0x01
+/** What a Receiver sends its commands to and submits its transfer on. */
+enum Port<C: UsbContext> {
+    Usb(Arc<DeviceHandle<C>>),
+    #[cfg(any(test, feature = "fake-device"))]
+    Fake(Arc<FakeDevice>),
 }
 
-fn find_packet(buffer: &[u8]) -> Result<&[u8], Box<dyn Error>> {
-    let mut buf = buffer;
-    while buf.len() > 8 && !valid_packet(buf) {
-        buf = &buf[1..];
+impl<C: UsbContext> Clone for Port<C> {
+    fn clone(&self) -> Self {
+        match self {
+            Port::Usb(handle) => Port::Usb(handle.clone()),
+            #[cfg(any(test, feature = "fake-device"))]
+            Port::Fake(device) => Port::Fake(device.clone()),
+        }
     }
-    if valid_packet(buf) {
-        Ok(buf)
-    } else {
-        bail!("Packet not found")
+}
+
+impl<C: UsbContext> Port<C> {
+    fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        match self {
+            Port::Usb(handle) => handle.write_bulk(endpoint, data, timeout),
+            #[cfg(any(test, feature = "fake-device"))]
+            Port::Fake(device) => device.write_bulk(endpoint, data),
+        }
+    }
+
+    /** True if the device can be asked to stream in the given format. */
+    fn offers_format(&self, format: FrameFormat) -> bool {
+        match self {
+            // The control protocol has no command to select a format yet
+            Port::Usb(_) => format == FrameFormat::AR2300,
+            #[cfg(any(test, feature = "fake-device"))]
+            Port::Fake(device) => format == FrameFormat::AR2300 ||
+                device.extra_formats.lock().unwrap().contains(&format),
+        }
+    }
+
+    fn handle_events(&self, timeout: Option<Duration>) -> rusb::Result<()> {
+        match self {
+            Port::Usb(handle) => handle.context().handle_events(timeout),
+            #[cfg(any(test, feature = "fake-device"))]
+            Port::Fake(device) => device.handle_events(timeout),
+        }
+    }
+
+    fn submit(&self,
+              endpoint: u8,
+              packet_count: usize,
+              packet_length: usize,
+              capture: Box<Capture>) -> rusb::Result<Transfer<C>> {
+        match self {
+            Port::Usb(handle) => handle
+                .submit_iso(endpoint, packet_count, packet_length, capture, Duration::from_millis(0))
+                .map(Transfer::Usb),
+            #[cfg(any(test, feature = "fake-device"))]
+            Port::Fake(device) => device.submit(packet_count, packet_length, capture).map(Transfer::Fake),
+        }
     }
 }
 
-const BASE: f32 = 2f32 * 2147483648.0f32;
+/** The Receiver's submitted transfer. */
+enum Transfer<C: UsbContext> {
+    Usb(IsoTransfer<Capture, C>),
+    #[cfg(any(test, feature = "fake-device"))]
+    Fake(FakeTransfer),
+}
 
-fn read_packet(packet: &[u8]) -> (f32, f32) {
-    let i = LittleEndian::read_u32(&packet[0..4]);
-    let q = LittleEndian::read_u32(&packet[4..8]);
+impl<C: UsbContext> Transfer<C> {
+    /** See `IsoTransfer::close`. */
+    fn close(self, timeout: Duration) -> bool {
+        match self {
+            Transfer::Usb(transfer) => transfer.close(timeout),
+            #[cfg(any(test, feature = "fake-device"))]
+            Transfer::Fake(transfer) => transfer.close(),
+        }
+    }
+}
 
-    let f = |n: u32| -> f32 {
-        let mut n16 = [
-            (n >> 16) as u16,
-            n as u16,
-        ];
-        // received data processing.
-        if (n16[0] & 0x8000) == 0x8000 {
-            n16[1] = n16[1] | 0x0001;
-        } else {
-            n16[1] = n16[1] & 0xfffe;
+/** Bounds on decode problems within a window of time. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub window: Duration,
+    /** Transfers with no valid packet allowed per window. */
+    pub unsynced_transfers: u64,
+    /** Invalid packets allowed per window. */
+    pub invalid_packets: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            window: Duration::from_secs(1),
+            unsynced_transfers: 0,
+            invalid_packets: 0,
         }
-        n16[0] = n16[0] << 1;
-        ((((n16[0] as u32) << 16) | (n16[1] as u32)) as f32) / BASE
-    };
+    }
+}
+
+/** How the Receiver reacts to corrupted data. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /** Decode what can be decoded and carry on. */
+    #[default]
+    BestEffort,
+    /** Carry on, but report each window in which the limits are exceeded. */
+    Warn(DecodeLimits),
+    /** Stop the capture the first time the limits are exceeded. */
+    Strict(DecodeLimits),
+}
+
+/**
+ Receiver settings. Start from one of the named profiles and override
+ individual fields as needed, e.g.
+ `ReceiverConfig { packet_count: 4, ..ReceiverConfig::robust() }`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReceiverConfig {
+    /** The profile the settings started from, for logging. */
+    pub profile: &'static str,
+    /** Isochronous packets per transfer. Fewer means samples arrive sooner. */
+    pub packet_count: usize,
+    /** Samples the queue holds before new ones are dropped. */
+    pub queue_capacity: usize,
+    /** See `Receiver::set_pre_start_drain`. */
+    pub pre_start_drain: Option<Duration>,
+    pub strictness: Strictness,
+    /**
+     Overflowed transfers tolerated per second. Each one is discarded and
+     the stream resynchronizes on the next transfer; beyond this rate the
+     capture is stopped.
+     */
+    pub max_overflows_per_sec: u64,
+    /** The frame layout to ask the device for. Current firmware only sends `FrameFormat::AR2300`. */
+    pub frame_format: FrameFormat,
+    /** The session the receiver's events are tagged with. A new one is generated if unset. */
+    pub session: Option<SessionId>,
+    /** The most memory the receiver's buffers may take. See `MemoryBudget`. */
+    pub memory_budget: Option<MemoryBudget>,
+}
+
+impl ReceiverConfig {
+    pub const PROFILES: [&'static str; 3] = ["default", "low-latency", "robust"];
+
+    /** Look up a profile by name. */
+    pub fn profile(name: &str) -> Option<ReceiverConfig> {
+        match name {
+            "default" => Some(ReceiverConfig::default()),
+            "low-latency" => Some(ReceiverConfig::low_latency()),
+            "robust" => Some(ReceiverConfig::robust()),
+            _ => None
+        }
+    }
+
+    /**
+     For interactive monitoring: one packet per transfer, a queue holding
+     about 60 ms of samples and the default drain.
+     */
+    pub fn low_latency() -> Self {
+        ReceiverConfig {
+            profile: "low-latency",
+            packet_count: 1,
+            queue_capacity: 1 << 16,
+            ..ReceiverConfig::default()
+        }
+    }
+
+    /**
+     For unattended recording: eight packets per transfer, a queue holding
+     about four seconds of samples, a 250 ms drain, warnings when data is
+     corrupted and up to 100 overflows per second.
+     */
+    pub fn robust() -> Self {
+        ReceiverConfig {
+            profile: "robust",
+            packet_count: 8,
+            queue_capacity: 1 << 22,
+            pre_start_drain: Some(Duration::from_millis(250)),
+            strictness: Strictness::Warn(DecodeLimits::default()),
+            max_overflows_per_sec: 100,
+            frame_format: FrameFormat::AR2300,
+            session: None,
+            memory_budget: None,
+        }
+    }
+
+    /** A queue with the configured capacity. */
+    pub fn new_queue(&self) -> Queue<(f32,f32)> {
+        Queue::named("samples", self.queue_capacity)
+    }
+
+    /**
+     A block queue holding about as many samples as `new_queue`, with one
+     block per transfer.
+     */
+    pub fn new_block_queue(&self) -> Queue<SampleBlock> {
+        let samples_per_block = (self.transfer_len() / PACKET_SIZE).max(1);
+        Queue::named("blocks", self.queue_capacity / samples_per_block)
+    }
+
+    /**
+     The buffers a receiver with these settings can fill when it sends to
+     the sample queue, for checking against a memory budget.
+     */
+    pub fn allocations(&self) -> Vec<Allocation> {
+        vec![
+            Allocation { name: "sample queue", count: self.queue_capacity, size: std::mem::size_of::<(f32,f32)>() },
+            Allocation { name: "transfer buffer", count: 1, size: PACKET_LENGTH * (self.packet_count + 1) },
+        ]
+    }
+
+    /** The bytes a transfer's packets carry when full. */
+    fn transfer_len(&self) -> usize {
+        PACKET_LENGTH * self.packet_count
+    }
+
+    /** The samples a transfer carries when full. */
+    pub(crate) fn transfer_samples(&self) -> usize {
+        self.transfer_len() / PACKET_SIZE
+    }
+}
+
+impl Default for ReceiverConfig {
+    /**
+     Two packets per transfer, a queue holding about a second of samples,
+     a 100 ms drain, best-effort decoding and up to 10 overflows per second.
+     */
+    fn default() -> Self {
+        ReceiverConfig {
+            profile: "default",
+            packet_count: PACKET_COUNT,
+            queue_capacity: 1 << 20,
+            pre_start_drain: Some(PRE_START_DRAIN),
+            strictness: Strictness::default(),
+            max_overflows_per_sec: 10,
+            frame_format: FrameFormat::AR2300,
+            session: None,
+            memory_budget: None,
+        }
+    }
+}
+
+/**
+ Queue depths at which a receiver stops and restarts reading from the
+ device. See `ReceiverBuilder::watermarks`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watermarks {
+    /** Stop resubmitting the transfer once the queue holds more than this. */
+    pub high: usize,
+    /** Resubmit it once the queue holds fewer than this. */
+    pub low: usize,
+}
+
+/**
+ Builds a Receiver, for settings beyond those in ReceiverConfig such as
+ the packet size and the endpoints. Get one from `Receiver::builder`, or
+ `ReceiverBuilder::default` for a receiver on another USB context.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct ReceiverBuilder {
+    config: ReceiverConfig,
+    packet_length: usize,
+    data_endpoint: u8,
+    control_endpoint: u8,
+    startup_skip_packets: usize,
+    watermarks: Option<Watermarks>,
+}
+
+impl Default for ReceiverBuilder {
+    /** The default ReceiverConfig, the AR2300's endpoints and packet size, and one packet skipped. */
+    fn default() -> Self {
+        ReceiverBuilder {
+            config: ReceiverConfig::default(),
+            packet_length: PACKET_LENGTH,
+            data_endpoint: DATA_ENDPOINT,
+            control_endpoint: CONTROL_ENDPOINT,
+            startup_skip_packets: 1,
+            watermarks: None,
+        }
+    }
+}
+
+impl ReceiverBuilder {
+    /** Start from these settings. This replaces any packet count given before. */
+    pub fn config(mut self, config: ReceiverConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /** Isochronous packets per transfer. */
+    pub fn packet_count(mut self, n: usize) -> Self {
+        self.config.packet_count = n;
+        self
+    }
+
+    /** Bytes per isochronous packet. */
+    pub fn packet_length(mut self, n: usize) -> Self {
+        self.packet_length = n;
+        self
+    }
+
+    /** The isochronous IN endpoint samples arrive on. */
+    pub fn data_endpoint(mut self, ep: u8) -> Self {
+        self.data_endpoint = ep;
+        self
+    }
+
+    /** The bulk OUT endpoint that starts and stops the capture. */
+    pub fn control_endpoint(mut self, ep: u8) -> Self {
+        self.control_endpoint = ep;
+        self
+    }
+
+    /**
+     Transfers discarded when a capture starts, since the first may hold
+     data from before it. The default is one, but a busy host controller
+     can need two or three. Zero keeps everything.
+     */
+    pub fn startup_skip_packets(mut self, n: usize) -> Self {
+        self.startup_skip_packets = n;
+        self
+    }
+
+    /**
+     Pause reading from the device while the queue is backed up, instead
+     of dropping samples as they arrive. Once the queue holds more than
+     `high` items the transfer isn't resubmitted; `Receiver::resume` starts
+     it again when the queue has fallen below `low`. Depths are counted in
+     blocks when using a block queue. Not applied when sending to a
+     broadcaster. The device keeps streaming while paused, so what it sends
+     in the meantime is lost.
+     */
+    pub fn watermarks(mut self, high: usize, low: usize) -> Self {
+        self.watermarks = Some(Watermarks { high, low });
+        self
+    }
+
+    /**
+     Refuse to build or start the receiver if its buffers could take more
+     memory than this. The same as setting the config's `memory_budget`.
+     */
+    pub fn memory_budget(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.config.memory_budget = budget;
+        self
+    }
+
+    /** The same as `startup_skip_packets`. */
+    pub fn startup_skip_count(self, n: u32) -> Self {
+        self.startup_skip_packets(n as usize)
+    }
+
+    /**
+     Open the device, claim its interface and create the receiver. The
+     config's `queue_capacity` is ignored; see `ReceiverConfig::new_queue`.
+     */
+    pub fn build<C: UsbContext>(self, device: Device<C>, queue: Queue<(f32,f32)>) -> Result<Receiver<C>, Ar2300Error> {
+        self.validate()?;
+        let started = Instant::now();
+        let mut handle = device.open()?;
+        claim_interface(&mut handle, IQ_INTERFACE)
+            .map_err(|e| Ar2300Error::InterfaceUnavailable(e.to_string()))?;
+        let receiver = self.assemble(Port::Usb(Arc::new(handle)), queue, started.elapsed());
+        receiver.memory_plan().check()?;
+        Ok(receiver)
+    }
+
+    /**
+     Create a receiver on a simulated device, which delivers whatever has
+     been queued on it. Pre-start drains read from it like any other.
+     */
+    #[cfg(any(test, feature = "fake-device"))]
+    pub fn build_fake(self, device: Arc<FakeDevice>, queue: Queue<(f32,f32)>) -> Result<Receiver, Ar2300Error> {
+        self.validate()?;
+        let receiver = self.assemble(Port::Fake(device), queue, Duration::ZERO);
+        receiver.memory_plan().check()?;
+        Ok(receiver)
+    }
+
+    fn validate(&self) -> Result<(), Ar2300Error> {
+        let config = self.config;
+        if config.packet_count == 0 {
+            return Err(Ar2300Error::InvalidConfig("A transfer needs at least one packet".to_string()));
+        }
+        if self.packet_length == 0 {
+            return Err(Ar2300Error::InvalidConfig("Packets must hold at least one byte".to_string()));
+        }
+        if let Some(w) = self.watermarks {
+            if w.low > w.high {
+                return Err(Ar2300Error::InvalidConfig(
+                    format!("The low watermark ({}) is above the high watermark ({})", w.low, w.high)));
+            }
+        }
+        Ok(())
+    }
+
+    fn assemble<C: UsbContext>(self, port: Port<C>, queue: Queue<(f32,f32)>, claim_interface: Duration) -> Receiver<C> {
+        let config = self.config;
+        let mut startup = StartupTracking::default();
+        startup.timings.claim_interface = Some(claim_interface);
+        let shared = Shared {
+            session: config.session.unwrap_or_else(SessionId::generate),
+            running: AtomicBool::new(false),
+            skip_count: AtomicUsize::new(self.startup_skip_packets),
+            paused: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            discarded_bytes: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            block_seq: AtomicU64::new(0),
+            frame_format: Mutex::new(FrameFormat::AR2300),
+            decode_tracking: Mutex::new(DecodeTracking {
+                total: DecodeReport::default(),
+                window: DecodeReport::default(),
+                window_start: Instant::now(),
+                overflows_this_second: 0,
+                overflow_second_start: Instant::now(),
+                failure: None,
+            }),
+            startup: Mutex::new(startup),
+        };
+        Receiver {
+            port,
+            shared: Arc::new(shared),
+            packet_count: config.packet_count,
+            packet_length: self.packet_length,
+            data_endpoint: self.data_endpoint,
+            control_endpoint: self.control_endpoint,
+            startup_skip_packets: self.startup_skip_packets,
+            watermarks: self.watermarks,
+            accounting: Arc::new(SampleAccounting::new()),
+            pre_start_drain: config.pre_start_drain,
+            queue,
+            broadcaster: None,
+            block_queue: None,
+            raw_queue: None,
+            requested_format: config.frame_format,
+            rounding: Rounding::default(),
+            strictness: config.strictness,
+            max_overflows_per_sec: config.max_overflows_per_sec,
+            memory_budget: config.memory_budget,
+            transfer: None,
+            stopped: false,
+            before_start: None,
+            after_stop: None,
+        }
+    }
+}
 
-    (f(i), f(q))
+/**
+ How long each step of starting a capture took. The steps after
+ START_CAPTURE are measured from when it was sent, so `first_sample` is the
+ delay between triggering and the first retained sample.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StartupTimings {
+    /** Opening the device and claiming the interface. */
+    pub claim_interface: Option<Duration>,
+    /** Draining data left over from a previous session. */
+    pub drain: Option<Duration>,
+    /** Sending START_CAPTURE. */
+    pub send_start: Option<Duration>,
+    /** From START_CAPTURE to the first transfer completing. */
+    pub first_transfer: Option<Duration>,
+    /** From START_CAPTURE to the first sample being enqueued. */
+    pub first_sample: Option<Duration>,
+}
+
+#[derive(Default)]
+struct StartupTracking {
+    timings: StartupTimings,
+    start_sent: Option<Instant>,
+}
+
+struct DecodeTracking {
+    total: DecodeReport,
+    window: DecodeReport,
+    window_start: Instant,
+    overflows_this_second: u64,
+    overflow_second_start: Instant,
+    failure: Option<Arc<Ar2300Error>>,
+}
+
+/** How raw transfer data is laid out in a dump file. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RawFormat {
+    /**
+     Transfer payloads concatenated with nothing in between. Transfer
+     boundaries are lost, so the data is decoded in blocks the size of the
+     receiver's transfer buffer.
+     */
+    Flat,
+    /**
+     Each transfer payload is preceded by its length in bytes as a
+     little-endian u32.
+     */
+    LengthPrefixed,
+}
+
+/**
+ Decode a dump of raw transfer data, writing samples to `out` in the same
+ format as Writer: interleaved big-endian f32 I and Q values.
+ */
+pub fn decode_raw(input: &mut dyn Read,
+                  format: RawFormat,
+                  rounding: Rounding,
+                  out: &mut dyn Write) -> Result<DecodeReport, Ar2300Error> {
+    let mut report = DecodeReport::default();
+    let mut buf = Vec::with_capacity(BUFFER_LEN);
+    let mut samples = Vec::with_capacity(BUFFER_LEN / 8);
+    let mut bytes = Vec::with_capacity(BUFFER_LEN);
+    loop {
+        buf.clear();
+        match format {
+            RawFormat::Flat => {
+                input.take(BUFFER_LEN as u64).read_to_end(&mut buf)?;
+            },
+            RawFormat::LengthPrefixed => {
+                let len = match input.read_u32::<LittleEndian>() {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into())
+                };
+                input.take(len as u64).read_to_end(&mut buf)?;
+                if buf.len() < len as usize {
+                    return Err(Ar2300Error::DecodeFailed(
+                        format!("Truncated transfer: expected {} bytes, found {}", len, buf.len())));
+                }
+            }
+        }
+        if buf.is_empty() {
+            break;
+        }
+        samples.clear();
+        report.add(&decode(&buf, rounding, &mut samples));
+        bytes.clear();
+        for sample in &samples {
+            SampleFormat::BigEndianF32.encode(*sample, &mut bytes);
+        }
+        out.write_all(&bytes)?;
+    }
+    out.flush()?;
+    Ok(report)
 }
 
-impl TransferCallback for Receiver {
+impl TransferCallback for Capture {
     fn buffer(&mut self) -> &mut [u8] {
         self.buf.as_mut_slice()
     }
 
-    fn callback(&self, result: rusb::Result<()>) -> bool {
+    fn callback(&mut self, result: rusb::Result<()>, packets: &IsoPackets) -> bool {
         let success = match result {
             Ok(_) => true,
             Err(rusb::Error::Other) => true,
+            Err(rusb::Error::Overflow) => {
+                self.track_overflow();
+                false
+            },
+            // Cancelled while being dropped
+            Err(rusb::Error::Interrupted) if !self.shared.running.load(Ordering::Relaxed) => false,
             Err(e) => {
-                eprintln!("Error reading IQ data: {}", e);
-                self.running.swap(false, Ordering::Relaxed);
+                emit_in(Some(self.shared.session), Level::Error, format_args!("Error reading IQ data: {}", e));
+                let mut tracking = self.shared.decode_tracking.lock().unwrap();
+                self.fail(&mut tracking, Ar2300Error::UsbError(e));
                 false
             }
         };
-        if success && !self.skip_packet.swap(false, Ordering::Relaxed) {
-            let buffer = *self.buf.clone();
-            match find_packet(buffer.as_slice()) {
-                Ok(buf) => {
-                    for packet in buf.chunks(8) {
-                        if packet.len() == 8 && valid_packet(packet) {
-                            self.queue.enqueue(read_packet(packet));
+        if success {
+            self.shared.packets_received.fetch_add(packets.completed() as u64, Ordering::Relaxed);
+            self.shared.bytes_received.fetch_add(packets.received() as u64, Ordering::Relaxed);
+        }
+        if success && !self.shared.draining.load(Ordering::Relaxed) {
+            let mut startup = self.shared.startup.lock().unwrap();
+            if let (Some(sent), None) = (startup.start_sent, startup.timings.first_transfer) {
+                startup.timings.first_transfer = Some(sent.elapsed());
+            }
+        }
+        if success && (self.shared.draining.load(Ordering::Relaxed) || self.take_skip()) {
+            self.shared.discarded_bytes.fetch_add(packets.received() as u64, Ordering::Relaxed);
+        } else if success {
+            // Short and failed packets leave stale data in their slots
+            let received = packets.compact(&mut self.buf);
+            if let Some((raw_queue, pool)) = &self.raw_queue {
+                let mut raw = pool.get();
+                raw.extend_from_slice(&self.buf[..received]);
+                if raw_queue.enqueue(raw) != EnqueueResult::Queued {
+                    debug!("Raw queue full; a transfer was dropped");
+                }
+            }
+            let mut samples = Vec::with_capacity(received / 8);
+            let format = *self.shared.frame_format.lock().unwrap();
+            let report = decode_with_format(&self.buf[..received], format, self.rounding, &mut samples);
+            if report.unsynced_transfers > 0 {
+                emit_in(Some(self.shared.session), Level::Warn, format_args!("Couldn't find packet"));
+            }
+            if self.track_decode(&report) && !samples.is_empty() {
+                // One lock per transfer rather than one per sample
+                let len = samples.len();
+                // Counted before the writer can see them
+                self.accounting.record_received(len as u64);
+                let dropped = match (&self.block_queue, &self.broadcaster) {
+                    (Some(block_queue), _) => {
+                        let seq = self.shared.block_seq.fetch_add(1, Ordering::Relaxed);
+                        // An evicted block is counted at this block's size
+                        match block_queue.enqueue(SampleBlock { samples, seq }) {
+                            EnqueueResult::Queued => 0,
+                            _ => len
                         }
-                        // TODO: Handle buffering the last partial packet
-                    }
-                },
-                Err(_) => eprintln!("Couldn't find packet"),
+                    },
+                    (None, Some(broadcaster)) => broadcaster.send_all(&samples),
+                    (None, None) => self.queue.enqueue_all(samples)
+                };
+                if dropped > 0 {
+                    self.accounting.record_dropped(dropped as u64);
+                }
+                let mut startup = self.shared.startup.lock().unwrap();
+                if let (Some(sent), None) = (startup.start_sent, startup.timings.first_sample) {
+                    startup.timings.first_sample = Some(sent.elapsed());
+                }
             }
+        }
+        let running = self.shared.running.load(Ordering::Relaxed);
+        if running && !self.shared.draining.load(Ordering::Relaxed) && self.above_high_watermark() {
+            debug!("Queue above the high watermark; pausing the transfer");
+            self.shared.paused.store(true, Ordering::Relaxed);
+            return false;
+        }
+        running
+    }
+}
+
+impl Capture {
+    /**
+     Add a transfer's decode report to the totals and apply the strictness
+     policy. Returns false if the transfer's samples should be discarded
+     because the capture is being aborted.
+     */
+    fn track_decode(&self, report: &DecodeReport) -> bool {
+        let mut tracking = self.shared.decode_tracking.lock().unwrap();
+        tracking.total.add(report);
+        let (limits, strict) = match self.strictness {
+            Strictness::BestEffort => return true,
+            Strictness::Warn(limits) => (limits, false),
+            Strictness::Strict(limits) => (limits, true),
+        };
+        if tracking.window_start.elapsed() >= limits.window {
+            tracking.window = DecodeReport::default();
+            tracking.window_start = Instant::now();
+        }
+        tracking.window.add(report);
+        let window = tracking.window;
+        let problem = if window.unsynced_transfers > limits.unsynced_transfers {
+            Some(format!("{} transfers without a valid packet within {:?} (limit {})",
+                         window.unsynced_transfers, limits.window, limits.unsynced_transfers))
+        } else if window.invalid_packets > limits.invalid_packets {
+            Some(format!("{} invalid packets within {:?} (limit {})",
+                         window.invalid_packets, limits.window, limits.invalid_packets))
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) if strict => {
+                emit_in(Some(self.shared.session), Level::Error, format_args!("Stopping IQ capture: {}", problem));
+                self.fail(&mut tracking, Ar2300Error::DecodeFailed(problem));
+                false
+            },
+            Some(problem) => {
+                emit_in(Some(self.shared.session), Level::Warn, format_args!("{}", problem));
+                // Report once per window
+                tracking.window = DecodeReport::default();
+                tracking.window_start = Instant::now();
+                true
+            },
+            None => true
+        }
+    }
+
+    /**
+     Count an overflowed transfer. Its data is dropped; since every
+     transfer is synchronized independently, the next one starts clean.
+     Stops the capture if overflows come too often.
+     */
+    fn track_overflow(&self) {
+        let mut tracking = self.shared.decode_tracking.lock().unwrap();
+        tracking.total.overflows += 1;
+        if tracking.overflow_second_start.elapsed() >= Duration::from_secs(1) {
+            tracking.overflows_this_second = 0;
+            tracking.overflow_second_start = Instant::now();
+        }
+        tracking.overflows_this_second += 1;
+        emit_in(Some(self.shared.session), Level::Warn, format_args!("USB overflow, discarding transfer"));
+        if tracking.overflows_this_second > self.max_overflows_per_sec {
+            let problem = format!("more than {} USB overflows per second", self.max_overflows_per_sec);
+            emit_in(Some(self.shared.session), Level::Error, format_args!("Stopping IQ capture: {}", problem));
+            self.fail(&mut tracking, Ar2300Error::DecodeFailed(problem));
+        }
+    }
+
+    /**
+     Stop the capture because of an error, recording it in the ledger. Only
+     the first error is kept; later ones are usually caused by it.
+     */
+    fn fail(&self, tracking: &mut DecodeTracking, error: Ar2300Error) {
+        if tracking.failure.is_none() {
+            let error = Arc::new(error);
+            self.accounting.record_error(error.clone());
+            tracking.failure = Some(error);
+        }
+        self.shared.running.store(false, Ordering::Relaxed);
+    }
 
+    /** Use up one of the transfers to skip, returning false once there are none left. */
+    fn take_skip(&self) -> bool {
+        self.shared.skip_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok()
+    }
+
+    /** True if watermarks are set and the queue being filled is past the high one. */
+    fn above_high_watermark(&self) -> bool {
+        match (self.watermarks, &self.block_queue) {
+            (None, _) => false,
+            (Some(_), None) if self.broadcaster.is_some() => false,
+            (Some(w), Some(block_queue)) => block_queue.is_above(w.high),
+            (Some(w), None) => self.queue.is_above(w.high)
         }
-        self.running.load(Ordering::Relaxed)
     }
 }
 
 impl Receiver {
-    pub fn new(device: Device<GlobalContext>, queue: Queue<(f32,f32)>) -> Result<Receiver, Box<dyn Error>> {
-        let mut handle = device.open()?;
-        claim_interface(&mut handle, IQ_INTERFACE)?;
-        Ok(Receiver {
-            running: Arc::new(AtomicBool::new(false)),
-            handle: Arc::new(handle),
-            buf: Box::new(vec![0; BUFFER_LEN]),
-            skip_packet: Arc::new(AtomicBool::new(true)),
-            queue: queue,
-        })
+    /** Start building a receiver with settings beyond a ReceiverConfig. */
+    pub fn builder() -> ReceiverBuilder {
+        ReceiverBuilder::default()
+    }
+}
+
+impl<C: UsbContext> Receiver<C> {
+    pub fn new(device: Device<C>, queue: Queue<(f32,f32)>) -> Result<Receiver<C>, Ar2300Error> {
+        ReceiverBuilder::default().build(device, queue)
+    }
+
+    /** Create a receiver with the given settings. `queue_capacity` is ignored; see `ReceiverConfig::new_queue`. */
+    pub fn with_config(device: Device<C>,
+                       queue: Queue<(f32,f32)>,
+                       config: ReceiverConfig) -> Result<Receiver<C>, Ar2300Error> {
+        ReceiverBuilder::default().config(config).build(device, queue)
     }
 
     pub fn is_running(&self) -> Box<dyn Fn()->bool> {
-        let r = self.running.clone();
-        Box::new(move || r.load(Ordering::Relaxed))
+        let shared = self.shared.clone();
+        Box::new(move || shared.running.load(Ordering::Relaxed))
     }
 
     pub fn queue(&self) -> Queue<(f32,f32)> {
         self.queue.clone()
     }
 
-    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
-        let running = self.running.clone();
-        if let Ok(_) = running.compare_exchange(false,
-                                          true,
-                                          Ordering::Acquire,
-                                          Ordering::Relaxed) {
-            // Start IQ capture
-            println!("IQ receiver starting");
-            match self.handle.write_bulk(CONTROL_ENDPOINT,
-                                         &START_CAPTURE,
-                                         Duration::from_secs(1)) {
-                Ok(_) => {
-                    let handle = self.handle.clone();
-
-                    println!("Submitting transfer request");
-                    match handle.submit_iso(
-                        DATA_ENDPOINT,
-                        PACKET_COUNT,
-                        PACKET_LENGTH,
-                        self,
-                        Duration::from_millis(0)) {
-                        Ok(_) => {
-                            println!("Transfer request submitted");
-                            Ok(())
-                        }
-                        Err(e) => {
-                            bail!("Error submitting transfer request: {}", e);
-                        }
-                    }
-                },
-                Err(e) => {
-                    bail!("Error starting IQ receiver: {}", e);
-                }
-            }
-        } else {
-            bail!("IQ receiver is already running")
+    /**
+     Send samples to the broadcaster's subscribers instead of the queue.
+     Set before starting. The queue is still closed when the receiver
+     stops, along with every subscriber.
+     */
+    pub fn set_broadcaster(&mut self, broadcaster: Option<Broadcaster<(f32,f32)>>) {
+        self.broadcaster = broadcaster;
+    }
+
+    /**
+     Send each transfer's samples to the block queue as one SampleBlock,
+     instead of to the queue or the broadcaster, taking the queue's lock
+     once per transfer rather than adding samples one by one. Set before
+     starting. The block queue is closed with the queue when the receiver
+     stops.
+     */
+    pub fn set_block_queue(&mut self, block_queue: Option<Queue<SampleBlock>>) {
+        self.block_queue = block_queue;
+    }
+
+    /**
+     Also send a copy of each transfer's raw bytes to `raw_queue`, for
+     dumping or decoding elsewhere: what its packets received, in order,
+     without the stale bytes short and failed packets leave. The copies are made into buffers from
+     `pool`, which go back to it once the consumer drops them, so nothing
+     is allocated per transfer while the pool has idle buffers. Set before
+     starting. The raw queue is closed with the queue when the receiver
+     stops.
+     */
+    pub fn set_raw_queue(&mut self, raw_queue: Option<(Queue<PooledBuf>, BufferPool)>) {
+        self.raw_queue = raw_queue;
+    }
+
+    /** Set how sample codes are rounded when converted to f32. Set before starting. */
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+    /** Set how the receiver reacts to corrupted data. Set before starting. */
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    /** Totals for everything decoded so far. */
+    pub fn decode_report(&self) -> DecodeReport {
+        self.shared.decode_tracking.lock().unwrap().total
+    }
+
+    /**
+     Why the receiver stopped itself, if it did: a USB error, too many
+     overflows, or the limits of `Strictness::Strict`.
+     */
+    pub fn decode_failure(&self) -> Option<String> {
+        self.failure().map(|e| e.to_string())
+    }
+
+    /**
+     The error the receiver stopped itself with, if it did. It is also
+     recorded in the ledger, and is what `stop_with` should close the queue
+     with.
+     */
+    pub fn failure(&self) -> Option<Arc<Ar2300Error>> {
+        self.shared.decode_tracking.lock().unwrap().failure.clone()
+    }
+
+    /**
+     Set how long `start` drains the data endpoint before starting a capture.
+     If a previous session died without stopping the capture, the device is
+     still streaming when we attach. To recover, `start` first stops the
+     capture, then discards whatever arrives during this window. `None`
+     skips both steps.
+     */
+    pub fn set_pre_start_drain(&mut self, drain: Option<Duration>) {
+        self.pre_start_drain = drain;
+    }
+
+    /** How long each step of starting the capture took, as far as it has got. */
+    pub fn startup_timings(&self) -> StartupTimings {
+        self.shared.startup.lock().unwrap().timings
+    }
+
+    /** Samples lost because the queue was full, summed across subscribers when broadcasting. */
+    pub fn dropped_samples(&self) -> u64 {
+        self.accounting.dropped()
+    }
+
+    /**
+     Isochronous packets that completed with data, counting those discarded
+     at start-up. Short packets count; empty and failed ones don't.
+     Like the other counters it can be read from any thread, so a monitor
+     can tell a stalled receiver from a busy one.
+     */
+    pub fn packets_received(&self) -> u64 {
+        self.shared.packets_received.load(Ordering::Relaxed)
+    }
+
+    /** Bytes received, counting those discarded at start-up. */
+    pub fn bytes_received(&self) -> u64 {
+        self.shared.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /**
+     Samples handed to the queue, block queue or broadcaster, including
+     any it then dropped for being full. See `dropped_samples`.
+     */
+    pub fn samples_enqueued(&self) -> u64 {
+        self.accounting.received()
+    }
+
+    /**
+     Record samples in this ledger, shared with the writer, instead of the
+     receiver's own. Set before starting.
+     */
+    pub fn set_accounting(&mut self, accounting: Arc<SampleAccounting>) {
+        self.accounting = accounting;
+    }
+
+    /**
+     The most memory each of the receiver's buffers can take with its
+     current outputs, and its budget. Only the queue it sends samples to is
+     counted: the block queue if it has one, otherwise the sample queue.
+     */
+    pub fn memory_plan(&self) -> MemoryPlan {
+        let mut allocations = Vec::new();
+        match &self.block_queue {
+            Some(blocks) => {
+                let samples = self.packet_length * self.packet_count / PACKET_SIZE;
+                let size = std::mem::size_of::<SampleBlock>() + samples * std::mem::size_of::<(f32,f32)>();
+                allocations.push(Allocation { name: "block queue", count: blocks.capacity(), size });
+            },
+            None => allocations.push(Allocation {
+                name: "sample queue",
+                count: self.queue.capacity(),
+                size: std::mem::size_of::<(f32,f32)>(),
+            }),
+        }
+        allocations.push(Allocation { name: "transfer buffer", count: 1, size: self.buffer_len() });
+        if let Some((queue, pool)) = &self.raw_queue {
+            allocations.push(Allocation { name: "raw queue", count: queue.capacity(), size: pool.buf_len() });
+            allocations.push(Allocation { name: "buffer pool", count: pool.size(), size: pool.buf_len() });
         }
+        MemoryPlan { budget: self.memory_budget, allocations }
     }
 
-    pub fn stop(&mut self) {
-        let running = self.running.clone();
-        if let Ok(_) = running.compare_exchange(true,
-                                                false,
-                                                Ordering::Acquire,
-                                                Ordering::Relaxed) {
-            print!("Stopping IQ receiver");
-           
-            self.queue.close();
+    /** The session the receiver's events are tagged with. See `ReceiverConfig::session`. */
+    pub fn session(&self) -> SessionId {
+        self.shared.session
+    }
 
-            // End IQ capture
-            match self.handle.write_bulk(CONTROL_ENDPOINT,
-                                    &END_CAPTURE,
-                                    Duration::from_secs(1)) {
-                Ok(_) => {}
+    /** The ledger the receiver records its samples in. See `SampleAccounting`. */
+    pub fn accounting(&self) -> Arc<SampleAccounting> {
+        self.accounting.clone()
+    }
+
+    /** The number of bytes received and thrown away while starting up. */
+    pub fn discarded_bytes(&self) -> u64 {
+        self.shared.discarded_bytes.load(Ordering::Relaxed)
+    }
+
+    /**
+     Run a hook just before START_CAPTURE is sent. By then the interface is
+     claimed, any pre-start drain has finished and the transfer is ready, so
+     the first sample retained is taken after the hook returns. If the hook
+     fails, the capture is not started and `start` returns its error.
+     */
+    pub fn on_before_start(&mut self, hook: Hook) {
+        self.before_start = Some(hook);
+    }
+
+    /**
+     Run a hook after END_CAPTURE has been sent and the queue closed. No
+     samples are enqueued after this point, but a writer may still be
+     draining the queue. Errors from the hook are reported, not returned.
+     */
+    pub fn on_after_stop(&mut self, hook: Hook) {
+        self.after_stop = Some(hook);
+    }
+
+    /** Start the capture. The same as `prepare` followed by `trigger`. */
+    pub fn start(&mut self) -> Result<(), Ar2300Error> {
+        self.prepare()?;
+        self.trigger()
+    }
+
+    /**
+     Do the slow part of starting a capture: stop any capture left running
+     and, if a pre-start drain is set, submit the transfer and discard what
+     arrives. Call `trigger` afterwards to start capturing. If this fails,
+     the receiver is left stopped, with no transfer in flight, and can be
+     prepared again.
+     */
+    pub fn prepare(&mut self) -> Result<(), Ar2300Error> {
+        if self.shared.running.compare_exchange(false,
+                                                true,
+                                                Ordering::Acquire,
+                                                Ordering::Relaxed).is_ok() {
+            info!("IQ receiver starting");
+            match self.memory_plan().check() {
+                Ok(plan) => info!("Memory plan:\n{}", plan),
                 Err(e) => {
-                    eprintln!("Error stopping IQ capture: {}", e);
+                    self.abandon_start();
+                    return Err(e);
+                }
+            }
+            // A new capture needs its own stop, and skips its own startup transfers
+            self.stopped = false;
+            self.shared.skip_count.store(self.startup_skip_packets, Ordering::Relaxed);
+            if let Some(drain) = self.pre_start_drain {
+                if let Err(e) = self.port.write_bulk(self.control_endpoint,
+                                                       &END_CAPTURE,
+                                                       Duration::from_secs(1)) {
+                    emit_in(Some(self.shared.session), Level::Warn, format_args!("Error stopping previous IQ capture: {}", e));
+                }
+                if let Err(e) = self.drain(drain) {
+                    self.abandon_start();
+                    return Err(e);
                 }
             }
+            Ok(())
+        } else {
+            Err(Ar2300Error::AlreadyRunning)
         }
     }
-}
 
-impl Drop for Receiver {
-    fn drop(&mut self) {
-        self.stop();
+    /** Submit the transfer and discard what arrives for the given time. */
+    fn drain(&mut self, drain: Duration) -> Result<(), Ar2300Error> {
+        self.shared.draining.store(true, Ordering::Relaxed);
+        self.submit()?;
+        let started = Instant::now();
+        while started.elapsed() < drain {
+            self.port
+                .handle_events(Some(drain.saturating_sub(started.elapsed())))?;
+        }
+        self.shared.skip_count.store(self.startup_skip_packets, Ordering::Relaxed);
+        self.shared.draining.store(false, Ordering::Relaxed);
+        self.shared.startup.lock().unwrap().timings.drain = Some(started.elapsed());
+        Ok(())
     }
-}
 
-pub struct Writer {
-    queue: Queue<(f32,f32)>,
-    out: Box<dyn Write>,
-}
+    /**
+     Start capturing after `prepare`: run the before start hook and send
+     START_CAPTURE. When `prepare` already submitted the transfer this is
+     all that happens, so the first sample follows within a transfer or two.
 
-impl Writer {
-    pub fn new(queue: Queue<(f32,f32)>, out: Box<dyn Write>) -> Writer {
-        Writer {
-            queue: queue,
-            out: out,
+     With the default of one startup transfer skipped, the first retained
+     sample is in the second transfer to complete after START_CAPTURE. A
+     transfer holds about half a millisecond of samples, so on a quiet bus
+     the first sample is retained within a few milliseconds of calling this.
+     `StartupTimings::first_sample` reports the delay actually seen.
+
+     If this fails, the receiver is left stopped, as for `prepare`.
+     */
+    pub fn trigger(&mut self) -> Result<(), Ar2300Error> {
+        if !self.shared.running.load(Ordering::Relaxed) {
+            return Err(Ar2300Error::NotPrepared);
+        }
+        if let Err(e) = self.run_before_start().and_then(|_| self.send_start()) {
+            self.abandon_start();
+            return Err(e);
         }
+        let format = self.negotiate_format();
+        *self.shared.frame_format.lock().unwrap() = format;
+        if self.transfer.is_none() {
+            if let Err(e) = self.submit() {
+                self.abandon_start();
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 
-    pub fn queue(&self) -> Queue<(f32,f32)> {
-        self.queue.clone()
+    /** Undo a failed `prepare` or `trigger`, leaving the receiver stopped. */
+    fn abandon_start(&mut self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+        self.shared.draining.store(false, Ordering::Relaxed);
+        self.close_transfer();
     }
 
-    pub fn write(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
-        if let Some((i,q)) = self.queue.dequeue(timeout) {
-            self.out.write_f32::<BigEndian>(i)?;
-            self.out.write_f32::<BigEndian>(q)?;
+    /**
+     Cancel the transfer, if there is one, and wait for its last callback.
+     Returns false if it didn't come in time, in which case the transfer
+     leaks its callback and buffer, as libusb may still write into it.
+     */
+    fn close_transfer(&mut self) -> bool {
+        match self.transfer.take() {
+            Some(transfer) => transfer.close(TEARDOWN_TIMEOUT),
+            None => true
         }
-        Ok(())
     }
 
-    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
-        while !self.queue.is_empty() {
-            self.write(Duration::from_millis(50))?;
+    fn run_before_start(&mut self) -> Result<(), Ar2300Error> {
+        if let Some(hook) = self.before_start.as_mut() {
+            if let Err(e) = hook() {
+                return Err(Ar2300Error::HookFailed(format!("Before start hook failed: {}", e)));
+            }
         }
         Ok(())
     }
-}
 
-pub fn new_queue() -> Queue<(f32,f32)> {
-    Queue::new(BUFFER_LEN/8)
-}
\ No newline at end of file
+    fn send_start(&self) -> Result<(), Ar2300Error> {
+        // Start IQ capture
+        let started = Instant::now();
+        match self.port.write_bulk(self.control_endpoint,
+                                     &START_CAPTURE,
+                                     Duration::from_secs(1)) {
+            Ok(_) => {
+                let mut startup = self.shared.startup.lock().unwrap();
+                startup.start_sent = Some(Instant::now());
+                startup.timings.send_start = Some(started.elapsed());
+                Ok(())
+            },
+            Err(e) => {
+                emit_in(Some(self.shared.session), Level::Error, format_args!("Error starting IQ receiver: {}", e));
+                Err(e.into())
+            }
+        }
+    }
+
+    /**
+     Settle the frame format for the stream just started: the requested
+     one if the device offers it, otherwise the AR2300's own, with a
+     warning. The control protocol has no command to select a format yet,
+     so a real device only offers its own.
+     */
+    fn negotiate_format(&self) -> FrameFormat {
+        if self.port.offers_format(self.requested_format) {
+            self.requested_format
+        } else {
+            emit_in(Some(self.shared.session), Level::Warn, format_args!("The device can't be asked for the {} frame format, using {}",
+                                           self.requested_format.name, FrameFormat::AR2300.name));
+            FrameFormat::AR2300
+        }
+    }
+
+    /** The frame format the stream is decoded with. */
+    pub fn frame_format(&self) -> FrameFormat {
+        *self.shared.frame_format.lock().unwrap()
+    }
+
+    /**
+     True if the transfer was paused because the queue passed the high
+     watermark and hasn't been resumed yet.
+     */
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Relaxed)
+    }
+
+    /**
+     If the transfer is paused, wait up to `timeout` for the queue to fall
+     below the low watermark and resubmit it. Returns true if it resumed.
+     Nothing happens if the receiver isn't paused or has stopped.
+     */
+    pub fn resume(&mut self, timeout: Duration) -> Result<bool, Ar2300Error> {
+        let low = match self.watermarks {
+            Some(w) if self.is_paused() && self.shared.running.load(Ordering::Relaxed) => w.low,
+            _ => return Ok(false)
+        };
+        let below = match &self.block_queue {
+            Some(block_queue) => block_queue.wait_below(low, timeout),
+            None => self.queue.wait_below(low, timeout)
+        };
+        if !below || !self.shared.running.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        if !self.close_transfer() {
+            return Err(Ar2300Error::UsbError(rusb::Error::Timeout));
+        }
+        self.shared.paused.store(false, Ordering::Relaxed);
+        debug!("Queue below the low watermark; resuming the transfer");
+        self.submit()?;
+        Ok(true)
+    }
+
+    /**
+     Handle the device's USB events for up to `timeout`. This is what calls
+     the transfer's callback, so it must be called in a loop while
+     capturing.
+     */
+    pub(crate) fn handle_events(&self, timeout: Duration) -> Result<(), Ar2300Error> {
+        Ok(self.port.handle_events(Some(timeout))?)
+    }
+
+    /** The size of the buffer each transfer reads into. */
+    fn buffer_len(&self) -> usize {
+        self.packet_length * (self.packet_count + 1)
+    }
+
+    /** A callback for a new transfer, with the receiver's current outputs and settings. */
+    fn capture(&self) -> Box<Capture> {
+        Box::new(Capture {
+            buf: vec![0; self.buffer_len()],
+            shared: self.shared.clone(),
+            accounting: self.accounting.clone(),
+            queue: self.queue.clone(),
+            broadcaster: self.broadcaster.clone(),
+            block_queue: self.block_queue.clone(),
+            raw_queue: self.raw_queue.clone(),
+            rounding: self.rounding,
+            strictness: self.strictness,
+            max_overflows_per_sec: self.max_overflows_per_sec,
+            watermarks: self.watermarks,
+        })
+    }
+
+    fn submit(&mut self) -> Result<(), Ar2300Error> {
+        debug!("Submitting transfer request");
+        match self.port.submit(
+            self.data_endpoint,
+            self.packet_count,
+            self.packet_length,
+            self.capture()) {
+            Ok(transfer) => {
+                debug!("Transfer request submitted");
+                self.transfer = Some(transfer);
+                Ok(())
+            }
+            Err(e) => {
+                emit_in(Some(self.shared.session), Level::Error, format_args!("Error submitting transfer request: {}", e));
+                Err(e.into())
+            }
+        }
+    }
+
+    /** Stop the capture, closing the queue as `CloseReason::Finished`. */
+    pub fn stop(&mut self) {
+        self.stop_with(CloseReason::Finished);
+    }
+
+    /**
+     Stop the capture, closing the queue with the given reason. This also
+     cleans up after a receiver that stopped itself: the queue is closed
+     and END_CAPTURE sent exactly once. The transfer is cancelled and its
+     last callback waited for before this returns.
+     */
+    pub fn stop_with(&mut self, reason: CloseReason) {
+        let was_running = self.shared.running.swap(false, Ordering::AcqRel);
+        if !self.stopped && (was_running || self.transfer.is_some()) {
+            self.stopped = true;
+            info!("Stopping IQ receiver");
+           
+            if let Some(broadcaster) = &self.broadcaster {
+                broadcaster.close_with(reason.clone());
+            }
+            if let Some(block_queue) = &self.block_queue {
+                block_queue.close_with(reason.clone());
+            }
+            if let Some((raw_queue, _)) = &self.raw_queue {
+                raw_queue.close_with(reason.clone());
+            }
+            self.queue.close_with(reason);
+
+            // End IQ capture
+            match self.port.write_bulk(self.control_endpoint,
+                                    &END_CAPTURE,
+                                    Duration::from_secs(1)) {
+                Ok(_) => {}
+                Err(e) => {
+                    emit_in(Some(self.shared.session), Level::Error, format_args!("Error stopping IQ capture: {}", e));
+                }
+            }
+            // No callback can run once this returns
+            self.close_transfer();
+
+            if let Some(hook) = self.after_stop.as_mut() {
+                if let Err(e) = hook() {
+                    emit_in(Some(self.shared.session), Level::Error, format_args!("After stop hook failed: {}", e));
+                }
+            }
+        }
+    }
+}
+
+impl<C: UsbContext> Drop for Receiver<C> {
+    /**
+     Stops the capture and waits for the transfer's last callback, so no
+     callback can reach the receiver after it is gone.
+     */
+    fn drop(&mut self) {
+        self.stop();
+        self.close_transfer();
+    }
+}
+
+/** Statistics about the periodic syncs performed by a Writer. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncStats {
+    pub count: u64,
+    pub total: Duration,
+    pub slowest: Duration,
+}
+
+pub struct Writer {
+    queue: Queue<(f32,f32)>,
+    batch: Vec<(f32,f32)>,
+    output: Output,
+}
+
+/** The most samples the Writer takes from the queue at once. */
+const WRITE_BATCH: usize = 4096;
+
+impl Writer {
+    /** A writer producing big-endian f32 samples. */
+    pub fn new(queue: Queue<(f32,f32)>, out: Box<dyn Write>) -> Writer {
+        Writer::with_format(queue, out, SampleFormat::default())
+    }
+
+    pub fn with_format(queue: Queue<(f32,f32)>, out: Box<dyn Write>, format: SampleFormat) -> Writer {
+        Writer {
+            queue,
+            batch: Vec::with_capacity(WRITE_BATCH),
+            output: Output::new(out, format, WRITE_BATCH),
+        }
+    }
+
+    pub fn queue(&self) -> Queue<(f32,f32)> {
+        self.queue.clone()
+    }
+
+    pub fn format(&self) -> SampleFormat {
+        self.output.format
+    }
+
+    /**
+     Describe the recording in `filename` with a SigMF metadata file, written
+     now, next to it. The metadata file takes the recording's name with a
+     `.sigmf-data` extension replaced, or otherwise appended, by
+     `.sigmf-meta`. The current time is recorded as the capture's start, so
+     call this just before starting the receiver. Set the sample format
+     before calling this.
+     */
+    pub fn with_sigmf_metadata<P: AsRef<Path>>(self,
+                                               filename: P,
+                                               sample_rate: u32,
+                                               center_freq_hz: f64) -> Result<Writer, Ar2300Error> {
+        self.output.write_sigmf_metadata(filename.as_ref(), sample_rate, center_freq_hz)?;
+        Ok(self)
+    }
+
+    /** Sync the output each time this much time has passed since the last sync. */
+    pub fn set_sync_interval(&mut self, interval: Option<Duration>) {
+        self.output.sync_interval = interval;
+    }
+
+    /** Sync the output each time this many bytes have been written since the last sync. */
+    pub fn set_sync_bytes(&mut self, bytes: Option<u64>) {
+        self.output.sync_bytes = bytes;
+    }
+
+    /** Also call `sync_data` on this file when syncing. It should be the file behind the output. */
+    pub fn set_sync_file(&mut self, file: Option<File>) {
+        self.output.sync_file = file;
+    }
+
+    /** Record written samples in this ledger, normally the receiver's. See `SampleAccounting`. */
+    pub fn set_accounting(&mut self, accounting: Option<Arc<SampleAccounting>>) {
+        self.output.accounting = accounting;
+    }
+
+    /**
+     Record this session, normally the receiver's, in the SigMF metadata as
+     `ar2300:session`. Set it before calling `with_sigmf_metadata`.
+     */
+    pub fn set_session(&mut self, session: Option<SessionId>) {
+        self.output.extensions.session = session;
+    }
+
+    /**
+     Record this plan, normally the receiver's, in the SigMF metadata as
+     `ar2300:memory`. Set it before calling `with_sigmf_metadata`.
+     */
+    pub fn set_memory_plan(&mut self, plan: Option<MemoryPlan>) {
+        self.output.extensions.memory_plan = plan;
+    }
+
+    /**
+     Record what the bandwidth check found before the capture in the SigMF
+     metadata as `ar2300:bandwidth`. Set it before calling `with_sigmf_metadata`.
+     */
+    pub fn set_bandwidth_report(&mut self, report: Option<BandwidthReport>) {
+        self.output.extensions.bandwidth = report;
+    }
+
+    pub fn sync_stats(&self) -> SyncStats {
+        self.output.sync_stats
+    }
+
+    /**
+     Wait up to `timeout` for samples, then write everything available, up
+     to a batch, with a single write to the output. Returns false once the
+     queue is closed and every sample in it has been written.
+     */
+    pub fn write(&mut self, timeout: Duration) -> Result<bool, Ar2300Error> {
+        let result = self.write_batch(timeout)?;
+        if self.output.sync_due() {
+            self.output.sync()?;
+        }
+        Ok(result != DequeueResult::Closed)
+    }
+
+    /** Write whatever is in the queue without waiting for more, then sync. */
+    pub fn flush(&mut self) -> Result<(), Ar2300Error> {
+        while let DequeueResult::Item(_) = self.write_batch(Duration::ZERO)? {}
+        self.output.sync()
+    }
+
+    fn write_batch(&mut self, timeout: Duration) -> Result<DequeueResult<usize>, Ar2300Error> {
+        self.batch.clear();
+        let result = self.queue.drain_result(&mut self.batch, WRITE_BATCH, timeout);
+        self.output.encode(&self.batch);
+        self.output.write_encoded()?;
+        Ok(result)
+    }
+
+    /** Flush the output and, if a sync file is set, wait for its data to reach the disk. */
+    pub fn sync(&mut self) -> Result<(), Ar2300Error> {
+        self.output.sync()
+    }
+}
+
+/**
+ Writes sample blocks from a block queue, as filled by a Receiver with
+ `set_block_queue`. The output and its syncing work as for Writer; only
+ the queue differs.
+ */
+pub struct BlockWriter {
+    queue: Queue<SampleBlock>,
+    blocks: Vec<SampleBlock>,
+    output: Output,
+}
+
+/** The most blocks the BlockWriter takes from the queue at once. */
+const WRITE_BATCH_BLOCKS: usize = 16;
+
+impl BlockWriter {
+    /** A writer producing big-endian f32 samples. */
+    pub fn new(queue: Queue<SampleBlock>, out: Box<dyn Write>) -> BlockWriter {
+        BlockWriter::with_format(queue, out, SampleFormat::default())
+    }
+
+    pub fn with_format(queue: Queue<SampleBlock>, out: Box<dyn Write>, format: SampleFormat) -> BlockWriter {
+        BlockWriter {
+            queue,
+            blocks: Vec::with_capacity(WRITE_BATCH_BLOCKS),
+            output: Output::new(out, format, WRITE_BATCH),
+        }
+    }
+
+    pub fn queue(&self) -> Queue<SampleBlock> {
+        self.queue.clone()
+    }
+
+    pub fn format(&self) -> SampleFormat {
+        self.output.format
+    }
+
+    /** Like `Writer::with_sigmf_metadata`. */
+    pub fn with_sigmf_metadata<P: AsRef<Path>>(self,
+                                               filename: P,
+                                               sample_rate: u32,
+                                               center_freq_hz: f64) -> Result<BlockWriter, Ar2300Error> {
+        self.output.write_sigmf_metadata(filename.as_ref(), sample_rate, center_freq_hz)?;
+        Ok(self)
+    }
+
+    pub fn set_sync_interval(&mut self, interval: Option<Duration>) {
+        self.output.sync_interval = interval;
+    }
+
+    pub fn set_sync_bytes(&mut self, bytes: Option<u64>) {
+        self.output.sync_bytes = bytes;
+    }
+
+    /** Like `Writer::set_session`. */
+    pub fn set_session(&mut self, session: Option<SessionId>) {
+        self.output.extensions.session = session;
+    }
+
+    /** Like `Writer::set_memory_plan`. */
+    pub fn set_memory_plan(&mut self, plan: Option<MemoryPlan>) {
+        self.output.extensions.memory_plan = plan;
+    }
+
+    /** Like `Writer::set_bandwidth_report`. */
+    pub fn set_bandwidth_report(&mut self, report: Option<BandwidthReport>) {
+        self.output.extensions.bandwidth = report;
+    }
+
+    pub fn set_sync_file(&mut self, file: Option<File>) {
+        self.output.sync_file = file;
+    }
+
+    pub fn set_accounting(&mut self, accounting: Option<Arc<SampleAccounting>>) {
+        self.output.accounting = accounting;
+    }
+
+    pub fn sync_stats(&self) -> SyncStats {
+        self.output.sync_stats
+    }
+
+    /**
+     Wait up to `timeout` for blocks, then write every available one, up to
+     a batch, with a single write to the output. Returns false once the
+     queue is closed and every block in it has been written.
+     */
+    pub fn write(&mut self, timeout: Duration) -> Result<bool, Ar2300Error> {
+        let result = self.write_batch(timeout)?;
+        if self.output.sync_due() {
+            self.output.sync()?;
+        }
+        Ok(result != DequeueResult::Closed)
+    }
+
+    /** Write whatever is in the queue without waiting for more, then sync. */
+    pub fn flush(&mut self) -> Result<(), Ar2300Error> {
+        while let DequeueResult::Item(_) = self.write_batch(Duration::ZERO)? {}
+        self.output.sync()
+    }
+
+    fn write_batch(&mut self, timeout: Duration) -> Result<DequeueResult<usize>, Ar2300Error> {
+        self.blocks.clear();
+        let result = self.queue.drain_result(&mut self.blocks, WRITE_BATCH_BLOCKS, timeout);
+        for block in &self.blocks {
+            self.output.encode(&block.samples);
+        }
+        self.output.write_encoded()?;
+        Ok(result)
+    }
+
+    /** Flush the output and, if a sync file is set, wait for its data to reach the disk. */
+    pub fn sync(&mut self) -> Result<(), Ar2300Error> {
+        self.output.sync()
+    }
+}
+
+/** The encoding, output and syncing shared by Writer and BlockWriter. */
+struct Output {
+    format: SampleFormat,
+    out: Box<dyn Write>,
+    sync_file: Option<File>,
+    sync_interval: Option<Duration>,
+    sync_bytes: Option<u64>,
+    last_sync: Instant,
+    bytes_since_sync: u64,
+    sync_stats: SyncStats,
+    bytes: Vec<u8>,
+    /** Samples encoded into `bytes`. */
+    samples: u64,
+    accounting: Option<Arc<SampleAccounting>>,
+    extensions: SigmfExtensions,
+}
+
+impl Output {
+    fn new(out: Box<dyn Write>, format: SampleFormat, batch: usize) -> Output {
+        Output {
+            format,
+            out,
+            sync_file: None,
+            sync_interval: None,
+            sync_bytes: None,
+            last_sync: Instant::now(),
+            bytes_since_sync: 0,
+            sync_stats: SyncStats::default(),
+            bytes: Vec::with_capacity(batch * format.bytes_per_sample()),
+            samples: 0,
+            accounting: None,
+            extensions: SigmfExtensions::default(),
+        }
+    }
+
+    fn write_sigmf_metadata(&self, filename: &Path, sample_rate: u32, center_freq_hz: f64) -> Result<(), Ar2300Error> {
+        if !center_freq_hz.is_finite() {
+            return Err(Ar2300Error::InvalidConfig(format!("Invalid centre frequency: {}", center_freq_hz)));
+        }
+        let path = sigmf_meta_path(filename);
+        let metadata = sigmf_metadata(self.format, sample_rate, center_freq_hz, &self.extensions, SystemTime::now());
+        write_atomically(&path, metadata.as_bytes())?;
+        debug!("Wrote SigMF metadata to {}", path.display());
+        Ok(())
+    }
+
+    /** Encode samples, to be written by the next `write_encoded`. */
+    fn encode(&mut self, samples: &[(f32,f32)]) {
+        for sample in samples {
+            self.format.encode(*sample, &mut self.bytes);
+        }
+        self.samples += samples.len() as u64;
+    }
+
+    /** Write everything encoded since the last call with a single write. */
+    fn write_encoded(&mut self) -> Result<(), Ar2300Error> {
+        if self.bytes.is_empty() {
+            return Ok(());
+        }
+        let result = self.out.write_all(&self.bytes);
+        if let Some(accounting) = &self.accounting {
+            // Samples that couldn't be written are lost
+            match result {
+                Ok(_) => accounting.record_written(self.samples),
+                Err(_) => accounting.record_dropped(self.samples)
+            }
+        }
+        self.bytes_since_sync += self.bytes.len() as u64;
+        self.bytes.clear();
+        self.samples = 0;
+        Ok(result?)
+    }
+
+    fn sync(&mut self) -> Result<(), Ar2300Error> {
+        let started = Instant::now();
+        self.out.flush()?;
+        if let Some(file) = &self.sync_file {
+            file.sync_data()?;
+        }
+        let elapsed = started.elapsed();
+        self.sync_stats.count += 1;
+        self.sync_stats.total += elapsed;
+        if elapsed > self.sync_stats.slowest {
+            self.sync_stats.slowest = elapsed;
+        }
+        self.last_sync = Instant::now();
+        self.bytes_since_sync = 0;
+        Ok(())
+    }
+
+    fn sync_due(&self) -> bool {
+        let by_time = match self.sync_interval {
+            Some(interval) => self.last_sync.elapsed() >= interval,
+            None => false
+        };
+        let by_size = match self.sync_bytes {
+            Some(bytes) => self.bytes_since_sync >= bytes,
+            None => false
+        };
+        by_time || by_size
+    }
+}
+
+/** Where the SigMF metadata for a recording goes. */
+pub fn sigmf_meta_path(recording: &Path) -> PathBuf {
+    if recording.extension().is_some_and(|e| e == "sigmf-data") {
+        recording.with_extension("sigmf-meta")
+    } else {
+        let mut path = recording.as_os_str().to_owned();
+        path.push(".sigmf-meta");
+        PathBuf::from(path)
+    }
+}
+
+/**
+ Write a file so that it is either left as it was or whole, never half
+ written: the contents go to a temporary file next to it, which is synced
+ and then renamed into place.
+ */
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let result = File::create(&temporary)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .and_then(|_| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result?;
+    // The rename is only durable once the directory is synced
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/** What a recording's SigMF metadata says about how it was made, under `ar2300:` keys in its global object. */
+#[derive(Clone, Debug, Default)]
+struct SigmfExtensions {
+    session: Option<SessionId>,
+    memory_plan: Option<MemoryPlan>,
+    bandwidth: Option<BandwidthReport>,
+}
+
+impl SigmfExtensions {
+    /** The fields that are set, each preceded by a comma, to follow the global object's last core field. */
+    fn fields(&self) -> String {
+        let mut fields = String::new();
+        if let Some(session) = self.session {
+            fields.push_str(&format!(",\n    \"ar2300:session\": \"{}\"", session));
+        }
+        if let Some(plan) = &self.memory_plan {
+            fields.push_str(&format!(",\n    \"ar2300:memory\": {}", plan.to_json()));
+        }
+        if let Some(bandwidth) = &self.bandwidth {
+            fields.push_str(&format!(",\n    \"ar2300:bandwidth\": {}", bandwidth.to_json()));
+        }
+        fields
+    }
+}
+
+/**
+ A SigMF metadata document for a recording at the given centre frequency,
+ starting at `start`, with whatever `extensions` are known.
+ */
+fn sigmf_metadata(format: SampleFormat,
+                  sample_rate: u32,
+                  center_freq_hz: f64,
+                  extensions: &SigmfExtensions,
+                  start: SystemTime) -> String {
+    format!(r#"{{
+  "global": {{
+    "core:datatype": "{}",
+    "core:sample_rate": {},
+    "core:hw": "AOR AR2300",
+    "core:version": "1.0.0"{}
+  }},
+  "captures": [
+    {{
+      "core:sample_start": 0,
+      "core:frequency": {},
+      "core:datetime": "{}"
+    }}
+  ],
+  "annotations": []
+}}
+"#, format.sigmf_datatype(), sample_rate, extensions.fields(), center_freq_hz, format_time(start))
+}
+
+/** The size of the RIFF, fmt and data chunk headers at the start of a WAV file. */
+const WAV_HEADER_LEN: u64 = 44;
+
+/**
+ The sizes in the header of a WAV file still being written: unknown, so
+ readers that follow the convention read to the end of the file. A
+ finished header never claims this much.
+ */
+const WAV_STREAMING: u32 = u32::MAX;
+
+/** The bytes of one sample: 16 bit I and Q. */
+const WAV_BLOCK_ALIGN: u16 = 4;
+
+/**
+ Writes samples from a queue as a two channel, 16 bit PCM WAV file, with I
+ on the left channel and Q on the right, so recordings open in tools like
+ Audacity. The header is written up front marked as streaming, and its
+ sizes are patched on `flush` and when the writer is dropped. WAV sizes
+ are 32 bit, so past 4 GB the header is left claiming the largest size it
+ can hold.
+
+ The header is patched with a single write, after the data it describes
+ has been synced if a sync file is set, so a crash leaves it either still
+ streaming or claiming no more than is on disk. `repair_wav_header` fixes
+ the sizes of a file left streaming.
+ */
+pub struct WavWriter<W: Write + Seek> {
+    queue: Queue<(f32,f32)>,
+    out: W,
+    sample_rate: u32,
+    data_bytes: u64,
+    batch: Vec<(f32,f32)>,
+    bytes: Vec<u8>,
+    sync_file: Option<File>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /** A writer at the AR2300's IQ sample rate. */
+    pub fn new(queue: Queue<(f32,f32)>, out: W) -> Result<Self, Ar2300Error> {
+        WavWriter::with_sample_rate(queue, out, SAMPLE_RATE)
+    }
+
+    pub fn with_sample_rate(queue: Queue<(f32,f32)>, out: W, sample_rate: u32) -> Result<Self, Ar2300Error> {
+        let mut writer = WavWriter {
+            queue,
+            out,
+            sample_rate,
+            data_bytes: 0,
+            batch: Vec::with_capacity(WRITE_BATCH),
+            bytes: Vec::with_capacity(WRITE_BATCH * SampleFormat::LittleEndianI16.bytes_per_sample()),
+            sync_file: None,
+        };
+        writer.out.seek(SeekFrom::Start(0))?;
+        writer.out.write_all(&wav_header(sample_rate, WAV_STREAMING))?;
+        Ok(writer)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /**
+     Sync this file's data before and after patching the header. It should
+     be the file behind the output.
+     */
+    pub fn set_sync_file(&mut self, file: Option<File>) {
+        self.sync_file = file;
+    }
+
+    /**
+     Wait up to `timeout` for samples, then write everything available, up
+     to a batch. Returns false once the queue is closed and every sample in
+     it has been written.
+     */
+    pub fn write(&mut self, timeout: Duration) -> Result<bool, Ar2300Error> {
+        Ok(self.write_batch(timeout)? != DequeueResult::Closed)
+    }
+
+    /** Write what is left in the queue, then patch the header and flush the output. */
+    pub fn flush(&mut self) -> Result<(), Ar2300Error> {
+        while let DequeueResult::Item(_) = self.write_batch(Duration::ZERO)? {}
+        self.patch_header()?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, timeout: Duration) -> Result<DequeueResult<usize>, Ar2300Error> {
+        self.batch.clear();
+        let result = self.queue.drain_result(&mut self.batch, WRITE_BATCH, timeout);
+        if !self.batch.is_empty() {
+            self.bytes.clear();
+            for sample in &self.batch {
+                SampleFormat::LittleEndianI16.encode(*sample, &mut self.bytes);
+            }
+            self.out.write_all(&self.bytes)?;
+            self.data_bytes += self.bytes.len() as u64;
+        }
+        Ok(result)
+    }
+
+    /**
+     Rewrite the header with the current sizes, once the data is on disk,
+     leaving the output positioned at the end.
+     */
+    fn patch_header(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if let Some(file) = &self.sync_file {
+            file.sync_data()?;
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&wav_header(self.sample_rate, wav_data_len(self.data_bytes)))?;
+        self.out.flush()?;
+        if let Some(file) = &self.sync_file {
+            file.sync_data()?;
+        }
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+/** The size a WAV header gives for `data_bytes` of samples, clamped to what it can hold. */
+fn wav_data_len(data_bytes: u64) -> u32 {
+    data_bytes.min(u32::MAX as u64 - WAV_HEADER_LEN) as u32
+}
+
+/** The header of a two channel, 16 bit PCM WAV file holding `data_len` bytes of samples. */
+fn wav_header(sample_rate: u32, data_len: u32) -> [u8; WAV_HEADER_LEN as usize] {
+    let channels: u16 = 2;
+    let bits_per_sample: u16 = 16;
+    let mut header = [0; WAV_HEADER_LEN as usize];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&data_len.saturating_add(WAV_HEADER_LEN as u32 - 8).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&channels.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&(sample_rate * WAV_BLOCK_ALIGN as u32).to_le_bytes());
+    header[32..34].copy_from_slice(&WAV_BLOCK_ALIGN.to_le_bytes());
+    header[34..36].copy_from_slice(&bits_per_sample.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+/**
+ Fix the sizes in the header of a WAV file written by WavWriter that was
+ never finished, such as one left marked as streaming by a crash, from
+ the length of the file. A trailing partial sample is left out. Returns
+ true if the header was changed.
+ */
+pub fn repair_wav_header<F: Read + Write + Seek>(file: &mut F) -> Result<bool, Ar2300Error> {
+    let mut header = [0; WAV_HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" || &header[36..40] != b"data" {
+        return Err(Ar2300Error::DecodeFailed("Not a WAV file written by WavWriter".to_string()));
+    }
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let data_bytes = file_len - WAV_HEADER_LEN;
+    let data_len = wav_data_len(data_bytes - data_bytes % WAV_BLOCK_ALIGN as u64);
+    let sample_rate = LittleEndian::read_u32(&header[24..28]);
+    let repaired = wav_header(sample_rate, data_len);
+    if repaired == header {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&repaired)?;
+    file.flush()?;
+    Ok(true)
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.patch_header().and_then(|_| self.out.flush()) {
+            emit(Level::Error, format_args!("Couldn't finish WAV header: {}", e));
+        }
+    }
+}
+
+/**
+ A queue for the receiver's samples with the default capacity. One queue
+ gives each sample to a single consumer. To feed several consumers at
+ once, for example a disk writer and a live spectrum display, give the
+ receiver a Broadcaster and let each consumer subscribe to it:
+
+ ```no_run
+ use ar2300::prelude::*;
+ use std::time::Duration;
+
+ let broadcaster = Broadcaster::new(1 << 20);
+ // The writer keeps every sample it can; the display only wants recent ones.
+ let to_disk = broadcaster.subscribe();
+ let to_display = broadcaster.subscribe_with(1 << 14, OverflowPolicy::DropOldest);
+
+ let mut receiver = Receiver::new(iq_device().unwrap(), new_queue())?;
+ receiver.set_broadcaster(Some(broadcaster));
+
+ let writer = std::thread::spawn(move || {
+     let mut writer = Writer::new(to_disk, Box::new(std::io::sink()));
+     while writer.write(Duration::from_millis(100))? {}
+     writer.flush()
+ });
+ let display = std::thread::spawn(move || {
+     for block in to_display.iter_blocking(Duration::from_millis(100)) {
+         // Feed the spectrum display
+         let _ = block;
+     }
+ });
+
+ receiver.start()?;
+ // ... handle USB events until done, then:
+ receiver.stop();
+ writer.join().unwrap()?;
+ display.join().unwrap();
+ # Ok::<(), Ar2300Error>(())
+ ```
+ */
+pub fn new_queue() -> Queue<(f32,f32)> {
+    ReceiverConfig::default().new_queue()
+}
+
+/** A block queue for `Receiver::set_block_queue`, with the default capacity. */
+pub fn new_block_queue() -> Queue<SampleBlock> {
+    ReceiverConfig::default().new_block_queue()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::accounting::AccountingSummary;
+    use crate::codec::tests::{half_frame, HALF};
+    use crate::events::{Event, EventLog};
+    use crate::queue::OverflowPolicy;
+
+    /** A receiver on the fake device, with no pre-start drain. */
+    pub(crate) fn fake_receiver(device: &Arc<FakeDevice>, queue: Queue<(f32,f32)>) -> Receiver {
+        let config = ReceiverConfig { pre_start_drain: None, ..ReceiverConfig::default() };
+        Receiver::builder().config(config).build_fake(device.clone(), queue).unwrap()
+    }
+
+    /** The bytes a transfer's packets carry, with the default packet size and count. */
+    pub(crate) const TRANSFER_LEN: usize = PACKET_LENGTH * PACKET_COUNT;
+
+    /** A full transfer of valid frames, for a receiver with the default packet size and count. */
+    pub(crate) fn valid_transfer() -> Vec<u8> {
+        vec![0x01; TRANSFER_LEN]
+    }
+
+    /** The number of samples in `valid_transfer`. */
+    pub(crate) const SAMPLES_PER_TRANSFER: usize = TRANSFER_LEN / PACKET_SIZE;
+
+    /** Deliver every queued completion, letting the writer, if any, run after each. */
+    pub(crate) fn deliver_all(receiver: &Receiver, device: &FakeDevice, mut writer: Option<&mut Writer>) {
+        while device.pending() > 0 && device.is_active() {
+            receiver.handle_events(Duration::from_millis(1)).unwrap();
+            if let Some(writer) = writer.as_mut() {
+                writer.write(Duration::ZERO).unwrap();
+            }
+        }
+    }
+
+    /** An output that records what is written and counts flushes. */
+    #[derive(Clone, Default)]
+    pub(crate) struct CountingSink {
+        pub data: Arc<Mutex<Vec<u8>>>,
+        pub flushes: Arc<AtomicUsize>,
+    }
+
+    impl Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn enqueue_samples(queue: &Queue<(f32,f32)>, n: usize) {
+        queue.enqueue_all((0..n).map(|i| (i as f32, 0.0)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn writer_syncs_every_n_bytes() {
+        let queue = Queue::new(1 << 16);
+        let sink = CountingSink::default();
+        let mut writer = Writer::new(queue.clone(), Box::new(sink.clone()));
+        writer.set_sync_bytes(Some(100 * BYTES_PER_SAMPLE));
+        for round in 1..=5 {
+            enqueue_samples(&queue, 50);
+            assert!(writer.write(Duration::from_millis(10)).unwrap());
+            enqueue_samples(&queue, 50);
+            assert!(writer.write(Duration::from_millis(10)).unwrap());
+            // One sync per 100 samples
+            assert_eq!(writer.sync_stats().count, round);
+            assert_eq!(sink.flushes.load(Ordering::Relaxed), round as usize);
+        }
+        assert_eq!(sink.data.lock().unwrap().len() as u64, 500 * BYTES_PER_SAMPLE);
+    }
+
+    #[test]
+    fn writer_syncs_on_the_interval_while_idle() {
+        let queue = Queue::new(1 << 16);
+        let sink = CountingSink::default();
+        let mut writer = Writer::new(queue.clone(), Box::new(sink.clone()));
+        writer.set_sync_interval(Some(Duration::from_millis(20)));
+        assert!(writer.write(Duration::ZERO).unwrap());
+        assert_eq!(writer.sync_stats().count, 0);
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(writer.write(Duration::ZERO).unwrap());
+        assert_eq!(writer.sync_stats().count, 1);
+        // Not due again straight away
+        assert!(writer.write(Duration::ZERO).unwrap());
+        assert_eq!(writer.sync_stats().count, 1);
+        assert_eq!(sink.flushes.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn writer_does_not_sync_unless_asked() {
+        let queue = Queue::new(1 << 16);
+        let sink = CountingSink::default();
+        let mut writer = Writer::new(queue.clone(), Box::new(sink.clone()));
+        enqueue_samples(&queue, 1000);
+        assert!(writer.write(Duration::ZERO).unwrap());
+        assert_eq!(sink.flushes.load(Ordering::Relaxed), 0);
+        queue.close();
+        assert!(!writer.write(Duration::ZERO).unwrap());
+        writer.flush().unwrap();
+        assert_eq!(writer.sync_stats().count, 1);
+    }
+
+    #[test]
+    fn writer_syncs_a_real_file_to_disk() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let queue = Queue::new(1 << 16);
+        let mut writer = Writer::new(queue.clone(), Box::new(file.reopen().unwrap()));
+        writer.set_sync_file(Some(file.reopen().unwrap()));
+        writer.set_sync_bytes(Some(1));
+        enqueue_samples(&queue, 1000);
+        queue.close();
+        while writer.write(Duration::from_millis(10)).unwrap() {}
+        writer.flush().unwrap();
+        assert!(writer.sync_stats().count >= 2);
+        assert_eq!(std::fs::metadata(file.path()).unwrap().len(), 1000 * BYTES_PER_SAMPLE);
+    }
+
+    /**
+     Run a capture of the scripted completions into a queue, writing as it
+     goes if a writer is given, and return the reconciled ledger.
+     */
+    fn account_capture(device: &Arc<FakeDevice>, queue: Queue<(f32,f32)>,
+                       mut writer: Option<Writer>) -> AccountingSummary {
+        let mut receiver = fake_receiver(device, queue.clone());
+        if let Some(writer) = writer.as_mut() {
+            writer.set_accounting(Some(receiver.accounting()));
+        }
+        receiver.start().unwrap();
+        deliver_all(&receiver, device, writer.as_mut());
+        receiver.stop();
+        if let Some(writer) = writer.as_mut() {
+            while writer.write(Duration::ZERO).unwrap() {}
+            writer.flush().unwrap();
+        }
+        receiver.accounting().reconcile(queue.len() as u64)
+    }
+
+    fn writer_for(queue: &Queue<(f32,f32)>) -> Writer {
+        Writer::new(queue.clone(), Box::new(io::sink()))
+    }
+
+    #[test]
+    fn ledger_balances_for_a_clean_capture() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..10 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::new(1 << 16);
+        let summary = account_capture(&device, queue.clone(), Some(writer_for(&queue)));
+        // The first transfer is skipped
+        assert_eq!(summary.received, 9 * SAMPLES_PER_TRANSFER as u64);
+        assert_eq!(summary.written, summary.received);
+        assert_eq!(summary.dropped, 0);
+        assert!(summary.is_balanced());
+    }
+
+    #[test]
+    fn ledger_balances_when_the_queue_drops_new_samples() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..10 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::with_overflow_policy(1000, OverflowPolicy::DropNewest);
+        let summary = account_capture(&device, queue.clone(), None);
+        assert_eq!(summary.in_flight, 1000);
+        assert_eq!(summary.dropped, 9 * SAMPLES_PER_TRANSFER as u64 - 1000);
+        assert!(summary.is_balanced());
+    }
+
+    #[test]
+    fn ledger_balances_when_the_queue_evicts_old_samples() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..10 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::with_overflow_policy(1000, OverflowPolicy::DropOldest);
+        let summary = account_capture(&device, queue.clone(), None);
+        assert_eq!(summary.in_flight, 1000);
+        assert!(summary.dropped > 0);
+        assert!(summary.is_balanced());
+    }
+
+    #[test]
+    fn ledger_balances_through_usb_faults() {
+        let device = Arc::new(FakeDevice::new());
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Overflow), valid_transfer());
+        device.complete_ok(vec![0; TRANSFER_LEN]);
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Overflow), Vec::new());
+        device.complete_ok(valid_transfer());
+        let queue = Queue::new(1 << 16);
+        let summary = account_capture(&device, queue.clone(), Some(writer_for(&queue)));
+        // Only the three good transfers after the skipped one
+        assert_eq!(summary.received, 3 * SAMPLES_PER_TRANSFER as u64);
+        assert_eq!(summary.written, summary.received);
+        assert!(summary.is_balanced());
+    }
+
+    #[test]
+    fn ledger_balances_when_the_capture_stops_with_samples_queued() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..20 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let mut writer = writer_for(&queue);
+        writer.set_accounting(Some(receiver.accounting()));
+        receiver.start().unwrap();
+        deliver_all(&receiver, &device, None);
+        // Write one batch of what was received, then stop
+        writer.write(Duration::ZERO).unwrap();
+        receiver.stop();
+        let summary = receiver.accounting().reconcile(queue.len() as u64);
+        assert!(summary.written > 0 && summary.in_flight > 0);
+        assert!(summary.is_balanced());
+    }
+
+    /** An output that fails every write. */
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(ErrorKind::BrokenPipe, "broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ledger_counts_samples_lost_to_a_failed_write_as_dropped() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..3 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let mut writer = Writer::new(queue.clone(), Box::new(BrokenSink));
+        writer.set_accounting(Some(receiver.accounting()));
+        receiver.start().unwrap();
+        deliver_all(&receiver, &device, None);
+        assert!(writer.write(Duration::ZERO).is_err());
+        receiver.stop();
+        let summary = receiver.accounting().reconcile(queue.len() as u64);
+        assert_eq!(summary.written, 0);
+        assert!(summary.dropped > 0);
+        assert!(summary.is_balanced());
+    }
+
+    const RAW_TRANSFERS: &[u8] = include_bytes!("../testdata/raw_transfers.bin");
+
+    #[test]
+    fn decodes_the_length_prefixed_fixture() {
+        let mut out = Vec::new();
+        let report = decode_raw(&mut &RAW_TRANSFERS[..], RawFormat::LengthPrefixed, Rounding::Nearest, &mut out).unwrap();
+        assert_eq!(report.transfers, 4);
+        assert_eq!(report.bytes, 1024 + 1021 + 512 + 1024);
+        assert_eq!(report.samples, 128 + 127 + 127);
+        assert_eq!(report.unsynced_transfers, 1);
+        assert_eq!(report.skipped_bytes, 3 + 512);
+        assert_eq!(report.invalid_packets, 1);
+        assert_eq!(report.partial_bytes, 2);
+        assert_eq!(out.len() as u64, report.samples * BYTES_PER_SAMPLE);
+        let value = |n: usize| f32::from_be_bytes([out[n], out[n + 1], out[n + 2], out[n + 3]]).to_bits();
+        assert_eq!((value(0), value(4)), (0x3f00_0001, 0x3f0a_0a0b));
+        assert_eq!((value(out.len() - 8), value(out.len() - 4)), (0x3efd_e5f8, 0x3f08_fc05));
+    }
+
+    #[test]
+    fn decodes_the_fixture_as_a_flat_dump() {
+        // Without the prefixes, the transfers run together into one block
+        let mut flat = Vec::new();
+        let mut rest = RAW_TRANSFERS;
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            flat.extend_from_slice(&rest[4..4 + len]);
+            rest = &rest[4 + len..];
+        }
+        let mut out = Vec::new();
+        let report = decode_raw(&mut &flat[..], RawFormat::Flat, Rounding::Nearest, &mut out).unwrap();
+        assert_eq!(report.transfers, 1);
+        assert_eq!(report.bytes, flat.len() as u64);
+        assert_eq!(report.samples + report.invalid_packets, flat.len() as u64 / 8);
+    }
+
+    #[test]
+    fn rejects_a_truncated_container() {
+        let truncated = &RAW_TRANSFERS[..RAW_TRANSFERS.len() - 1];
+        let e = decode_raw(&mut &truncated[..], RawFormat::LengthPrefixed, Rounding::Nearest, &mut io::sink());
+        assert!(matches!(e, Err(Ar2300Error::DecodeFailed(_))));
+    }
+
+    /** A transfer whose frames are all valid apart from `invalid` of them. */
+    fn transfer_with_invalid(invalid: usize) -> Vec<u8> {
+        let mut data = valid_transfer();
+        for frame in 1..=invalid {
+            data[frame * PACKET_SIZE + 1] = 0;
+        }
+        data
+    }
+
+    /**
+     Start the receiver with the given strictness and deliver the scripted
+     completions.
+     */
+    fn run_with_strictness(receiver: &mut Receiver, device: &FakeDevice, strictness: Strictness) {
+        receiver.set_strictness(strictness);
+        receiver.start().unwrap();
+        deliver_all(receiver, device, None);
+    }
+
+    fn faulty_stream(device: &FakeDevice) {
+        // The first transfer is skipped at start-up
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        device.complete_ok(transfer_with_invalid(2));
+        device.complete_ok(vec![0; TRANSFER_LEN]);
+        device.complete_ok(valid_transfer());
+    }
+
+    const LIMITS: DecodeLimits = DecodeLimits {
+        window: Duration::from_secs(60),
+        unsynced_transfers: 0,
+        invalid_packets: 0,
+    };
+
+    #[test]
+    fn best_effort_decodes_everything_it_can() {
+        let device = Arc::new(FakeDevice::new());
+        faulty_stream(&device);
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        run_with_strictness(&mut receiver, &device, Strictness::BestEffort);
+        assert!(receiver.is_running()());
+        assert_eq!(receiver.decode_failure(), None);
+        let report = receiver.decode_report();
+        assert_eq!((report.invalid_packets, report.unsynced_transfers), (2, 1));
+        assert_eq!(queue.len(), 3 * SAMPLES_PER_TRANSFER - 2);
+    }
+
+    #[test]
+    fn warn_carries_on_past_the_limits() {
+        let device = Arc::new(FakeDevice::new());
+        faulty_stream(&device);
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        run_with_strictness(&mut receiver, &device, Strictness::Warn(LIMITS));
+        assert!(receiver.is_running()());
+        assert_eq!(receiver.decode_failure(), None);
+        assert_eq!(queue.len(), 3 * SAMPLES_PER_TRANSFER - 2);
+    }
+
+    #[test]
+    fn strict_stops_at_the_first_problem_and_discards_its_samples() {
+        let device = Arc::new(FakeDevice::new());
+        faulty_stream(&device);
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        run_with_strictness(&mut receiver, &device, Strictness::Strict(LIMITS));
+        assert!(!receiver.is_running()());
+        assert!(receiver.decode_failure().unwrap().contains("invalid packets"));
+        // Only the transfer before the invalid packets got through, and the transfer stopped
+        assert_eq!(queue.len(), SAMPLES_PER_TRANSFER);
+        assert!(!device.is_active());
+        receiver.stop_with(CloseReason::Error(receiver.failure().unwrap()));
+        match queue.close_reason() {
+            Some(CloseReason::Error(e)) => assert!(matches!(*e, Ar2300Error::DecodeFailed(_))),
+            reason => panic!("Unexpected close reason {:?}", reason)
+        }
+        assert_eq!(receiver.accounting().errors().len(), 1);
+    }
+
+    #[test]
+    fn strict_tolerates_problems_within_the_limits() {
+        let device = Arc::new(FakeDevice::new());
+        faulty_stream(&device);
+        let limits = DecodeLimits { unsynced_transfers: 1, invalid_packets: 2, ..LIMITS };
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        run_with_strictness(&mut receiver, &device, Strictness::Strict(limits));
+        assert!(receiver.is_running()());
+        assert_eq!(queue.len(), 3 * SAMPLES_PER_TRANSFER - 2);
+    }
+
+    fn draining_receiver(device: &Arc<FakeDevice>, queue: Queue<(f32,f32)>) -> Receiver {
+        let config = ReceiverConfig { pre_start_drain: Some(Duration::from_millis(5)), ..ReceiverConfig::default() };
+        Receiver::builder().config(config).build_fake(device.clone(), queue).unwrap()
+    }
+
+    #[test]
+    fn a_failed_drain_leaves_the_receiver_stopped() {
+        let device = Arc::new(FakeDevice::new());
+        let mut receiver = draining_receiver(&device, Queue::new(1 << 16));
+        device.fail_events.store(true, Ordering::Relaxed);
+        assert!(receiver.prepare().is_err());
+        assert!(!receiver.is_running()());
+        assert!(!device.is_active());
+        assert!(matches!(receiver.trigger(), Err(Ar2300Error::NotPrepared)));
+        // Nothing is left over to stop a second attempt
+        device.fail_events.store(false, Ordering::Relaxed);
+        receiver.start().unwrap();
+        assert!(receiver.is_running()());
+        assert!(device.is_active());
+        assert_eq!(device.submits.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn a_failed_submit_leaves_the_receiver_stopped() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = draining_receiver(&device, queue.clone());
+        device.fail_submit.store(true, Ordering::Relaxed);
+        assert!(receiver.prepare().is_err());
+        assert!(!receiver.is_running()());
+        device.fail_submit.store(false, Ordering::Relaxed);
+        receiver.start().unwrap();
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        // The draining flag was cleared, so the second transfer is kept
+        assert_eq!(queue.len(), SAMPLES_PER_TRANSFER);
+    }
+
+    #[test]
+    fn a_failed_trigger_leaves_the_receiver_stopped() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = draining_receiver(&device, queue.clone());
+        receiver.on_before_start(Box::new(|| Err("not ready".into())));
+        receiver.prepare().unwrap();
+        assert!(matches!(receiver.trigger(), Err(Ar2300Error::HookFailed(_))));
+        assert!(!receiver.is_running()());
+        assert!(!device.is_active());
+        assert!(!device.written().contains(&START_CAPTURE.to_vec()));
+        assert_eq!(queue.close_reason(), None);
+    }
+
+    #[test]
+    fn the_first_sample_follows_the_trigger_within_a_few_milliseconds() {
+        let device = Arc::new(FakeDevice::new());
+        let mut receiver = draining_receiver(&device, Queue::new(1 << 16));
+        receiver.prepare().unwrap();
+        assert!(receiver.startup_timings().drain.is_some());
+        // The device starts streaming as soon as it is told to
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        receiver.trigger().unwrap();
+        deliver_all(&receiver, &device, None);
+        // trigger sent START_CAPTURE and nothing else: the transfer was already submitted
+        assert_eq!(device.submits.load(Ordering::Relaxed), 1);
+        assert_eq!(device.written().last(), Some(&START_CAPTURE.to_vec()));
+        let timings = receiver.startup_timings();
+        assert!(timings.first_transfer.unwrap() <= timings.first_sample.unwrap());
+        assert!(timings.first_sample.unwrap() < Duration::from_millis(5), "{:?}", timings);
+    }
+
+    #[test]
+    fn stop_cancels_the_transfer() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        receiver.stop();
+        assert!(!device.is_active());
+        // Nothing more reaches the receiver
+        device.complete_ok(valid_transfer());
+        receiver.handle_events(Duration::from_millis(1)).unwrap();
+        assert_eq!(device.pending(), 1);
+        assert_eq!(receiver.packets_received(), 0);
+    }
+
+    #[test]
+    fn a_started_receiver_keeps_receiving_after_it_is_moved() {
+        fn started(device: &Arc<FakeDevice>, queue: Queue<(f32,f32)>) -> Receiver {
+            let mut receiver = fake_receiver(device, queue);
+            receiver.start().unwrap();
+            receiver
+        }
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        // Returned from a function, then moved onto the heap and into a Vec
+        let receiver = Box::new(started(&device, queue.clone()));
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        let mut receivers = vec![*receiver];
+        receivers.reserve(64);
+        let mut receiver = receivers.pop().unwrap();
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        assert_eq!(queue.len(), 2 * SAMPLES_PER_TRANSFER);
+        assert_eq!(receiver.accounting().received(), 2 * SAMPLES_PER_TRANSFER as u64);
+        receiver.stop();
+        assert!(!device.is_active());
+    }
+
+    #[test]
+    fn received_bytes_count_only_what_the_packets_carried() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        // The skipped transfer and a short one
+        device.complete_ok(vec![0x01; TRANSFER_LEN / 4]);
+        device.complete_ok(vec![0x01; TRANSFER_LEN / 2]);
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        assert_eq!(receiver.discarded_bytes(), (TRANSFER_LEN / 4) as u64);
+        assert_eq!(receiver.bytes_received(), (TRANSFER_LEN / 4 + TRANSFER_LEN / 2 + TRANSFER_LEN) as u64);
+        receiver.stop();
+    }
+
+    #[test]
+    fn only_packets_that_carried_data_are_counted() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        device.complete_ok(valid_transfer());
+        device.complete_packets(vec![Ok(vec![0x01; PACKET_LENGTH]), Err(rusb::Error::Io)]);
+        device.complete_packets(vec![Ok(vec![0x01; 8]), Ok(Vec::new())]);
+        device.complete_packets(vec![Err(rusb::Error::Overflow), Err(rusb::Error::Io)]);
+        deliver_all(&receiver, &device, None);
+        assert_eq!(receiver.packets_received(), PACKET_COUNT as u64 + 2);
+        assert_eq!(receiver.bytes_received(), (TRANSFER_LEN + PACKET_LENGTH + 8) as u64);
+        receiver.stop();
+    }
+
+    #[test]
+    fn raw_copies_hold_only_what_the_packets_received() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let raw_queue = Queue::new(16);
+        let pool = BufferPool::new(4, TRANSFER_LEN);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.set_raw_queue(Some((raw_queue.clone(), pool.clone())));
+        receiver.start().unwrap();
+        device.complete_ok(vec![0xee; TRANSFER_LEN]);
+        device.complete_ok(valid_transfer());
+        device.complete_packets(vec![Ok(vec![0x03; 16]), Ok(vec![0x05; PACKET_LENGTH])]);
+        device.complete_packets(vec![Err(rusb::Error::Io), Ok(vec![0x07; 8])]);
+        deliver_all(&receiver, &device, None);
+        let raw: Vec<Vec<u8>> = raw_queue.dequeue_batch(16, Duration::ZERO).iter().map(|b| b.to_vec()).collect();
+        let mut short = vec![0x03; 16];
+        short.extend_from_slice(&[0x05; PACKET_LENGTH]);
+        assert_eq!(raw, vec![valid_transfer(), short, vec![0x07; 8]]);
+        assert_eq!(pool.stats().outstanding, 0);
+        receiver.stop();
+    }
+
+    #[test]
+    fn stale_data_behind_short_and_failed_packets_is_not_decoded() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        // The skipped transfer leaves frames of 0x01 in every slot
+        device.complete_ok(valid_transfer());
+        let short = PACKET_LENGTH / 2;
+        device.complete_packets(vec![Ok(vec![0x03; short]), Ok(vec![0x03; PACKET_LENGTH])]);
+        device.complete_packets(vec![Err(rusb::Error::Io), Ok(vec![0x03; PACKET_LENGTH])]);
+        deliver_all(&receiver, &device, None);
+        let mut fresh = Vec::new();
+        decode(&[0x03; PACKET_SIZE], Rounding::default(), &mut fresh);
+        let samples = queue.dequeue_batch(1 << 16, Duration::ZERO);
+        assert_eq!(samples.len(), (short + 2 * PACKET_LENGTH) / PACKET_SIZE);
+        assert!(samples.iter().all(|s| *s == fresh[0]));
+        assert_eq!(receiver.decode_report().invalid_packets, 0);
+        receiver.stop();
+    }
+
+    #[test]
+    fn a_receiver_can_be_started_and_stopped_again() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let stops = Arc::new(AtomicU64::new(0));
+        let counter = stops.clone();
+        receiver.on_after_stop(Box::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }));
+        for run in 1..=2 {
+            receiver.start().unwrap();
+            assert!(receiver.is_running()());
+            assert!(device.is_active());
+            receiver.stop();
+            assert!(!receiver.is_running()());
+            assert!(!device.is_active());
+            // Each run sends its own END_CAPTURE and runs the hook once
+            let ends = device.written().iter().filter(|w| **w == END_CAPTURE.to_vec()).count();
+            assert_eq!(ends, run);
+            assert_eq!(stops.load(Ordering::Relaxed), run as u64);
+        }
+        assert_eq!(device.submits.load(Ordering::Relaxed), 2);
+        // A stopped receiver stays stopped
+        receiver.stop();
+        assert_eq!(stops.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn a_queue_can_be_cleared_and_reopened_between_runs() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        for run in 0..3 {
+            receiver.start().unwrap();
+            // The first transfer after START_CAPTURE is skipped
+            for _ in 0..3 {
+                device.complete_ok(valid_transfer());
+            }
+            deliver_all(&receiver, &device, None);
+            receiver.stop();
+            assert!(queue.is_closed(), "run {}", run);
+            // Only this run's samples are left, the last run's having been cleared
+            assert_eq!(queue.clear(), 2 * SAMPLES_PER_TRANSFER, "run {}", run);
+            queue.reopen();
+            assert!(!queue.is_closed());
+        }
+        assert_eq!(queue.stats().cleared, 6 * SAMPLES_PER_TRANSFER as u64);
+    }
+
+    /** A receiver sending its samples to a block queue instead of `queue`. */
+    fn block_receiver(device: &Arc<FakeDevice>, queue: &Queue<(f32,f32)>, blocks: &Queue<SampleBlock>) -> Receiver {
+        let mut receiver = fake_receiver(device, queue.clone());
+        receiver.set_block_queue(Some(blocks.clone()));
+        receiver
+    }
+
+    #[test]
+    fn each_transfer_becomes_one_numbered_block() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let blocks = new_block_queue();
+        let mut receiver = block_receiver(&device, &queue, &blocks);
+        receiver.start().unwrap();
+        // The first transfer after START_CAPTURE is skipped
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        for transfers in 1..=3u64 {
+            device.complete_ok(valid_transfer());
+            let before = blocks.lock_count();
+            deliver_all(&receiver, &device, None);
+            assert_eq!(blocks.lock_count() - before, 1);
+            assert_eq!(blocks.stats().enqueued, transfers);
+        }
+        receiver.stop();
+        let received: Vec<SampleBlock> = blocks.iter_blocking(Duration::from_millis(10)).collect();
+        assert_eq!(received.iter().map(|b| b.seq).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(received.iter().all(|b| b.samples.len() == SAMPLES_PER_TRANSFER));
+        // Nothing went to the per-sample queue, which is closed along with the blocks
+        assert!(queue.is_empty());
+        assert!(queue.is_closed());
+    }
+
+    #[test]
+    fn a_dropped_block_leaves_a_gap_in_the_sequence() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let blocks = Queue::with_overflow_policy(1, OverflowPolicy::DropNewest);
+        let mut receiver = block_receiver(&device, &queue, &blocks);
+        receiver.start().unwrap();
+        for _ in 0..4 {
+            device.complete_ok(valid_transfer());
+        }
+        deliver_all(&receiver, &device, None);
+        assert_eq!(blocks.dequeue(Duration::ZERO).map(|b| b.seq), Some(0));
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        assert_eq!(blocks.dequeue(Duration::ZERO).map(|b| b.seq), Some(3));
+        // Dropped blocks are counted in samples
+        assert_eq!(receiver.dropped_samples(), 2 * SAMPLES_PER_TRANSFER as u64);
+        receiver.stop();
+    }
+
+    #[test]
+    fn the_block_writer_writes_what_the_sample_writer_does() {
+        let capture = |use_blocks: bool| {
+            let device = Arc::new(FakeDevice::new());
+            let queue = Queue::new(1 << 16);
+            let blocks = new_block_queue();
+            let mut receiver = if use_blocks {
+                block_receiver(&device, &queue, &blocks)
+            } else {
+                fake_receiver(&device, queue.clone())
+            };
+            let mut rng = Lcg(7);
+            receiver.start().unwrap();
+            for _ in 0..5 {
+                // Random codes, each frame flagged as valid
+                device.complete_ok((0..TRANSFER_LEN).map(|_| rng.below(256) as u8 | 0x01).collect());
+            }
+            deliver_all(&receiver, &device, None);
+            receiver.stop();
+            let sink = CountingSink::default();
+            if use_blocks {
+                let mut writer = BlockWriter::with_format(blocks, Box::new(sink.clone()), SampleFormat::LittleEndianI16);
+                while writer.write(Duration::from_millis(10)).unwrap() {}
+                writer.flush().unwrap();
+            } else {
+                let mut writer = Writer::with_format(queue, Box::new(sink.clone()), SampleFormat::LittleEndianI16);
+                while writer.write(Duration::from_millis(10)).unwrap() {}
+                writer.flush().unwrap();
+            }
+            let data = sink.data.lock().unwrap();
+            data.clone()
+        };
+        let samples = capture(false);
+        assert_eq!(samples.len(), 4 * SAMPLES_PER_TRANSFER * SampleFormat::LittleEndianI16.bytes_per_sample());
+        assert_eq!(capture(true), samples);
+    }
+
+    #[test]
+    fn the_block_queue_holds_about_as_many_samples() {
+        let config = ReceiverConfig::default();
+        let blocks = config.new_block_queue().capacity() * SAMPLES_PER_TRANSFER;
+        let samples = config.new_queue().capacity();
+        assert!(blocks <= samples && blocks + SAMPLES_PER_TRANSFER > samples, "{} vs {}", blocks, samples);
+    }
+
+    #[test]
+    fn blocks_move_samples_faster_than_single_samples() {
+        const TRANSFERS: usize = 1_000;
+        let transfer: Vec<(f32,f32)> = (0..SAMPLES_PER_TRANSFER).map(|i| (i as f32, 0.0)).collect();
+
+        let samples = Queue::with_overflow_policy(1 << 16, OverflowPolicy::Block);
+        let consumer = {
+            let q = samples.clone();
+            std::thread::spawn(move || q.iter_blocking(Duration::from_millis(10)).count())
+        };
+        let started = Instant::now();
+        for _ in 0..TRANSFERS {
+            for &sample in &transfer {
+                samples.enqueue(sample);
+            }
+        }
+        samples.close();
+        assert_eq!(consumer.join().unwrap(), TRANSFERS * SAMPLES_PER_TRANSFER);
+        let per_sample = started.elapsed();
+
+        let blocks = Queue::with_overflow_policy(64, OverflowPolicy::Block);
+        let consumer = {
+            let q = blocks.clone();
+            std::thread::spawn(move || q.iter_blocking(Duration::from_millis(10)).map(|b: SampleBlock| b.samples.len()).sum::<usize>())
+        };
+        let started = Instant::now();
+        for seq in 0..TRANSFERS as u64 {
+            blocks.enqueue(SampleBlock { samples: transfer.clone(), seq });
+        }
+        blocks.close();
+        assert_eq!(consumer.join().unwrap(), TRANSFERS * SAMPLES_PER_TRANSFER);
+        let per_block = started.elapsed();
+
+        // One lock per transfer instead of one per sample
+        assert!(blocks.lock_count() * 100 < samples.lock_count(), "{} vs {}", blocks.lock_count(), samples.lock_count());
+        assert!(per_block < per_sample, "blocks took {:?}, samples {:?}", per_block, per_sample);
+    }
+
+    #[test]
+    fn a_transfer_is_queued_under_one_lock() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        // The first transfer after START_CAPTURE is skipped
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        for transfers in 1..=3 {
+            device.complete_ok(valid_transfer());
+            let before = queue.lock_count();
+            deliver_all(&receiver, &device, None);
+            assert_eq!(queue.lock_count() - before, 1);
+            assert_eq!(queue.len(), transfers * SAMPLES_PER_TRANSFER);
+        }
+    }
+
+    #[test]
+    fn another_frame_format_is_decoded_end_to_end() {
+        let device = Arc::new(FakeDevice::new());
+        device.extra_formats.lock().unwrap().push(HALF);
+        let queue = Queue::new(1 << 16);
+        let config = ReceiverConfig { pre_start_drain: None, frame_format: HALF, ..ReceiverConfig::default() };
+        let mut receiver = Receiver::builder().config(config).build_fake(device.clone(), queue.clone()).unwrap();
+        receiver.start().unwrap();
+        assert_eq!(receiver.frame_format(), HALF);
+        let transfer: Vec<u8> = (0..TRANSFER_LEN / 4).flat_map(|k| half_frame(k as u16, 1000)).collect();
+        device.complete_ok(transfer.clone());
+        device.complete_ok(transfer);
+        deliver_all(&receiver, &device, None);
+        // Twice as many samples per transfer as the AR2300's 8 byte frames give
+        assert_eq!(queue.len(), TRANSFER_LEN / 4);
+        let samples = queue.dequeue_batch(TRANSFER_LEN, Duration::ZERO);
+        assert_eq!(samples[3], (3.0 / 65536.0, 1000.0 / 32768.0));
+    }
+
+    #[test]
+    fn a_format_the_device_doesnt_offer_falls_back() {
+        let device = Arc::new(FakeDevice::new());
+        let config = ReceiverConfig { pre_start_drain: None, frame_format: HALF, ..ReceiverConfig::default() };
+        let mut receiver = Receiver::builder().config(config).build_fake(device.clone(), Queue::new(1 << 16)).unwrap();
+        receiver.start().unwrap();
+        assert_eq!(receiver.frame_format(), FrameFormat::AR2300);
+    }
+
+    #[test]
+    fn receiver_warnings_are_published_as_events() {
+        let events = EventLog::global().subscribe_with(1 << 16);
+        let device = Arc::new(FakeDevice::new());
+        let mut receiver = fake_receiver(&device, Queue::new(1 << 16));
+        receiver.start().unwrap();
+        device.complete(Err(rusb::Error::Overflow), Vec::new());
+        deliver_all(&receiver, &device, None);
+        let published: Vec<Event> = std::iter::from_fn(|| events.next(Duration::ZERO)).collect();
+        // Other tests may be publishing too
+        assert!(published.iter().any(|e| e.level == Level::Warn && e.message == "USB overflow, discarding transfer"));
+    }
+
+    /** A small deterministic generator, so a failure can be replayed. */
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    #[test]
+    fn receivers_can_be_dropped_with_completions_in_flight() {
+        let device = Arc::new(FakeDevice::new());
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..500 {
+            let queue = Queue::new(SAMPLES_PER_TRANSFER * 2);
+            let mut receiver = fake_receiver(&device, queue.clone());
+            let started = rng.below(4) > 0;
+            if started {
+                receiver.start().unwrap();
+            }
+            for _ in 0..rng.below(5) {
+                match rng.below(4) {
+                    0 => device.complete(Err(rusb::Error::Overflow), Vec::new()),
+                    1 => device.complete(Err(rusb::Error::Other), valid_transfer()),
+                    _ => device.complete_ok(valid_transfer())
+                }
+            }
+            for _ in 0..rng.below(4) {
+                receiver.handle_events(Duration::from_millis(1)).unwrap();
+            }
+            if rng.below(2) == 0 {
+                receiver.stop();
+            }
+            drop(receiver);
+            assert!(!device.is_active());
+            assert_eq!(queue.is_closed(), started);
+            // Completions still queued have no callback to reach
+            let pending = device.pending();
+            device.handle_events(Some(Duration::ZERO)).unwrap();
+            assert_eq!(device.pending(), pending);
+        }
+    }
+
+    #[test]
+    fn presets_have_their_documented_values() {
+        let default = ReceiverConfig::default();
+        assert_eq!((default.packet_count, default.queue_capacity, default.pre_start_drain, default.max_overflows_per_sec),
+                   (2, 1 << 20, Some(Duration::from_millis(100)), 10));
+        assert_eq!(default.strictness, Strictness::BestEffort);
+
+        let low_latency = ReceiverConfig::low_latency();
+        assert_eq!((low_latency.packet_count, low_latency.queue_capacity, low_latency.pre_start_drain),
+                   (1, 1 << 16, Some(Duration::from_millis(100))));
+        // About 60 ms of samples
+        assert_eq!(low_latency.queue_capacity as u32 * 1000 / SAMPLE_RATE, 58);
+
+        let robust = ReceiverConfig::robust();
+        assert_eq!((robust.packet_count, robust.queue_capacity, robust.pre_start_drain, robust.max_overflows_per_sec),
+                   (8, 1 << 22, Some(Duration::from_millis(250)), 100));
+        assert_eq!(robust.strictness, Strictness::Warn(DecodeLimits::default()));
+        // About four seconds of samples
+        assert_eq!(robust.queue_capacity as u32 / SAMPLE_RATE, 3);
+    }
+
+    #[test]
+    fn presets_are_found_by_name() {
+        for name in ReceiverConfig::PROFILES.iter() {
+            assert_eq!(ReceiverConfig::profile(name).unwrap().profile, *name);
+        }
+        assert_eq!(ReceiverConfig::profile("robust"), Some(ReceiverConfig::robust()));
+        assert_eq!(ReceiverConfig::profile("fast"), None);
+    }
+
+    #[test]
+    fn overriding_a_field_keeps_the_rest_of_the_preset() {
+        let config = ReceiverConfig { packet_count: 4, ..ReceiverConfig::robust() };
+        assert_eq!(config.packet_count, 4);
+        assert_eq!(ReceiverConfig { packet_count: 8, ..config }, ReceiverConfig::robust());
+        assert_eq!(ReceiverConfig { queue_capacity: 10, ..ReceiverConfig::low_latency() }.new_queue().capacity(), 10);
+    }
+
+    #[test]
+    fn builder_overrides_apply_on_top_of_the_preset() {
+        let device = Arc::new(FakeDevice::new());
+        let receiver = Receiver::builder()
+            .config(ReceiverConfig::robust())
+            .packet_count(3)
+            .build_fake(device.clone(), Queue::new(1))
+            .unwrap();
+        assert_eq!(receiver.packet_count, 3);
+        assert_eq!(receiver.buffer_len(), PACKET_LENGTH * 4);
+        assert_eq!(receiver.pre_start_drain, Some(Duration::from_millis(250)));
+        assert_eq!(receiver.max_overflows_per_sec, 100);
+
+        // A preset set afterwards replaces earlier overrides
+        let receiver = Receiver::builder()
+            .packet_count(3)
+            .config(ReceiverConfig::low_latency())
+            .build_fake(device, Queue::new(1))
+            .unwrap();
+        assert_eq!(receiver.packet_count, 1);
+    }
+
+    #[test]
+    fn overflows_are_discarded_and_the_transfer_resubmitted_with_a_full_queue() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(SAMPLES_PER_TRANSFER);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        // The first transfer is skipped, the second fills the queue
+        device.complete_ok(valid_transfer());
+        device.complete_ok(valid_transfer());
+        for _ in 0..2 {
+            device.complete(Err(rusb::Error::Overflow), Vec::new());
+            device.complete_ok(valid_transfer());
+        }
+        deliver_all(&receiver, &device, None);
+        assert_eq!(receiver.decode_report().overflows, 2);
+        assert!(receiver.is_running()());
+        assert!(device.is_active());
+        assert_eq!(receiver.decode_failure(), None);
+        assert_eq!(receiver.packets_received(), 4 * PACKET_COUNT as u64);
+        assert_eq!(queue.len(), SAMPLES_PER_TRANSFER);
+        assert_eq!(receiver.dropped_samples(), 2 * SAMPLES_PER_TRANSFER as u64);
+
+        // Once the consumer catches up, samples get through again
+        queue.clear();
+        device.complete_ok(valid_transfer());
+        deliver_all(&receiver, &device, None);
+        assert_eq!(queue.len(), SAMPLES_PER_TRANSFER);
+        assert_eq!(receiver.dropped_samples(), 2 * SAMPLES_PER_TRANSFER as u64);
+    }
+
+    #[test]
+    fn too_many_overflows_stop_the_capture() {
+        let device = Arc::new(FakeDevice::new());
+        let mut receiver = fake_receiver(&device, Queue::new(1 << 16));
+        receiver.start().unwrap();
+        for _ in 0..ReceiverConfig::default().max_overflows_per_sec + 1 {
+            device.complete(Err(rusb::Error::Overflow), Vec::new());
+        }
+        deliver_all(&receiver, &device, None);
+        assert!(!receiver.is_running()());
+        assert!(!device.is_active());
+        assert!(receiver.decode_failure().unwrap().contains("overflows per second"));
+    }
+
+    #[test]
+    fn sigmf_metadata_replaces_the_old_file_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("capture.sigmf-data");
+        let meta = sigmf_meta_path(&recording);
+        // Left half written by an earlier run
+        std::fs::write(&meta, "{ \"global\": {").unwrap();
+        Writer::new(Queue::new(16), Box::new(io::sink())).with_sigmf_metadata(&recording, SAMPLE_RATE, 100e6).unwrap();
+        let written = std::fs::read_to_string(&meta).unwrap();
+        assert!(written.trim_end().ends_with('}'), "{}", written);
+        assert_eq!(crate::timeline::sigmf_timing(&written).0, Some(SAMPLE_RATE as f64));
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![meta.file_name().unwrap().to_owned()]);
+    }
+
+    fn wav_sizes(wav: &[u8]) -> (u32, u32) {
+        (LittleEndian::read_u32(&wav[4..8]), LittleEndian::read_u32(&wav[40..44]))
+    }
+
+    #[test]
+    fn a_wav_header_is_streaming_until_patched() {
+        let queue = Queue::new(16);
+        let mut out = io::Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(queue.clone(), &mut out).unwrap();
+            queue.enqueue((0.5, 0.25));
+            queue.enqueue((0.5, 0.25));
+            writer.write(Duration::ZERO).unwrap();
+            std::mem::forget(writer);
+        }
+        // As a crash before flushing would leave it
+        let mut wav = out.into_inner();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(wav_sizes(&wav), (WAV_STREAMING, WAV_STREAMING));
+
+        let mut file = io::Cursor::new(&mut wav);
+        assert!(repair_wav_header(&mut file).unwrap());
+        assert_eq!(wav_sizes(&wav), (36 + 8, 8));
+        assert!(!repair_wav_header(&mut io::Cursor::new(&mut wav)).unwrap());
+
+        let queue = Queue::new(16);
+        let mut out = io::Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(queue.clone(), &mut out).unwrap();
+        queue.enqueue((0.5, 0.25));
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(wav_sizes(out.get_ref()), (36 + 4, 4));
+    }
+
+    #[test]
+    fn repairing_a_wav_header_drops_a_partial_sample() {
+        let mut wav = wav_header(SAMPLE_RATE, WAV_STREAMING).to_vec();
+        wav.extend_from_slice(&[1; 4 * 3 + 2]);
+        assert!(repair_wav_header(&mut io::Cursor::new(&mut wav)).unwrap());
+        assert_eq!(wav_sizes(&wav), (36 + 12, 12));
+        assert!(repair_wav_header(&mut io::Cursor::new(vec![0u8; 64])).is_err());
+    }
+}
@@ -17,16 +17,82 @@
     along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use iq::{Receiver, Writer};
-use queue::Queue;
-use rusb::{Device, GlobalContext, UsbContext};
-use simple_error::bail;
-use std::{error::Error, io::Write, thread::sleep, time::Duration};
+// The library reports through the log crate and never writes to the console
+#![deny(clippy::print_stdout, clippy::print_stderr)]
 
+use accounting::SampleAccounting;
+use cancel::CancelToken;
+pub use error::Ar2300Error;
+use iq::{Hook, Receiver, ReceiverConfig, Writer};
+use log::{debug, info, Level};
+use crate::events::emit;
+use queue::{CloseReason, Queue};
+use rusb::{Device, DeviceHandle, GlobalContext, UsbContext};
+use std::{fs::File, io::Write, path::Path, sync::Arc, time::Duration};
+
+/**
+ Finding, describing and claiming USB devices. These helpers are a stable
+ part of the API, for tools that need the device before handing it to a
+ Receiver; the isochronous transfers the Receiver runs on are internal.
+ */
 pub mod usb;
+/** A single ledger of every sample received, written and dropped. */
+pub mod accounting;
+/** Checking a capture's buffers against a memory budget. */
+pub mod budget;
+pub mod cancel;
+/** A machine-readable log of each capture, kept next to the recording. */
+pub mod capture_log;
+/** Wall-clock time for a capture that keeps going forward when the system clock is set. */
+pub mod clock;
+/**
+ Decoding of the raw AR2300 stream, with no USB or I/O. Usable on its own
+ by anything that has the raw bytes.
+ */
+pub mod codec;
+pub mod diagnostics;
+/** Signal processing helpers for working with captured samples. */
+pub mod dsp;
+pub mod error;
+/** The crate's warnings and errors, published for programs to show or record. */
+pub mod events;
+/** Scheduled faults for a simulated device. Needs the `fake-device` feature. */
+#[cfg(any(test, feature = "fake-device"))]
+pub mod fault;
 pub mod firmware;
+/** Conversions between samples and the formats they are stored in. */
+pub mod format;
+/**
+ Generic Cypress FX2LP bring-up, usable for any FX2-based board. Nothing
+ in it is specific to the AR2300.
+ */
+pub mod fx2;
+/** Process-wide state shared by everything in the process that uses the crate. */
+pub mod global;
 pub mod iq;
+/** Presentation of errors to users, kept apart from the errors themselves. */
+pub mod message;
+/** Reusable buffers, so the receive path doesn't allocate for every transfer. */
+pub mod pool;
+/** Working out what an IQ recording holds, from the file alone. */
+pub mod probe;
 pub mod queue;
+pub mod reblock;
+/** Session IDs, tying a capture's recording, log and events together. */
+pub mod session;
+/** Parsing of the device's replies to status queries. */
+pub mod status;
+pub mod timeline;
+
+/**
+ The supported public API. Everything re-exported here follows semantic
+ versioning; `use ar2300::prelude::*` is the intended way to use the crate.
+ Items reachable only through their modules are lower level and may change
+ more freely.
+ */
+pub mod prelude;
+#[cfg(unix)]
+pub mod fifo;
 
 /** Return the AR2300 IQ device. */
 pub fn iq_device() -> Option<Device<GlobalContext>> {
@@ -34,26 +100,94 @@ pub fn iq_device() -> Option<Device<GlobalContext>> {
 }
 
 /** Program the AR2300 firmware. */
-pub fn program(device: &Device<GlobalContext>) -> Result<usize, Box<dyn Error>> {
+pub fn program(device: &Device<GlobalContext>) -> Result<usize, Ar2300Error> {
     firmware::program(device)
 }
 
-pub fn init_device(load_firmware: bool) -> Result<(), Box<dyn Error>> {
-    match iq_device() {
-        Some(iq_device) => {
-            let device_info = crate::usb::device_info(&iq_device);
-            if load_firmware && !device_info.contains("AOR, LTD") {
-                println!("Writing firmware");
-                let bytes_written = program(&iq_device)?;
-                println!("Bytes written: {}", bytes_written);
-                sleep(Duration::from_secs(1));
-                init_device(false)?;
-            } else {
-                println!("IQ Device: {}", device_info);
+/**
+ Check that the device's RAM holds the AR2300 firmware. See
+ `firmware::verify`.
+ */
+pub fn verify_firmware<C: UsbContext>(handle: &DeviceHandle<C>) -> Result<bool, Ar2300Error> {
+    firmware::verify(handle, firmware::FIRMWARE_HEX)
+}
+
+/** How many times `init_device` tries to program the device. */
+const INIT_ATTEMPTS: usize = 3;
+
+/**
+ Find the IQ device and, if `load_firmware` is set and it is still in the
+ boot loader, program it. The firmware is read from `firmware_path` if
+ given, otherwise the embedded firmware is used. Programming is tried up
+ to three times. The error from the last attempt is returned, as
+ `Ar2300Error::FirmwareError` where the failure is specific to
+ programming.
+ */
+pub fn init_device(load_firmware: bool, firmware_path: Option<&Path>) -> Result<(), Ar2300Error> {
+    init_device_until(load_firmware, firmware_path, &CancelToken::new())
+}
+
+/**
+ Like `init_device`, but stops with `Ar2300Error::Cancelled` if the token is
+ cancelled, including while waiting for the device to re-enumerate.
+ */
+pub fn init_device_until(load_firmware: bool,
+                         firmware_path: Option<&Path>,
+                         cancel: &CancelToken) -> Result<(), Ar2300Error> {
+    // Read the file up front, so a bad path is reported before anything happens
+    let firmware = match firmware_path {
+        Some(path) if load_firmware => Some(firmware::read_firmware_file(path)?),
+        _ => None
+    };
+    let iq_device = bring_up(
+        cancel,
+        iq_device,
+        |device| load_firmware && firmware::check_bootloader(device).is_ok(),
+        |device| match &firmware {
+            Some(firmware) => firmware::program_hex_with(device, firmware, false, cancel),
+            None => firmware::program_with(device, false, cancel)
+        })?;
+    info!("IQ Device: {}", usb::device_info(&iq_device));
+    Ok(())
+}
+
+/**
+ The retry loop behind `init_device_until`: find the device and, while it
+ still needs programming, program it, up to `INIT_ATTEMPTS` times.
+ */
+fn bring_up<D, F, N, P>(cancel: &CancelToken,
+                        mut find: F,
+                        mut needs_programming: N,
+                        mut program: P) -> Result<D, Ar2300Error>
+    where F: FnMut() -> Option<D>, N: FnMut(&D) -> bool, P: FnMut(&D) -> Result<usize, Ar2300Error> {
+    let mut attempts = 0;
+    let mut last_error: Option<Ar2300Error> = None;
+    loop {
+        cancel.check()?;
+        let device = match find() {
+            Some(device) => device,
+            None => match last_error {
+                // It went away while being programmed
+                Some(e) => return Err(e),
+                None => return Err(Ar2300Error::DeviceNotFound)
             }
-            Ok(())
-        },
-        None => bail!("IQ Device Not Found")
+        };
+        if !needs_programming(&device) {
+            return Ok(device);
+        }
+        if attempts == INIT_ATTEMPTS {
+            return Err(last_error.unwrap_or_else(|| firmware::FirmwareError::StillUnprogrammed.into()));
+        }
+        attempts += 1;
+        info!("Writing firmware (attempt {} of {})", attempts, INIT_ATTEMPTS);
+        match program(&device) {
+            Ok(bytes_written) => info!("Bytes written: {}", bytes_written),
+            Err(Ar2300Error::Cancelled) => return Err(Ar2300Error::Cancelled),
+            Err(e) => {
+                emit(Level::Warn, format_args!("Attempt {} failed: {}", attempts, e));
+                last_error = Some(e);
+            }
+        }
     }
 }
 
@@ -61,33 +195,478 @@ pub fn new_queue() -> Queue<(f32,f32)> {
     iq::new_queue()
 }
 
-pub fn receive(queue: Queue<(f32,f32)>) -> Result<(), Box<dyn Error>> {
+pub fn receive(queue: Queue<(f32,f32)>) -> Result<(), Ar2300Error> {
+    receive_with_hooks(queue, None, None)
+}
+
+/**
+ Receive samples, running the given hooks around the capture. See
+ `Receiver::on_before_start`. Stops on Ctrl-C.
+ */
+pub fn receive_with_hooks(queue: Queue<(f32,f32)>,
+                          before_start: Option<Hook>,
+                          after_stop: Option<Hook>) -> Result<(), Ar2300Error> {
+    receive_until(queue, cancel::on_ctrlc()?, before_start, after_stop)
+}
+
+/**
+ Receive samples until the token is cancelled or the receiver stops.
+ Cancellation is noticed within 50 ms, the time spent waiting for USB
+ events on each pass of the loop. A token cancelled before the capture
+ starts stops it as soon as it has started.
+ */
+pub fn receive_until(queue: Queue<(f32,f32)>,
+                     cancel: CancelToken,
+                     before_start: Option<Hook>,
+                     after_stop: Option<Hook>) -> Result<(), Ar2300Error> {
+    receive_with_config(queue, cancel, ReceiverConfig::default(), before_start, after_stop)
+}
+
+/** Like `receive_until`, using the given receiver settings. */
+pub fn receive_with_config(queue: Queue<(f32,f32)>,
+                           cancel: CancelToken,
+                           config: ReceiverConfig,
+                           before_start: Option<Hook>,
+                           after_stop: Option<Hook>) -> Result<(), Ar2300Error> {
+    receive_with_accounting(queue, cancel, config, None, before_start, after_stop)
+}
+
+/**
+ Like `receive_with_config`, recording samples in the given ledger. Pass
+ the same ledger to `write_with_accounting` to account for every sample.
+ */
+pub fn receive_with_accounting(queue: Queue<(f32,f32)>,
+                               cancel: CancelToken,
+                               config: ReceiverConfig,
+                               accounting: Option<Arc<SampleAccounting>>,
+                               before_start: Option<Hook>,
+                               after_stop: Option<Hook>) -> Result<(), Ar2300Error> {
     if let Some(iq_device) = iq_device() {
-        let mut receiver = Receiver::new(iq_device, queue)?;
-        receiver.start()?;
-        let is_running= receiver.is_running();
-        ctrlc::set_handler(move || {
-            receiver.stop();
-        })?;
-        println!("IQ receiver started");
-        while is_running() {
-            GlobalContext::default().handle_events(Some(Duration::from_millis(50)))?;
+        info!("Receiver profile: {}, settings: {:?}", config.profile, config);
+        let mut receiver = Receiver::with_config(iq_device, queue, config)?;
+        if let Some(accounting) = accounting {
+            receiver.set_accounting(accounting);
+        }
+        if let Some(hook) = before_start {
+            receiver.on_before_start(hook);
         }
-        println!("IQ receiver stopped");
-        Ok(())
+        if let Some(hook) = after_stop {
+            receiver.on_after_stop(hook);
+        }
+        run_receiver(&mut receiver, &cancel)
     } else {
-        bail!("IQ Device Not Found")
+        Err(Ar2300Error::DeviceNotFound)
+    }
+}
+
+/**
+ Like `receive_with_accounting`, but from a simulated device following
+ `plan` instead of an AR2300. The capture finishes once the plan has been
+ delivered. The pre-start drain is skipped, as it would read the plan's
+ first transfers.
+ */
+#[cfg(any(test, feature = "fake-device"))]
+pub fn receive_fault_plan(queue: Queue<(f32,f32)>,
+                          cancel: CancelToken,
+                          config: ReceiverConfig,
+                          accounting: Option<Arc<SampleAccounting>>,
+                          before_start: Option<Hook>,
+                          after_stop: Option<Hook>,
+                          plan: &fault::FaultPlan) -> Result<(), Ar2300Error> {
+    let device = Arc::new(usb::fake::FakeDevice::new());
+    plan.apply(&device, config.packet_count)?;
+    let config = ReceiverConfig { pre_start_drain: None, ..config };
+    let mut receiver = Receiver::builder().config(config).build_fake(device.clone(), queue)?;
+    if let Some(accounting) = accounting {
+        receiver.set_accounting(accounting);
+    }
+    if let Some(hook) = before_start {
+        receiver.on_before_start(hook);
     }
+    if let Some(hook) = after_stop {
+        receiver.on_after_stop(hook);
+    }
+    run_receiver_while(&mut receiver, &cancel, || device.pending() > 0)
+}
+
+/**
+ Start the receiver and handle its events until the token is cancelled,
+ the receiver stops or the queue is closed, then stop it with the
+ matching reason.
+ */
+fn run_receiver<C: UsbContext>(receiver: &mut Receiver<C>, cancel: &CancelToken) -> Result<(), Ar2300Error> {
+    run_receiver_while(receiver, cancel, || true)
 }
 
-pub fn write(queue: Queue<(f32,f32)>, out: Box<dyn Write>) -> Result<(), Box<dyn Error>> {
+/** Like `run_receiver`, also finishing once `more` returns false. */
+fn run_receiver_while<C: UsbContext, M: Fn() -> bool>(receiver: &mut Receiver<C>,
+                                                      cancel: &CancelToken,
+                                                      more: M) -> Result<(), Ar2300Error> {
+    if let Err(e) = receiver.start() {
+        // Let the writer finish instead of waiting for samples that won't come.
+        let error = Arc::new(e.duplicate());
+        receiver.accounting().record_error(error.clone());
+        receiver.queue().close_with(CloseReason::Error(error));
+        return Err(e);
+    }
+    let is_running = receiver.is_running();
+    let q = receiver.queue();
+    info!("IQ receiver started. Frame format: {}", receiver.frame_format().name);
+    // The writer closes the queue if it fails
+    while is_running() && !cancel.is_cancelled() && !q.is_closed() && more() {
+        if receiver.is_paused() {
+            // Nothing is in flight, so wait on the queue instead of the device
+            receiver.resume(Duration::from_millis(50))?;
+        } else {
+            receiver.handle_events(Duration::from_millis(50))?;
+        }
+    }
+    let reason = if let Some(failure) = receiver.failure() {
+        CloseReason::Error(failure)
+    } else if cancel.is_cancelled() {
+        CloseReason::Cancelled
+    } else {
+        CloseReason::Finished
+    };
+    receiver.stop_with(reason);
+    info!("IQ receiver stopped. Discarded at start-up: {} bytes, dropped when the queue was full: {} samples",
+          receiver.discarded_bytes(), receiver.dropped_samples());
+    info!("Received: {} packets, {} bytes, {} samples",
+          receiver.packets_received(), receiver.bytes_received(), receiver.samples_enqueued());
+    info!("Queue high-water mark: {} of {} samples", q.high_water_mark(), q.capacity());
+    debug!("Startup timings: {:?}", receiver.startup_timings());
+    if let Some(failure) = receiver.decode_failure() {
+        return Err(Ar2300Error::DecodeFailed(format!("IQ capture aborted: {}", failure)));
+    }
+    Ok(())
+}
+
+const MAX_WRITER_WAIT: Duration = Duration::from_secs(1);
+
+pub fn write(queue: Queue<(f32,f32)>, out: Box<dyn Write>) -> Result<(), Ar2300Error> {
+    write_with_sync(queue, out, None, None)
+}
+
+/**
+ Write samples to the output, syncing it to disk every `sync_interval`.
+ The writer blocks until samples arrive and is woken when the receiver
+ closes the queue, so it returns as soon as the remaining samples are
+ written: with an idle stream, well within 50 ms of `Receiver::stop`. Its waits are capped at the sync interval, so periodic syncs
+ happen while the stream is idle, and at one second in case a wake-up is
+ missed.
+ */
+pub fn write_with_sync(queue: Queue<(f32,f32)>,
+                       out: Box<dyn Write>,
+                       sync_file: Option<File>,
+                       sync_interval: Option<Duration>) -> Result<(), Ar2300Error> {
+    write_with_accounting(queue, out, sync_file, sync_interval, None)
+}
+
+/** Like `write_with_sync`, recording written samples in the receiver's ledger. */
+pub fn write_with_accounting(queue: Queue<(f32,f32)>,
+                             out: Box<dyn Write>,
+                             sync_file: Option<File>,
+                             sync_interval: Option<Duration>,
+                             accounting: Option<Arc<SampleAccounting>>) -> Result<(), Ar2300Error> {
     let q = queue.clone();
     let mut writer = Writer::new(queue, out);
-    println!("Writer started");
-    while !q.is_closed() {
-        writer.write(Duration::from_millis(100))?;
+    writer.set_accounting(accounting.clone());
+    writer.set_sync_file(sync_file);
+    writer.set_sync_interval(sync_interval);
+    let timeout = sync_interval.map_or(MAX_WRITER_WAIT, |i| i.min(MAX_WRITER_WAIT));
+    info!("Writer started");
+    let mut result = Ok(true);
+    while let Ok(true) = result {
+        result = writer.write(timeout);
     }
-    writer.flush()?;
-    println!("Writer stopped");
-    Ok(())
-}
\ No newline at end of file
+    let result = result.and_then(|_| writer.flush());
+    if let Err(e) = &result {
+        // Tell the receiver to stop
+        let error = Arc::new(e.duplicate());
+        if let Some(accounting) = &accounting {
+            accounting.record_error(error.clone());
+        }
+        q.close_with(CloseReason::Error(error));
+    }
+    let stats = writer.sync_stats();
+    info!("Writer stopped. Syncs: {}, Slowest: {:?}, Close reason: {:?}",
+             stats.count, stats.slowest, q.close_reason());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq::{END_CAPTURE, START_CAPTURE};
+    use crate::iq::tests::{fake_receiver, valid_transfer, SAMPLES_PER_TRANSFER};
+    use crate::firmware::FirmwareError;
+    use crate::usb::fake::FakeDevice;
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    #[test]
+    fn cancelling_before_the_loop_starts_still_stops_the_receiver() {
+        let device = Arc::new(FakeDevice::new());
+        device.complete_ok(valid_transfer());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        run_receiver(&mut receiver, &cancel).unwrap();
+        assert_eq!(device.written(), vec![START_CAPTURE.to_vec(), END_CAPTURE.to_vec()]);
+        assert!(!receiver.is_running()());
+        assert_eq!(queue.close_reason(), Some(CloseReason::Cancelled));
+    }
+
+    #[test]
+    fn cancelling_during_the_capture_stops_the_receiver() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..3 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        run_receiver(&mut receiver, &cancel).unwrap();
+        handle.join().unwrap();
+        assert_eq!(device.written().last(), Some(&END_CAPTURE.to_vec()));
+        assert_eq!(queue.close_reason(), Some(CloseReason::Cancelled));
+    }
+
+    /** How long the writer thread takes to return after `stop`, with an idle stream. */
+    fn writer_shutdown_latency(sync_interval: Option<Duration>) -> Duration {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.start().unwrap();
+        let writer_queue = queue.clone();
+        let writer = std::thread::spawn(move || {
+            write_with_sync(writer_queue, Box::new(std::io::sink()), None, sync_interval).unwrap();
+            std::time::Instant::now()
+        });
+        // Long enough for the writer to be blocked waiting for samples
+        std::thread::sleep(Duration::from_millis(100));
+        let stopped = std::time::Instant::now();
+        receiver.stop();
+        writer.join().unwrap().duration_since(stopped)
+    }
+
+    #[test]
+    fn an_idle_writer_returns_promptly_after_stop() {
+        let latency = writer_shutdown_latency(None);
+        assert!(latency < Duration::from_millis(50), "{:?}", latency);
+    }
+
+    #[test]
+    fn an_idle_syncing_writer_returns_promptly_after_stop() {
+        let latency = writer_shutdown_latency(Some(Duration::from_secs(5)));
+        assert!(latency < Duration::from_millis(50), "{:?}", latency);
+    }
+
+    #[test]
+    fn cancelling_before_bring_up_stops_before_touching_the_bus() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(matches!(init_device_until(true, None, &cancel), Err(Ar2300Error::Cancelled)));
+    }
+
+    #[test]
+    fn cancelling_in_the_before_start_hook_stops_the_receiver() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        receiver.on_before_start(Box::new(move || {
+            canceller.cancel();
+            Ok(())
+        }));
+        run_receiver(&mut receiver, &cancel).unwrap();
+        assert_eq!(device.written(), vec![START_CAPTURE.to_vec(), END_CAPTURE.to_vec()]);
+        assert!(!device.is_active());
+        assert_eq!(queue.close_reason(), Some(CloseReason::Cancelled));
+    }
+
+    #[test]
+    fn cancelling_while_paused_stops_the_receiver() {
+        let device = Arc::new(FakeDevice::new());
+        for _ in 0..3 {
+            device.complete_ok(valid_transfer());
+        }
+        let queue = Queue::new(1 << 16);
+        let mut receiver = Receiver::builder()
+            .config(ReceiverConfig { pre_start_drain: None, ..ReceiverConfig::default() })
+            .watermarks(1, 0)
+            .build_fake(device.clone(), queue.clone())
+            .unwrap();
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        let is_running = receiver.is_running();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+        run_receiver(&mut receiver, &cancel).unwrap();
+        handle.join().unwrap();
+        assert!(!is_running());
+        // The queue was never drained, so the receiver was still paused when cancelled
+        assert!(receiver.is_paused());
+        assert!(!device.is_active());
+        assert_eq!(device.written().last(), Some(&END_CAPTURE.to_vec()));
+        assert_eq!(queue.close_reason(), Some(CloseReason::Cancelled));
+        // The queue isn't left locked
+        assert_eq!(queue.len(), SAMPLES_PER_TRANSFER);
+    }
+
+    /** The error the queue was closed with, which the ledger should also have recorded. */
+    fn close_error(queue: &Queue<(f32,f32)>, accounting: &SampleAccounting) -> Arc<Ar2300Error> {
+        let error = match queue.close_reason() {
+            Some(CloseReason::Error(error)) => error,
+            reason => panic!("Unexpected close reason {:?}", reason)
+        };
+        assert!(accounting.reconcile_closed(queue.len() as u64, queue.close_reason().as_ref()).agrees_with(&CloseReason::Error(error.clone())));
+        error
+    }
+
+    #[test]
+    fn a_failed_start_closes_the_queue_with_its_error() {
+        let device = Arc::new(FakeDevice::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        receiver.on_before_start(Box::new(|| Err("not ready".into())));
+        assert!(matches!(run_receiver(&mut receiver, &CancelToken::new()), Err(Ar2300Error::HookFailed(_))));
+        let error = close_error(&queue, &receiver.accounting());
+        assert!(matches!(*error, Ar2300Error::HookFailed(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn a_usb_error_closes_the_queue_with_its_error() {
+        let device = Arc::new(FakeDevice::new());
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Io), Vec::new());
+        let queue = Queue::new(1 << 16);
+        let mut receiver = fake_receiver(&device, queue.clone());
+        assert!(matches!(run_receiver(&mut receiver, &CancelToken::new()), Err(Ar2300Error::DecodeFailed(_))));
+        let error = close_error(&queue, &receiver.accounting());
+        assert!(matches!(*error, Ar2300Error::UsbError(rusb::Error::Io)), "{:?}", error);
+        assert!(Arc::ptr_eq(&error, &receiver.failure().unwrap()));
+    }
+
+    /** Output that fails every write. */
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_failed_writer_closes_the_queue_with_its_error() {
+        let queue = Queue::new(1 << 16);
+        let accounting = Arc::new(SampleAccounting::new());
+        accounting.record_received(1);
+        queue.enqueue((0.5, 0.5));
+        let result = write_with_accounting(queue.clone(), Box::new(Broken), None, None, Some(accounting.clone()));
+        assert!(matches!(result, Err(Ar2300Error::IoError(_))));
+        let error = close_error(&queue, &accounting);
+        assert!(matches!(&*error, Ar2300Error::IoError(e) if e.to_string() == "disk full"), "{:?}", error);
+    }
+
+    /** A board for `bring_up`: whether it is on the bus and whether it is programmed. */
+    #[derive(Default)]
+    struct Board {
+        present: Cell<bool>,
+        programmed: Cell<bool>,
+        attempts: Cell<usize>,
+    }
+
+    impl Board {
+        fn new() -> Board {
+            Board { present: Cell::new(true), ..Board::default() }
+        }
+
+        /** Run `bring_up`, programming the board with `program`. */
+        fn bring_up<P: FnMut(&Board) -> Result<usize, Ar2300Error>>(&self, mut program: P) -> Result<(), Ar2300Error> {
+            bring_up(
+                &CancelToken::new(),
+                || if self.present.get() { Some(()) } else { None },
+                |_| !self.programmed.get(),
+                |_| {
+                    self.attempts.set(self.attempts.get() + 1);
+                    program(self)
+                }).map(|_| ())
+        }
+    }
+
+    #[test]
+    fn a_programmed_board_is_left_alone() {
+        let board = Board::new();
+        board.programmed.set(true);
+        board.bring_up(|_| panic!("programmed again")).unwrap();
+    }
+
+    #[test]
+    fn a_missing_board_is_not_found() {
+        let board = Board::new();
+        board.present.set(false);
+        assert!(matches!(board.bring_up(|_| Ok(1)), Err(Ar2300Error::DeviceNotFound)));
+        assert_eq!(board.attempts.get(), 0);
+    }
+
+    #[test]
+    fn a_failed_attempt_is_retried() {
+        let board = Board::new();
+        board.bring_up(|board| {
+            if board.attempts.get() == 1 {
+                return Err(FirmwareError::ProgrammingFailed { reason: "pipe error".to_string() }.into());
+            }
+            board.programmed.set(true);
+            Ok(1)
+        }).unwrap();
+        assert_eq!(board.attempts.get(), 2);
+    }
+
+    #[test]
+    fn programming_that_keeps_failing_is_reported_after_the_last_attempt() {
+        let board = Board::new();
+        let result = board.bring_up(|_| Err(FirmwareError::ProgrammingFailed { reason: "pipe error".to_string() }.into()));
+        assert!(matches!(result, Err(Ar2300Error::FirmwareError(FirmwareError::ProgrammingFailed { .. }))));
+        assert_eq!(board.attempts.get(), INIT_ATTEMPTS);
+    }
+
+    #[test]
+    fn a_board_that_never_comes_back_reports_the_timeout() {
+        let board = Board::new();
+        let result = board.bring_up(|board| {
+            board.present.set(false);
+            Err(FirmwareError::RenumerationTimeout { waited: Duration::from_secs(5) }.into())
+        });
+        assert!(matches!(result, Err(Ar2300Error::FirmwareError(FirmwareError::RenumerationTimeout { .. }))));
+        assert_eq!(board.attempts.get(), 1);
+    }
+
+    #[test]
+    fn a_board_that_comes_back_unprogrammed_is_reported() {
+        let board = Board::new();
+        let result = board.bring_up(|_| Ok(1));
+        assert!(matches!(result, Err(Ar2300Error::FirmwareError(FirmwareError::StillUnprogrammed))));
+        assert_eq!(board.attempts.get(), INIT_ATTEMPTS);
+    }
+
+    #[test]
+    fn a_cancelled_attempt_is_not_retried() {
+        let board = Board::new();
+        assert!(matches!(board.bring_up(|_| Err(Ar2300Error::Cancelled)), Err(Ar2300Error::Cancelled)));
+        assert_eq!(board.attempts.get(), 1);
+    }
+}
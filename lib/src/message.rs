@@ -0,0 +1,174 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::budget::{format_bytes, MemoryPlan};
+use crate::error::Ar2300Error;
+use crate::firmware::FirmwareError;
+use crate::global::IncompatibleGlobalConfig;
+
+/**
+ Turns the crate's errors into text for the person running the program.
+ The errors carry their details as fields, so a renderer for another
+ language only has to supply the wording. The library's log messages are
+ for developers and aren't rendered through this.
+ */
+pub trait MessageRenderer {
+    fn error(&self, e: &Ar2300Error) -> String;
+    fn firmware_error(&self, e: &FirmwareError) -> String;
+    fn incompatible_global_config(&self, e: &IncompatibleGlobalConfig) -> String;
+    fn over_budget(&self, plan: &MemoryPlan) -> String;
+}
+
+/** The default renderer. The errors' `Display` implementations use it too. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnglishRenderer;
+
+impl MessageRenderer for EnglishRenderer {
+    fn error(&self, e: &Ar2300Error) -> String {
+        match e {
+            Ar2300Error::DeviceNotFound => "IQ Device Not Found".to_string(),
+            Ar2300Error::InterfaceUnavailable(reason) => reason.clone(),
+            Ar2300Error::UsbError(e) => format!("USB error: {}", e),
+            Ar2300Error::FirmwareError(e) => self.firmware_error(e),
+            Ar2300Error::Cancelled => "Cancelled".to_string(),
+            Ar2300Error::AlreadyRunning => "IQ receiver is already running".to_string(),
+            Ar2300Error::NotPrepared => "IQ receiver has not been prepared".to_string(),
+            Ar2300Error::InvalidConfig(reason) => format!("Invalid configuration: {}", reason),
+            Ar2300Error::HookFailed(reason) => reason.clone(),
+            Ar2300Error::DecodeFailed(reason) => reason.clone(),
+            Ar2300Error::IoError(e) => e.to_string(),
+            Ar2300Error::IncompatibleGlobalConfig(e) => self.incompatible_global_config(e),
+            Ar2300Error::OverBudget(plan) => self.over_budget(plan),
+            Ar2300Error::CtrlCError(e) => format!("Couldn't install the Ctrl-C handler: {}", e),
+        }
+    }
+
+    fn firmware_error(&self, e: &FirmwareError) -> String {
+        match e {
+            FirmwareError::NotInBootloader { current_state } =>
+                format!("Device is not in the boot loader: {}", current_state),
+            FirmwareError::ProgrammingFailed { reason } =>
+                format!("Programming failed: {}", reason),
+            FirmwareError::RenumerationTimeout { waited } =>
+                format!("Device was programmed but did not re-enumerate within {:?}", waited),
+            FirmwareError::StillUnprogrammed =>
+                "Device re-enumerated but still looks unprogrammed".to_string()
+        }
+    }
+
+    fn incompatible_global_config(&self, e: &IncompatibleGlobalConfig) -> String {
+        format!("Global setting {} is already {}, can't change it to {}", e.setting, e.current, e.requested)
+    }
+
+    fn over_budget(&self, plan: &MemoryPlan) -> String {
+        let budget = plan.budget.map_or(0, |b| b.bytes());
+        format!("The capture could need {} of memory, more than its budget of {}:\n{}",
+                format_bytes(plan.total()), format_bytes(budget), plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::{Allocation, MemoryBudget};
+    use std::io;
+    use std::time::Duration;
+
+    /** One of every variant, each paired with the field values its text must include. */
+    fn every_error() -> Vec<(Ar2300Error, Vec<&'static str>)> {
+        vec![
+            (Ar2300Error::DeviceNotFound, vec![]),
+            (Ar2300Error::InterfaceUnavailable("held by pid 4242".to_string()), vec!["4242"]),
+            (Ar2300Error::UsbError(rusb::Error::Busy), vec!["busy"]),
+            (FirmwareError::NotInBootloader { current_state: "already programmed".to_string() }.into(),
+             vec!["already programmed"]),
+            (FirmwareError::ProgrammingFailed { reason: "pipe error".to_string() }.into(), vec!["pipe error"]),
+            (FirmwareError::RenumerationTimeout { waited: Duration::from_secs(5) }.into(), vec!["5s"]),
+            (FirmwareError::StillUnprogrammed.into(), vec![]),
+            (Ar2300Error::Cancelled, vec![]),
+            (Ar2300Error::AlreadyRunning, vec![]),
+            (Ar2300Error::NotPrepared, vec![]),
+            (Ar2300Error::InvalidConfig("queue capacity 0".to_string()), vec!["queue capacity 0"]),
+            (Ar2300Error::HookFailed("'true' timed out".to_string()), vec!["'true' timed out"]),
+            (Ar2300Error::DecodeFailed("bad sync byte".to_string()), vec!["bad sync byte"]),
+            (io::Error::new(io::ErrorKind::WriteZero, "disk full").into(), vec!["disk full"]),
+            (IncompatibleGlobalConfig {
+                setting: "usb_log_level",
+                current: "Info".to_string(),
+                requested: "Debug".to_string()
+            }.into(), vec!["usb_log_level", "Info", "Debug"]),
+            (Ar2300Error::OverBudget(MemoryPlan {
+                budget: Some(MemoryBudget::new(1 << 20)),
+                allocations: vec![Allocation { name: "sample queue", count: 1 << 20, size: 8 }],
+            }), vec!["sample queue", "8.0 MiB", "1.0 MiB"]),
+        ]
+    }
+
+    #[test]
+    fn every_variant_renders_with_its_fields() {
+        for (e, fields) in every_error() {
+            let text = EnglishRenderer.error(&e);
+            assert!(!text.trim().is_empty(), "{:?} rendered empty", e);
+            for field in fields {
+                assert!(text.contains(field), "{:?} rendered as {:?}, without {:?}", e, text, field);
+            }
+        }
+    }
+
+    #[test]
+    fn display_uses_the_english_renderer() {
+        for (e, _) in every_error() {
+            assert_eq!(e.to_string(), EnglishRenderer.error(&e));
+        }
+    }
+
+    /** Shows that a renderer only supplies wording: this one just names the variant. */
+    struct TerseRenderer;
+
+    impl MessageRenderer for TerseRenderer {
+        fn error(&self, e: &Ar2300Error) -> String {
+            match e {
+                Ar2300Error::FirmwareError(e) => self.firmware_error(e),
+                e => format!("{:?}", e)
+            }
+        }
+
+        fn firmware_error(&self, e: &FirmwareError) -> String {
+            format!("firmware: {:?}", e)
+        }
+
+        fn incompatible_global_config(&self, e: &IncompatibleGlobalConfig) -> String {
+            format!("{:?}", e)
+        }
+
+        fn over_budget(&self, plan: &MemoryPlan) -> String {
+            format!("{:?}", plan)
+        }
+    }
+
+    #[test]
+    fn renderers_can_be_swapped() {
+        let renderers: Vec<Box<dyn MessageRenderer>> = vec![Box::new(EnglishRenderer), Box::new(TerseRenderer)];
+        let e: Ar2300Error = FirmwareError::StillUnprogrammed.into();
+        let texts: Vec<String> = renderers.iter().map(|r| r.error(&e)).collect();
+        assert_eq!(texts[1], "firmware: StillUnprogrammed");
+        assert_ne!(texts[0], texts[1]);
+    }
+}
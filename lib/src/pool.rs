@@ -0,0 +1,287 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/**
+ A pool of byte buffers that are reused instead of allocated for every
+ transfer. `get` hands out a PooledBuf, which goes back to the pool when
+ it is dropped, wherever that happens. The pool keeps at most `size` idle
+ buffers; any returned beyond that are freed, so it never grows past its
+ configured size however many are checked out at once.
+ */
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    free: Mutex<Vec<Vec<u8>>>,
+    size: usize,
+    buf_len: usize,
+    outstanding: AtomicUsize,
+    allocated: AtomicU64,
+    recycled: AtomicU64,
+}
+
+/** A snapshot of a pool's counters. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /** Buffers checked out and not yet returned. A steady rise means a leak. */
+    pub outstanding: usize,
+    /** Buffers waiting in the pool. Never more than its size. */
+    pub idle: usize,
+    /** Buffers handed out again rather than allocated. */
+    pub recycled: u64,
+    /** Buffers allocated, including those the pool started with. */
+    pub allocated: u64,
+}
+
+impl BufferPool {
+    /** Create a pool holding `size` buffers, each with room for `buf_len` bytes. */
+    pub fn new(size: usize, buf_len: usize) -> Self {
+        let free = (0..size).map(|_| Vec::with_capacity(buf_len)).collect();
+        BufferPool {
+            inner: Arc::new(PoolInner {
+                free: Mutex::new(free),
+                size,
+                buf_len,
+                outstanding: AtomicUsize::new(0),
+                allocated: AtomicU64::new(size as u64),
+                recycled: AtomicU64::new(0),
+            })
+        }
+    }
+
+    /** The most idle buffers the pool keeps. */
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    /** The capacity each new buffer is allocated with. */
+    pub fn buf_len(&self) -> usize {
+        self.inner.buf_len
+    }
+
+    /**
+     Take an empty buffer from the pool, allocating one if none are idle.
+     It returns to the pool when dropped.
+     */
+    pub fn get(&self) -> PooledBuf {
+        let buf = match self.inner.free.lock().unwrap().pop() {
+            Some(buf) => {
+                self.inner.recycled.fetch_add(1, Ordering::Relaxed);
+                buf
+            },
+            None => {
+                self.inner.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(self.inner.buf_len)
+            }
+        };
+        self.inner.outstanding.fetch_add(1, Ordering::Relaxed);
+        PooledBuf { buf, pool: self.inner.clone() }
+    }
+
+    /** A snapshot of the pool's counters. */
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            outstanding: self.inner.outstanding.load(Ordering::Relaxed),
+            idle: self.inner.free.lock().unwrap().len(),
+            recycled: self.inner.recycled.load(Ordering::Relaxed),
+            allocated: self.inner.allocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/** A buffer checked out of a BufferPool. Derefs to the `Vec<u8>`. */
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    /** Empty the buffer and return it to the pool, or free it if the pool is full. */
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        self.pool.outstanding.fetch_sub(1, Ordering::Relaxed);
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.size {
+            free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{OverflowPolicy, Queue};
+    use std::time::{Duration, Instant};
+
+    /** About the size of one transfer. */
+    const BUF_LEN: usize = 4608;
+    const CYCLES: usize = 10_000;
+
+    #[test]
+    fn ten_thousand_cycles_never_grow_the_pool() {
+        let pool = BufferPool::new(4, BUF_LEN);
+        let data = vec![0x5a; BUF_LEN];
+        let (mut allocated, mut recycled) = (4, 0);
+        for cycle in 0..CYCLES {
+            // Sometimes more at once than the pool holds
+            let n = 1 + cycle % 6;
+            let bufs: Vec<PooledBuf> = (0..n).map(|_| {
+                let mut buf = pool.get();
+                assert!(buf.is_empty());
+                buf.extend_from_slice(&data);
+                buf
+            }).collect();
+            assert_eq!(pool.stats().outstanding, n);
+            drop(bufs);
+            let stats = pool.stats();
+            assert_eq!(stats.outstanding, 0);
+            assert!(stats.idle <= pool.size(), "{:?}", stats);
+            allocated += n.saturating_sub(4) as u64;
+            recycled += n.min(4) as u64;
+        }
+        let stats = pool.stats();
+        assert_eq!(stats, PoolStats { outstanding: 0, idle: 4, recycled, allocated });
+    }
+
+    #[test]
+    fn a_buffer_that_is_never_returned_shows_as_outstanding() {
+        let pool = BufferPool::new(2, BUF_LEN);
+        let mut leaked = Vec::new();
+        for _ in 0..CYCLES {
+            let buf = pool.get();
+            if leaked.len() < 3 {
+                leaked.push(buf);
+            }
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.outstanding, 3);
+        assert!(stats.idle <= pool.size());
+        drop(leaked);
+        assert_eq!(pool.stats().outstanding, 0);
+        assert_eq!(pool.stats().idle, 2);
+    }
+
+    #[test]
+    fn buffers_dropped_by_a_consumer_go_back_to_the_pool() {
+        // Room for a full queue and a buffer at each end
+        let pool = BufferPool::new(32, BUF_LEN);
+        let queue = Queue::with_overflow_policy(16, OverflowPolicy::Block);
+        let consumer = {
+            let (queue, pool) = (queue.clone(), pool.clone());
+            std::thread::spawn(move || {
+                let mut received = 0;
+                for buf in queue.iter_blocking(Duration::from_millis(10)) {
+                    let buf: PooledBuf = buf;
+                    assert_eq!(buf.len(), BUF_LEN);
+                    drop(buf);
+                    assert!(pool.stats().idle <= pool.size());
+                    received += 1;
+                }
+                received
+            })
+        };
+        let data = vec![0x5a; BUF_LEN];
+        for _ in 0..CYCLES {
+            let mut buf = pool.get();
+            buf.extend_from_slice(&data);
+            queue.enqueue(buf);
+        }
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), CYCLES);
+        let stats = pool.stats();
+        assert_eq!(stats.outstanding, 0);
+        assert!(stats.idle <= pool.size());
+        // Every buffer came from the pool
+        assert_eq!(stats.allocated, pool.size() as u64, "{:?}", stats);
+        assert_eq!(stats.recycled, CYCLES as u64);
+    }
+
+    #[test]
+    fn pooled_buffers_keep_up_with_allocating() {
+        const ROUNDS: usize = 5;
+        let data = vec![0x5a; BUF_LEN];
+
+        let allocate = || {
+            let queue = Queue::with_overflow_policy(16, OverflowPolicy::Block);
+            let consumer = {
+                let queue = queue.clone();
+                std::thread::spawn(move || queue.iter_blocking(Duration::from_millis(10)).map(|b: Vec<u8>| b.len()).sum::<usize>())
+            };
+            let started = Instant::now();
+            for _ in 0..CYCLES {
+                let mut buf = Vec::with_capacity(BUF_LEN);
+                buf.extend_from_slice(&data);
+                queue.enqueue(buf);
+            }
+            queue.close();
+            assert_eq!(consumer.join().unwrap(), CYCLES * BUF_LEN);
+            started.elapsed()
+        };
+
+        let pool = BufferPool::new(32, BUF_LEN);
+        let pooled = || {
+            let queue = Queue::with_overflow_policy(16, OverflowPolicy::Block);
+            let consumer = {
+                let queue = queue.clone();
+                std::thread::spawn(move || queue.iter_blocking(Duration::from_millis(10)).map(|b: PooledBuf| b.len()).sum::<usize>())
+            };
+            let started = Instant::now();
+            for _ in 0..CYCLES {
+                let mut buf = pool.get();
+                buf.extend_from_slice(&data);
+                queue.enqueue(buf);
+            }
+            queue.close();
+            assert_eq!(consumer.join().unwrap(), CYCLES * BUF_LEN);
+            started.elapsed()
+        };
+
+        // The best of a few rounds each, to keep scheduling noise out
+        let by_allocation = (0..ROUNDS).map(|_| allocate()).min().unwrap();
+        let by_pool = (0..ROUNDS).map(|_| pooled()).min().unwrap();
+        // The saving is in allocations, not time; a warm allocator is about
+        // as fast, so this only catches the pool becoming a bottleneck
+        assert!(by_pool < by_allocation * 2, "pooled took {:?}, allocating {:?}", by_pool, by_allocation);
+        let stats = pool.stats();
+        assert_eq!(stats.outstanding, 0);
+        assert_eq!(stats.allocated, pool.size() as u64);
+        assert_eq!(stats.recycled, (ROUNDS * CYCLES) as u64);
+    }
+}
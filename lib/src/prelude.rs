@@ -0,0 +1,237 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub use crate::accounting::{AccountingSummary, SampleAccounting};
+pub use crate::budget::{Allocation, MemoryBudget, MemoryPlan};
+pub use crate::cancel::CancelToken;
+pub use crate::error::Ar2300Error;
+pub use crate::events::{DroppedEvents, Event, EventLog, EventSubscriber};
+pub use crate::iq::{BlockWriter, DecodeLimits, DecodeReport, FrameFormat, Hook, RawFormat, Receiver, ReceiverBuilder, ReceiverConfig, Rounding, SampleBlock, SampleFormat, StartupTimings, Strictness, SyncStats, WavWriter, Watermarks, Writer};
+pub use crate::message::{EnglishRenderer, MessageRenderer};
+pub use crate::pool::{BufferPool, PoolStats, PooledBuf};
+pub use crate::queue::{BlockingIter, Broadcaster, CloseReason, DequeueResult, EnqueueResult, OverflowPolicy, PeekGuard, Queue, QueueStats};
+pub use crate::reblock::{Block, Reblocker};
+pub use crate::session::SessionId;
+pub use crate::{init_device, init_device_until, iq_device, new_queue, receive, receive_until, receive_with_config, write};
+
+/**
+ The public API is checked against a snapshot, `testdata/public-api.txt`,
+ so that anything added to it, or taken out, is a deliberate change. After
+ changing it on purpose, update the snapshot with
+ `UPDATE_PUBLIC_API=1 cargo test --lib prelude` and commit the result.
+ */
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::{Path, PathBuf};
+
+    const SNAPSHOT: &str = "testdata/public-api.txt";
+
+    /** An open item: a module, a type, a trait or an impl, with the indent of its closing brace. */
+    struct Scope {
+        indent: usize,
+        path: String,
+        public: bool,
+        kind: &'static str,
+    }
+
+    /** The identifier at the start of `s`. */
+    fn ident(s: &str) -> &str {
+        let end = s.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(s.len());
+        &s[..end]
+    }
+
+    /** The type an impl line is for: the one after `for`, or else the one after `impl` and its generics. */
+    fn impl_owner(line: &str) -> String {
+        let mut rest = &line["impl".len()..];
+        if rest.starts_with('<') {
+            let mut depth = 0;
+            let end = rest.char_indices().find(|&(_, c)| {
+                depth += match c { '<' => 1, '>' => -1, _ => 0 };
+                depth == 0
+            }).map_or(rest.len(), |(i, _)| i + 1);
+            rest = &rest[end..];
+        }
+        let rest = match rest.find(" for ") {
+            Some(i) => &rest[i + " for ".len()..],
+            None => rest,
+        };
+        ident(rest.trim_start()).to_string()
+    }
+
+    /**
+     The public items declared in one source file of module `module`, each
+     as its kind and path. Methods are listed under their type, whether or
+     not the type turns out to be public; `public_api` drops the rest.
+     */
+    fn scan(module: &str, source: &str, items: &mut Vec<(String, String, Option<String>)>) {
+        let mut scopes: Vec<Scope> = Vec::new();
+        let mut test_only = false;
+        let mut in_comment = false;
+        for line in source.lines() {
+            let indent = line.len() - line.trim_start().len();
+            let text = line.trim();
+            if in_comment || text.starts_with("/*") {
+                in_comment = !text.ends_with("*/");
+                continue;
+            }
+            if text == "}" || text == "};" {
+                if scopes.last().map(|s| s.indent) == Some(indent) {
+                    scopes.pop();
+                }
+                continue;
+            }
+            if text.starts_with("#[cfg(test)]") || text.starts_with("#[cfg(all(test") {
+                test_only = true;
+                continue;
+            }
+            if text.starts_with("#[") || text.starts_with("//") {
+                continue;
+            }
+            let skip = test_only;
+            test_only = false;
+            let parent = scopes.last();
+            let path = parent.map_or(module.to_string(), |s| s.path.clone());
+            let visible = !skip && scopes.iter().all(|s| s.public);
+            let opens = text.ends_with('{');
+
+            if text.starts_with("impl") && (text.len() == 4 || !text[4..].starts_with(|c: char| c.is_alphanumeric() || c == '_')) {
+                if text.ends_with('}') {
+                    continue;
+                }
+                let owner = impl_owner(text);
+                scopes.push(Scope { indent, path: format!("{}::{}", path, owner), public: visible, kind: "impl" });
+                continue;
+            }
+            let (public, decl) = match text.strip_prefix("pub ") {
+                Some(decl) => (true, decl),
+                None => (false, text),
+            };
+            let decl = decl.trim_start_matches("unsafe ").trim_start_matches("const fn").trim_start_matches("async ");
+            let decl = if text.contains("const fn ") { "fn" } else { decl };
+            let kind = ["mod ", "struct ", "enum ", "trait ", "fn ", "const ", "static ", "type ", "use "]
+                .iter().find(|k| decl.starts_with(*k)).map(|k| k.trim_end());
+            let in_trait = matches!(parent, Some(s) if s.kind == "trait" && indent == s.indent + 4);
+            let in_type = parent.filter(|s| (s.kind == "struct" || s.kind == "enum") && indent == s.indent + 4);
+
+            match kind {
+                Some("use") if public && visible => {
+                    let target = decl["use ".len()..].trim_end_matches(';');
+                    match target.find("::{") {
+                        Some(i) => {
+                            let base = &target[..i];
+                            for name in target[i + 3..].trim_end_matches('}').split(',').map(str::trim) {
+                                items.push(("use".to_string(), format!("{}::{} = {}::{}", path, name, base, name), None));
+                            }
+                        }
+                        None => {
+                            let name = target.rsplit("::").next().unwrap_or(target);
+                            items.push(("use".to_string(), format!("{}::{} = {}", path, name, target), None));
+                        }
+                    }
+                }
+                Some(kind) if kind != "use" => {
+                    let name = ident(decl[kind.len()..].trim_start()).to_string();
+                    let item_path = format!("{}::{}", path, name);
+                    let shown = visible && (public || in_trait);
+                    if shown && !(kind == "mod" && name == "tests") {
+                        let owner = parent.filter(|s| s.kind == "impl").map(|s| s.path.clone());
+                        items.push((kind.to_string(), item_path.clone(), owner));
+                    }
+                    if opens && kind != "fn" {
+                        let kind = match kind { "mod" => "mod", "struct" => "struct", "enum" => "enum", "trait" => "trait", _ => "other" };
+                        scopes.push(Scope { indent, path: item_path, public: shown, kind });
+                    }
+                }
+                _ => {
+                    if !visible {
+                        continue;
+                    }
+                    match in_type {
+                        Some(s) if s.kind == "struct" && public => {
+                            items.push(("field".to_string(), format!("{}::{}", s.path, ident(decl)), None));
+                        }
+                        Some(s) if s.kind == "enum" && text.starts_with(|c: char| c.is_ascii_uppercase()) => {
+                            items.push(("variant".to_string(), format!("{}::{}", s.path, ident(text)), None));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /** The source files under `dir`, with the module each declares. */
+    fn sources(dir: &Path, module: &str, files: &mut Vec<(String, PathBuf)>) {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+        entries.sort();
+        for path in entries {
+            let stem = path.file_stem().unwrap().to_str().unwrap().to_string();
+            let child = if module.is_empty() {
+                if stem == "lib" { "crate".to_string() } else { format!("crate::{}", stem) }
+            } else {
+                format!("{}::{}", module, stem)
+            };
+            if path.is_dir() {
+                sources(&path, &format!("crate::{}", stem), files);
+            } else if path.extension() == Some("rs".as_ref()) {
+                files.push((child, path));
+            }
+        }
+    }
+
+    /** Every public item of the crate, one per line, sorted. */
+    fn public_api() -> String {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut files = Vec::new();
+        sources(&root.join("src"), "", &mut files);
+        let mut items = Vec::new();
+        for (module, path) in &files {
+            scan(module, &std::fs::read_to_string(path).unwrap(), &mut items);
+        }
+        // Methods only count on types that are themselves public
+        let types: BTreeSet<String> = items.iter()
+            .filter(|(kind, _, _)| kind == "struct" || kind == "enum" || kind == "trait")
+            .map(|(_, path, _)| path.clone())
+            .collect();
+        let lines: BTreeSet<String> = items.into_iter()
+            .filter(|(_, _, owner)| match owner { Some(o) => types.contains(o), None => true })
+            .map(|(kind, path, _)| format!("{} {}", kind, path))
+            .collect();
+        lines.into_iter().map(|l| l + "\n").collect()
+    }
+
+    #[test]
+    fn the_public_api_matches_its_snapshot() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SNAPSHOT);
+        let actual = public_api();
+        if std::env::var_os("UPDATE_PUBLIC_API").is_some() {
+            std::fs::write(&path, &actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_default();
+        let expected: BTreeSet<&str> = expected.lines().collect();
+        let actual: BTreeSet<&str> = actual.lines().collect();
+        let added: Vec<_> = actual.difference(&expected).collect();
+        let removed: Vec<_> = expected.difference(&actual).collect();
+        assert!(added.is_empty() && removed.is_empty(),
+                "The public API has changed.\nAdded: {:#?}\nRemoved: {:#?}\nIf this is deliberate, run \
+                 UPDATE_PUBLIC_API=1 cargo test --lib prelude and commit {}", added, removed, SNAPSHOT);
+    }
+}
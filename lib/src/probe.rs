@@ -0,0 +1,537 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::error::Ar2300Error;
+use crate::format::SampleFormat;
+use crate::iq::sigmf_meta_path;
+use crate::timeline::{json_value, sigmf_timing};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/** How much of a file the heuristics and statistics look at. */
+pub const PROBE_LEN: usize = 1 << 20;
+
+/** The formats the heuristics choose between. */
+const CANDIDATES: [SampleFormat; 4] = [
+    SampleFormat::BigEndianF32,
+    SampleFormat::LittleEndianF32,
+    SampleFormat::LittleEndianI16,
+    SampleFormat::LittleEndianI32,
+];
+
+/**
+ A float format is taken to be right when at least this fraction of its
+ samples look like real data, and ruled out below `IMPLAUSIBLE`.
+ */
+const PLAUSIBLE: f64 = 0.99;
+const IMPLAUSIBLE: f64 = 0.5;
+
+/** What the samples in a file are wrapped in. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Container {
+    /** Nothing: the file is samples from start to end. */
+    Raw,
+    /** A RIFF WAV file. The samples are the `data_len` bytes at `data_offset`. */
+    Wav {
+        /** 1 for integer PCM, 3 for IEEE float. */
+        audio_format: u16,
+        channels: u16,
+        sample_rate: u32,
+        bits_per_sample: u16,
+        data_offset: u64,
+        data_len: u64,
+    },
+}
+
+/** What the SigMF metadata next to a recording says about it. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigmfInfo {
+    /** `core:datatype`, as written. */
+    pub datatype: Option<String>,
+    pub sample_rate: Option<f64>,
+    pub start: Option<SystemTime>,
+}
+
+/** How plausible the samples are when read in one format. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FormatGuess {
+    pub format: SampleFormat,
+    /**
+     The fraction of samples that look like real data, or None where the
+     values can't tell: every bit pattern is a reasonable integer sample.
+     */
+    pub plausibility: Option<f64>,
+}
+
+/** What the probe concluded about the sample format. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detection {
+    /** Named by the WAV header or the SigMF metadata. */
+    Declared(SampleFormat),
+    /** Not named anywhere, but only one format fits the data. */
+    Detected(SampleFormat),
+    /** More than one format fits; these are the ones that do. */
+    Ambiguous(Vec<SampleFormat>),
+    /** No supported format fits. */
+    Unknown,
+}
+
+/** Statistics over the samples examined, for a known format. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleStats {
+    /** The number of samples the statistics cover. */
+    pub samples: u64,
+    /** Root mean square magnitude. */
+    pub rms: f64,
+    /** The largest magnitude. */
+    pub peak: f64,
+    /** The mean of I and of Q. */
+    pub dc_offset: (f64, f64),
+}
+
+/** Everything `probe` could work out about a recording. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeReport {
+    /** The file's size in bytes. */
+    pub len: u64,
+    pub container: Container,
+    /** The recording's SigMF metadata, if it has any. */
+    pub sigmf: Option<SigmfInfo>,
+    /** Every supported format, with how well the data fits it. */
+    pub candidates: Vec<FormatGuess>,
+    pub detection: Detection,
+    /** The number of samples in the file, once the format is known. */
+    pub samples: Option<u64>,
+    /** Statistics over the first `PROBE_LEN` bytes of samples, once the format is known. */
+    pub stats: Option<SampleStats>,
+    /** Anything that doesn't add up, such as a declared format the data doesn't fit. */
+    pub warnings: Vec<String>,
+}
+
+impl ProbeReport {
+    /** The format, if it was declared or only one fits. */
+    pub fn format(&self) -> Option<SampleFormat> {
+        match self.detection {
+            Detection::Declared(format) | Detection::Detected(format) => Some(format),
+            _ => None
+        }
+    }
+
+    /** The sample rate given by the WAV header or the SigMF metadata. */
+    pub fn sample_rate(&self) -> Option<f64> {
+        match &self.container {
+            Container::Wav { sample_rate, .. } => Some(*sample_rate as f64),
+            Container::Raw => self.sigmf.as_ref().and_then(|s| s.sample_rate)
+        }
+    }
+
+    /** How long the recording lasts at the given sample rate, once the format is known. */
+    pub fn duration(&self, sample_rate: f64) -> Option<Duration> {
+        let seconds = self.samples? as f64 / sample_rate;
+        if seconds.is_finite() && seconds >= 0.0 {
+            Some(Duration::from_secs_f64(seconds))
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ Work out what a recording holds: its container, its sample format, how
+ many samples it has and what they look like. The SigMF metadata beside
+ it is read if there is any. Only the first `PROBE_LEN` bytes of samples
+ are examined.
+
+ Floats give themselves away, since most bit patterns read in the wrong
+ byte order are absurdly large or small. Integers don't: any bit pattern
+ is a reasonable sample, so a file of integers is reported as ambiguous
+ unless its length or metadata settles it.
+ */
+pub fn probe(path: &Path) -> Result<ProbeReport, Ar2300Error> {
+    let len = fs::metadata(path)?.len();
+    // With room for a header before the samples
+    let mut head = Vec::new();
+    File::open(path)?.take(PROBE_LEN as u64 + 4096).read_to_end(&mut head)?;
+    let sigmf = fs::read_to_string(sigmf_meta_path(path)).ok();
+    Ok(probe_bytes(len, &head, sigmf.as_deref()))
+}
+
+/**
+ Like `probe`, for a file of `len` bytes that starts with `head`, and has
+ the given SigMF metadata, if any.
+ */
+pub fn probe_bytes(len: u64, head: &[u8], sigmf: Option<&str>) -> ProbeReport {
+    let mut warnings = Vec::new();
+    let container = parse_wav(head).unwrap_or(Container::Raw);
+    let (data_offset, data_len) = match &container {
+        Container::Wav { data_offset, data_len, .. } => {
+            let available = len.saturating_sub(*data_offset);
+            if *data_len > available {
+                warnings.push(format!("The WAV header claims {} bytes of samples, but the file holds {}",
+                                      data_len, available));
+            }
+            (*data_offset, (*data_len).min(available))
+        },
+        Container::Raw => (0, len)
+    };
+    let data = &head[(data_offset as usize).min(head.len())..];
+    let data = &data[..data.len().min(PROBE_LEN).min(data_len as usize)];
+
+    let sigmf = sigmf.map(|meta| {
+        let (sample_rate, start) = sigmf_timing(meta);
+        SigmfInfo {
+            datatype: json_value(meta, "core:datatype").map(|v| v.trim_matches('"').to_string()),
+            sample_rate,
+            start,
+        }
+    });
+
+    let candidates: Vec<FormatGuess> = CANDIDATES.iter()
+        .map(|&format| FormatGuess { format, plausibility: plausibility(format, data) })
+        .collect();
+    let fits = |format: SampleFormat| data_len % format.bytes_per_sample() as u64 == 0;
+
+    let declared = match (&container, &sigmf) {
+        (Container::Wav { audio_format, channels, bits_per_sample, .. }, _) =>
+            wav_format(*audio_format, *channels, *bits_per_sample).or_else(|| {
+                warnings.push("The WAV file's sample layout isn't a supported IQ format".to_string());
+                None
+            }),
+        (Container::Raw, Some(SigmfInfo { datatype: Some(datatype), .. })) =>
+            CANDIDATES.iter().copied().find(|f| f.sigmf_datatype() == datatype).or_else(|| {
+                warnings.push(format!("The SigMF datatype '{}' isn't supported", datatype));
+                None
+            }),
+        _ => None
+    };
+
+    let detection = match declared {
+        Some(format) => {
+            let guess = candidates.iter().find(|g| g.format == format).and_then(|g| g.plausibility);
+            if guess.is_some_and(|p| p < IMPLAUSIBLE) {
+                warnings.push(format!("The data doesn't look like the declared {}", format.sigmf_datatype()));
+            }
+            if !fits(format) {
+                warnings.push(format!("The data isn't a whole number of {} samples", format.sigmf_datatype()));
+            }
+            Detection::Declared(format)
+        },
+        None => detect(&candidates, fits)
+    };
+
+    let (samples, stats) = match &detection {
+        Detection::Declared(format) | Detection::Detected(format) => {
+            let bytes = format.bytes_per_sample() as u64;
+            (Some(data_len / bytes), Some(stats(*format, data)))
+        },
+        _ => (None, None)
+    };
+
+    ProbeReport { len, container, sigmf, candidates, detection, samples, stats, warnings }
+}
+
+/**
+ Choose a format from the heuristics alone. A float format wins if it is
+ plausible and every other float format isn't. Otherwise the formats that
+ can't be ruled out are all returned.
+ */
+fn detect<F: Fn(SampleFormat) -> bool>(candidates: &[FormatGuess], fits: F) -> Detection {
+    let possible: Vec<&FormatGuess> = candidates.iter()
+        .filter(|g| fits(g.format) && !matches!(g.plausibility, Some(p) if p < IMPLAUSIBLE))
+        .collect();
+    let floats: Vec<&&FormatGuess> = possible.iter().filter(|g| g.plausibility.is_some()).collect();
+    if floats.len() == 1 && floats[0].plausibility.unwrap_or(0.0) >= PLAUSIBLE {
+        return Detection::Detected(floats[0].format);
+    }
+    match possible.len() {
+        0 => Detection::Unknown,
+        1 => Detection::Detected(possible[0].format),
+        _ => Detection::Ambiguous(possible.iter().map(|g| g.format).collect())
+    }
+}
+
+/**
+ The fraction of samples that read as ordinary floats: zero, or finite
+ and within twelve orders of magnitude either side of one. None for
+ integer formats, or if there are no whole samples to judge.
+ */
+fn plausibility(format: SampleFormat, data: &[u8]) -> Option<f64> {
+    match format {
+        SampleFormat::BigEndianF32 | SampleFormat::LittleEndianF32 => {}
+        _ => return None
+    }
+    let ordinary = |v: f32| v == 0.0 || (v.is_finite() && (1e-12..=1e12).contains(&v.abs()));
+    let chunks = data.chunks_exact(format.bytes_per_sample());
+    let total = chunks.len();
+    if total == 0 {
+        return None;
+    }
+    let good = chunks
+        .filter_map(|c| format.decode(c))
+        .filter(|&(i, q)| ordinary(i) && ordinary(q))
+        .count();
+    Some(good as f64 / total as f64)
+}
+
+/** RMS, peak and DC offset of the samples in `data`. Non-finite samples are skipped. */
+fn stats(format: SampleFormat, data: &[u8]) -> SampleStats {
+    let mut stats = SampleStats { samples: 0, rms: 0.0, peak: 0.0, dc_offset: (0.0, 0.0) };
+    let (mut sum_i, mut sum_q, mut sum_sq) = (0.0, 0.0, 0.0);
+    for (i, q) in data.chunks_exact(format.bytes_per_sample()).filter_map(|c| format.decode(c)) {
+        if !(i.is_finite() && q.is_finite()) {
+            continue;
+        }
+        let (i, q) = (i as f64, q as f64);
+        let power = i * i + q * q;
+        stats.samples += 1;
+        sum_i += i;
+        sum_q += q;
+        sum_sq += power;
+        stats.peak = stats.peak.max(power.sqrt());
+    }
+    if stats.samples > 0 {
+        let n = stats.samples as f64;
+        stats.rms = (sum_sq / n).sqrt();
+        stats.dc_offset = (sum_i / n, sum_q / n);
+    }
+    stats
+}
+
+/** The IQ format of a two channel WAV file, if it is one the crate reads. */
+fn wav_format(audio_format: u16, channels: u16, bits_per_sample: u16) -> Option<SampleFormat> {
+    match (audio_format, channels, bits_per_sample) {
+        (1, 2, 16) => Some(SampleFormat::LittleEndianI16),
+        (1, 2, 32) => Some(SampleFormat::LittleEndianI32),
+        (3, 2, 32) => Some(SampleFormat::LittleEndianF32),
+        _ => None
+    }
+}
+
+/** Read a RIFF WAV header, or None if `head` doesn't start with one. */
+fn parse_wav(head: &[u8]) -> Option<Container> {
+    if head.len() < 12 || &head[..4] != b"RIFF" || &head[8..12] != b"WAVE" {
+        return None;
+    }
+    let u16_at = |at: usize| head.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| head.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let mut format = None;
+    let mut at = 12;
+    while let (Some(id), Some(size)) = (head.get(at..at + 4), u32_at(at + 4)) {
+        let body = at + 8;
+        match id {
+            b"fmt " => format = Some((u16_at(body)?, u16_at(body + 2)?, u32_at(body + 4)?, u16_at(body + 14)?)),
+            b"data" => {
+                let (audio_format, channels, sample_rate, bits_per_sample) = format?;
+                return Some(Container::Wav {
+                    audio_format,
+                    channels,
+                    sample_rate,
+                    bits_per_sample,
+                    data_offset: body as u64,
+                    data_len: size as u64,
+                });
+            },
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        at = body.checked_add(size as usize)?.checked_add(size as usize & 1)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq::WavWriter;
+    use crate::queue::Queue;
+    use std::io::Cursor;
+
+    /** A tone at a quarter of full scale, offset into [0, 1] as the receiver's samples are. */
+    fn tone(n: usize) -> Vec<(f32, f32)> {
+        (0..n).map(|k| {
+            let phase = k as f32 * 0.1;
+            (0.5 + 0.25 * phase.cos(), 0.5 + 0.25 * phase.sin())
+        }).collect()
+    }
+
+    fn encode(format: SampleFormat, samples: &[(f32, f32)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for sample in samples {
+            format.encode(*sample, &mut bytes);
+        }
+        bytes
+    }
+
+    fn probe_data(data: &[u8], sigmf: Option<&str>) -> ProbeReport {
+        probe_bytes(data.len() as u64, data, sigmf)
+    }
+
+    /** Bytes that look like nothing in particular. */
+    fn noise(n: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..n).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn floats_are_detected_in_either_byte_order() {
+        for &format in &[SampleFormat::BigEndianF32, SampleFormat::LittleEndianF32] {
+            let report = probe_data(&encode(format, &tone(1000)), None);
+            assert_eq!(report.detection, Detection::Detected(format));
+            assert_eq!(report.container, Container::Raw);
+            assert_eq!(report.samples, Some(1000));
+            assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+            let guess = report.candidates.iter().find(|g| g.format == format).unwrap();
+            assert_eq!(guess.plausibility, Some(1.0));
+        }
+    }
+
+    #[test]
+    fn statistics_describe_the_signal() {
+        let stats = probe_data(&encode(SampleFormat::BigEndianF32, &tone(10_000)), None).stats.unwrap();
+        assert_eq!(stats.samples, 10_000);
+        // A quarter scale tone around (0.5, 0.5)
+        assert!((stats.dc_offset.0 - 0.5).abs() < 0.01, "{:?}", stats);
+        assert!((stats.dc_offset.1 - 0.5).abs() < 0.01, "{:?}", stats);
+        let expected_rms = (0.5f64 + 0.0625).sqrt();
+        assert!((stats.rms - expected_rms).abs() < 0.01, "{:?}", stats);
+        assert!(stats.peak <= 0.5f64.hypot(0.5) + 0.25 + 1e-6);
+    }
+
+    #[test]
+    fn integers_are_ambiguous_unless_the_length_decides() {
+        let report = probe_data(&noise(4096), None);
+        let ints = vec![SampleFormat::LittleEndianI16, SampleFormat::LittleEndianI32];
+        assert_eq!(report.detection, Detection::Ambiguous(ints));
+        assert_eq!(report.samples, None);
+        assert_eq!(report.stats, None);
+        // Only 16 bit samples come in fours that aren't also eights
+        let report = probe_data(&noise(4100), None);
+        assert_eq!(report.detection, Detection::Detected(SampleFormat::LittleEndianI16));
+        assert_eq!(report.samples, Some(1025));
+    }
+
+    #[test]
+    fn silence_fits_every_format() {
+        let report = probe_data(&[0; 800], None);
+        assert_eq!(report.detection, Detection::Ambiguous(CANDIDATES.to_vec()));
+        // So does an empty file
+        assert_eq!(probe_data(&[], None).detection, Detection::Ambiguous(CANDIDATES.to_vec()));
+    }
+
+    #[test]
+    fn odd_lengths_fit_nothing() {
+        assert_eq!(probe_data(&noise(4099), None).detection, Detection::Unknown);
+    }
+
+    #[test]
+    fn sigmf_metadata_declares_the_format() {
+        let meta = r#"{"global": {"core:datatype": "ci16_le", "core:sample_rate": 1125000}}"#;
+        let report = probe_data(&noise(4096), Some(meta));
+        assert_eq!(report.detection, Detection::Declared(SampleFormat::LittleEndianI16));
+        assert_eq!(report.sample_rate(), Some(1_125_000.0));
+        assert_eq!(report.samples, Some(1024));
+        assert_eq!(report.duration(1024.0), Some(Duration::from_secs(1)));
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn a_declared_format_the_data_contradicts_is_reported() {
+        let meta = r#"{"global": {"core:datatype": "cf32_le"}}"#;
+        let report = probe_data(&encode(SampleFormat::BigEndianF32, &tone(1000)), Some(meta));
+        // The declaration stands, but not silently
+        assert_eq!(report.detection, Detection::Declared(SampleFormat::LittleEndianF32));
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        let report = probe_data(&noise(64), Some(r#"{"global": {"core:datatype": "cu8"}}"#));
+        assert_eq!(report.detection, Detection::Ambiguous(vec![SampleFormat::LittleEndianI16,
+                                                               SampleFormat::LittleEndianI32]));
+        assert!(report.warnings[0].contains("cu8"));
+    }
+
+    fn wav(samples: &[(f32, f32)]) -> Vec<u8> {
+        let queue = Queue::new(samples.len());
+        queue.enqueue_all(samples.iter().copied());
+        queue.close();
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = WavWriter::with_sample_rate(queue, &mut out, 48_000).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        out.into_inner()
+    }
+
+    #[test]
+    fn wav_headers_are_read() {
+        let data = wav(&tone(1000));
+        let report = probe_data(&data, None);
+        assert_eq!(report.container, Container::Wav {
+            audio_format: 1,
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            data_offset: 44,
+            data_len: 4000,
+        });
+        assert_eq!(report.detection, Detection::Declared(SampleFormat::LittleEndianI16));
+        assert_eq!(report.samples, Some(1000));
+        assert_eq!(report.sample_rate(), Some(48_000.0));
+        let stats = report.stats.unwrap();
+        assert!((stats.dc_offset.0 - 0.5).abs() < 0.01, "{:?}", stats);
+    }
+
+    #[test]
+    fn a_truncated_wav_file_is_reported() {
+        let data = wav(&tone(1000));
+        let report = probe_data(&data[..2044], None);
+        assert_eq!(report.samples, Some(500));
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn unsupported_wav_layouts_are_reported() {
+        let mut data = wav(&tone(10));
+        // One channel
+        data[22] = 1;
+        let report = probe_data(&data, None);
+        assert!(matches!(report.container, Container::Wav { channels: 1, .. }));
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        // Not a RIFF file at all
+        assert!(parse_wav(b"RIFF\x00\x00\x00\x00WAVEdata").is_none());
+        assert!(parse_wav(b"RIFX").is_none());
+    }
+
+    #[test]
+    fn files_are_probed_with_their_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mystery.sigmf-data");
+        fs::write(&path, encode(SampleFormat::LittleEndianI32, &tone(100))).unwrap();
+        let report = probe(&path).unwrap();
+        assert!(matches!(report.detection, Detection::Ambiguous(_)));
+        fs::write(sigmf_meta_path(&path), r#"{"global": {"core:datatype": "ci32_le"}}"#).unwrap();
+        let report = probe(&path).unwrap();
+        assert_eq!(report.detection, Detection::Declared(SampleFormat::LittleEndianI32));
+        assert_eq!(report.len, 800);
+        assert_eq!(report.samples, Some(100));
+        assert!(probe(&dir.path().join("missing")).is_err());
+    }
+}
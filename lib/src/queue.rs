@@ -17,51 +17,830 @@
     along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
  */
  
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, Condvar};
+use log::{debug, error, warn};
+use self::sync::{AtomicBool, AtomicU64, AtomicUsize, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, PoisonError, TryLockError};
+use crate::error::Ar2300Error;
 use std::collections::VecDeque;
-use std::time::Duration;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
 
-#[derive(Clone)]
+/**
+ The locks, condition variable and atomics the queue is built on. A build
+ with `--cfg loom` swaps in loom's, so the model tests at the end of this
+ file can try every interleaving of the queue's operations.
+ */
+mod sync {
+    #[cfg(not(loom))]
+    pub(super) use std::sync::{Condvar, Mutex, MutexGuard};
+    #[cfg(not(loom))]
+    pub(super) use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+    #[cfg(loom)]
+    pub(super) use loom::sync::{Condvar, Mutex, MutexGuard};
+    #[cfg(loom)]
+    pub(super) use loom::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+
+    use std::sync::LockResult;
+    use std::time::Duration;
+
+    #[cfg(not(loom))]
+    pub(super) fn wait_while<'a, T, F>(cv: &Condvar, guard: MutexGuard<'a, T>, condition: F) -> LockResult<MutexGuard<'a, T>>
+    where F: FnMut(&mut T) -> bool {
+        cv.wait_while(guard, condition)
+    }
+
+    #[cfg(loom)]
+    pub(super) fn wait_while<'a, T, F>(cv: &Condvar, mut guard: MutexGuard<'a, T>, mut condition: F) -> LockResult<MutexGuard<'a, T>>
+    where F: FnMut(&mut T) -> bool {
+        while condition(&mut *guard) {
+            guard = cv.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /** Wait while `condition` holds, for at most `timeout`. Whether it timed out isn't needed. */
+    #[cfg(not(loom))]
+    pub(super) fn wait_timeout_while<'a, T, F>(cv: &Condvar,
+                                               guard: MutexGuard<'a, T>,
+                                               timeout: Duration,
+                                               condition: F) -> LockResult<MutexGuard<'a, T>>
+    where F: FnMut(&mut T) -> bool {
+        cv.wait_timeout_while(guard, timeout, condition)
+            .map(|(guard, _)| guard)
+            .map_err(|e| std::sync::PoisonError::new(e.into_inner().0))
+    }
+
+    /** loom can't model time, so this waits until the condition fails. */
+    #[cfg(loom)]
+    pub(super) fn wait_timeout_while<'a, T, F>(cv: &Condvar,
+                                               guard: MutexGuard<'a, T>,
+                                               _timeout: Duration,
+                                               condition: F) -> LockResult<MutexGuard<'a, T>>
+    where F: FnMut(&mut T) -> bool {
+        wait_while(cv, guard, condition)
+    }
+
+    #[cfg(not(loom))]
+    pub(super) fn clear_poison<T>(mutex: &Mutex<T>) {
+        mutex.clear_poison();
+    }
+
+    /** loom's mutexes are never poisoned. */
+    #[cfg(loom)]
+    pub(super) fn clear_poison<T>(_mutex: &Mutex<T>) {}
+}
+
+/**
+ Callbacks for watching a queue's activity, such as from a profiler.
+ Called after the operation, outside the queue's lock, with the queue's
+ name, its length afterwards and the number of items moved.
+ */
+#[cfg(feature = "instrument")]
+pub trait QueueHooks: Send + Sync {
+    fn on_enqueue(&self, _name: &str, _len_after: usize, _batch: usize) {}
+    fn on_dequeue(&self, _name: &str, _len_after: usize, _batch: usize) {}
+    fn on_close(&self, _name: &str) {}
+}
+
+/** Emits a tracing event for each queue operation. */
+#[cfg(feature = "tracing-hooks")]
+pub struct TracingHooks;
+
+#[cfg(feature = "tracing-hooks")]
+impl QueueHooks for TracingHooks {
+    fn on_enqueue(&self, name: &str, len_after: usize, batch: usize) {
+        tracing::trace!(queue = name, len_after, batch, "enqueue");
+    }
+
+    fn on_dequeue(&self, name: &str, len_after: usize, batch: usize) {
+        tracing::trace!(queue = name, len_after, batch, "dequeue");
+    }
+
+    fn on_close(&self, name: &str) {
+        tracing::trace!(queue = name, "close");
+    }
+}
+
+/** Why a queue was closed. */
+#[derive(Clone, Debug)]
+pub enum CloseReason {
+    /** The producer finished normally. */
+    Finished,
+    /** The capture was cancelled, e.g. by Ctrl-C. */
+    Cancelled,
+    /** The producer or consumer failed, with the error it failed with. */
+    Error(Arc<Ar2300Error>),
+    /**
+     A thread panicked while holding the queue's lock. What was queued is
+     intact and can still be taken.
+     */
+    Poisoned,
+}
+
+/**
+ Errors aren't comparable, so two `Error` reasons are equal if they hold
+ the same error, or errors with the same message.
+ */
+impl PartialEq for CloseReason {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (CloseReason::Error(a), CloseReason::Error(b)) => Arc::ptr_eq(a, b) || a.to_string() == b.to_string(),
+            (CloseReason::Finished, CloseReason::Finished) |
+            (CloseReason::Cancelled, CloseReason::Cancelled) |
+            (CloseReason::Poisoned, CloseReason::Poisoned) => true,
+            _ => false
+        }
+    }
+}
+
+impl Eq for CloseReason {}
+
+/** What `enqueue` does when the queue is full. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /** Wait for the consumer to make room. Never use this from a USB callback. */
+    Block,
+    /** Discard the incoming item. */
+    #[default]
+    DropNewest,
+    /** Discard the oldest queued item to make room. */
+    DropOldest,
+}
+
+/** What happened to an item passed to `enqueue`. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnqueueResult {
+    /** The item was queued. */
+    Queued,
+    /** The queue was full (or closed while waiting) and the item was discarded. */
+    Dropped,
+    /** The item was queued, and the oldest item was discarded to make room. */
+    Evicted,
+    /**
+     A thread panicked while holding the queue's lock, so the queue was
+     closed as `CloseReason::Poisoned` and the item was discarded.
+     */
+    Poisoned,
+}
+
+/** What a wait for an item ended with, from `Queue::dequeue_result`. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DequeueResult<T> {
+    Item(T),
+    /** Nothing arrived before the timeout; the queue is still open. */
+    Timeout,
+    /** The queue is closed and everything in it has been taken. */
+    Closed,
+}
+
+/** A snapshot of a queue's counters, from `Queue::stats`. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /** Items added to the queue. */
+    pub enqueued: u64,
+    /** Items taken by consumers. */
+    pub dequeued: u64,
+    /** Items discarded because the queue was full, whether incoming or evicted. */
+    pub dropped: u64,
+    /** Items discarded by `Queue::clear`. */
+    pub cleared: u64,
+    /** The most items the queue has held at once. */
+    pub high_water_mark: usize,
+    /** When the queue was closed, if it has been. */
+    pub closed_at: Option<Instant>,
+}
+
+/** The counters behind QueueStats, shared by clones of a queue. */
+#[derive(Default)]
+struct Counters {
+    enqueued: AtomicU64,
+    dequeued: AtomicU64,
+    dropped: AtomicU64,
+    cleared: AtomicU64,
+    high_water_mark: AtomicUsize,
+    closed_at: Mutex<Option<Instant>>,
+    // Bumped by every close, so a waiter can tell it was closed even if
+    // the queue has been reopened by the time it wakes.
+    closes: AtomicU64,
+    // Set once a thread panics holding the lock; producers are refused until a reopen
+    poisoned: AtomicBool,
+    // Threads in wait_below. Dequeues only wake waiters while there are
+    // some, so a plain consumer doesn't pay for the wake-ups.
+    below_waiters: AtomicUsize,
+    // Acquisitions of the queue lock, so tests can check what is batched
+    #[cfg(test)]
+    locks: AtomicU64,
+}
+
+/**
+ The front item of a queue, returned by `Queue::peek`. The queue stays
+ locked until the guard is dropped, so hold it only briefly.
+ */
+pub struct PeekGuard<'a, T> {
+    queue: MutexGuard<'a, VecDeque<T>>,
+}
+
+impl<T> Deref for PeekGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Only created for a non-empty queue, which can't change while locked
+        self.queue.front().unwrap()
+    }
+}
+
+/**
+ A queue shared between threads, holding at most `capacity` items. What
+ happens when it is full is set by its OverflowPolicy.
+ */
 pub struct Queue<T> {
+    name: Arc<str>,
+    capacity: usize,
+    policy: OverflowPolicy,
     closed: Arc<AtomicBool>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+    counters: Arc<Counters>,
     q: Arc<(Mutex<VecDeque<T>>, Condvar)>,
+    #[cfg(feature = "instrument")]
+    hooks: Option<Arc<dyn QueueHooks>>,
+}
+
+/** Clones share the same items and state, so T itself needn't be Clone. */
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Queue {
+            name: self.name.clone(),
+            capacity: self.capacity,
+            policy: self.policy,
+            closed: self.closed.clone(),
+            close_reason: self.close_reason.clone(),
+            counters: self.counters.clone(),
+            q: self.q.clone(),
+            #[cfg(feature = "instrument")]
+            hooks: self.hooks.clone(),
+        }
+    }
 }
 
 impl<T> Queue<T> {
     pub fn new(capacity: usize) -> Self {
+        Queue::named("", capacity)
+    }
+
+    /** Create a queue with a name, to tell it apart in instrumentation. */
+    pub fn named(name: &str, capacity: usize) -> Self {
+        Queue::named_with_policy(name, capacity, OverflowPolicy::default())
+    }
+
+    /** Create a queue with the given overflow policy. */
+    pub fn with_overflow_policy(capacity: usize, policy: OverflowPolicy) -> Self {
+        Queue::named_with_policy("", capacity, policy)
+    }
+
+    /** Create a named queue with the given overflow policy. A capacity of zero is treated as one. */
+    pub fn named_with_policy(name: &str, capacity: usize, policy: OverflowPolicy) -> Self {
+        let capacity = capacity.max(1);
         Queue {
+            name: Arc::from(name),
+            capacity,
+            policy,
             closed: Arc::new(AtomicBool::new(false)),
+            close_reason: Arc::new(Mutex::new(None)),
+            counters: Arc::new(Counters::default()),
             q: Arc::new(
                 (Mutex::new(
                     VecDeque::with_capacity(capacity)),
                 Condvar::new())),
+            #[cfg(feature = "instrument")]
+            hooks: None,
         }
     }
-    
-    pub fn enqueue(&self, v: T) {
-        let (l, cv) = &*self.q;
-        let mut queue = l.lock().unwrap();
-        let queue_was_empty = queue.is_empty();
-        queue.push_back(v);
-        if queue_was_empty {
-            cv.notify_all();
+
+    /** Create a named queue that reports its activity to the hooks. */
+    #[cfg(feature = "instrument")]
+    pub fn with_hooks(name: &str, capacity: usize, hooks: Arc<dyn QueueHooks>) -> Self {
+        let mut queue = Queue::named(name, capacity);
+        queue.hooks = Some(hooks);
+        queue
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /** The most items the queue holds, as given when it was created. */
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /** The number of items waiting in the queue. */
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /** The most items the queue has ever held at once. */
+    pub fn high_water_mark(&self) -> usize {
+        self.counters.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /** A snapshot of the queue's counters. */
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            enqueued: self.counters.enqueued.load(Ordering::Relaxed),
+            dequeued: self.counters.dequeued.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            cleared: self.counters.cleared.load(Ordering::Relaxed),
+            high_water_mark: self.counters.high_water_mark.load(Ordering::Relaxed),
+            closed_at: *self.counters.closed_at.lock().unwrap(),
+        }
+    }
+
+    /** Items discarded because the queue was full, whether incoming or evicted. */
+    pub fn dropped_count(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    /** Add an item, applying the overflow policy if the queue is full. */
+    pub fn enqueue(&self, v: T) -> EnqueueResult {
+        let (_, cv) = &*self.q;
+        let (queue, result) = self.push_locked(self.lock(), v);
+        cv.notify_all();
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let Some(hooks) = &self.hooks {
+                hooks.on_enqueue(&self.name, len_after, 1);
+            }
+        }
+        #[cfg(not(feature = "instrument"))]
+        drop(queue);
+        result
+    }
+
+    /**
+     Add several items under a single acquisition of the lock, applying the
+     overflow policy to each. Returns how many items were lost, whether
+     rejected or evicted.
+     */
+    pub fn enqueue_all(&self, items: impl IntoIterator<Item = T>) -> usize {
+        let (_, cv) = &*self.q;
+        let mut queue = self.lock();
+        let mut lost = 0;
+        #[cfg(feature = "instrument")]
+        let mut batch = 0;
+        for v in items {
+            let (q, result) = self.push_locked(queue, v);
+            queue = q;
+            if result != EnqueueResult::Queued {
+                lost += 1;
+            }
+            #[cfg(feature = "instrument")]
+            {
+                batch += 1;
+            }
+        }
+        cv.notify_all();
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let (Some(hooks), true) = (&self.hooks, batch > 0) {
+                hooks.on_enqueue(&self.name, len_after, batch);
+            }
+        }
+        lost
+    }
+
+    /**
+     Apply the overflow policy and push one item onto the locked queue.
+     The caller wakes consumers once it has finished pushing.
+     */
+    fn push_locked<'a>(&self,
+                       mut queue: MutexGuard<'a, VecDeque<T>>,
+                       v: T) -> (MutexGuard<'a, VecDeque<T>>, EnqueueResult) {
+        let (_, cv) = &*self.q;
+        let mut result = EnqueueResult::Queued;
+        if self.counters.poisoned.load(Ordering::Relaxed) {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return (queue, EnqueueResult::Poisoned);
         }
+        if queue.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    // The consumer may not have been told about earlier items in a batch yet
+                    cv.notify_all();
+                    let closes = self.closes();
+                    queue = sync::wait_while(cv, queue, |queue| {
+                        !self.closed_since(closes) && queue.len() >= self.capacity
+                    }).unwrap_or_else(|e| self.recover(e));
+                    if self.counters.poisoned.load(Ordering::Relaxed) {
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        return (queue, EnqueueResult::Poisoned);
+                    }
+                    if queue.len() >= self.capacity {
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        return (queue, EnqueueResult::Dropped);
+                    }
+                },
+                OverflowPolicy::DropNewest => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    return (queue, EnqueueResult::Dropped);
+                },
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    result = EnqueueResult::Evicted;
+                }
+            }
+        }
+        queue.push_back(v);
+        self.pushed(queue.len());
+        (queue, result)
     }
-    
+
+    /**
+     Wait up to `timeout` for an item and take it. Returns None both on
+     timeout and once the queue is closed and empty; use `dequeue_result`
+     to tell them apart.
+     */
     pub fn dequeue(&self, timeout: Duration) -> Option<T> {
+        match self.dequeue_result(timeout) {
+            DequeueResult::Item(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /**
+     Wait up to `timeout` for an item and take it. Unlike `dequeue`, tells
+     a timeout apart from a queue that is closed and has nothing left.
+     */
+    pub fn dequeue_result(&self, timeout: Duration) -> DequeueResult<T> {
+        let (_, cv) = &*self.q;
+        let queue = self.lock();
+        let closes = self.closes();
+        let mut queue = sync::wait_timeout_while(
+            cv,
+            queue,
+            timeout,
+            |queue| !self.closed_since(closes) && queue.is_empty()
+        ).unwrap_or_else(|e| self.recover(e));
+        let v = queue.pop_front();
+        let closed = self.closed_since(closes);
+        if v.is_some() {
+            self.taken(cv, queue.len() + 1, 1);
+        }
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let (Some(hooks), true) = (&self.hooks, v.is_some()) {
+                hooks.on_dequeue(&self.name, len_after, 1);
+            }
+        }
+        match v {
+            Some(v) => DequeueResult::Item(v),
+            None if closed => DequeueResult::Closed,
+            None => DequeueResult::Timeout
+        }
+    }
+
+    /**
+     Wait up to `timeout` for an item, then take up to `max` items under a
+     single acquisition of the lock. Returns an empty Vec on timeout; once
+     the queue is closed, returns whatever is left without waiting.
+     */
+    pub fn dequeue_batch(&self, max: usize, timeout: Duration) -> Vec<T> {
+        let mut batch = Vec::new();
+        self.drain_into(&mut batch, max, timeout);
+        batch
+    }
+
+    /**
+     Like `dequeue_batch`, but appends to `out` so its allocation can be
+     reused. Returns the number of items added.
+     */
+    pub fn drain_into(&self, out: &mut Vec<T>, max: usize, timeout: Duration) -> usize {
+        match self.drain_result(out, max, timeout) {
+            DequeueResult::Item(n) => n,
+            _ => 0
+        }
+    }
+
+    /**
+     Like `drain_into`, returning `Item` with the number of items added,
+     or whether nothing was added because the wait timed out or because
+     the queue is closed and empty.
+     */
+    pub fn drain_result(&self, out: &mut Vec<T>, max: usize, timeout: Duration) -> DequeueResult<usize> {
+        let (_, cv) = &*self.q;
+        let queue = self.lock();
+        let closes = self.closes();
+        let mut queue = sync::wait_timeout_while(
+            cv,
+            queue,
+            timeout,
+            |queue| !self.closed_since(closes) && queue.is_empty()
+        ).unwrap_or_else(|e| self.recover(e));
+        let len_before = queue.len();
+        let n = max.min(len_before);
+        out.extend(queue.drain(..n));
+        let closed = self.closed_since(closes) && len_before == 0;
+        if n > 0 {
+            self.taken(cv, len_before, n);
+        }
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let (Some(hooks), true) = (&self.hooks, n > 0) {
+                hooks.on_dequeue(&self.name, len_after, n);
+            }
+        }
+        if n > 0 {
+            DequeueResult::Item(n)
+        } else if closed {
+            DequeueResult::Closed
+        } else {
+            DequeueResult::Timeout
+        }
+    }
+
+    /**
+     A copy of the front item, or None if the queue is empty. This is a
+     snapshot: another consumer may take the item as soon as this returns.
+     Unlike `peek`, the lock isn't held afterwards.
+     */
+    pub fn peek_cloned(&self) -> Option<T> where T: Clone {
+        self.peek_n(1).pop()
+    }
+
+    /**
+     Copies of up to `n` items from the front of the queue, oldest first.
+     Like `peek_cloned`, a snapshot that other consumers may overtake.
+     */
+    pub fn peek_n(&self, n: usize) -> Vec<T> where T: Clone {
+        let queue = self.lock();
+        queue.iter().take(n).cloned().collect()
+    }
+
+    /**
+     Look at the front item without taking it, or None if the queue is
+     empty. The item may be gone by the time the guard is dropped and
+     `dequeue` is called; use `peek_and_dequeue` to decide and take it in
+     one step.
+     */
+    pub fn peek(&self) -> Option<PeekGuard<'_, T>> {
+        let queue = self.lock();
+        if queue.is_empty() {
+            None
+        } else {
+            Some(PeekGuard { queue })
+        }
+    }
+
+    /**
+     Wait up to `timeout` for an item, then take it only if the predicate
+     accepts it. The check and the removal happen under the same lock.
+     Returns None on timeout or if the predicate rejects the item, which
+     then stays at the front.
+     */
+    pub fn peek_and_dequeue<F: FnOnce(&T) -> bool>(&self, timeout: Duration, predicate: F) -> Option<T> {
+        let (_, cv) = &*self.q;
+        let queue = self.lock();
+        let closes = self.closes();
+        let mut queue = sync::wait_timeout_while(
+            cv,
+            queue,
+            timeout,
+            |queue| !self.closed_since(closes) && queue.is_empty()
+        ).unwrap_or_else(|e| self.recover(e));
+        if !queue.front().is_some_and(predicate) {
+            return None;
+        }
+        let v = queue.pop_front();
+        self.taken(cv, queue.len() + 1, 1);
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let Some(hooks) = &self.hooks {
+                hooks.on_dequeue(&self.name, len_after, 1);
+            }
+        }
+        v
+    }
+
+    /**
+     Iterate over the items as they arrive. Each wait for an item lasts at
+     most `timeout` before the queue is checked for having been closed;
+     iteration ends once it is closed and every remaining item has been
+     yielded.
+     */
+    pub fn into_iter_blocking(self, timeout: Duration) -> BlockingIter<T> {
+        BlockingIter { queue: self, timeout }
+    }
+
+    /** Like `into_iter_blocking`, over a clone of the queue. */
+    pub fn iter_blocking(&self, timeout: Duration) -> BlockingIter<T> {
+        self.clone().into_iter_blocking(timeout)
+    }
+
+    /**
+     Add an item without waiting. Returns false, without applying the
+     overflow policy, if another thread holds the lock or the queue is full
+     or poisoned.
+     */
+    pub fn try_enqueue(&self, v: T) -> bool {
+        let (_, cv) = &*self.q;
+        let mut queue = match self.try_lock() {
+            Some(queue) => queue,
+            None => return false
+        };
+        if queue.len() >= self.capacity || self.counters.poisoned.load(Ordering::Relaxed) {
+            return false;
+        }
+        queue.push_back(v);
+        self.pushed(queue.len());
+        cv.notify_all();
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let Some(hooks) = &self.hooks {
+                hooks.on_enqueue(&self.name, len_after, 1);
+            }
+        }
+        true
+    }
+
+    /**
+     Take an item without waiting, for callers such as GUI event loops that
+     must never block. It never waits on the condition variable. Returns
+     None if the queue is empty or another thread holds the lock at that
+     moment, so poll again later. Items left in a closed queue are still
+     returned; check `is_closed` once this returns None to tell the end of
+     the stream from a pause.
+     */
+    pub fn try_dequeue(&self) -> Option<T> {
+        let (_, cv) = &*self.q;
+        let mut queue = self.try_lock()?;
+        let v = queue.pop_front();
+        if v.is_some() {
+            self.taken(cv, queue.len() + 1, 1);
+        }
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let (Some(hooks), true) = (&self.hooks, v.is_some()) {
+                hooks.on_dequeue(&self.name, len_after, 1);
+            }
+        }
+        v
+    }
+
+    /**
+     Take up to `max` items under a single acquisition of the lock, without
+     waiting. Returns an empty Vec if the queue is empty or another thread
+     holds the lock. Like `try_dequeue`, it never waits and still drains a
+     closed queue.
+     */
+    pub fn try_dequeue_batch(&self, max: usize) -> Vec<T> {
+        let (_, cv) = &*self.q;
+        let mut queue = match self.try_lock() {
+            Some(queue) => queue,
+            None => return Vec::new()
+        };
+        let len_before = queue.len();
+        let batch: Vec<T> = queue.drain(..max.min(len_before)).collect();
+        if !batch.is_empty() {
+            self.taken(cv, len_before, batch.len());
+        }
+        #[cfg(feature = "instrument")]
+        {
+            let len_after = queue.len();
+            drop(queue);
+            if let (Some(hooks), false) = (&self.hooks, batch.is_empty()) {
+                hooks.on_dequeue(&self.name, len_after, batch.len());
+            }
+        }
+        batch
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<T>> {
+        let (l, _) = &*self.q;
+        #[cfg(test)]
+        self.counters.locks.fetch_add(1, Ordering::Relaxed);
+        l.lock().unwrap_or_else(|e| self.recover(e))
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, VecDeque<T>>> {
+        let (l, _) = &*self.q;
+        #[cfg(test)]
+        self.counters.locks.fetch_add(1, Ordering::Relaxed);
+        match l.try_lock() {
+            Ok(queue) => Some(queue),
+            Err(TryLockError::Poisoned(e)) => Some(self.recover(e)),
+            Err(TryLockError::WouldBlock) => None
+        }
+    }
+
+    /**
+     Take the lock back from a thread that panicked while holding it. The
+     items are intact, since no method panics part way through changing
+     them, so the queue stays usable: it is closed as `CloseReason::Poisoned`,
+     so consumers drain what is left, and producers are refused with
+     `EnqueueResult::Poisoned`. The poison is cleared so this happens only
+     once.
+     */
+    fn recover<G>(&self, e: PoisonError<G>) -> G {
         let (l, cv) = &*self.q;
-        let mut queue = cv.wait_timeout_while(
-            l.lock().unwrap(), 
+        let guard = e.into_inner();
+        sync::clear_poison(l);
+        error!("Queue {}: a thread panicked while holding the lock; closing it", self.name);
+        self.counters.poisoned.store(true, Ordering::Relaxed);
+        self.mark_closed(CloseReason::Poisoned);
+        cv.notify_all();
+        guard
+    }
+
+    /** How many times the queue's lock has been taken, waits aside. */
+    #[cfg(test)]
+    pub(crate) fn lock_count(&self) -> u64 {
+        self.counters.locks.load(Ordering::Relaxed)
+    }
+
+    /** Count an item pushed, leaving `len_after` items queued. */
+    fn pushed(&self, len_after: usize) {
+        self.counters.enqueued.fetch_add(1, Ordering::Relaxed);
+        self.counters.high_water_mark.fetch_max(len_after, Ordering::Relaxed);
+    }
+
+    /**
+     Count `n` items taken and wake producers blocked on a full queue, or
+     waiting in `wait_below`. The caller must hold the queue lock.
+     */
+    fn taken(&self, cv: &Condvar, len_before: usize, n: usize) {
+        self.counters.dequeued.fetch_add(n as u64, Ordering::Relaxed);
+        if (self.policy == OverflowPolicy::Block && len_before >= self.capacity) ||
+            self.counters.below_waiters.load(Ordering::Relaxed) > 0 {
+            cv.notify_all();
+        }
+    }
+
+    /**
+     Wait up to `timeout` for the queue to hold fewer than `threshold`
+     items. Returns true if it does. A producer can use this to hold off
+     until the consumer catches up. Also returns, with false if the queue
+     is still too full, when the queue is closed.
+     */
+    pub fn wait_below(&self, threshold: usize, timeout: Duration) -> bool {
+        let (_, cv) = &*self.q;
+        let queue = self.lock();
+        let closes = self.closes();
+        // Counted under the lock, so a dequeue can't miss this waiter
+        self.counters.below_waiters.fetch_add(1, Ordering::Relaxed);
+        let queue = sync::wait_timeout_while(
+            cv,
+            queue,
             timeout,
-            |queue| !self.is_closed() && queue.is_empty()
-        ).unwrap().0;
-        queue.pop_front()
+            |queue| !self.closed_since(closes) && queue.len() >= threshold
+        ).unwrap_or_else(|e| self.recover(e));
+        self.counters.below_waiters.fetch_sub(1, Ordering::Relaxed);
+        queue.len() < threshold
+    }
+
+    /** True if the queue holds more than `threshold` items. Doesn't wait. */
+    pub fn is_above(&self, threshold: usize) -> bool {
+        self.len() > threshold
+    }
+
+    /**
+     Discard every item in the queue, returning how many there were. Use it
+     with `reopen` to keep stale items from a previous run out of the next
+     one. Discarded items are counted in `QueueStats::cleared`.
+     */
+    pub fn clear(&self) -> usize {
+        let (_, cv) = &*self.q;
+        let mut queue = self.lock();
+        let len_before = queue.len();
+        queue.clear();
+        self.counters.cleared.fetch_add(len_before as u64, Ordering::Relaxed);
+        if (self.policy == OverflowPolicy::Block && len_before >= self.capacity) ||
+            self.counters.below_waiters.load(Ordering::Relaxed) > 0 {
+            cv.notify_all();
+        }
+        len_before
     }
 
     pub fn is_empty(&self) -> bool {
-        let (l, _) = &*self.q;
-        let queue = l.lock().unwrap();
+        let queue = self.lock();
         queue.is_empty()
     }
 
@@ -71,12 +850,1283 @@ impl<T> Queue<T> {
     }
 
     pub fn is_closed(&self) -> bool {
-        self.closed.load(Ordering::Relaxed)
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /** How many times the queue has been closed. Read it under the queue lock. */
+    fn closes(&self) -> u64 {
+        self.counters.closes.load(Ordering::Acquire)
+    }
+
+    /** True if the queue is closed, or was closed and reopened after `closes` was read. */
+    fn closed_since(&self, closes: u64) -> bool {
+        self.is_closed() || self.closes() != closes
+    }
+
+    /** Why the queue was closed, or None if it is still open. */
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.lock().unwrap().clone()
+    }
+
+    /** Close the queue as `CloseReason::Finished`. */
+    pub fn close(&self) {
+        self.close_with(CloseReason::Finished);
+    }
+
+    /**
+     Close the queue, recording why, and wake everything waiting on it so
+     consumers drain what is left and return. Only the first reason is
+     kept, so a later close can't mask the original cause. It takes the
+     queue's lock, so don't call it while holding a PeekGuard.
+     */
+    pub fn close_with(&self, reason: CloseReason) {
+        let (_, cv) = &*self.q;
+        {
+            // Set the flag under the queue lock. Waiters check it under the
+            // same lock before sleeping, so none can see the queue open and
+            // then miss the wake-up below.
+            let _queue = self.lock();
+            self.mark_closed(reason);
+        }
+        cv.notify_all();
+        debug!("Queue {} closed", self.name);
+        #[cfg(feature = "instrument")]
+        if let Some(hooks) = &self.hooks {
+            hooks.on_close(&self.name);
+        }
+    }
+
+    /** Record the queue as closed. The caller must hold the queue lock. */
+    fn mark_closed(&self, reason: CloseReason) {
+        self.close_reason.lock().unwrap().get_or_insert(reason);
+        self.counters.closed_at.lock().unwrap().get_or_insert_with(Instant::now);
+        self.closed.store(true, Ordering::Release);
+        self.counters.closes.fetch_add(1, Ordering::Release);
+    }
+
+    /**
+     Open a closed queue again so it can be used for another run, clearing
+     its close reason and time, and whether it was poisoned. Items still queued are kept; call `clear`
+     first to drop them. The other counters keep running.
+
+     A producer or consumer that was waiting when the queue was closed
+     still returns as it would for a close, even if the queue is reopened
+     before it wakes: `dequeue` returns None if nothing is queued, and a
+     blocked `enqueue` drops its item. Waits that start after `reopen`
+     behave as on a new queue.
+     */
+    pub fn reopen(&self) {
+        {
+            // Under the queue lock, as close_with records its reason, so a
+            // close can't land between opening and clearing its reason
+            let _queue = self.lock();
+            *self.close_reason.lock().unwrap() = None;
+            *self.counters.closed_at.lock().unwrap() = None;
+            self.closed.store(false, Ordering::Release);
+            self.counters.poisoned.store(false, Ordering::Relaxed);
+        }
+        debug!("Queue {} reopened", self.name);
+    }
+}
+
+/**
+ An iterator that takes items from a queue, waiting for them as needed,
+ and ends once the queue is closed and empty. From
+ `Queue::into_iter_blocking`.
+ */
+pub struct BlockingIter<T> {
+    queue: Queue<T>,
+    timeout: Duration,
+}
+
+impl<T> Iterator for BlockingIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            match self.queue.dequeue_result(self.timeout) {
+                DequeueResult::Item(v) => return Some(v),
+                DequeueResult::Closed => return None,
+                DequeueResult::Timeout => {}
+            }
+        }
+    }
+}
+
+/**
+ Delivers every item to each of its subscribers, so several consumers can
+ read the same stream independently. Each subscriber has its own queue,
+ bounded and with its own overflow policy, so a slow consumer only loses
+ its own items. Sending never waits for a subscriber: `Block` would let
+ one slow consumer stall the sender, such as the USB callback, and every
+ other subscriber with it, so it is replaced by `DropNewest`. Each
+ subscriber can be closed on its own without affecting the others.
+ Clones share the same subscribers.
+ */
+#[derive(Clone)]
+pub struct Broadcaster<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    subscribers: Arc<Mutex<Vec<Queue<T>>>>,
+}
+
+impl<T: Clone> Broadcaster<T> {
+    pub fn new(capacity: usize) -> Self {
+        Broadcaster::with_overflow_policy(capacity, OverflowPolicy::default())
+    }
+
+    /** Create a broadcaster whose subscribers default to the given overflow policy. */
+    pub fn with_overflow_policy(capacity: usize, policy: OverflowPolicy) -> Self {
+        Broadcaster {
+            capacity,
+            policy: non_blocking(policy),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /** Create a queue that receives every item sent from now on. */
+    pub fn subscribe(&self) -> Queue<T> {
+        self.subscribe_with(self.capacity, self.policy)
+    }
+
+    /**
+     Like `subscribe`, with a capacity and overflow policy for this
+     subscriber alone. A consumer that must see every item it can keep up
+     with, like a disk writer, wants a large queue; a live display would
+     rather lose old items with `DropOldest` than show stale ones.
+     */
+    pub fn subscribe_with(&self, capacity: usize, policy: OverflowPolicy) -> Queue<T> {
+        let queue = Queue::with_overflow_policy(capacity, non_blocking(policy));
+        self.subscribers.lock().unwrap().push(queue.clone());
+        queue
+    }
+
+    /** The number of subscribers still open. */
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().iter().filter(|q| !q.is_closed()).count()
+    }
+
+    /**
+     Send an item to every open subscriber. Returns the number of
+     subscribers that lost an item because they were full.
+     */
+    pub fn send(&self, v: T) -> usize {
+        self.send_all(std::slice::from_ref(&v))
+    }
+
+    /**
+     Send several items to every open subscriber, taking each subscriber's
+     lock once. Subscribers closed by their consumer are dropped. Returns the
+     total number of items lost across subscribers.
+     */
+    pub fn send_all(&self, items: &[T]) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|q| !q.is_closed());
+        subscribers.iter()
+            .map(|q| q.enqueue_all(items.iter().cloned()))
+            .sum()
+    }
+
+    /** Close every subscriber with the given reason. */
+    pub fn close_with(&self, reason: CloseReason) {
+        for q in self.subscribers.lock().unwrap().iter() {
+            q.close_with(reason.clone());
+        }
+    }
+}
+
+/** Broadcast subscribers must never make the sender wait. */
+fn non_blocking(policy: OverflowPolicy) -> OverflowPolicy {
+    if policy == OverflowPolicy::Block {
+        warn!("Broadcast subscribers can't block the sender; dropping new items instead");
+        OverflowPolicy::DropNewest
+    } else {
+        policy
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn queues_are_named() {
+        assert_eq!(Queue::<u8>::named("decoder-out", 1).name(), "decoder-out");
+        assert_eq!(Queue::<u8>::new(1).name(), "");
+        assert_eq!(Queue::<u8>::named("decoder-out", 1).clone().name(), "decoder-out");
+    }
+
+    #[test]
+    fn a_full_queue_applies_its_policy() {
+        let cases = [
+            (OverflowPolicy::DropNewest, vec![0, 1, 2], EnqueueResult::Dropped),
+            (OverflowPolicy::DropOldest, vec![2, 3, 4], EnqueueResult::Evicted),
+        ];
+        for (policy, kept, overflow) in cases.iter() {
+            let q = Queue::with_overflow_policy(3, *policy);
+            assert_eq!(q.overflow_policy(), *policy);
+            let results: Vec<EnqueueResult> = (0..5).map(|v| q.enqueue(v)).collect();
+            assert_eq!(&results[..3], &[EnqueueResult::Queued; 3]);
+            assert_eq!(&results[3..], &[*overflow; 2]);
+            assert_eq!(q.dropped_count(), 2);
+            assert_eq!(q.peek_n(5), *kept);
+        }
+    }
+
+    #[test]
+    fn a_blocked_enqueue_waits_for_room() {
+        let q = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+        q.enqueue(0);
+        let producer = q.clone();
+        let handle = thread::spawn(move || producer.enqueue(1));
+        thread::sleep(Duration::from_millis(20));
+        // Still waiting for the consumer
+        assert_eq!(q.peek_n(2), vec![0]);
+        assert_eq!(q.dequeue(Duration::ZERO), Some(0));
+        assert_eq!(handle.join().unwrap(), EnqueueResult::Queued);
+        assert_eq!(q.dequeue(Duration::ZERO), Some(1));
+        assert_eq!(q.dropped_count(), 0);
+    }
+
+    /**
+     Push `count` items as fast as possible through a queue of `capacity`
+     to a consumer that sleeps between items. Returns what it received.
+     */
+    fn slow_consumer(q: &Queue<usize>, count: usize) -> Vec<usize> {
+        let consumer = q.clone();
+        let handle = thread::spawn(move || {
+            let mut received = Vec::new();
+            loop {
+                match consumer.dequeue_result(Duration::from_millis(10)) {
+                    DequeueResult::Item(v) => {
+                        received.push(v);
+                        thread::sleep(Duration::from_micros(200));
+                    },
+                    DequeueResult::Timeout => {},
+                    DequeueResult::Closed => return received
+                }
+            }
+        });
+        for v in 0..count {
+            q.enqueue(v);
+        }
+        q.close();
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn a_slow_consumer_loses_the_newest_items() {
+        let q = Queue::with_overflow_policy(10, OverflowPolicy::DropNewest);
+        let received = slow_consumer(&q, 200);
+        assert!(q.dropped_count() > 0);
+        assert_eq!(received.len() as u64 + q.dropped_count(), 200);
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        // The first items always fit
+        assert_eq!(&received[..10], &(0..10).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn a_slow_consumer_loses_the_oldest_items() {
+        let q = Queue::with_overflow_policy(10, OverflowPolicy::DropOldest);
+        let received = slow_consumer(&q, 200);
+        assert!(q.dropped_count() > 0);
+        assert_eq!(received.len() as u64 + q.dropped_count(), 200);
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        // The last items are never evicted
+        assert_eq!(&received[received.len() - 10..], &(190..200).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn a_slow_consumer_holds_back_a_blocking_producer() {
+        let q = Queue::with_overflow_policy(10, OverflowPolicy::Block);
+        let received = slow_consumer(&q, 200);
+        assert_eq!(q.dropped_count(), 0);
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+        assert_eq!(q.high_water_mark(), 10);
+    }
+
+    #[test]
+    fn depth_and_capacity_are_reported() {
+        let q = Queue::new(4);
+        assert_eq!(q.capacity(), 4);
+        assert_eq!(Queue::<u8>::new(0).capacity(), 1);
+        q.enqueue_all(0..3);
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.high_water_mark(), 3);
+        q.dequeue_batch(2, Duration::ZERO);
+        assert_eq!(q.len(), 1);
+        // The mark remembers the deepest point, not the current depth
+        assert_eq!(q.high_water_mark(), 3);
+        q.enqueue_all(0..10);
+        assert_eq!(q.high_water_mark(), 4);
+    }
+
+    #[test]
+    fn the_high_water_mark_never_falls_under_concurrent_use() {
+        let q = Queue::new(64);
+        let done = Arc::new(AtomicBool::new(false));
+        let producers: Vec<_> = (0..3).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                for v in 0..5_000 {
+                    q.enqueue(v);
+                }
+            })
+        }).collect();
+        let consumer = {
+            let (q, done) = (q.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) || !q.is_empty() {
+                    q.dequeue_batch(7, Duration::from_millis(1));
+                }
+            })
+        };
+        let watcher = {
+            let (q, done) = (q.clone(), done.clone());
+            thread::spawn(move || {
+                let mut last = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let mark = q.high_water_mark();
+                    assert!(mark >= last, "fell from {} to {}", last, mark);
+                    last = mark;
+                }
+                last
+            })
+        };
+        for p in producers {
+            p.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        consumer.join().unwrap();
+        let last_seen = watcher.join().unwrap();
+        assert!(q.high_water_mark() >= last_seen);
+        assert!(q.high_water_mark() <= q.capacity());
+        assert!(q.high_water_mark() > 0);
+    }
+
+    #[test]
+    fn a_batch_takes_the_lock_once() {
+        let q = Queue::new(1024);
+        let before = q.lock_count();
+        assert_eq!(q.enqueue_all((0..384).map(|i| (i as f32, 0.0))), 0);
+        assert_eq!(q.lock_count() - before, 1);
+        assert_eq!(q.len(), 384);
+        let before = q.lock_count();
+        for i in 0..384 {
+            q.enqueue((i as f32, 0.0));
+        }
+        assert_eq!(q.lock_count() - before, 384);
+    }
+
+    #[test]
+    fn a_batch_applies_the_policy_to_each_item() {
+        let q = Queue::with_overflow_policy(4, OverflowPolicy::DropNewest);
+        assert_eq!(q.enqueue_all(0..6), 2);
+        assert_eq!(q.peek_n(6), vec![0, 1, 2, 3]);
+        let q = Queue::with_overflow_policy(4, OverflowPolicy::DropOldest);
+        assert_eq!(q.enqueue_all(0..6), 2);
+        assert_eq!(q.peek_n(6), vec![2, 3, 4, 5]);
+        assert_eq!(q.dropped_count(), 2);
+    }
+
+    #[test]
+    fn a_blocked_batch_lets_the_consumer_see_what_it_pushed() {
+        let q = Queue::with_overflow_policy(2, OverflowPolicy::Block);
+        let producer = q.clone();
+        let handle = thread::spawn(move || producer.enqueue_all(0..10));
+        let received: Vec<i32> = (0..10).map(|_| q.dequeue(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(handle.join().unwrap(), 0);
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_dequeue_returns_at_once_when_empty() {
+        let q = Queue::<u32>::new(8);
+        assert_eq!(q.try_dequeue(), None);
+        assert_eq!(q.try_dequeue_batch(4), Vec::<u32>::new());
+        q.enqueue_all(0..5);
+        assert_eq!(q.try_dequeue(), Some(0));
+        assert_eq!(q.try_dequeue_batch(3), vec![1, 2, 3]);
+        assert_eq!(q.try_dequeue_batch(3), vec![4]);
+        assert_eq!(q.stats().dequeued, 5);
+    }
+
+    #[test]
+    fn try_dequeue_drains_a_closed_queue() {
+        let q = Queue::new(8);
+        q.enqueue_all(0..3);
+        q.close();
+        assert_eq!(q.try_dequeue(), Some(0));
+        assert_eq!(q.try_dequeue_batch(8), vec![1, 2]);
+        assert_eq!(q.try_dequeue(), None);
+        assert!(q.is_closed());
+    }
+
+    #[test]
+    fn try_dequeue_never_waits_for_the_lock() {
+        let q = Queue::new(8);
+        q.enqueue(1);
+        let guard = q.peek().unwrap();
+        let other = q.clone();
+        let started = Instant::now();
+        let (one, batch) = thread::spawn(move || (other.try_dequeue(), other.try_dequeue_batch(8)))
+            .join().unwrap();
+        assert_eq!((one, batch), (None, vec![]));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(guard);
+        assert_eq!(q.try_dequeue(), Some(1));
+    }
+
+    #[test]
+    fn try_dequeue_keeps_up_with_concurrent_producers() {
+        let q = Queue::with_overflow_policy(256, OverflowPolicy::Block);
+        let producers: Vec<_> = (0..4u32).map(|p| {
+            let q = q.clone();
+            thread::spawn(move || {
+                for v in 0..2_500 {
+                    q.enqueue((p, v));
+                }
+            })
+        }).collect();
+        let mut received = Vec::new();
+        let mut last_mark = 0;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received.len() < 10_000 && Instant::now() < deadline {
+            match q.try_dequeue() {
+                Some(v) => received.push(v),
+                None => received.extend(q.try_dequeue_batch(16)),
+            }
+            // Taking items never lowers the mark
+            let mark = q.high_water_mark();
+            assert!(mark >= last_mark);
+            last_mark = mark;
+        }
+        for p in producers {
+            p.join().unwrap();
+        }
+        assert_eq!(received.len(), 10_000);
+        for p in 0..4 {
+            let mine: Vec<u32> = received.iter().filter(|(from, _)| *from == p).map(|(_, v)| *v).collect();
+            assert_eq!(mine, (0..2_500).collect::<Vec<_>>());
+        }
+    }
+
+    /** How long the waits woken by a close would otherwise block. */
+    const LONG_WAIT: Duration = Duration::from_secs(10);
+
+    /**
+     Check a wait ended by a close ended promptly: well within LONG_WAIT,
+     so it was the close that woke it, with room left for a loaded machine.
+     */
+    fn assert_woken_by_close(latency: Duration) {
+        assert!(latency < LONG_WAIT / 10, "woke {:?} after the close", latency);
+    }
+
+    /** Run `wait` on another thread, close the queue, and return how long the wait outlived the close. */
+    fn woken_by_close<R: Send + 'static>(q: &Queue<u32>, wait: impl FnOnce(Queue<u32>) -> R + Send + 'static) -> (R, Duration) {
+        let waiter = q.clone();
+        let handle = thread::spawn(move || {
+            let result = wait(waiter);
+            (result, Instant::now())
+        });
+        thread::sleep(Duration::from_millis(50));
+        let closed = Instant::now();
+        q.close();
+        let (result, woke) = handle.join().unwrap();
+        (result, woke.saturating_duration_since(closed))
+    }
+
+    #[test]
+    fn close_wakes_a_blocked_dequeue_at_once() {
+        let q = Queue::new(8);
+        let (result, latency) = woken_by_close(&q, |q| q.dequeue_result(LONG_WAIT));
+        assert_eq!(result, DequeueResult::Closed);
+        assert_woken_by_close(latency);
+    }
+
+    #[test]
+    fn close_wakes_every_kind_of_waiter() {
+        let q = Queue::new(8);
+        let (n, latency) = woken_by_close(&q, |q| q.drain_into(&mut Vec::new(), 8, LONG_WAIT));
+        assert_eq!(n, 0);
+        assert_woken_by_close(latency);
+
+        let q = Queue::new(8);
+        q.enqueue_all(0..8);
+        let (below, latency) = woken_by_close(&q, |q| q.wait_below(4, LONG_WAIT));
+        assert!(!below);
+        assert_woken_by_close(latency);
+
+        let q = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+        q.enqueue(0);
+        let (result, latency) = woken_by_close(&q, |q| q.enqueue(1));
+        assert_eq!(result, EnqueueResult::Dropped);
+        assert_woken_by_close(latency);
+    }
+
+    #[test]
+    fn close_takes_the_lock_once_through_a_shared_reference() {
+        let q = Queue::<u32>::new(8);
+        let shared: &Queue<u32> = &q;
+        let before = q.lock_count();
+        shared.close();
+        assert_eq!(q.lock_count() - before, 1);
+        assert!(q.is_closed());
+    }
+
+    #[test]
+    fn stats_count_every_item() {
+        let q = Queue::with_overflow_policy(4, OverflowPolicy::DropNewest);
+        assert_eq!(q.stats(), QueueStats::default());
+        q.enqueue_all(0..6);
+        q.dequeue(Duration::ZERO);
+        q.try_dequeue_batch(2);
+        q.enqueue_all(0..2);
+        q.clear();
+        let stats = q.stats();
+        assert_eq!(stats.enqueued, 6);
+        assert_eq!(stats.dequeued, 3);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.cleared, 3);
+        assert_eq!(stats.high_water_mark, 4);
+        assert_eq!(stats.closed_at, None);
+        let before = Instant::now();
+        q.close();
+        q.close();
+        let closed_at = q.stats().closed_at.unwrap();
+        assert!(closed_at >= before);
+        // Only the first close is recorded
+        assert_eq!(q.stats().closed_at, Some(closed_at));
+    }
+
+    #[test]
+    fn evictions_are_counted_as_drops() {
+        let q = Queue::with_overflow_policy(2, OverflowPolicy::DropOldest);
+        q.enqueue_all(0..5);
+        let stats = q.stats();
+        assert_eq!((stats.enqueued, stats.dropped), (5, 3));
+        assert_eq!(q.len(), 2);
+    }
+
+    #[test]
+    fn stats_balance_under_concurrent_use() {
+        let q = Queue::with_overflow_policy(32, OverflowPolicy::DropNewest);
+        let producers: Vec<_> = (0..4).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                for v in 0..10_000 {
+                    q.enqueue(v);
+                }
+            })
+        }).collect();
+        let consumer = {
+            let q = q.clone();
+            thread::spawn(move || {
+                let mut taken = 0u64;
+                while !q.is_closed() || !q.is_empty() {
+                    taken += q.dequeue_batch(8, Duration::from_millis(1)).len() as u64;
+                }
+                taken
+            })
+        };
+        for p in producers {
+            p.join().unwrap();
+        }
+        q.close();
+        let taken = consumer.join().unwrap();
+        let stats = q.stats();
+        // Every item offered was either queued or dropped, and every queued one taken
+        assert_eq!(stats.enqueued + stats.dropped, 40_000);
+        assert_eq!(stats.dequeued, taken);
+        assert_eq!(stats.enqueued, stats.dequeued);
+    }
+
+    #[test]
+    fn the_iterator_yields_what_is_left_then_ends_after_close() {
+        let q = Queue::new(8);
+        q.enqueue_all(0..5);
+        q.close();
+        let started = Instant::now();
+        let received: Vec<u32> = q.iter_blocking(Duration::from_secs(10)).collect();
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+        // Closed and drained, so it ended without waiting out the timeout
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn the_iterator_waits_for_items_until_closed() {
+        let q = Queue::with_overflow_policy(4, OverflowPolicy::Block);
+        let consumer = q.clone();
+        // A short timeout, so the iterator has to keep waiting across timeouts
+        let handle = thread::spawn(move || consumer.into_iter_blocking(Duration::from_millis(1)).collect::<Vec<_>>());
+        for v in 0..100 {
+            q.enqueue(v);
+            if v % 10 == 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        q.close();
+        assert_eq!(handle.join().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_iterator_works_with_adapters() {
+        let q = Queue::new(16);
+        q.enqueue_all((0..10).map(|i| (i as f32, -(i as f32))));
+        q.close();
+        let power: f32 = q.iter_blocking(Duration::from_millis(10))
+            .take_while(|(i, _)| *i < 5.0)
+            .map(|(i, q)| i * i + q * q)
+            .sum();
+        assert_eq!(power, 60.0);
+    }
+
+    #[test]
+    fn peeking_an_empty_queue_finds_nothing() {
+        let q = Queue::<u32>::new(4);
+        assert!(q.peek().is_none());
+        assert_eq!(q.peek_cloned(), None);
+        assert_eq!(q.peek_n(3), Vec::<u32>::new());
+        q.close();
+        assert!(q.peek().is_none());
+        assert_eq!(q.peek_n(3), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn peeking_leaves_the_items_queued() {
+        let q = Queue::new(8);
+        q.enqueue_all(0..5);
+        assert_eq!(*q.peek().unwrap(), 0);
+        assert_eq!(q.peek_cloned(), Some(0));
+        assert_eq!(q.peek_n(3), vec![0, 1, 2]);
+        assert_eq!(q.peek_n(10), vec![0, 1, 2, 3, 4]);
+        assert_eq!(q.peek_n(0), Vec::<u32>::new());
+        assert_eq!(q.len(), 5);
+        assert_eq!(q.stats().dequeued, 0);
+        assert_eq!(q.dequeue_batch(5, Duration::ZERO), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_peek_is_a_snapshot_a_concurrent_consumer_can_overtake() {
+        let q = Queue::with_overflow_policy(64, OverflowPolicy::Block);
+        let producer = {
+            let q = q.clone();
+            thread::spawn(move || {
+                for v in 0..20_000u32 {
+                    q.enqueue(v);
+                }
+                q.close();
+            })
+        };
+        let consumer = {
+            let q = q.clone();
+            thread::spawn(move || q.iter_blocking(Duration::from_millis(10)).collect::<Vec<_>>())
+        };
+        let mut last = 0;
+        while !q.is_closed() {
+            let peeked = q.peek_n(4);
+            // Always a run of consecutive items from the front, never behind what was seen before
+            assert!(peeked.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", peeked);
+            if let Some(&first) = peeked.first() {
+                assert!(first >= last, "{} after {}", first, last);
+                last = first;
+            }
+            if let Some(front) = q.peek() {
+                assert!(*front >= last);
+            }
+        }
+        producer.join().unwrap();
+        // Peeking took nothing away from the consumer
+        assert_eq!(consumer.join().unwrap(), (0..20_000).collect::<Vec<_>>());
+    }
+
+    /** An item tagged with the producer that sent it. */
+    type Tagged = (u32, u32);
+
+    /**
+     Run producers and consumers on a queue with the given policy, each
+     producer sending `(producer, sequence)` pairs. Returns what each
+     consumer received, in order, once the queue is closed and drained.
+     */
+    fn many_to_many(policy: OverflowPolicy, producers: u32, consumers: usize, per_producer: u32) -> (Queue<Tagged>, Vec<Vec<Tagged>>) {
+        let q = Queue::with_overflow_policy(16, policy);
+        let consumers: Vec<_> = (0..consumers).map(|c| {
+            let q = q.clone();
+            thread::spawn(move || {
+                let mut received = Vec::new();
+                loop {
+                    // Mix single and batched takes, which hold the lock differently
+                    let closed = if c % 2 == 0 {
+                        match q.dequeue_result(Duration::from_millis(5)) {
+                            DequeueResult::Item(v) => {
+                                received.push(v);
+                                false
+                            },
+                            result => result == DequeueResult::Closed
+                        }
+                    } else {
+                        q.drain_result(&mut received, 5, Duration::from_millis(5)) == DequeueResult::Closed
+                    };
+                    if closed {
+                        return received;
+                    }
+                }
+            })
+        }).collect();
+        let producers: Vec<_> = (0..producers).map(|p| {
+            let q = q.clone();
+            thread::spawn(move || {
+                for v in 0..per_producer {
+                    q.enqueue((p, v));
+                }
+            })
+        }).collect();
+        for p in producers {
+            p.join().unwrap();
+        }
+        q.close();
+        let received = consumers.into_iter().map(|c| c.join().unwrap()).collect();
+        (q, received)
+    }
+
+    #[test]
+    fn every_item_is_received_once_or_counted_as_dropped() {
+        for &policy in &[OverflowPolicy::Block, OverflowPolicy::DropNewest, OverflowPolicy::DropOldest] {
+            let (q, received) = many_to_many(policy, 4, 3, 5_000);
+            let all: Vec<Tagged> = received.iter().flatten().copied().collect();
+            let unique: std::collections::HashSet<_> = all.iter().collect();
+            assert_eq!(unique.len(), all.len(), "{:?}: an item was received twice", policy);
+            assert_eq!(all.len() as u64 + q.dropped_count(), 20_000, "{:?}", policy);
+            assert!(q.is_empty());
+            if policy == OverflowPolicy::Block {
+                assert_eq!(q.dropped_count(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn each_consumer_sees_each_producer_in_order() {
+        for &policy in &[OverflowPolicy::Block, OverflowPolicy::DropNewest, OverflowPolicy::DropOldest] {
+            let (_, received) = many_to_many(policy, 4, 3, 5_000);
+            for (c, items) in received.iter().enumerate() {
+                for p in 0..4 {
+                    let mine: Vec<u32> = items.iter().filter(|(from, _)| *from == p).map(|(_, v)| *v).collect();
+                    assert!(mine.windows(2).all(|w| w[0] < w[1]),
+                            "{:?}: consumer {} got producer {} out of order", policy, c, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn close_wakes_many_waiters_promptly() {
+        for _ in 0..20 {
+            let empty = Queue::<u32>::new(4);
+            let full = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+            full.enqueue(0);
+            let consumers: Vec<_> = (0..6).map(|_| {
+                let q = empty.clone();
+                thread::spawn(move || (q.dequeue_result(Duration::from_secs(10)) == DequeueResult::Closed, Instant::now()))
+            }).collect();
+            let producers: Vec<_> = (0..3).map(|v| {
+                let q = full.clone();
+                thread::spawn(move || (q.enqueue(v) == EnqueueResult::Dropped, Instant::now()))
+            }).collect();
+            thread::sleep(Duration::from_millis(20));
+            let closed = Instant::now();
+            empty.close();
+            full.close();
+            for handle in consumers.into_iter().chain(producers) {
+                let (woken_by_close, at) = handle.join().unwrap();
+                assert!(woken_by_close);
+                let latency = at.saturating_duration_since(closed);
+                assert!(latency < Duration::from_millis(100), "{:?}", latency);
+            }
+        }
+    }
+
+    #[test]
+    fn clear_discards_and_counts_what_was_queued() {
+        let q = Queue::new(8);
+        assert_eq!(q.clear(), 0);
+        q.enqueue_all(0..5);
+        assert_eq!(q.clear(), 5);
+        assert!(q.is_empty());
+        assert_eq!(q.stats().cleared, 5);
+        assert_eq!(q.stats().dequeued, 0);
+        // The queue carries on as before
+        q.enqueue(7);
+        assert_eq!(q.dequeue(Duration::ZERO), Some(7));
+    }
+
+    #[test]
+    fn clear_makes_room_for_a_blocked_producer() {
+        let q = Queue::with_overflow_policy(2, OverflowPolicy::Block);
+        q.enqueue_all(0..2);
+        let producer = q.clone();
+        let handle = thread::spawn(move || producer.enqueue(2));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(q.clear(), 2);
+        assert_eq!(handle.join().unwrap(), EnqueueResult::Queued);
+        assert_eq!(q.peek_n(2), vec![2]);
+    }
+
+    #[test]
+    fn a_reopened_queue_is_open_again() {
+        let q = Queue::new(8);
+        q.enqueue_all(0..3);
+        q.close_with(CloseReason::Cancelled);
+        q.reopen();
+        assert!(!q.is_closed());
+        assert_eq!(q.close_reason(), None);
+        assert_eq!(q.stats().closed_at, None);
+        // Items queued before the close are kept, and the counters keep running
+        assert_eq!(q.dequeue_batch(8, Duration::ZERO), vec![0, 1, 2]);
+        assert_eq!(q.stats().enqueued, 3);
+        assert_eq!(q.dequeue_result(Duration::from_millis(10)), DequeueResult::Timeout);
+        q.enqueue(3);
+        assert_eq!(q.dequeue(Duration::ZERO), Some(3));
+        // The next close records its own reason
+        q.close_with(CloseReason::Finished);
+        assert_eq!(q.close_reason(), Some(CloseReason::Finished));
+        assert_eq!(q.dequeue_result(Duration::from_secs(10)), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn a_dequeue_blocked_across_close_and_reopen_returns_closed() {
+        for _ in 0..20 {
+            let q = Queue::<u32>::new(8);
+            let consumer = q.clone();
+            let handle = thread::spawn(move || consumer.dequeue_result(Duration::from_secs(10)));
+            thread::sleep(Duration::from_millis(10));
+            // Reopened at once, so the consumer usually wakes to an open queue
+            q.close();
+            q.reopen();
+            let started = Instant::now();
+            assert_eq!(handle.join().unwrap(), DequeueResult::Closed);
+            assert!(started.elapsed() < Duration::from_secs(1));
+            // A wait that starts after the reopen behaves as on a new queue
+            assert_eq!(q.dequeue_result(Duration::from_millis(1)), DequeueResult::Timeout);
+            q.enqueue(1);
+            assert_eq!(q.dequeue_result(Duration::from_secs(1)), DequeueResult::Item(1));
+        }
+    }
+
+    #[test]
+    fn every_waiter_blocked_across_close_and_reopen_returns() {
+        let q = Queue::<u32>::new(8);
+        let consumer = q.clone();
+        let dequeue = thread::spawn(move || consumer.dequeue(Duration::from_secs(10)));
+        let batch = {
+            let q = q.clone();
+            thread::spawn(move || q.drain_result(&mut Vec::new(), 8, Duration::from_secs(10)))
+        };
+        let full = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+        full.enqueue(0);
+        let producer = full.clone();
+        let enqueue = thread::spawn(move || producer.enqueue(1));
+        thread::sleep(Duration::from_millis(20));
+        q.close();
+        q.reopen();
+        full.close();
+        full.reopen();
+        assert_eq!(dequeue.join().unwrap(), None);
+        assert_eq!(batch.join().unwrap(), DequeueResult::Closed);
+        // The blocked producer drops its item even though the queue is open again
+        assert_eq!(enqueue.join().unwrap(), EnqueueResult::Dropped);
+        assert_eq!(full.peek_n(2), vec![0]);
+        assert_eq!(full.dropped_count(), 1);
+    }
+
+    #[test]
+    fn two_blocked_consumers_both_keep_up_with_a_burst() {
+        let q = Queue::with_overflow_policy(64, OverflowPolicy::Block);
+        let consumers: Vec<_> = (0..2).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                let mut received = Vec::new();
+                // A consumer left asleep would time out here long before the producer finishes
+                while let DequeueResult::Item(v) = q.dequeue_result(Duration::from_secs(5)) {
+                    received.push(v);
+                }
+                received
+            })
+        }).collect();
+        // Let both consumers block on the empty queue first
+        thread::sleep(Duration::from_millis(20));
+        let started = Instant::now();
+        // Bursts larger than the queue, so most pushes find it already holding items
+        for burst in 0..100 {
+            assert_eq!(q.enqueue_all(burst * 100..(burst + 1) * 100), 0);
+        }
+        q.close();
+        let received: Vec<Vec<u32>> = consumers.into_iter().map(|c| c.join().unwrap()).collect();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(received.iter().all(|r| !r.is_empty()), "a consumer made no progress");
+        let mut all: Vec<u32> = received.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..10_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_consumer_that_loses_the_race_keeps_waiting() {
+        let q = Queue::new(8);
+        let consumers: Vec<_> = (0..2).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                (q.dequeue_result(Duration::from_millis(200)), started.elapsed())
+            })
+        }).collect();
+        thread::sleep(Duration::from_millis(20));
+        q.enqueue(1);
+        let mut results: Vec<_> = consumers.into_iter().map(|c| c.join().unwrap()).collect();
+        results.sort_by_key(|(_, waited)| *waited);
+        // Both were woken, but the empty queue sent the loser back to sleep until its timeout
+        assert_eq!(results[0].0, DequeueResult::Item(1));
+        assert_eq!(results[1].0, DequeueResult::Timeout);
+        assert!(results[1].1 >= Duration::from_millis(200));
     }
 
-    pub fn close(&mut self) {
-        self.closed.swap(true, Ordering::Relaxed);
-        println!("Queue closed");
+    #[test]
+    fn closing_wakes_every_blocked_consumer() {
+        let q = Queue::<u32>::new(8);
+        let consumers: Vec<_> = (0..4).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || q.dequeue_result(Duration::from_secs(10)))
+        }).collect();
+        thread::sleep(Duration::from_millis(20));
+        let closed = Instant::now();
+        q.close();
+        for c in consumers {
+            assert_eq!(c.join().unwrap(), DequeueResult::Closed);
+        }
+        assert!(closed.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn dequeue_result_tells_a_timeout_from_a_close() {
+        let q = Queue::new(8);
+        assert_eq!(q.dequeue_result(Duration::from_millis(1)), DequeueResult::Timeout);
+        assert_eq!(q.drain_result(&mut Vec::new(), 8, Duration::from_millis(1)), DequeueResult::Timeout);
+        q.enqueue_all(0..3);
+        q.close();
+        // What was queued before the close comes first
+        assert_eq!(q.dequeue_result(Duration::from_secs(10)), DequeueResult::Item(0));
+        let mut rest = Vec::new();
+        assert_eq!(q.drain_result(&mut rest, 8, Duration::from_secs(10)), DequeueResult::Item(2));
+        assert_eq!(rest, vec![1, 2]);
+        assert_eq!(q.dequeue_result(Duration::from_secs(10)), DequeueResult::Closed);
+        assert_eq!(q.drain_result(&mut rest, 8, Duration::from_secs(10)), DequeueResult::Closed);
+        // The Option wrapper can't tell them apart
+        assert_eq!(q.dequeue(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn a_consumer_racing_a_close_gets_everything_sent_before_it() {
+        for round in 0..200u32 {
+            let q = Queue::new(4);
+            let consumer = q.clone();
+            let handle = thread::spawn(move || {
+                let mut received = Vec::new();
+                loop {
+                    match consumer.dequeue_result(Duration::from_secs(10)) {
+                        DequeueResult::Item(v) => received.push(v),
+                        DequeueResult::Closed => return received,
+                        DequeueResult::Timeout => panic!("the close was missed"),
+                    }
+                }
+            });
+            // Sometimes the consumer is blocked on an empty queue at the close, sometimes not
+            let sent = round % 4;
+            q.enqueue_all(0..sent);
+            q.close();
+            assert_eq!(handle.join().unwrap(), (0..sent).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn a_slow_batch_consumer_sees_each_policy_until_closed() {
+        for &policy in &[OverflowPolicy::Block, OverflowPolicy::DropNewest, OverflowPolicy::DropOldest] {
+            let q = Queue::with_overflow_policy(10, policy);
+            let consumer = q.clone();
+            let handle = thread::spawn(move || {
+                let mut received = Vec::new();
+                while consumer.drain_result(&mut received, 4, Duration::from_millis(10)) != DequeueResult::Closed {
+                    thread::sleep(Duration::from_micros(500));
+                }
+                received
+            });
+            for v in 0..300 {
+                q.enqueue(v);
+            }
+            q.close();
+            let received = handle.join().unwrap();
+            assert_eq!(received.len() as u64 + q.dropped_count(), 300, "{:?}", policy);
+            assert!(received.windows(2).all(|w| w[0] < w[1]), "{:?}", policy);
+            match policy {
+                OverflowPolicy::Block => assert_eq!(received, (0..300).collect::<Vec<_>>()),
+                OverflowPolicy::DropNewest => assert_eq!(received[0], 0),
+                OverflowPolicy::DropOldest => assert_eq!(received.last(), Some(&299)),
+            }
+            if policy != OverflowPolicy::Block {
+                assert!(q.dropped_count() > 0, "{:?}", policy);
+            }
+        }
+    }
+
+    /** Panic on another thread while holding the queue's lock. */
+    fn poison<T: Send + 'static>(q: &Queue<T>) {
+        let q = q.clone();
+        let panicked = thread::spawn(move || {
+            let _front = q.peek().expect("an item to hold the lock by");
+            panic!("panicking while holding the queue's lock");
+        }).join();
+        assert!(panicked.is_err());
+    }
+
+    #[test]
+    fn a_poisoned_queue_can_still_be_drained() {
+        let q = Queue::new(8);
+        q.enqueue_all(0..5);
+        poison(&q);
+        // Nothing is lost; the consumer takes what is left and sees why it ended
+        let received: Vec<u32> = q.iter_blocking(Duration::from_secs(10)).collect();
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+        assert_eq!(q.dequeue_result(Duration::from_secs(10)), DequeueResult::Closed);
+        assert_eq!(q.close_reason(), Some(CloseReason::Poisoned));
+        assert!(q.is_closed());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn a_producer_is_told_the_queue_is_poisoned() {
+        let q = Queue::new(8);
+        q.enqueue(0);
+        poison(&q);
+        assert_eq!(q.enqueue(1), EnqueueResult::Poisoned);
+        assert_eq!(q.enqueue_all(2..4), 2);
+        assert!(!q.try_enqueue(4));
+        assert_eq!(q.peek_n(8), vec![0]);
+        // Refused items are counted as dropped, so the counters still balance
+        let stats = q.stats();
+        assert_eq!((stats.enqueued, stats.dropped), (1, 3));
+        // A later close doesn't hide the cause
+        q.close_with(CloseReason::Finished);
+        assert_eq!(q.close_reason(), Some(CloseReason::Poisoned));
+    }
+
+    #[test]
+    fn a_waiter_sees_the_poisoning() {
+        let q = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+        q.enqueue(0);
+        let producer = q.clone();
+        let blocked = thread::spawn(move || producer.enqueue(1));
+        thread::sleep(Duration::from_millis(20));
+        poison(&q);
+        // Poisoning doesn't wake the producer itself, but anything touching the queue does
+        assert_eq!(q.dequeue(Duration::ZERO), Some(0));
+        assert_eq!(blocked.join().unwrap(), EnqueueResult::Poisoned);
+        assert_eq!(q.dequeue_result(Duration::from_secs(10)), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn a_poisoned_queue_can_be_reopened() {
+        let q = Queue::new(8);
+        q.enqueue(0);
+        poison(&q);
+        q.reopen();
+        assert_eq!(q.close_reason(), None);
+        assert_eq!(q.enqueue(1), EnqueueResult::Queued);
+        assert_eq!(q.dequeue_batch(8, Duration::ZERO), vec![0, 1]);
+    }
+
+    #[cfg(feature = "instrument")]
+    mod hooks {
+        use super::*;
+
+        #[derive(Debug, PartialEq)]
+        enum Call {
+            Enqueue(String, usize, usize),
+            Dequeue(String, usize, usize),
+            Close(String),
+        }
+
+        /** Records every callback, in order. */
+        #[derive(Default)]
+        struct CountingHooks {
+            calls: Mutex<Vec<Call>>,
+        }
+
+        impl QueueHooks for CountingHooks {
+            fn on_enqueue(&self, name: &str, len_after: usize, batch: usize) {
+                self.calls.lock().unwrap().push(Call::Enqueue(name.to_string(), len_after, batch));
+            }
+
+            fn on_dequeue(&self, name: &str, len_after: usize, batch: usize) {
+                self.calls.lock().unwrap().push(Call::Dequeue(name.to_string(), len_after, batch));
+            }
+
+            fn on_close(&self, name: &str) {
+                self.calls.lock().unwrap().push(Call::Close(name.to_string()));
+            }
+        }
+
+        #[test]
+        fn callbacks_match_operations() {
+            let hooks = Arc::new(CountingHooks::default());
+            let q = Queue::with_hooks("decoder-out", 10, hooks.clone());
+            let wait = Duration::from_millis(1);
+            q.enqueue(1);
+            q.enqueue_all(vec![2, 3, 4]);
+            assert!(q.try_enqueue(5));
+            assert_eq!(q.dequeue(wait), Some(1));
+            assert_eq!(q.dequeue_batch(2, wait), vec![2, 3]);
+            assert_eq!(q.try_dequeue(), Some(4));
+            assert_eq!(q.peek_and_dequeue(wait, |_| true), Some(5));
+            // Nothing moved, so nothing is reported
+            assert_eq!(q.dequeue(wait), None);
+            assert_eq!(q.try_dequeue_batch(3), Vec::<i32>::new());
+            q.enqueue_all(Vec::new());
+            q.close();
+            let name = || "decoder-out".to_string();
+            assert_eq!(*hooks.calls.lock().unwrap(), vec![
+                Call::Enqueue(name(), 1, 1),
+                Call::Enqueue(name(), 4, 3),
+                Call::Enqueue(name(), 5, 1),
+                Call::Dequeue(name(), 4, 1),
+                Call::Dequeue(name(), 2, 2),
+                Call::Dequeue(name(), 1, 1),
+                Call::Dequeue(name(), 0, 1),
+                Call::Close(name()),
+            ]);
+        }
+
+        #[test]
+        fn clones_report_to_the_same_hooks() {
+            let hooks = Arc::new(CountingHooks::default());
+            let q = Queue::with_hooks("shared", 10, hooks.clone());
+            q.clone().enqueue(1);
+            q.clone().close();
+            assert_eq!(hooks.calls.lock().unwrap().len(), 2);
+        }
+    }
+}
+
+/**
+ Model checks of the queue's core interleavings, which try every order
+ the threads could run in. Run them with
+ `RUSTFLAGS="--cfg loom" cargo test --release --lib queue::model`.
+ */
+#[cfg(all(test, loom))]
+mod model {
+    use super::*;
+    use loom::thread;
+
+    /** Take items until the queue is closed. A lost wake-up hangs here, which loom reports. */
+    fn drain(q: &Queue<u32>) -> Vec<u32> {
+        let mut received = Vec::new();
+        loop {
+            match q.dequeue_result(Duration::from_secs(1)) {
+                DequeueResult::Item(v) => received.push(v),
+                DequeueResult::Closed => return received,
+                DequeueResult::Timeout => unreachable!("loom waits never time out"),
+            }
+        }
+    }
+
+    #[test]
+    fn items_sent_before_close_are_received() {
+        loom::model(|| {
+            let q = Queue::new(2);
+            let producer = q.clone();
+            let handle = thread::spawn(move || {
+                producer.enqueue(1);
+                producer.enqueue(2);
+                producer.close();
+            });
+            assert_eq!(drain(&q), vec![1, 2]);
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn close_wakes_a_waiting_consumer() {
+        loom::model(|| {
+            let q = Queue::<u32>::new(1);
+            let closer = q.clone();
+            let handle = thread::spawn(move || closer.close());
+            assert_eq!(q.dequeue_result(Duration::from_secs(1)), DequeueResult::Closed);
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn a_close_racing_a_reopen_keeps_its_reason() {
+        loom::model(|| {
+            let q = Queue::<u32>::new(1);
+            q.close();
+            let closer = q.clone();
+            let handle = thread::spawn(move || closer.close_with(CloseReason::Cancelled));
+            q.reopen();
+            handle.join().unwrap();
+            match q.close_reason() {
+                Some(reason) => assert!(q.is_closed(), "open, but closed as {:?}", reason),
+                None => assert!(!q.is_closed(), "closed without a reason"),
+            }
+        });
+    }
+
+    #[test]
+    fn a_blocked_producer_queues_or_drops_on_close() {
+        loom::model(|| {
+            let q = Queue::with_overflow_policy(1, OverflowPolicy::Block);
+            q.enqueue(0);
+            let producer = q.clone();
+            let blocked = thread::spawn(move || producer.enqueue(1));
+            let closer = q.clone();
+            let closing = thread::spawn(move || closer.close());
+            let mut received = vec![q.dequeue(Duration::from_secs(1)).unwrap()];
+            let result = blocked.join().unwrap();
+            closing.join().unwrap();
+            received.extend(q.try_dequeue());
+            match result {
+                EnqueueResult::Queued => assert_eq!(received, vec![0, 1]),
+                EnqueueResult::Dropped => assert_eq!((received, q.dropped_count()), (vec![0], 1)),
+                EnqueueResult::Evicted | EnqueueResult::Poisoned => panic!("{:?} from a blocking queue", result),
+            }
+        });
+    }
+
+    #[test]
+    fn two_producers_each_keep_their_order() {
+        // Three threads and a tiny queue are already too many orders to try exhaustively
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let q = Queue::with_overflow_policy(1, OverflowPolicy::DropOldest);
+            let other = {
+                let q = q.clone();
+                thread::spawn(move || {
+                    q.enqueue(10);
+                    q.enqueue(11);
+                })
+            };
+            let consumer = {
+                let q = q.clone();
+                thread::spawn(move || drain(&q))
+            };
+            q.enqueue(0);
+            q.enqueue(1);
+            other.join().unwrap();
+            q.close();
+            let received = consumer.join().unwrap();
+            assert_eq!(received.len() as u64 + q.dropped_count(), 4);
+            for producer in [0, 1].iter() {
+                let mine: Vec<u32> = received.iter().copied().filter(|v| v / 10 == *producer).collect();
+                assert!(mine.windows(2).all(|w| w[0] < w[1]), "{:?}", received);
+            }
+        });
+    }
+}
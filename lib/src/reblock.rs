@@ -0,0 +1,197 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::queue::{DequeueResult, Queue};
+use std::time::{Duration, Instant};
+
+/** A run of consecutive samples. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block<T> {
+    /** The index of the first sample in the stream. */
+    pub index: u64,
+    pub samples: Vec<T>,
+    /** True for the final block, which may be shorter than the block size. */
+    pub last: bool,
+}
+
+/**
+ Repackages the samples on a queue into blocks of a fixed size, whatever
+ size the transfers that produced them were. Each consumer can use its own
+ Reblocker with its own block size.
+ */
+pub struct Reblocker<T> {
+    queue: Queue<T>,
+    block_size: usize,
+    pending: Vec<T>,
+    next_index: u64,
+    finished: bool,
+}
+
+impl<T> Reblocker<T> {
+    /** Panics if the block size is zero. */
+    pub fn new(queue: Queue<T>, block_size: usize) -> Self {
+        assert!(block_size > 0, "block size must be at least one sample");
+        Reblocker {
+            queue,
+            block_size,
+            pending: Vec::with_capacity(block_size),
+            next_index: 0,
+            finished: false,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /** True once the queue is closed and every sample has been returned. */
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /**
+     Return the next full block, waiting up to the timeout for it to fill.
+     Returns None on timeout, keeping the samples read so far for the next
+     call. Once the queue is closed and drained, the remaining samples are
+     returned as a short block marked last.
+     */
+    pub fn next_block(&mut self, timeout: Duration) -> Option<Block<T>> {
+        if self.finished {
+            return None;
+        }
+        let deadline = Instant::now() + timeout;
+        while self.pending.len() < self.block_size {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.queue.dequeue_result(remaining) {
+                DequeueResult::Item(v) => self.pending.push(v),
+                DequeueResult::Closed => {
+                    self.finished = true;
+                    if self.pending.is_empty() {
+                        return None;
+                    }
+                    return Some(self.take(true));
+                },
+                DequeueResult::Timeout if remaining.is_zero() => return None,
+                DequeueResult::Timeout => {}
+            }
+        }
+        Some(self.take(false))
+    }
+
+    fn take(&mut self, last: bool) -> Block<T> {
+        let samples = std::mem::replace(&mut self.pending, Vec::with_capacity(self.block_size));
+        let index = self.next_index;
+        self.next_index += samples.len() as u64;
+        Block { index, samples, last }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(10);
+
+    /** Enqueue `0..total` in chunks of the given sizes, cycling through them. */
+    fn feed(queue: &Queue<u64>, total: u64, chunks: &[usize]) {
+        let mut next = 0;
+        for &chunk in chunks.iter().cycle() {
+            let end = (next + chunk as u64).min(total);
+            queue.enqueue_all(next..end);
+            next = end;
+            if next == total {
+                break;
+            }
+        }
+    }
+
+    fn collect(reblocker: &mut Reblocker<u64>) -> Vec<Block<u64>> {
+        std::iter::from_fn(|| reblocker.next_block(WAIT)).collect()
+    }
+
+    #[test]
+    fn blocks_are_contiguous_whatever_the_transfer_size() {
+        for &block_size in &[1, 7, 64, 4096] {
+            let queue = Queue::new(100_000);
+            feed(&queue, 10_000, &[3, 1000, 17, 576]);
+            queue.close();
+            let mut reblocker = Reblocker::new(queue, block_size);
+            let blocks = collect(&mut reblocker);
+            let mut expected_index = 0;
+            for block in &blocks {
+                assert_eq!(block.index, expected_index);
+                assert_eq!(block.samples, (block.index..block.index + block.samples.len() as u64).collect::<Vec<_>>());
+                expected_index += block.samples.len() as u64;
+            }
+            assert_eq!(expected_index, 10_000);
+            assert!(reblocker.is_finished());
+        }
+    }
+
+    #[test]
+    fn every_block_but_the_last_is_full() {
+        let queue = Queue::new(100);
+        feed(&queue, 10, &[4]);
+        queue.close();
+        let blocks = collect(&mut Reblocker::new(queue, 4));
+        let shape: Vec<(u64, usize, bool)> = blocks.iter().map(|b| (b.index, b.samples.len(), b.last)).collect();
+        assert_eq!(shape, vec![(0, 4, false), (4, 4, false), (8, 2, true)]);
+    }
+
+    #[test]
+    fn an_exact_multiple_has_no_short_block() {
+        let queue = Queue::new(100);
+        feed(&queue, 8, &[8]);
+        queue.close();
+        let mut reblocker = Reblocker::new(queue, 4);
+        let blocks = collect(&mut reblocker);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|b| !b.last && b.samples.len() == 4));
+        assert!(reblocker.is_finished());
+        assert_eq!(reblocker.next_block(WAIT), None);
+    }
+
+    #[test]
+    fn a_timeout_keeps_the_partial_block() {
+        let queue = Queue::new(100);
+        let mut reblocker = Reblocker::new(queue.clone(), 4);
+        queue.enqueue_all(0..3);
+        assert_eq!(reblocker.next_block(WAIT), None);
+        assert!(!reblocker.is_finished());
+        queue.enqueue_all(3..6);
+        assert_eq!(reblocker.next_block(WAIT), Some(Block { index: 0, samples: vec![0, 1, 2, 3], last: false }));
+        queue.close();
+        assert_eq!(reblocker.next_block(WAIT), Some(Block { index: 4, samples: vec![4, 5], last: true }));
+        assert_eq!(reblocker.next_block(WAIT), None);
+    }
+
+    #[test]
+    fn each_subscriber_chooses_its_own_block_size() {
+        let broadcast = crate::queue::Broadcaster::new(100);
+        let mut small = Reblocker::new(broadcast.subscribe(), 3);
+        let mut large = Reblocker::new(broadcast.subscribe(), 5);
+        broadcast.send_all(&(0..10).collect::<Vec<u64>>());
+        broadcast.close_with(crate::queue::CloseReason::Finished);
+        let small: Vec<usize> = collect(&mut small).iter().map(|b| b.samples.len()).collect();
+        let large: Vec<usize> = collect(&mut large).iter().map(|b| b.samples.len()).collect();
+        assert_eq!(small, vec![3, 3, 3, 1]);
+        assert_eq!(large, vec![5, 5]);
+    }
+}
@@ -0,0 +1,185 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use crate::error::Ar2300Error;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+ Identifies one capture, so that its recording, its capture log and the
+ events it published can be matched up afterwards. Generated IDs are
+ UUIDv7s: they start with the time they were made in milliseconds, so
+ they sort in the order the captures started, and within a process each
+ is greater than the last. An ID made elsewhere, such as by an
+ orchestrator, can be parsed from its UUID form instead.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionId(u128);
+
+/** The last ID generated, so the next can be made greater. */
+static LAST: Mutex<u128> = Mutex::new(0);
+
+impl SessionId {
+    /** A new ID, greater than any this process has generated before. */
+    pub fn generate() -> SessionId {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()) as u64;
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let random = hasher.finish();
+        // Time in the top 48 bits, then version 7, 12 bits counting up within a millisecond, the variant and 62 random bits
+        let time = ((millis & 0xffff_ffff_ffff) as u128) << 80 | 0x7000 << 64 | 0b10 << 62;
+        let mut last = LAST.lock().unwrap_or_else(|e| e.into_inner());
+        let same_millisecond = *last >> 80 == time >> 80;
+        let sequence = if same_millisecond { (*last >> 64 & 0xfff) + 1 } else { 0 };
+        let mut id = if sequence <= 0xfff {
+            time | sequence << 64 | (random & 0x3fff_ffff_ffff_ffff) as u128
+        } else {
+            // More than 4096 in a millisecond: borrow the next one
+            SessionId::next_millisecond(*last)
+        };
+        if id <= *last {
+            // The clock went back: carry on from the last ID
+            id = *last + 1;
+        }
+        *last = id;
+        SessionId(id)
+    }
+
+    /** The first ID of the millisecond after `id`'s. */
+    fn next_millisecond(id: u128) -> u128 {
+        ((id >> 80) + 1) << 80 | 0x7000 << 64 | 0b10 << 62
+    }
+
+    /** The ID as a number. */
+    pub fn as_u128(&self) -> u128 {
+        self.0
+    }
+}
+
+impl fmt::Display for SessionId {
+    /** The usual UUID form: 32 lower case hex digits in groups of 8, 4, 4, 4 and 12. */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(f, "{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = Ar2300Error;
+
+    /** Parse an ID in UUID form, with or without its dashes. Any UUID is accepted, not just a UUIDv7. */
+    fn from_str(s: &str) -> Result<SessionId, Ar2300Error> {
+        let invalid = || Ar2300Error::InvalidConfig(format!("Invalid session ID: '{}'", s));
+        let hex: String = s.chars().filter(|&c| c != '-').collect();
+        if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        u128::from_str_radix(&hex, 16).map(SessionId).map_err(|_| invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture_log::{CaptureLog, Entry};
+    use crate::events::EventLog;
+    use crate::iq::tests::{deliver_all, valid_transfer};
+    use crate::iq::{sigmf_meta_path, Receiver, ReceiverConfig, Writer, SAMPLE_RATE};
+    use crate::queue::Queue;
+    use crate::usb::fake::FakeDevice;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn generated_ids_are_ordered_uuid_v7s() {
+        let ids: Vec<SessionId> = (0..10_000).map(|_| SessionId::generate()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        let id = ids[0].to_string();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "7");
+        assert!("89ab".contains(&id[19..20]), "{}", id);
+        assert_eq!(id.parse::<SessionId>().unwrap(), ids[0]);
+    }
+
+    #[test]
+    fn ids_from_elsewhere_are_parsed() {
+        let id: SessionId = "123E4567-E89B-12D3-A456-426614174000".parse().unwrap();
+        assert_eq!(id.to_string(), "123e4567-e89b-12d3-a456-426614174000");
+        assert_eq!("123e4567e89b12d3a456426614174000".parse::<SessionId>().unwrap(), id);
+        assert!("123e4567-e89b-12d3-a456".parse::<SessionId>().is_err());
+        assert!("123e4567-e89b-12d3-a456-42661417400g".parse::<SessionId>().is_err());
+    }
+
+    /** An output that keeps what is written. */
+    #[derive(Clone, Default)]
+    struct Kept(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Kept {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_capture_is_tagged_with_its_session_throughout() {
+        let session: SessionId = "0192b3c4-d5e6-7f00-8123-456789abcdef".parse().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("capture.cf32");
+        let mut writer = Writer::new(Queue::new(16), Box::new(std::io::sink()));
+        writer.set_session(Some(session));
+        writer.with_sigmf_metadata(&recording, SAMPLE_RATE, 100e6).unwrap();
+        let meta = std::fs::read_to_string(sigmf_meta_path(&recording)).unwrap();
+        assert!(meta.contains(&format!("\"ar2300:session\": \"{}\"", session)), "{}", meta);
+
+        let device = Arc::new(FakeDevice::new());
+        device.complete_ok(valid_transfer());
+        device.complete(Err(rusb::Error::Overflow), Vec::new());
+        let queue = Queue::new(1 << 16);
+        let config = ReceiverConfig { pre_start_drain: None, session: Some(session), ..ReceiverConfig::default() };
+        let mut receiver = Receiver::builder().config(config).build_fake(device.clone(), queue.clone()).unwrap();
+        assert_eq!(receiver.session(), session);
+        let kept = Kept::default();
+        let mut log = CaptureLog::new(Box::new(kept.clone()));
+        log.record(&Entry::Start { recording: "capture.cf32".to_string(), profile: "default".to_string(), session: Some(session) }).unwrap();
+        let subscriber = EventLog::global().subscribe_with(1 << 16);
+        let follower = log.follow(EventLog::global(), receiver.accounting(), queue.clone(), Duration::from_secs(60));
+        receiver.start().unwrap();
+        deliver_all(&receiver, &device, None);
+        receiver.stop();
+        follower.finish().unwrap();
+
+        let events: Vec<_> = std::iter::from_fn(|| subscriber.next(Duration::ZERO)).collect();
+        let overflow = events.iter().find(|e| e.session == Some(session)).unwrap();
+        assert_eq!(overflow.message, "USB overflow, discarding transfer");
+        let lines = String::from_utf8(kept.0.lock().unwrap().clone()).unwrap();
+        let tag = format!("\"session\":\"{}\"", session);
+        assert!(lines.lines().next().unwrap().contains(&tag), "{}", lines);
+        assert!(lines.lines().any(|l| l.contains("USB overflow") && l.contains(&tag)), "{}", lines);
+    }
+}
@@ -0,0 +1,143 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use std::error::Error;
+use std::fmt;
+
+/** The bytes that start every control message, such as START_CAPTURE. */
+const HEADER: [u8; 2] = [0x5a, 0xa5];
+
+/** The header and the big-endian payload length. */
+const FRAME_OVERHEAD: usize = HEADER.len() + 2;
+
+/** What the device reported about itself. */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /** The firmware revision, if the reply carries it as text. */
+    pub firmware_revision: Option<String>,
+    /** The reply's payload as received, including anything not understood. */
+    pub raw: Vec<u8>,
+}
+
+/** Why a status reply couldn't be used. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatusError {
+    /** The device sent nothing back, so it doesn't support the query. */
+    Unsupported,
+    /** The reply doesn't start with the control message header. */
+    BadHeader,
+    /** The reply is shorter than its length field says. */
+    Truncated { expected: usize, received: usize },
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::Unsupported => write!(f, "The device doesn't report its status"),
+            StatusError::BadHeader => write!(f, "The status reply has no control message header"),
+            StatusError::Truncated { expected, received } =>
+                write!(f, "The status reply is {} bytes long, but should be {}", received, expected),
+        }
+    }
+}
+
+impl Error for StatusError {}
+
+/**
+ Parse a reply to a status query. Replies are framed like the commands the
+ firmware accepts: the header, a big-endian payload length and the
+ payload. The payload's layout isn't documented, so only a payload that is
+ all printable text, perhaps padded with NULs, is taken as the firmware
+ revision; it is always kept in `raw`. Bytes after the frame are ignored.
+ */
+pub fn parse_status(reply: &[u8]) -> Result<DeviceStatus, StatusError> {
+    if reply.is_empty() {
+        return Err(StatusError::Unsupported);
+    }
+    if reply.len() < FRAME_OVERHEAD || reply[..HEADER.len()] != HEADER {
+        return Err(StatusError::BadHeader);
+    }
+    let len = u16::from_be_bytes([reply[2], reply[3]]) as usize;
+    let expected = FRAME_OVERHEAD + len;
+    if reply.len() < expected {
+        return Err(StatusError::Truncated { expected, received: reply.len() });
+    }
+    let raw = reply[FRAME_OVERHEAD..expected].to_vec();
+    let text = raw.split(|&b| b == 0).next().unwrap_or_default();
+    let padding_only = raw[text.len()..].iter().all(|&b| b == 0);
+    let firmware_revision = if !text.is_empty() && padding_only && text.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        Some(String::from_utf8_lossy(text).trim().to_string())
+    } else {
+        None
+    };
+    Ok(DeviceStatus { firmware_revision, raw })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** A reply carrying a revision string, NUL-padded to a fixed size. */
+    const REVISION_REPLY: [u8; 12] = [0x5a, 0xa5, 0x00, 0x08, b'V', b'1', b'.', b'0', b'2', 0, 0, 0];
+
+    /** A reply whose payload isn't text. */
+    const BINARY_REPLY: [u8; 7] = [0x5a, 0xa5, 0x00, 0x03, 0x01, 0xff, 0x42];
+
+    #[test]
+    fn a_text_payload_is_the_firmware_revision() {
+        let status = parse_status(&REVISION_REPLY).unwrap();
+        assert_eq!(status.firmware_revision.as_deref(), Some("V1.02"));
+        assert_eq!(status.raw, REVISION_REPLY[4..].to_vec());
+    }
+
+    #[test]
+    fn other_payloads_are_kept_raw() {
+        let status = parse_status(&BINARY_REPLY).unwrap();
+        assert_eq!(status.firmware_revision, None);
+        assert_eq!(status.raw, vec![0x01, 0xff, 0x42]);
+        // Text followed by more than padding isn't taken as a revision either
+        let mixed = [0x5a, 0xa5, 0x00, 0x04, b'V', b'1', 0, 0x07];
+        assert_eq!(parse_status(&mixed).unwrap().firmware_revision, None);
+    }
+
+    #[test]
+    fn an_empty_payload_has_no_revision() {
+        assert_eq!(parse_status(&[0x5a, 0xa5, 0x00, 0x00]), Ok(DeviceStatus::default()));
+    }
+
+    #[test]
+    fn no_reply_means_unsupported() {
+        assert_eq!(parse_status(&[]), Err(StatusError::Unsupported));
+    }
+
+    #[test]
+    fn malformed_replies_are_rejected() {
+        assert_eq!(parse_status(&[0x5a]), Err(StatusError::BadHeader));
+        assert_eq!(parse_status(&[0xa5, 0x5a, 0x00, 0x00]), Err(StatusError::BadHeader));
+        assert_eq!(parse_status(&REVISION_REPLY[..9]), Err(StatusError::Truncated { expected: 12, received: 9 }));
+    }
+
+    #[test]
+    fn bytes_after_the_frame_are_ignored() {
+        let mut reply = BINARY_REPLY.to_vec();
+        reply.extend_from_slice(&[0xde, 0xad]);
+        assert_eq!(parse_status(&reply).unwrap().raw, vec![0x01, 0xff, 0x42]);
+    }
+}
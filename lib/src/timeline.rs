@@ -0,0 +1,410 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+const MILLIHERTZ_PER_HERTZ: f64 = 1000.0;
+
+/** A run of samples missing from a recording. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gap {
+    /** Index in the recording of the first sample after the gap. */
+    pub at: u64,
+    /** The number of samples that are missing. */
+    pub missing: u64,
+}
+
+/** Where a point in time falls within a recording. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    /** Before the first sample. */
+    Before,
+    /** The sample covering this time. */
+    Sample(u64),
+    /** Inside a gap. `next` is the index of the first sample after the gap. */
+    InGap { next: u64 },
+    /** After the last sample. */
+    After,
+}
+
+/**
+ Converts between sample indices in a recording and wall-clock time.
+
+ Sample `n` of the recording was taken at
+ `start + (n + samples missing before n) / rate`. Arithmetic is done in
+ integer nanoseconds, with the rate held to a millihertz. The clock is
+ read only once, for `start`; later times are derived from it, so a step
+ in the system clock during a capture can't make them go backwards.
+ `CaptureClock` does the same for times taken while capturing.
+ */
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    start: SystemTime,
+    rate_millihertz: u128,
+    samples: u64,
+    gaps: Vec<Gap>,
+}
+
+impl Timeline {
+    /**
+     Create a timeline for a recording of `samples` samples that started at
+     `start` and ran at the measured `rate` in samples per second.
+     */
+    pub fn new(start: SystemTime, rate: f64, samples: u64, gaps: &[Gap]) -> Timeline {
+        let mut gaps: Vec<Gap> = gaps.iter()
+            .filter(|g| g.missing > 0)
+            .copied()
+            .collect();
+        gaps.sort_by_key(|g| g.at);
+        Timeline {
+            start,
+            rate_millihertz: (rate * MILLIHERTZ_PER_HERTZ).round().max(1.0) as u128,
+            samples,
+            gaps,
+        }
+    }
+
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn gaps(&self) -> &[Gap] {
+        &self.gaps
+    }
+
+    /**
+     The time the given sample was taken, rounded up to the nanosecond so that
+     `index_at(time_at(n))` is `n`. Indices past the end are extrapolated.
+     Returns None if the time is too far off to be represented.
+     */
+    pub fn time_at(&self, index: u64) -> Option<SystemTime> {
+        // Summed in u128 so that many large gaps can't overflow
+        let missing: u128 = self.gaps.iter()
+            .take_while(|g| g.at <= index)
+            .map(|g| g.missing as u128)
+            .sum();
+        self.start.checked_add(self.elapsed_at(index as u128 + missing))
+    }
+
+    /**
+     The sample that covers the given time, meaning the last sample taken at or
+     before it. Times inside a gap report the first sample after the gap.
+     */
+    pub fn index_at(&self, time: SystemTime) -> Position {
+        let elapsed = match time.duration_since(self.start) {
+            Ok(elapsed) => elapsed,
+            Err(_) => return Position::Before
+        };
+        let position = elapsed.as_nanos() * self.rate_millihertz
+            / (NANOS_PER_SECOND * MILLIHERTZ_PER_HERTZ as u128);
+
+        // Walk the gaps, converting the stream position to a recording index.
+        let mut missing: u128 = 0;
+        for gap in &self.gaps {
+            let gap_start = gap.at as u128 + missing;
+            if position < gap_start {
+                break;
+            }
+            if position < gap_start + gap.missing as u128 {
+                return Position::InGap { next: gap.at };
+            }
+            missing += gap.missing as u128;
+        }
+        let index = position - missing;
+        if index >= self.samples as u128 {
+            Position::After
+        } else {
+            Position::Sample(index as u64)
+        }
+    }
+
+    /** Time from the start to the given stream position, rounded up to the next nanosecond. */
+    fn elapsed_at(&self, position: u128) -> Duration {
+        let scaled = position * NANOS_PER_SECOND * MILLIHERTZ_PER_HERTZ as u128;
+        let nanos = scaled.div_ceil(self.rate_millihertz);
+        Duration::new((nanos / NANOS_PER_SECOND).min(u64::MAX as u128) as u64,
+                      (nanos % NANOS_PER_SECOND) as u32)
+    }
+}
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/**
+ Format a time as an RFC 3339 UTC timestamp with nanoseconds, e.g.
+ `2021-06-01T12:00:00.000000000Z`.
+ */
+pub fn format_time(time: SystemTime) -> String {
+    let (seconds, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            let nanos = d.subsec_nanos();
+            if nanos == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - nanos)
+            }
+        }
+    };
+    let days = seconds.div_euclid(SECONDS_PER_DAY);
+    let rest = seconds.rem_euclid(SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+            year, month, day, rest / 3600, rest / 60 % 60, rest % 60, nanos)
+}
+
+/**
+ Parse a UTC time given either as an RFC 3339 timestamp ending in `Z`,
+ with up to nanosecond precision, or as seconds since the Unix epoch.
+ */
+pub fn parse_time(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+    if let Some(s) = s.strip_suffix('Z').or_else(|| s.strip_suffix('z')) {
+        return parse_rfc3339(s);
+    }
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    let seconds: u64 = whole.parse().ok()?;
+    UNIX_EPOCH.checked_add(Duration::new(seconds, parse_nanos(fraction)?))
+}
+
+/** Parse `YYYY-MM-DDTHH:MM:SS[.fraction]`, already stripped of its `Z`. */
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: u32 = date.next()?.parse().ok()?;
+    let day: u32 = date.next()?.parse().ok()?;
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    let second: i64 = time.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+    let nanos = parse_nanos(fraction)?;
+    if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))?
+            .checked_add(Duration::from_nanos(nanos as u64))
+    }
+}
+
+/** Parse the digits after a decimal point as nanoseconds, ignoring any past the ninth. */
+fn parse_nanos(fraction: &str) -> Option<u32> {
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let padded = format!("{:0<9}", digits);
+    padded.parse().ok()
+}
+
+/** Days since 1970-01-01 of a date in the proleptic Gregorian calendar. */
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/** The date of a number of days since 1970-01-01. */
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/**
+ The sample rate and start time recorded in a SigMF metadata document,
+ as written by `Writer::with_sigmf_metadata`: `core:sample_rate` from the
+ global object and `core:datetime` from the first capture segment.
+ Either is `None` if it is missing or can't be read.
+ */
+pub fn sigmf_timing(meta: &str) -> (Option<f64>, Option<SystemTime>) {
+    let rate = json_value(meta, "core:sample_rate").and_then(|v| v.parse().ok());
+    let start = json_value(meta, "core:datetime")
+        .and_then(|v| parse_time(v.trim_matches('"')));
+    (rate, start)
+}
+
+/** The raw text of the first value for the given key. Enough for the flat values SigMF uses. */
+pub(crate) fn json_value<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let quoted = format!("\"{}\"", key);
+    let rest = &json[json.find(&quoted)? + quoted.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = if let Some(string) = rest.strip_prefix('"') {
+        string.find('"')? + 2
+    } else {
+        rest.find([',', '}', '\n']).unwrap_or(rest.len())
+    };
+    Some(rest[..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    /** 1000 samples a second, with 10 missing before sample 100 and 5 before sample 200. */
+    fn gappy() -> Timeline {
+        Timeline::new(start(), 1000.0, 300, &[Gap { at: 200, missing: 5 }, Gap { at: 100, missing: 10 }])
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn times_skip_over_gaps() {
+        let timeline = gappy();
+        assert_eq!(timeline.time_at(0), Some(start()));
+        assert_eq!(timeline.time_at(99), Some(start() + ms(99)));
+        // Sample 100 follows the 10 missing samples
+        assert_eq!(timeline.time_at(100), Some(start() + ms(110)));
+        assert_eq!(timeline.time_at(199), Some(start() + ms(209)));
+        assert_eq!(timeline.time_at(200), Some(start() + ms(215)));
+    }
+
+    #[test]
+    fn times_inside_a_gap_report_the_next_sample() {
+        let timeline = gappy();
+        assert_eq!(timeline.index_at(start() + ms(100)), Position::InGap { next: 100 });
+        assert_eq!(timeline.index_at(start() + ms(109) + Duration::from_micros(999)), Position::InGap { next: 100 });
+        assert_eq!(timeline.index_at(start() + ms(210)), Position::InGap { next: 200 });
+    }
+
+    #[test]
+    fn gap_boundaries_belong_to_the_neighbouring_samples() {
+        let timeline = gappy();
+        // The last sample before the gap covers up to the gap's start
+        assert_eq!(timeline.index_at(start() + ms(99)), Position::Sample(99));
+        assert_eq!(timeline.index_at(start() + ms(100) - Duration::from_nanos(1)), Position::Sample(99));
+        // and the first sample after it starts exactly where the gap ends
+        assert_eq!(timeline.index_at(start() + ms(110)), Position::Sample(100));
+    }
+
+    #[test]
+    fn times_outside_the_recording() {
+        let timeline = gappy();
+        assert_eq!(timeline.index_at(start() - Duration::from_nanos(1)), Position::Before);
+        assert_eq!(timeline.index_at(start()), Position::Sample(0));
+        assert_eq!(timeline.index_at(timeline.time_at(299).unwrap()), Position::Sample(299));
+        assert_eq!(timeline.index_at(timeline.time_at(300).unwrap()), Position::After);
+    }
+
+    #[test]
+    fn index_and_time_round_trip() {
+        // A rate that doesn't divide a second evenly, so times are rounded
+        let timeline = Timeline::new(start(), 1_124_999.7, 5_000_000,
+                                     &[Gap { at: 1000, missing: 3 }, Gap { at: 2_000_000, missing: 12_345 }]);
+        for index in (0..5_000_000).step_by(997).chain([999, 1000, 1_999_999, 2_000_000, 4_999_999]) {
+            assert_eq!(timeline.index_at(timeline.time_at(index).unwrap()), Position::Sample(index), "index {}", index);
+        }
+    }
+
+    #[test]
+    fn zero_length_gaps_are_ignored() {
+        let timeline = Timeline::new(start(), 1000.0, 10, &[Gap { at: 5, missing: 0 }]);
+        assert!(timeline.gaps().is_empty());
+        assert_eq!(timeline.time_at(5), Some(start() + ms(5)));
+    }
+
+    #[test]
+    fn times_format_and_parse() {
+        let time = UNIX_EPOCH + Duration::new(1_622_548_800, 123_456_789);
+        assert_eq!(format_time(time), "2021-06-01T12:00:00.123456789Z");
+        assert_eq!(parse_time("2021-06-01T12:00:00.123456789Z"), Some(time));
+        assert_eq!(parse_time("2021-06-01T12:00:00.123456789123Z"), Some(time));
+        assert_eq!(parse_time("1622548800.123456789"), Some(time));
+        assert_eq!(parse_time("2000-02-29T00:00:00Z"), Some(UNIX_EPOCH + Duration::from_secs(951_782_400)));
+        assert_eq!(format_time(UNIX_EPOCH - Duration::from_millis(500)), "1969-12-31T23:59:59.500000000Z");
+        assert_eq!(parse_time("1969-12-31T23:59:59.5Z"), Some(UNIX_EPOCH - Duration::from_millis(500)));
+        for bad in ["", "2021-13-01T00:00:00Z", "2021-06-01T25:00:00Z", "12.x", "2021-06-01Z"] {
+            assert_eq!(parse_time(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn dates_round_trip_through_days() {
+        for days in (-800_000..800_000).step_by(37) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn reads_timing_from_sigmf_metadata() {
+        let meta = r#"{
+  "global": { "core:datatype": "cf32_be", "core:sample_rate": 1125000, "core:version": "1.0.0" },
+  "captures": [ { "core:sample_start": 0, "core:datetime": "2021-06-01T12:00:00.000000000Z" } ]
+}"#;
+        let (rate, start) = sigmf_timing(meta);
+        assert_eq!(rate, Some(1_125_000.0));
+        assert_eq!(start, Some(UNIX_EPOCH + Duration::from_secs(1_622_548_800)));
+        assert_eq!(sigmf_timing("{}"), (None, None));
+    }
+
+    #[test]
+    fn indices_past_u32_do_not_wrap() {
+        let wrap = u32::MAX as u64 + 1;
+        let timeline = Timeline::new(start(), 1000.0, wrap + 10, &[Gap { at: wrap, missing: 1000 }]);
+        assert_eq!(timeline.time_at(wrap - 1), Some(start() + ms(wrap - 1)));
+        assert_eq!(timeline.time_at(wrap), Some(start() + ms(wrap + 1000)));
+        assert_eq!(timeline.index_at(start() + ms(wrap + 1005)), Position::Sample(wrap + 5));
+        assert_eq!(timeline.index_at(start() + ms(wrap)), Position::InGap { next: wrap });
+        for index in [wrap - 1, wrap, wrap + 9] {
+            assert_eq!(timeline.index_at(timeline.time_at(index).unwrap()), Position::Sample(index));
+        }
+    }
+
+    #[test]
+    fn times_too_far_off_to_represent_are_none() {
+        // At a millihertz, u64::MAX samples is far beyond any SystemTime
+        let slow = Timeline::new(start(), 0.0, 10, &[]);
+        assert_eq!(slow.time_at(0), Some(start()));
+        assert_eq!(slow.time_at(u64::MAX), None);
+        let gappy = Timeline::new(start(), 1.0, 10, &[Gap { at: 5, missing: u64::MAX }]);
+        assert_eq!(gappy.time_at(4), Some(start() + Duration::from_secs(4)));
+        assert_eq!(gappy.time_at(5), None);
+        // The last index at a fast rate is still about 500 years in
+        let fast = Timeline::new(start(), 1_125_000.0, u64::MAX, &[]);
+        assert!(fast.time_at(u64::MAX).is_some());
+    }
+}
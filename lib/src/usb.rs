@@ -19,43 +19,43 @@
 
 use rusb::ffi::{constants::*, *};
 use rusb::{Device, GlobalContext, DeviceHandle, Error};
+use log::{info, Level};
+use crate::events::emit;
 use simple_error::SimpleError;
-use std::time::Duration;
+use rusb::UsbContext;
+use std::time::{Duration, Instant};
 use std::os::raw::{c_int, c_uint};
 use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
-const IQ_VENDOR_ID: u16 = 0x08d0;
-const IQ_PRODUCT_ID: u16 = 0xa001;
+pub(crate) const IQ_VENDOR_ID: u16 = 0x08d0;
+pub(crate) const IQ_PRODUCT_ID: u16 = 0xa001;
 
 /** List all USB devices. */
 pub fn list_devices() {
     match rusb::devices() {
         Ok(devices) => {
-            println!("USB Devices:");
+            info!("USB Devices:");
             for device in devices.iter() {
-                println!("  {}", device_info(&device));
+                info!("  {}", device_info(&device));
             }
-            println!();
         },
         Err(e) => {
-            eprintln!("Error: {}", e);
+            emit(Level::Error, format_args!("Error listing USB devices: {}", e));
         }
     }
 }
 
-pub fn device_info(device: &Device<GlobalContext>) -> String {
+pub fn device_info<C: UsbContext>(device: &Device<C>) -> String {
     let (manufacturer, product) = match device.open() {
         Ok(handle) =>
             match device.device_descriptor() {
                 Ok(device_desc) => (
-                    match handle.read_manufacturer_string_ascii(&device_desc) {
-                        Ok(s) => s,
-                        Err(_) => String::new()
-                    },
-                    match handle.read_product_string_ascii(&device_desc) {
-                        Ok(s) => s,
-                        Err(_) => String::new()
-                    }
+                    handle.read_manufacturer_string_ascii(&device_desc)
+                        .unwrap_or_default(),
+                    handle.read_product_string_ascii(&device_desc)
+                        .unwrap_or_default()
                 ),
                 Err(_) => (String::new(),String::new())
             },
@@ -81,7 +81,7 @@ pub trait IsIQDevice {
     fn is_iq_device(&self) -> bool;
 }
 
-impl IsIQDevice for Device<GlobalContext> {
+impl<C: UsbContext> IsIQDevice for Device<C> {
     /** Returns true of the given USB device is an AR2300 IQ board */
     fn is_iq_device(&self) -> bool {
         match self.device_descriptor() {
@@ -96,7 +96,12 @@ impl IsIQDevice for Device<GlobalContext> {
 
 /** Find the AR2300 IQ device. */
 pub fn find_iq_device() -> Option<Device<GlobalContext>> {
-    match rusb::devices() {
+    find_iq_device_in(&GlobalContext::default())
+}
+
+/** Find the AR2300 IQ device among the devices seen by the given context. */
+pub fn find_iq_device_in<C: UsbContext>(context: &C) -> Option<Device<C>> {
+    match context.devices() {
         Ok(devices) =>
             devices.iter().find(|d| d.is_iq_device()),
         Err(_) => None
@@ -104,7 +109,7 @@ pub fn find_iq_device() -> Option<Device<GlobalContext>> {
 }
 
 // Check for a kernel driver and detach it if necessary
-pub fn check_for_kernel_driver(handle: &mut DeviceHandle<GlobalContext>)
+pub fn check_for_kernel_driver<C: UsbContext>(handle: &mut DeviceHandle<C>)
     -> Result<(),SimpleError> {
     match handle.set_auto_detach_kernel_driver(true) {
         Ok(_) => Ok(()),
@@ -118,7 +123,7 @@ pub fn check_for_kernel_driver(handle: &mut DeviceHandle<GlobalContext>)
 }
 
 // Claim an interface
-pub fn claim_interface(handle: &mut DeviceHandle<GlobalContext>, interface: u8)
+pub fn claim_interface<C: UsbContext>(handle: &mut DeviceHandle<C>, interface: u8)
     -> Result<(),SimpleError> {
     check_for_kernel_driver(handle)?;
     match handle.claim_interface(interface) {
@@ -131,34 +136,221 @@ pub fn claim_interface(handle: &mut DeviceHandle<GlobalContext>, interface: u8)
 
 ///// Isochronous Transfer Implementation /////
 
-pub trait TransferCallback {
-    fn callback(&self, r: rusb::Result<()>) -> bool;
+/**
+ Receives the data from an isochronous transfer. `buffer` is what the
+ transfer reads into; `callback` is called as each transfer completes, with
+ what each of its packets actually received, and returns whether to
+ resubmit it.
+ */
+pub(crate) trait TransferCallback {
+    fn callback(&mut self, r: rusb::Result<()>, packets: &IsoPackets) -> bool;
     fn buffer(&mut self) -> &mut [u8];
 }
 
-pub trait IsochronousTransfer {
-    /** Submits an Isochronous transfer. */
-    fn submit_iso<T: TransferCallback> (
+/**
+ The packets of a completed isochronous transfer, as libusb reports them:
+ where each starts in the buffer and how many bytes it actually received.
+ A packet can come back short or empty, and one that failed received
+ nothing, so the rest of its slot in the buffer holds stale data.
+ */
+#[derive(Clone, Copy)]
+pub(crate) struct IsoPackets<'a> {
+    descriptors: &'a [libusb_iso_packet_descriptor],
+}
+
+impl<'a> IsoPackets<'a> {
+    pub(crate) fn new(descriptors: &'a [libusb_iso_packet_descriptor]) -> Self {
+        IsoPackets { descriptors }
+    }
+
+    /** The number of packets in the transfer. */
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+
+    /** Each packet's offset in the buffer and the bytes it received, in order. */
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let mut offset = 0;
+        self.descriptors.iter().map(move |packet| {
+            let start = offset;
+            offset += packet.length as usize;
+            let received = match packet.status {
+                LIBUSB_TRANSFER_COMPLETED => packet.actual_length.min(packet.length) as usize,
+                _ => 0
+            };
+            (start, received)
+        })
+    }
+
+    /** The packets that completed with data. */
+    pub fn completed(&self) -> usize {
+        self.iter().filter(|&(_, received)| received > 0).count()
+    }
+
+    /** The bytes received across all the packets. */
+    pub fn received(&self) -> usize {
+        self.iter().map(|(_, received)| received).sum()
+    }
+
+    /**
+     Move what each packet received to the front of `buffer`, in order,
+     closing the gaps left by short and failed packets. Returns the number
+     of bytes moved, which begin the buffer afterwards.
+     */
+    pub fn compact(&self, buffer: &mut [u8]) -> usize {
+        let mut end = 0;
+        for (offset, received) in self.iter() {
+            let received = received.min(buffer.len().saturating_sub(offset));
+            if received > 0 && offset != end {
+                buffer.copy_within(offset..offset + received, end);
+            }
+            end += received;
+        }
+        end
+    }
+}
+
+/**
+ Isochronous transfers for rusb device handles, which rusb doesn't
+ provide. Implemented for `DeviceHandle` on any context, so it can be
+ used with any device, not only the AR2300.
+ */
+pub(crate) trait IsochronousTransfer {
+    /** The context whose events drive the transfer. */
+    type Context: UsbContext;
+
+    /**
+     Submits an Isochronous transfer. The returned IsoTransfer owns the
+     callback and its buffer, and frees them once it has been closed or
+     dropped, so whatever submitted it can move freely in the meantime.
+     */
+    fn submit_iso<T: TransferCallback + Send> (
         &self,
         endpoint: u8,
         num_packets: usize,
         packet_len: usize,
-        callback: &mut T,
+        callback: Box<T>,
         timeout: Duration,
-    ) -> rusb::Result<()>;
+    ) -> rusb::Result<IsoTransfer<T, Self::Context>>;
+}
+
+/**
+ State shared with libusb through the transfer's user data, including the
+ boxed callback. Both are freed only once the transfer has finished.
+ */
+struct TransferState<T> {
+    callback: AtomicPtr<T>,
+    in_flight: AtomicBool,
 }
 
-impl IsochronousTransfer for DeviceHandle<GlobalContext> {
+/** How long dropping an IsoTransfer waits for it to finish. */
+pub(crate) const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ A submitted isochronous transfer. It keeps resubmitting itself until its
+ callback returns false or it is cancelled. Closing or dropping it cancels
+ the transfer and waits for the last callback to finish before freeing it.
+ */
+pub(crate) struct IsoTransfer<T, C: UsbContext = GlobalContext> {
+    transfer: *mut libusb_transfer,
+    state: *mut TransferState<T>,
+    context: C,
+}
+
+impl<T, C: UsbContext> IsoTransfer<T, C> {
+    /** Returns true until the final callback for the transfer has finished. */
+    pub fn is_in_flight(&self) -> bool {
+        unsafe { (*self.state).in_flight.load(Ordering::Acquire) }
+    }
+
+    /** Ask libusb to cancel the transfer. The callback still runs once more. */
+    pub fn cancel(&self) -> rusb::Result<()> {
+        match unsafe { libusb_cancel_transfer(self.transfer) } {
+            // Not found means it has already completed
+            0 | LIBUSB_ERROR_NOT_FOUND => Ok(()),
+            err => Err(from_libusb(err))
+        }
+    }
+
+    /**
+     Handle USB events until the transfer's final callback has finished or
+     the timeout passes. Returns true if the transfer finished.
+     */
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while self.is_in_flight() {
+            let remaining = timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return false;
+            }
+            if self.context.handle_events(Some(remaining)).is_err() {
+                return !self.is_in_flight();
+            }
+        }
+        true
+    }
+
+    /**
+     Cancel the transfer and wait for it to finish, then free it and its
+     callback. If it doesn't finish in time it is abandoned: the callback
+     is detached so it will never be called again, and the transfer, its
+     state and the callback with its buffer are leaked rather than freed
+     while libusb may still use them. Returns false in that case.
+     */
+    pub fn close(mut self, timeout: Duration) -> bool {
+        self.teardown(timeout)
+    }
+
+    fn teardown(&mut self, timeout: Duration) -> bool {
+        if self.transfer.is_null() {
+            return true;
+        }
+        if self.is_in_flight() {
+            if let Err(e) = self.cancel() {
+                emit(Level::Warn, format_args!("Error cancelling transfer: {}", e));
+            }
+        }
+        let finished = self.wait(timeout);
+        unsafe {
+            if finished {
+                libusb_free_transfer(self.transfer);
+                let state = Box::from_raw(self.state);
+                drop(Box::from_raw(state.callback.load(Ordering::Acquire)));
+            } else {
+                (*self.state).callback.store(ptr::null_mut(), Ordering::Release);
+                emit(Level::Error, format_args!("Transfer did not finish within {:?}; leaking it", timeout));
+            }
+        }
+        self.transfer = ptr::null_mut();
+        self.state = ptr::null_mut();
+        finished
+    }
+}
+
+impl<T, C: UsbContext> Drop for IsoTransfer<T, C> {
+    fn drop(&mut self) {
+        self.teardown(TEARDOWN_TIMEOUT);
+    }
+}
+
+impl<C: UsbContext> IsochronousTransfer for DeviceHandle<C> {
+    type Context = C;
 
     /** Submits an Isochronous transfer. */
-    fn submit_iso<T: TransferCallback> (
+    fn submit_iso<T: TransferCallback + Send> (
         &self,
         endpoint: u8,
         num_packets: usize,
         packet_len: usize,
-        callback: &mut T,
+        mut callback: Box<T>,
         timeout: Duration,
-    ) -> rusb::Result<()> {
+    ) -> rusb::Result<IsoTransfer<T, C>> {
         if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
             return Err(Error::InvalidParam);
         }
@@ -168,50 +360,78 @@ impl IsochronousTransfer for DeviceHandle<GlobalContext> {
         if buffer.len() < buffer_len {
             return Err(Error::InvalidParam);
         }
+        // The buffer lives in the callback's heap allocation, so it stays put
+        let (buffer, buffer_size) = (buffer.as_mut_ptr(), buffer.len());
+        let callback_ptr = Box::into_raw(callback);
 
         unsafe {
             let transfer = libusb_alloc_transfer(num_packets as c_int);
+            if transfer.is_null() {
+                drop(Box::from_raw(callback_ptr));
+                return Err(Error::NoMem);
+            }
+            let state = Box::into_raw(Box::new(TransferState {
+                callback: AtomicPtr::new(callback_ptr),
+                in_flight: AtomicBool::new(true),
+            }));
 
             libusb_fill_iso_transfer(
                 transfer,
                 self.as_raw(),
                 endpoint,
-                buffer.as_mut_ptr(),
-                buffer.len() as c_int,
+                buffer,
+                buffer_size as c_int,
                 num_packets as c_int,
                 callback_wrapper::<T>,
-                callback as *mut _ as *mut c_void,
+                state as *mut c_void,
                 timeout.as_millis() as c_uint
             );
 
             libusb_set_iso_packet_lengths(transfer, packet_len as c_uint);
 
             match libusb_submit_transfer(transfer) {
-                0 => Ok(()),
-                err => Err(from_libusb(err))
+                0 => Ok(IsoTransfer { transfer, state, context: self.context().clone() }),
+                err => {
+                    libusb_free_transfer(transfer);
+                    drop(Box::from_raw(state));
+                    drop(Box::from_raw(callback_ptr));
+                    Err(from_libusb(err))
+                }
             }
         }
     }
 }
 
 extern "system" fn callback_wrapper<T: TransferCallback>(transfer: *mut libusb_transfer) {
-    let callback = unsafe {
-        &mut *((*transfer).user_data as *mut T)
+    let state = unsafe {
+        &*((*transfer).user_data as *const TransferState<T>)
     };
+    let callback = state.callback.load(Ordering::Acquire);
+    if callback.is_null() {
+        // Abandoned by IsoTransfer::close; the callback may no longer exist
+        state.in_flight.store(false, Ordering::Release);
+        return;
+    }
+    let callback = unsafe { &mut *callback };
 
     let status = unsafe {
         (*transfer).status
     };
 
+    let packets = IsoPackets::new(unsafe {
+        std::slice::from_raw_parts((*transfer).iso_packet_desc.as_ptr(),
+                                   (*transfer).num_iso_packets as usize)
+    });
+
     let cont = match status {
-        LIBUSB_TRANSFER_COMPLETED => callback.callback(Ok(())),
-        LIBUSB_TRANSFER_ERROR => callback.callback(Err(Error::Other)),
-        LIBUSB_TRANSFER_TIMED_OUT => callback.callback(Err(Error::Timeout)),
-        LIBUSB_TRANSFER_CANCELLED => callback.callback(Err(Error::Interrupted)),
-        LIBUSB_TRANSFER_STALL => callback.callback(Err(Error::Io)),
-        LIBUSB_TRANSFER_NO_DEVICE => callback.callback(Err(Error::NoDevice)),
-        LIBUSB_TRANSFER_OVERFLOW => callback.callback(Err(Error::Overflow)),
-        err => callback.callback(Err(from_libusb(err))),
+        LIBUSB_TRANSFER_COMPLETED => callback.callback(Ok(()), &packets),
+        LIBUSB_TRANSFER_ERROR => callback.callback(Err(Error::Other), &packets),
+        LIBUSB_TRANSFER_TIMED_OUT => callback.callback(Err(Error::Timeout), &packets),
+        LIBUSB_TRANSFER_CANCELLED => callback.callback(Err(Error::Interrupted), &packets),
+        LIBUSB_TRANSFER_STALL => callback.callback(Err(Error::Io), &packets),
+        LIBUSB_TRANSFER_NO_DEVICE => callback.callback(Err(Error::NoDevice), &packets),
+        LIBUSB_TRANSFER_OVERFLOW => callback.callback(Err(Error::Overflow), &packets),
+        err => callback.callback(Err(from_libusb(err)), &packets),
     };
 
     if cont {
@@ -219,12 +439,13 @@ extern "system" fn callback_wrapper<T: TransferCallback>(transfer: *mut libusb_t
             libusb_submit_transfer(transfer)
         };
         match s {
-            0 => {},
+            0 => return,
             err => {
-                callback.callback(Err(from_libusb(err)));
+                callback.callback(Err(from_libusb(err)), &IsoPackets::new(&[]));
             }
         }
     }
+    state.in_flight.store(false, Ordering::Release);
 }
 
 /** This is copied from error.rs in rusb */
@@ -242,6 +463,263 @@ fn from_libusb(err: i32) -> Error {
         LIBUSB_ERROR_INTERRUPTED => Error::Interrupted,
         LIBUSB_ERROR_NO_MEM => Error::NoMem,
         LIBUSB_ERROR_NOT_SUPPORTED => Error::NotSupported,
-        LIBUSB_ERROR_OTHER | _ => Error::Other,
+        _ => Error::Other,
+    }
+}
+/**
+ A scripted stand-in for a device, so code that sends commands and drives
+ an isochronous transfer can be tested without hardware. Completions are
+ queued up front and delivered one per call to `handle_events`, on the
+ calling thread, as libusb does.
+
+ Outside this crate's tests it needs the `fake-device` feature, and is
+ driven through `fault::FaultPlan` and `ReceiverBuilder::build_fake`.
+ */
+#[cfg(any(test, feature = "fake-device"))]
+pub mod fake {
+    use super::{IsoPackets, TransferCallback};
+    use crate::codec::FrameFormat;
+    use rusb::ffi::constants::{LIBUSB_TRANSFER_COMPLETED, LIBUSB_TRANSFER_ERROR};
+    use rusb::ffi::libusb_iso_packet_descriptor;
+    use std::os::raw::c_uint;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /** One completion of the transfer: its status, and what its packets read. */
+    pub(crate) struct Completion {
+        pub result: rusb::Result<()>,
+        pub payload: Payload,
+    }
+
+    pub(crate) enum Payload {
+        /** Bytes filling the packets in turn, with any packets beyond them empty. */
+        Flat(Vec<u8>),
+        /** Each packet's own data, or the error it failed with. */
+        Packets(Vec<rusb::Result<Vec<u8>>>),
+    }
+
+    /** A transfer's callback, owned by the transfer as with libusb. */
+    type Callback = Arc<Mutex<Box<dyn TransferCallback + Send>>>;
+
+    /** The submitted transfer. */
+    #[derive(Clone)]
+    struct Active {
+        id: usize,
+        callback: Callback,
+        packet_count: usize,
+        packet_length: usize,
     }
-}
\ No newline at end of file
+
+    /** A simulated AR2300 IQ device. */
+    #[derive(Default)]
+    pub struct FakeDevice {
+        /** Every bulk write, with its endpoint. */
+        pub(crate) writes: Mutex<Vec<(u8, Vec<u8>)>>,
+        /** The number of transfers submitted. */
+        pub(crate) submits: AtomicUsize,
+        pub(crate) fail_submit: AtomicBool,
+        pub(crate) fail_events: AtomicBool,
+        /** Frame formats the device can be asked for besides the AR2300's. */
+        pub(crate) extra_formats: Mutex<Vec<FrameFormat>>,
+        completions: Mutex<VecDeque<Completion>>,
+        active: Mutex<Option<Active>>,
+    }
+
+    /**
+     Copy each packet's data into its slot in the callback's buffer and call
+     it, describing the packets as libusb would.
+     */
+    fn deliver(active: &Active, completion: &Completion) -> bool {
+        let packet_length = active.packet_length;
+        let packets: Vec<rusb::Result<&[u8]>> = match &completion.payload {
+            Payload::Flat(data) => data.chunks(packet_length).map(Ok).collect(),
+            Payload::Packets(packets) => packets.iter().map(|p| p.as_ref().map(|d| d.as_slice()).map_err(|e| *e)).collect(),
+        };
+        let mut callback = active.callback.lock().unwrap();
+        let buffer = callback.buffer();
+        let descriptors: Vec<libusb_iso_packet_descriptor> = (0..active.packet_count).map(|i| {
+            let offset = i * packet_length;
+            let (status, actual_length) = match packets.get(i) {
+                Some(Ok(data)) => {
+                    let len = data.len().min(packet_length).min(buffer.len().saturating_sub(offset));
+                    buffer[offset..offset + len].copy_from_slice(&data[..len]);
+                    (LIBUSB_TRANSFER_COMPLETED, len)
+                },
+                Some(Err(_)) => (LIBUSB_TRANSFER_ERROR, 0),
+                None => (LIBUSB_TRANSFER_COMPLETED, 0)
+            };
+            libusb_iso_packet_descriptor {
+                length: packet_length as c_uint,
+                actual_length: actual_length as c_uint,
+                status,
+            }
+        }).collect();
+        callback.callback(completion.result, &IsoPackets::new(&descriptors))
+    }
+
+    impl FakeDevice {
+        pub fn new() -> FakeDevice {
+            FakeDevice::default()
+        }
+
+        /** Queue a completion for the transfer, its data filling the packets in turn. */
+        pub fn complete(&self, result: rusb::Result<()>, data: Vec<u8>) {
+            self.completions.lock().unwrap().push_back(Completion { result, payload: Payload::Flat(data) });
+        }
+
+        /**
+         Queue a successful completion giving each packet its own data, so
+         packets can come back short, empty or failed. Packets beyond those
+         given are empty.
+         */
+        pub fn complete_packets(&self, packets: Vec<rusb::Result<Vec<u8>>>) {
+            self.completions.lock().unwrap().push_back(Completion { result: Ok(()), payload: Payload::Packets(packets) });
+        }
+
+        /** Queue a successful completion with the given data. */
+        pub fn complete_ok(&self, data: Vec<u8>) {
+            self.complete(Ok(()), data);
+        }
+
+        /** Completions not yet delivered. */
+        pub fn pending(&self) -> usize {
+            self.completions.lock().unwrap().len()
+        }
+
+        /** True while a submitted transfer hasn't had its final callback. */
+        pub fn is_active(&self) -> bool {
+            self.active.lock().unwrap().is_some()
+        }
+
+        /** The bulk writes made so far, without their endpoints. */
+        pub fn written(&self) -> Vec<Vec<u8>> {
+            self.writes.lock().unwrap().iter().map(|(_, data)| data.clone()).collect()
+        }
+
+        pub(crate) fn write_bulk(&self, endpoint: u8, data: &[u8]) -> rusb::Result<usize> {
+            self.writes.lock().unwrap().push((endpoint, data.to_vec()));
+            Ok(data.len())
+        }
+
+        pub(crate) fn submit<T: TransferCallback + Send + 'static>(self: &Arc<Self>,
+                                                            packet_count: usize,
+                                                            packet_length: usize,
+                                                            callback: Box<T>) -> rusb::Result<FakeTransfer> {
+            if self.fail_submit.load(Ordering::Relaxed) {
+                return Err(rusb::Error::Io);
+            }
+            let mut active = self.active.lock().unwrap();
+            if active.is_some() {
+                return Err(rusb::Error::Busy);
+            }
+            let id = self.submits.fetch_add(1, Ordering::Relaxed) + 1;
+            let callback: Callback = Arc::new(Mutex::new(callback));
+            *active = Some(Active { id, callback: callback.clone(), packet_count, packet_length });
+            Ok(FakeTransfer { id, device: self.clone(), _callback: callback })
+        }
+
+        /**
+         Deliver the next completion, if a transfer is active and one is
+         queued, otherwise wait a little. The transfer finishes when its
+         callback returns false.
+         */
+        pub(crate) fn handle_events(&self, timeout: Option<Duration>) -> rusb::Result<()> {
+            if self.fail_events.load(Ordering::Relaxed) {
+                return Err(rusb::Error::Io);
+            }
+            let next = match self.active.lock().unwrap().as_ref() {
+                Some(active) => self.completions.lock().unwrap().pop_front()
+                    .map(|c| (active.clone(), c)),
+                None => None
+            };
+            match next {
+                Some((active, completion)) => self.call(&active, &completion),
+                None => std::thread::sleep(timeout.unwrap_or(Duration::MAX).min(Duration::from_millis(1)))
+            }
+            Ok(())
+        }
+
+        fn call(&self, called: &Active, completion: &Completion) {
+            // Called without the lock held, as the callback may be slow
+            let resubmit = deliver(called, completion);
+            if !resubmit {
+                let mut active = self.active.lock().unwrap();
+                if active.as_ref().map(|a| a.id) == Some(called.id) {
+                    *active = None;
+                }
+            }
+        }
+
+    }
+
+    /** A transfer submitted to a FakeDevice. Its callback is freed along with it. */
+    pub(crate) struct FakeTransfer {
+        id: usize,
+        device: Arc<FakeDevice>,
+        _callback: Callback,
+    }
+
+    impl FakeTransfer {
+        /** Cancel the transfer. Its callback gets a final `Interrupted` if it was still in flight. */
+        pub fn close(self) -> bool {
+            let mut active = self.device.active.lock().unwrap();
+            if active.as_ref().map(|a| a.id) == Some(self.id) {
+                let active = active.take().unwrap();
+                let completion = Completion { result: Err(rusb::Error::Interrupted), payload: Payload::Flat(Vec::new()) };
+                deliver(&active, &completion);
+            }
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(length: usize, actual_length: usize, status: c_int) -> libusb_iso_packet_descriptor {
+        libusb_iso_packet_descriptor {
+            length: length as c_uint,
+            actual_length: actual_length as c_uint,
+            status,
+        }
+    }
+
+    #[test]
+    fn packets_report_what_they_received_at_their_offsets() {
+        let descriptors = [
+            packet(4, 4, LIBUSB_TRANSFER_COMPLETED),
+            packet(4, 2, LIBUSB_TRANSFER_COMPLETED),
+            packet(4, 4, LIBUSB_TRANSFER_ERROR),
+            packet(4, 0, LIBUSB_TRANSFER_COMPLETED),
+            packet(4, 3, LIBUSB_TRANSFER_COMPLETED),
+        ];
+        let packets = IsoPackets::new(&descriptors);
+        assert_eq!(packets.iter().collect::<Vec<_>>(), vec![(0, 4), (4, 2), (8, 0), (12, 0), (16, 3)]);
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets.completed(), 3);
+        assert_eq!(packets.received(), 9);
+    }
+
+    #[test]
+    fn compacting_drops_the_gaps_between_packets() {
+        let descriptors = [
+            packet(4, 2, LIBUSB_TRANSFER_COMPLETED),
+            packet(4, 4, LIBUSB_TRANSFER_ERROR),
+            packet(4, 3, LIBUSB_TRANSFER_COMPLETED),
+        ];
+        let mut buffer = *b"aaxxbbbbccc.";
+        assert_eq!(IsoPackets::new(&descriptors).compact(&mut buffer), 5);
+        assert_eq!(&buffer[..5], b"aaccc");
+    }
+
+    #[test]
+    fn compacting_stays_within_the_buffer() {
+        let descriptors = [packet(4, 4, LIBUSB_TRANSFER_COMPLETED), packet(4, 4, LIBUSB_TRANSFER_COMPLETED)];
+        let mut buffer = *b"aaaabb";
+        assert_eq!(IsoPackets::new(&descriptors).compact(&mut buffer), 6);
+        assert!(IsoPackets::new(&[]).is_empty());
+    }
+}
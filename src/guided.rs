@@ -0,0 +1,227 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the AR2300 library.
+
+    The AR2300 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use ar2300::firmware::is_programmed;
+use ar2300::iq_device;
+use ar2300::usb::device_info;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/** Asks the user questions. Abstracted so the guided flow doesn't depend on a terminal. */
+pub trait Prompt {
+    /** Show a line of information. */
+    fn say(&mut self, message: &str);
+    /** Ask a yes/no question. */
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool>;
+    /** Ask for a value, returning the default if the answer is empty. */
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String>;
+}
+
+/** Prompts on stdout and reads answers from stdin. */
+pub struct TerminalPrompt;
+
+impl TerminalPrompt {
+    fn read_line(&self, prompt: &str) -> io::Result<String> {
+        print!("{} ", prompt);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No answer"));
+        }
+        Ok(line.trim().to_string())
+    }
+}
+
+impl Prompt for TerminalPrompt {
+    fn say(&mut self, message: &str) {
+        println!("{}", message);
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "[Y/n]" } else { "[y/N]" };
+        let answer = self.read_line(&format!("{} {}", question, hint))?;
+        Ok(match answer.to_lowercase().as_str() {
+            "" => default,
+            a => a.starts_with('y')
+        })
+    }
+
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        let answer = self.read_line(&format!("{} [{}]", question, default))?;
+        if answer.is_empty() {
+            Ok(default.to_string())
+        } else {
+            Ok(answer)
+        }
+    }
+}
+
+/** The device the guided flow found, and whether it needs its firmware. */
+pub struct FoundDevice {
+    pub info: String,
+    pub programmed: bool,
+}
+
+/** Look for the AR2300 IQ device. */
+pub fn detect() -> Option<FoundDevice> {
+    iq_device().map(|device| FoundDevice { info: device_info(&device), programmed: is_programmed(&device) })
+}
+
+/**
+ Walk the user through a capture. Returns the command line arguments to
+ run it with, or None if the user backed out.
+ */
+pub fn plan(prompt: &mut dyn Prompt) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    plan_for(prompt, detect(), SystemTime::now())
+}
+
+/** Walk the user through a capture on `device`, as `plan` does, naming the output for the time `now`. */
+pub fn plan_for(prompt: &mut dyn Prompt, device: Option<FoundDevice>, now: SystemTime)
+    -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let device = match device {
+        Some(device) => device,
+        None => return Err("No AR2300 IQ device found. Is it plugged in and powered on?".into())
+    };
+    prompt.say(&format!("Found IQ device: {}", device.info));
+    if device.programmed {
+        prompt.say("The firmware is already loaded.");
+    } else {
+        prompt.say("The device needs its firmware loaded before it can capture.");
+        if !prompt.confirm("Load the firmware now?", true)? {
+            return Ok(None);
+        }
+    }
+    let secs = now.duration_since(UNIX_EPOCH)?.as_secs();
+    prompt.say("Samples are written as interleaved big-endian f32 I and Q values.");
+    let output = prompt.ask("Output file", &format!("iq-{}.bin", secs))?;
+    if !prompt.confirm(&format!("Record to {}? Press Ctrl-C to stop.", output), true)? {
+        return Ok(None);
+    }
+    Ok(Some(vec!["ar2300".to_string(), "--output".to_string(), output]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use clap::Clap;
+    use std::collections::VecDeque;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /**
+     Answers each question from a script, in order, and keeps a transcript
+     of everything shown and asked. An empty answer takes the default, as
+     on a terminal; running out of answers is an error, like end of input.
+     */
+    struct ScriptedPrompt {
+        answers: VecDeque<&'static str>,
+        transcript: Vec<String>,
+    }
+
+    impl ScriptedPrompt {
+        fn new(answers: &[&'static str]) -> ScriptedPrompt {
+            ScriptedPrompt { answers: answers.iter().copied().collect(), transcript: Vec::new() }
+        }
+
+        fn answer(&mut self, question: &str) -> io::Result<&'static str> {
+            self.transcript.push(format!("? {}", question));
+            self.answers.pop_front().ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "No answer"))
+        }
+    }
+
+    impl Prompt for ScriptedPrompt {
+        fn say(&mut self, message: &str) {
+            self.transcript.push(message.to_string());
+        }
+
+        fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+            Ok(match self.answer(question)? {
+                "" => default,
+                a => a.starts_with('y')
+            })
+        }
+
+        fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+            Ok(match self.answer(question)? {
+                "" => default.to_string(),
+                a => a.to_string()
+            })
+        }
+    }
+
+    fn device(programmed: bool) -> Option<FoundDevice> {
+        Some(FoundDevice { info: "Bus 001 Device 004: ID 08d0:a001".to_string(), programmed })
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn defaults_plan_a_capture_to_a_timestamped_file() {
+        let mut prompt = ScriptedPrompt::new(&["", "", ""]);
+        let args = plan_for(&mut prompt, device(false), at(1_700_000_000)).unwrap().unwrap();
+        assert_eq!(args, ["ar2300", "--output", "iq-1700000000.bin"]);
+        assert_eq!(prompt.transcript, [
+            "Found IQ device: Bus 001 Device 004: ID 08d0:a001",
+            "The device needs its firmware loaded before it can capture.",
+            "? Load the firmware now?",
+            "Samples are written as interleaved big-endian f32 I and Q values.",
+            "? Output file",
+            "? Record to iq-1700000000.bin? Press Ctrl-C to stop.",
+        ]);
+        let opts = Opts::try_parse_from(&args).unwrap();
+        assert_eq!(opts.output, Path::new("iq-1700000000.bin"));
+        assert!(opts.command.is_none());
+    }
+
+    #[test]
+    fn a_programmed_device_is_not_offered_the_firmware() {
+        let mut prompt = ScriptedPrompt::new(&["capture.bin", "yes"]);
+        let args = plan_for(&mut prompt, device(true), at(0)).unwrap().unwrap();
+        assert_eq!(args, ["ar2300", "--output", "capture.bin"]);
+        assert!(prompt.transcript.contains(&"The firmware is already loaded.".to_string()));
+        assert!(!prompt.transcript.iter().any(|l| l.contains("firmware now")));
+    }
+
+    #[test]
+    fn declining_either_question_backs_out() {
+        let mut prompt = ScriptedPrompt::new(&["n"]);
+        assert_eq!(plan_for(&mut prompt, device(false), at(0)).unwrap(), None);
+        assert!(prompt.answers.is_empty());
+
+        let mut prompt = ScriptedPrompt::new(&["y", "", "no"]);
+        assert_eq!(plan_for(&mut prompt, device(false), at(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn no_device_or_no_answer_is_an_error() {
+        let mut prompt = ScriptedPrompt::new(&[]);
+        let e = plan_for(&mut prompt, None, at(0)).unwrap_err();
+        assert!(e.to_string().contains("No AR2300 IQ device found"), "{}", e);
+        assert!(prompt.transcript.is_empty());
+
+        let mut prompt = ScriptedPrompt::new(&["y"]);
+        let e = plan_for(&mut prompt, device(false), at(0)).unwrap_err();
+        assert_eq!(e.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
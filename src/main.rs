@@ -17,32 +17,557 @@
     along with the AR2300 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{error::Error, fs::File, thread::spawn};
-use ar2300::{init_device, new_queue, receive, write};
+use std::{error::Error, fs::File, path::PathBuf, process::{exit, Command}};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+use ar2300::{init_device_until, receive_with_accounting, write_with_accounting, Ar2300Error};
+use ar2300::accounting::SampleAccounting;
+use ar2300::budget::{MemoryBudget, DEFAULT_SYSTEM_FRACTION};
+use ar2300::cancel;
+use ar2300::capture_log::{capture_log_path, CaptureLog, Entry, DEFAULT_STATS_INTERVAL};
+use ar2300::events::EventLog;
+use ar2300::diagnostics::{check_bandwidth, required_byte_rate, BandwidthCheck, BandwidthReport, Decision, FileProbe, DEFAULT_MARGIN};
+use ar2300::message::{EnglishRenderer, MessageRenderer};
+use ar2300::probe::{Container, Detection};
+use ar2300::iq::{decode_raw, probe, sigmf_meta_path, Hook, RawFormat, ReceiverConfig, Rounding, SampleFormat, BYTES_PER_SAMPLE, SAMPLE_RATE};
+use ar2300::timeline::{format_time, parse_time, sigmf_timing, Gap, Position, Timeline};
+use ar2300::session::SessionId;
+use clap::{Clap, IntoApp};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
+mod guided;
+
+/// Record IQ data from an AOR AR2300
+#[derive(Clap)]
+#[clap(version = "0.1.0", author = "Andrew Young <andrew@vaelen.org>")]
+struct Opts {
+    /// File to write IQ data to. Named pipes are detected automatically
+    #[clap(short, long, default_value = "iq.bin", parse(from_os_str))]
+    output: PathBuf,
+    /// When the output is a named pipe, wait this long for a new reader if the current one goes away
+    #[clap(long, parse(try_from_str = parse_duration))]
+    fifo_reconnect: Option<Duration>,
+    /// Sync the output file to disk at this interval (e.g. 500ms, 10s, 1m)
+    #[clap(long, parse(try_from_str = parse_duration))]
+    sync_interval: Option<Duration>,
+    /// Command to run just before the capture starts
+    #[clap(long)]
+    pre_cmd: Option<String>,
+    /// Command to run after the capture stops and the output has been flushed
+    #[clap(long)]
+    post_cmd: Option<String>,
+    /// How long to wait for --pre-cmd and --post-cmd before killing them
+    #[clap(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    hook_timeout: Duration,
+    /// What to do when --pre-cmd or --post-cmd fails or times out
+    #[clap(long, default_value = "abort", possible_values = &["abort", "warn"])]
+    hook_failure: String,
+    /// Receiver settings: low-latency for interactive monitoring, robust for unattended recording
+    #[clap(long, default_value = "default", possible_values = &ReceiverConfig::PROFILES)]
+    profile: String,
+    /// Before starting, time a test write next to the output and check it can keep up with the stream
+    #[clap(long, default_value = "off", possible_values = &["off", "warn", "enforce"])]
+    bandwidth_check: String,
+    /// Load this Intel hex firmware file instead of the built-in firmware
+    #[clap(long, parse(from_os_str))]
+    firmware: Option<PathBuf>,
+    /// Don't keep a JSON-lines log of the capture's events next to the output, in OUTPUT.log
+    #[clap(long)]
+    no_capture_log: bool,
+    /// Capture from a simulated device following this TOML fault plan instead of an AR2300. Needs the fake-device feature
+    #[clap(long, parse(from_os_str))]
+    fault_plan: Option<PathBuf>,
+    /// Tag the capture's events and log with this UUID instead of a newly generated one
+    #[clap(long)]
+    session_id: Option<SessionId>,
+    /// Refuse to start if the receiver's buffers could need more memory than this (e.g. 64M, 1G), or "auto" for a quarter of the system's memory
+    #[clap(long, parse(try_from_str = parse_memory_budget))]
+    memory_budget: Option<MemoryBudget>,
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    DecodeRaw(DecodeRaw),
+    When(When),
+    Probe(Probe),
+}
+
+/// Decode a dump of raw USB transfer data into IQ samples
+#[derive(Clap)]
+struct DecodeRaw {
+    /// Raw transfer dump to read
+    #[clap(short, long, parse(from_os_str))]
+    input: PathBuf,
+    /// File to write IQ samples to
+    #[clap(short, long, parse(from_os_str))]
+    output: PathBuf,
+    /// Each transfer is preceded by its length as a little-endian u32
+    #[clap(long)]
+    length_prefixed: bool,
+}
+
+/// Convert between a sample index in a recording and the time it was taken
+#[derive(Clap)]
+struct When {
+    /// Recording of big-endian f32 I and Q samples, as written by default
+    #[clap(parse(from_os_str))]
+    recording: PathBuf,
+    /// Sample index to convert to a time
+    #[clap(long, required_unless_present = "time", conflicts_with = "time")]
+    sample: Option<u64>,
+    /// Time to convert to a sample index: RFC 3339 UTC, e.g. 2021-06-01T12:00:00.5Z, or Unix seconds
+    #[clap(long)]
+    time: Option<String>,
+    /// When the recording started, overriding its SigMF metadata. Same formats as --time
+    #[clap(long)]
+    start: Option<String>,
+    /// Measured sample rate, overriding its SigMF metadata
+    #[clap(long)]
+    rate: Option<f64>,
+    /// Samples missing from the recording, as INDEX:COUNT, where INDEX is the first sample after the gap
+    #[clap(long, parse(try_from_str = parse_gap), multiple_occurrences = true)]
+    gap: Vec<Gap>,
+}
+
+/// Work out what an IQ recording holds: its format, length and signal statistics
+#[derive(Clap)]
+struct Probe {
+    /// Recording to examine. Its SigMF metadata is read too, if there is any
+    #[clap(parse(from_os_str))]
+    recording: PathBuf,
+    /// Sample rate for the duration, overriding the file's own or the AR2300's
+    #[clap(long)]
+    rate: Option<f64>,
+}
+
+/** Parse a gap given as INDEX:COUNT. */
+fn parse_gap(s: &str) -> Result<Gap, String> {
+    let (at, missing) = s.split_once(':').ok_or_else(|| format!("Invalid gap: '{}'", s))?;
+    match (at.trim().parse(), missing.trim().parse()) {
+        (Ok(at), Ok(missing)) => Ok(Gap { at, missing }),
+        _ => Err(format!("Invalid gap: '{}'", s))
+    }
+}
+
+/** A size for --memory-budget, or "auto" for DEFAULT_SYSTEM_FRACTION of the system's memory. */
+fn parse_memory_budget(s: &str) -> Result<MemoryBudget, String> {
+    let budget = match s {
+        "auto" => MemoryBudget::of_system_memory(DEFAULT_SYSTEM_FRACTION),
+        s => s.parse()
+    };
+    budget.map_err(|e| RENDERER.error(&e))
+}
+
+/** Parse a duration with an optional ms, s, m, or h suffix. Plain numbers are seconds. */
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3600.0)
+    } else {
+        (s, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * scale)),
+        _ => Err(format!("Invalid duration: '{}'", s))
+    }
+}
+
+/** Format a count with commas between groups of three digits. */
+// `is_multiple_of` needs Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn group_digits(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/** Run a shell command, killing it if it runs longer than the timeout. */
+fn run_command(cmd: &str, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let mut child = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(cmd).spawn()?
+    } else {
+        Command::new("sh").arg("-c").arg(cmd).spawn()?
+    };
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(format!("'{}' failed: {}", cmd, status).into());
+        }
+        if started.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(format!("'{}' timed out after {:?}", cmd, timeout).into());
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
+
+/** Run a hook command, applying the --hook-failure policy to its result. */
+fn run_hook(cmd: &str, timeout: Duration, abort: bool) -> Result<(), Box<dyn Error>> {
+    match run_command(cmd, timeout) {
+        Err(e) if !abort => {
+            eprintln!("Warning: {}", e);
+            Ok(())
+        },
+        r => r
+    }
+}
+
+/**
+ Probe the output's write rate and apply the --bandwidth-check policy.
+ Returns what was found, or None for a named pipe, which isn't probed.
+ */
+fn check_output_bandwidth(opts: &Opts) -> Result<Option<BandwidthReport>, Box<dyn Error>> {
+    let policy = match opts.bandwidth_check.as_str() {
+        "warn" => BandwidthCheck::Warn,
+        "enforce" => BandwidthCheck::Enforce,
+        _ => BandwidthCheck::Off
+    };
+    #[cfg(unix)]
+    if policy != BandwidthCheck::Off && ar2300::fifo::is_fifo(&opts.output) {
+        println!("Skipping bandwidth check for a named pipe");
+        return Ok(None);
+    }
+    let required = required_byte_rate(SampleFormat::default());
+    // One second's worth of data
+    let report = check_bandwidth(policy, required, DEFAULT_MARGIN, &FileProbe::new(&opts.output), required as u64)?;
+    if report.decision == Decision::Refused {
+        return Err(format!("{} can't keep up with the IQ stream", opts.output.display()).into());
+    }
+    Ok(Some(report))
+}
+
+/** Prints the library's log messages: problems to stderr, everything else to stdout. */
+struct ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("{}", record.args()),
+            Level::Warn => eprintln!("Warning: {}", record.args()),
+            _ => println!("{}", record.args())
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+/** Renders the library's errors for the user. Another renderer would show them in another language. */
+static RENDERER: &(dyn MessageRenderer + Sync) = &EnglishRenderer;
+
+/** The conventional exit status for a usage error. */
+const EXIT_USAGE: i32 = 2;
+
+/** The conventional exit status for a process stopped by Ctrl-C. */
+const EXIT_INTERRUPTED: i32 = 130;
+
+type Output = Box<dyn Write + Send>;
+
+/** Open the output, returning the writer and, if syncing was requested, a handle to sync. */
+fn open_output(opts: &Opts) -> Result<(Output, Option<File>), Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use ar2300::fifo::{is_fifo, FifoWriter, PipePolicy};
+        if is_fifo(&opts.output) {
+            let policy = match opts.fifo_reconnect {
+                Some(timeout) => PipePolicy::Reconnect(timeout),
+                None => PipePolicy::Terminate
+            };
+            println!("Waiting for a reader to open {}", opts.output.display());
+            let w = FifoWriter::open(&opts.output, policy, Duration::from_secs(u64::MAX))?;
+            return Ok((Box::new(w), None));
+        }
+    }
+    let file = File::create(&opts.output)?;
+    let sync_file = match opts.sync_interval {
+        Some(_) => Some(file.try_clone()?),
+        None => None
+    };
+    Ok((Box::new(file), sync_file))
+}
+
+fn decode_raw_file(opts: &DecodeRaw) -> Result<(), Box<dyn Error>> {
+    let format = if opts.length_prefixed {
+        RawFormat::LengthPrefixed
+    } else {
+        RawFormat::Flat
+    };
+    let mut input = BufReader::new(File::open(&opts.input)?);
+    let mut output = BufWriter::new(File::create(&opts.output)?);
+    let report = decode_raw(&mut input, format, Rounding::default(), &mut output)?;
+    println!("Transfers: {}, Bytes: {}, Samples: {}", report.transfers, report.bytes, report.samples);
+    println!("Unsynced transfers: {}, Skipped bytes: {}, Invalid packets: {}, Partial bytes: {}",
+             report.unsynced_transfers, report.skipped_bytes, report.invalid_packets, report.partial_bytes);
+    Ok(())
+}
+
+/**
+ Build the recording's timeline from its SigMF metadata, if it has any,
+ and the options, which take precedence.
+ */
+fn recording_timeline(opts: &When) -> Result<Timeline, Box<dyn Error>> {
+    let samples = std::fs::metadata(&opts.recording)?.len() / BYTES_PER_SAMPLE;
+    let (meta_rate, meta_start) = match std::fs::read_to_string(sigmf_meta_path(&opts.recording)) {
+        Ok(meta) => sigmf_timing(&meta),
+        Err(_) => (None, None)
+    };
+    let start = match &opts.start {
+        Some(s) => parse_time(s).ok_or_else(|| format!("Invalid start time: '{}'", s))?,
+        None => meta_start.ok_or("The recording's start time isn't known; give it with --start")?
+    };
+    let rate = opts.rate.or(meta_rate).unwrap_or(SAMPLE_RATE as f64);
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(format!("Invalid sample rate: {}", rate).into());
+    }
+    Ok(Timeline::new(start, rate, samples, &opts.gap))
+}
+
+fn when(opts: &When) -> Result<(), Box<dyn Error>> {
+    let timeline = recording_timeline(opts)?;
+    if let Some(index) = opts.sample {
+        if index >= timeline.samples() {
+            eprintln!("Warning: the recording has only {} samples; extrapolating", group_digits(timeline.samples()));
+        }
+        let time = timeline.time_at(index).ok_or("That sample's time is too far off to represent")?;
+        println!("{}", format_time(time));
+    } else if let Some(time) = &opts.time {
+        let time = parse_time(time).ok_or_else(|| format!("Invalid time: '{}'", time))?;
+        match timeline.index_at(time) {
+            Position::Before => println!("Before the recording started at {}", format_time(timeline.start())),
+            Position::Sample(index) => println!("{}", index),
+            Position::InGap { next } => println!("In a gap; the next sample is {}", next),
+            Position::After => println!("After the recording's last sample, {}", timeline.samples().saturating_sub(1)),
+        }
+    }
+    Ok(())
+}
+
+fn probe_file(opts: &Probe) -> Result<(), Box<dyn Error>> {
+    let report = probe(&opts.recording)?;
+    println!("Size: {} bytes", group_digits(report.len));
+    match &report.container {
+        Container::Raw => println!("Container: none"),
+        Container::Wav { audio_format, channels, sample_rate, bits_per_sample, data_offset, data_len } =>
+            println!("Container: WAV, format {}, {} channels, {} Hz, {} bits, {} bytes of samples at offset {}",
+                     audio_format, channels, sample_rate, bits_per_sample, group_digits(*data_len), data_offset),
+    }
+    if let Some(sigmf) = &report.sigmf {
+        println!("SigMF metadata: datatype {}, sample rate {}, start {}",
+                 sigmf.datatype.as_deref().unwrap_or("not given"),
+                 sigmf.sample_rate.map_or("not given".to_string(), |r| format!("{} Hz", r)),
+                 sigmf.start.map_or("not given".to_string(), format_time));
+    }
+    for guess in &report.candidates {
+        match guess.plausibility {
+            Some(p) => println!("  {}: {:.1}% of samples plausible", guess.format.sigmf_datatype(), p * 100.0),
+            None => println!("  {}: can't be judged from the values", guess.format.sigmf_datatype()),
+        }
+    }
+    match &report.detection {
+        Detection::Declared(format) => println!("Format: {} (declared)", format.sigmf_datatype()),
+        Detection::Detected(format) => println!("Format: {} (detected)", format.sigmf_datatype()),
+        Detection::Ambiguous(formats) => {
+            let names: Vec<&str> = formats.iter().map(|f| f.sigmf_datatype()).collect();
+            println!("Format: uncertain, could be any of {}", names.join(", "));
+        },
+        Detection::Unknown => println!("Format: unknown"),
+    }
+    if let Some(samples) = report.samples {
+        let rate = opts.rate.or_else(|| report.sample_rate()).unwrap_or(SAMPLE_RATE as f64);
+        match report.duration(rate) {
+            Some(duration) => println!("Samples: {}, {:.3} s at {} Hz", group_digits(samples), duration.as_secs_f64(), rate),
+            None => println!("Samples: {}", group_digits(samples)),
+        }
+    }
+    if let Some(stats) = &report.stats {
+        println!("Over the first {} samples: RMS {:.6}, peak {:.6}, DC offset ({:.6}, {:.6})",
+                 group_digits(stats.samples), stats.rms, stats.peak, stats.dc_offset.0, stats.dc_offset.1);
+    }
+    for warning in &report.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(())
+}
+
+/** Where the samples come from: the AR2300, or with the fake-device feature, a simulated device. */
+enum Source {
+    Device,
+    #[cfg(feature = "fake-device")]
+    Simulated(ar2300::fault::FaultPlan),
+}
+
+fn source(opts: &Opts) -> Result<Source, Box<dyn Error>> {
+    match &opts.fault_plan {
+        None => Ok(Source::Device),
+        #[cfg(feature = "fake-device")]
+        Some(path) => Ok(Source::Simulated(ar2300::fault::FaultPlan::load(path).map_err(|e| RENDERER.error(&e))?)),
+        #[cfg(not(feature = "fake-device"))]
+        Some(_) => Err("--fault-plan needs a build with the fake-device feature".into()),
+    }
+}
+
+/**
+ With no arguments, guide the user through a capture on a terminal, or
+ print usage and fail anywhere else rather than start capturing.
+ */
 fn main() -> Result<(),Box<dyn Error>> {
-    let filename = "iq.bin";
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(LevelFilter::Info);
+    if std::env::args_os().len() > 1 {
+        return run(Opts::parse());
+    }
+    if !io::stdin().is_terminal() {
+        Opts::into_app().print_help()?;
+        println!();
+        exit(EXIT_USAGE);
+    }
+    match guided::plan(&mut guided::TerminalPrompt)? {
+        Some(args) => run(Opts::parse_from(args)),
+        None => Ok(())
+    }
+}
+
+fn run(opts: Opts) -> Result<(),Box<dyn Error>> {
+    match &opts.command {
+        Some(SubCommand::DecodeRaw(decode_opts)) => return decode_raw_file(decode_opts),
+        Some(SubCommand::When(when_opts)) => return when(when_opts),
+        Some(SubCommand::Probe(probe_opts)) => return probe_file(probe_opts),
+        None => {}
+    }
     //ar2300::usb::list_devices();
-    init_device(true)?;
-    let f = Box::new(File::create(filename)?);
-    let q = new_queue();
+    let source = source(&opts)?;
+    let cancel = cancel::on_ctrlc()?;
+    if opts.fault_plan.is_none() {
+        match init_device_until(true, opts.firmware.as_deref(), &cancel) {
+            Err(Ar2300Error::Cancelled) => {
+                eprintln!("Interrupted");
+                exit(EXIT_INTERRUPTED);
+            },
+            r => r.map_err(|e| RENDERER.error(&e))?
+        }
+    }
+    let session = opts.session_id.unwrap_or_else(SessionId::generate);
+    let config = ReceiverConfig {
+        session: Some(session),
+        memory_budget: opts.memory_budget,
+        ..ReceiverConfig::profile(&opts.profile).unwrap_or_default()
+    };
+    if let Some(budget) = opts.memory_budget {
+        // Fail before the capture log and the output are created
+        budget.check(config.allocations()).map_err(|e| RENDERER.error(&e))?;
+    }
+    let bandwidth = check_output_bandwidth(&opts)?;
+    let (f, sync_file) = open_output(&opts)?;
+    let capture_log = if opts.no_capture_log {
+        None
+    } else {
+        let mut log = CaptureLog::create(capture_log_path(&opts.output)).map_err(|e| RENDERER.error(&e))?;
+        log.record(&Entry::Start { recording: opts.output.display().to_string(), profile: opts.profile.clone(), session: Some(session) })
+            .map_err(|e| RENDERER.error(&e))?;
+        if let Some(report) = bandwidth {
+            log.record(&Entry::Bandwidth(report)).map_err(|e| RENDERER.error(&e))?;
+        }
+        Some(log)
+    };
+    let sync_interval = opts.sync_interval;
+    let hook_timeout = opts.hook_timeout;
+    let abort_on_hook_failure = opts.hook_failure == "abort";
+    let before_start: Option<Hook> = opts.pre_cmd.clone().map(|cmd| -> Hook {
+        Box::new(move || run_hook(&cmd, hook_timeout, abort_on_hook_failure))
+    });
+    let q = config.new_queue();
     let read_q = q.clone();
     let write_q = q.clone();
+    let accounting = Arc::new(SampleAccounting::new());
+    let read_accounting = Some(accounting.clone());
+    let write_accounting = Some(accounting.clone());
+    let follower = capture_log.map(|log| log.follow(EventLog::global(), accounting.clone(), q.clone(), DEFAULT_STATS_INTERVAL));
 
     let r = spawn(move || {
-        if let Err(e) = receive(read_q) {
-            eprint!("Error reading from radio: {}", e);
+        let result = match source {
+            Source::Device => receive_with_accounting(read_q, cancel, config, read_accounting, before_start, None),
+            #[cfg(feature = "fake-device")]
+            Source::Simulated(plan) => ar2300::receive_fault_plan(read_q, cancel, config, read_accounting, before_start, None, &plan),
+        };
+        if let Err(e) = result {
+            eprint!("Error reading from radio: {}", RENDERER.error(&e));
         }
     });
         
-    let w = spawn(|| {
-        if let Err(e) = write(write_q, f) {
-            eprint!("Error writing to file: {}", e);
+    let w = spawn(move || {
+        if let Err(e) = write_with_accounting(write_q, f, sync_file, sync_interval, write_accounting) {
+            eprint!("Error writing to file: {}", RENDERER.error(&e));
         }
     });
 
     r.join().unwrap();
     w.join().unwrap();
 
+    // Reports a ledger that doesn't balance, or that disagrees with why the queue was closed
+    let summary = accounting.reconcile_closed(q.len() as u64, q.close_reason().as_ref());
+    println!("{} samples captured, {} written, {} dropped", group_digits(summary.received),
+             group_digits(summary.written), group_digits(summary.dropped));
+    if let Some(follower) = follower {
+        follower.finish().map_err(|e| RENDERER.error(&e))?;
+    }
+
+    // Run after the writer has flushed, so the command sees the complete file.
+    if let Some(cmd) = &opts.post_cmd {
+        run_hook(cmd, hook_timeout, abort_on_hook_failure)?;
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_an_optional_unit() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration(" 1.5m ").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("3").unwrap(), Duration::from_secs(3));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn gaps_and_counts_are_read_and_shown() {
+        assert_eq!(parse_gap("1000:24").unwrap(), Gap { at: 1000, missing: 24 });
+        assert!(parse_gap("1000").is_err());
+        assert!(parse_gap("a:b").is_err());
+        assert_eq!(group_digits(0), "0");
+        assert_eq!(group_digits(999), "999");
+        assert_eq!(group_digits(1_234_567), "1,234,567");
+    }
+}